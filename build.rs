@@ -0,0 +1,55 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Formats days since the Unix epoch as a `YYYY-MM-DD` civil date.
+fn civil_date(days: i64) -> String {
+  // Days to civil date conversion, working in 400 years eras starting on March 1st
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let doe = z.rem_euclid(146097);
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+  format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Short SHA of the commit being built, from `GIT_SHA` if set (for builds without the repository, like Docker ones).
+fn git_sha() -> String {
+  if let Ok(sha) = std::env::var("GIT_SHA") {
+    return sha;
+  }
+  Command::new("git").args(["rev-parse", "--short", "HEAD"]).output().ok()
+    .filter(|output| output.status.success())
+    .and_then(|output| String::from_utf8(output.stdout).ok())
+    .map(|sha| sha.trim().to_string())
+    .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn main() {
+
+  // Rebuild when the checked out commit changes
+  println!("cargo:rerun-if-env-changed=GIT_SHA");
+  println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+  println!("cargo:rerun-if-changed=.git/HEAD");
+  if let Some(head) = std::fs::read_to_string(".git/HEAD").ok().and_then(|head| head.strip_prefix("ref: ").map(|r| r.trim().to_string())) {
+    println!("cargo:rerun-if-changed=.git/{}", head);
+  }
+
+  // Reproducible builds pin the date with SOURCE_DATE_EPOCH
+  let timestamp = std::env::var("SOURCE_DATE_EPOCH").ok()
+    .and_then(|epoch| epoch.parse::<i64>().ok())
+    .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64);
+
+  let mut features = std::env::vars()
+    .filter_map(|(name, _)| name.strip_prefix("CARGO_FEATURE_").map(|feature| feature.to_lowercase().replace('_', "-")))
+    .collect::<Vec<_>>();
+  features.sort();
+
+  println!("cargo:rustc-env=BUILD_GIT_SHA={}", git_sha());
+  println!("cargo:rustc-env=BUILD_DATE={}", civil_date(timestamp.div_euclid(24 * 60 * 60)));
+  println!("cargo:rustc-env=BUILD_FEATURES={}", features.join(","));
+
+}
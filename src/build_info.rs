@@ -0,0 +1,15 @@
+//! Metadata of the build, collected by the build script.
+
+/// Version of the crate, followed by the commit, the build date and the enabled features.
+pub const LONG_VERSION: &str = concat!(
+  env!("CARGO_PKG_VERSION"), " (", env!("BUILD_GIT_SHA"), " ", env!("BUILD_DATE"), ", features: [", env!("BUILD_FEATURES"), "])"
+);
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_SHA: &str = env!("BUILD_GIT_SHA");
+pub const BUILD_DATE: &str = env!("BUILD_DATE");
+
+/// Cargo features the binary was compiled with.
+pub fn features() -> Vec<&'static str> {
+  env!("BUILD_FEATURES").split(',').filter(|feature| !feature.is_empty()).collect()
+}
@@ -0,0 +1,112 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use tokio::task;
+
+use crate::cache::{CacheBackend, CacheEntry, Keyspace};
+
+/// Memcached keys cannot be longer than this.
+const MAX_KEY_LENGTH: usize = 250;
+
+/// Memcached interprets expirations longer than 30 days as absolute Unix timestamps.
+const MAX_RELATIVE_EXPIRATION: u64 = 60 * 60 * 24 * 30;
+
+/// A [`CacheBackend`](crate::cache::CacheBackend) storing entries in a memcached cluster,
+/// shared among all the replicas of the application.
+///
+/// The underlying client is blocking, so all the operations are run on the blocking thread pool.
+#[derive(Clone)]
+pub struct MemcachedCache {
+  client: memcache::Client
+}
+
+impl MemcachedCache {
+
+  /// Connects to the memcached servers at the given urls, like `memcache://127.0.0.1:11211`.
+  pub async fn connect(urls: Vec<String>) -> Result<Self> {
+    let client = task::spawn_blocking(move || memcache::Client::connect(urls))
+      .await?
+      .context("Cannot connect to memcached")?;
+    Ok(MemcachedCache { client })
+  }
+
+  /// Builds the memcached key for an entry, escaping the characters memcached does not accept.
+  /// Returns `None` if the resulting key is too long.
+  fn entry_key(keyspace: Keyspace, key: &str) -> Option<String> {
+    let escaped = form_urlencoded::byte_serialize(key.as_bytes()).collect::<String>();
+    let key = format!("pokechallenge:{}:{}", keyspace.name(), escaped);
+    if key.len() <= MAX_KEY_LENGTH { Some(key) } else { None }
+  }
+
+  fn expiration(ttl: Option<Duration>) -> u32 {
+    match ttl {
+      None => 0,
+      Some(ttl) => {
+        let secs = ttl.as_secs().max(1);
+        if secs <= MAX_RELATIVE_EXPIRATION {
+          secs as u32
+        } else {
+          let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+          (now + secs).min(u32::MAX as u64) as u32
+        }
+      }
+    }
+  }
+
+}
+
+#[async_trait]
+impl CacheBackend for MemcachedCache {
+
+  fn name(&self) -> &'static str {
+    "memcached"
+  }
+
+  async fn get(&self, keyspace: Keyspace, key: &str) -> Result<Option<CacheEntry>> {
+    let key = match MemcachedCache::entry_key(keyspace, key) {
+      Some(key) => key,
+      None => return Ok(None)
+    };
+    let client = self.client.clone();
+    let value = task::spawn_blocking(move || client.get::<Vec<u8>>(&key)).await??;
+    Ok(value.and_then(|value| CacheEntry::decode(&value)))
+  }
+
+  async fn put(&self, keyspace: Keyspace, key: &str, entry: &CacheEntry, ttl: Option<Duration>) -> Result<()> {
+    let key = match MemcachedCache::entry_key(keyspace, key) {
+      Some(key) => key,
+      None => return Ok(())
+    };
+    let value = entry.encode()?;
+    let client = self.client.clone();
+    task::spawn_blocking(move || client.set(&key, value.as_slice(), MemcachedCache::expiration(ttl))).await??;
+    Ok(())
+  }
+
+  async fn ping(&self) -> Result<()> {
+    let client = self.client.clone();
+    task::spawn_blocking(move || client.version()).await??;
+    Ok(())
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_entry_key() {
+    assert_eq!(MemcachedCache::entry_key(Keyspace::Translations, "mr. mime").unwrap(), "pokechallenge:translations:mr.+mime");
+    assert!(MemcachedCache::entry_key(Keyspace::Translations, &"a".repeat(300)).is_none());
+  }
+
+  #[test]
+  fn test_expiration() {
+    assert_eq!(MemcachedCache::expiration(None), 0);
+    assert_eq!(MemcachedCache::expiration(Some(Duration::from_secs(60))), 60);
+    assert!(MemcachedCache::expiration(Some(Duration::from_secs(MAX_RELATIVE_EXPIRATION + 1))) as u64 > MAX_RELATIVE_EXPIRATION);
+  }
+
+}
@@ -0,0 +1,324 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use tracing::debug;
+
+use crate::cache::{CacheEntry, Keyspace};
+use crate::metrics;
+
+struct Slot {
+  entry: CacheEntry,
+  expires_at: Option<Instant>
+}
+
+/// Estimated bookkeeping of each entry besides its strings: the key and the slot themselves,
+/// plus the pointers of the LRU list and of the hash table.
+const ENTRY_OVERHEAD: usize = std::mem::size_of::<(String, Slot)>() + 3 * std::mem::size_of::<usize>();
+
+/// Capacity of the checkpoints keyspace, as there are only a handful of jobs.
+const CHECKPOINTS_SIZE: usize = 16;
+
+/// Approximate number of bytes taken by an entry.
+fn entry_size(key: &str, entry: &CacheEntry) -> usize {
+  ENTRY_OVERHEAD + key.len() + entry.text.len() + entry.language.len() + entry.translator.as_ref().map_or(0, String::len)
+}
+
+/// The entries of a keyspace, together with an estimate of the memory they take.
+struct Space {
+  name: &'static str,
+  entries: LruCache<String, Slot>,
+  bytes: usize
+}
+
+impl Space {
+
+  fn new(keyspace: Keyspace, capacity: usize) -> Mutex<Self> {
+    Mutex::new(Space { name: keyspace.name(), entries: LruCache::new(capacity), bytes: 0 })
+  }
+
+  /// Stores a slot, returning whether another entry had to be evicted to make room for it.
+  fn insert(&mut self, key: String, slot: Slot) -> bool {
+    let before = self.bytes;
+    let mut evicted = false;
+    if !self.entries.contains(&key) && self.entries.len() >= self.entries.cap() {
+      if let Some((key, slot)) = self.entries.pop_lru() {
+        self.bytes -= entry_size(&key, &slot.entry);
+        evicted = true;
+      }
+    }
+    let size = entry_size(&key, &slot.entry);
+    if let Some(replaced) = self.entries.put(key.clone(), slot) {
+      self.bytes -= entry_size(&key, &replaced.entry);
+    }
+    if self.entries.cap() > 0 {
+      self.bytes += size;
+    }
+    self.update_gauge(before);
+    evicted
+  }
+
+  fn remove(&mut self, key: &String) {
+    if let Some(slot) = self.entries.pop(key) {
+      let before = self.bytes;
+      self.bytes -= entry_size(key, &slot.entry);
+      self.update_gauge(before);
+    }
+  }
+
+  /// Adds the change to the gauge, which sums the keyspaces with the same name of all the caches.
+  fn update_gauge(&self, before: usize) {
+    metrics::CACHE_MEMORY_BYTES.with_label_values(&[self.name]).add(self.bytes as i64 - before as i64);
+  }
+
+}
+
+// The memory is given back when the cache goes away
+impl Drop for Space {
+  fn drop(&mut self) {
+    metrics::CACHE_MEMORY_BYTES.with_label_values(&[self.name]).sub(self.bytes as i64);
+  }
+}
+
+/// In-memory LRU cache, split into separate [`Keyspace`](crate::cache::Keyspace)s.
+///
+/// Each keyspace has its own capacity, so that, for example, raw descriptions
+/// can outlive the translations and be reused to retry a failed translation.
+pub struct MemoryCache {
+  descriptions: Mutex<Space>,
+  translations: Mutex<Space>,
+  stats: Mutex<Space>,
+  species: Mutex<Space>,
+  profiles: Mutex<Space>,
+  texts: Mutex<Space>,
+  checkpoints: Mutex<Space>,
+  /// How long expired entries are kept around, to be served if they cannot be refreshed.
+  stale_grace: Duration
+}
+
+impl MemoryCache {
+
+  /// Creates a new cache with the given capacities for each keyspace.
+  /// Stats, species and profiles are small and fetched together with the descriptions, so they share the same capacity.
+  /// Texts are not cached unless given a capacity with [`with_texts_size`](MemoryCache::with_texts_size).
+  pub fn new(descriptions_size: usize, translations_size: usize) -> Self {
+    MemoryCache {
+      descriptions: Space::new(Keyspace::Descriptions, descriptions_size),
+      translations: Space::new(Keyspace::Translations, translations_size),
+      stats: Space::new(Keyspace::Stats, descriptions_size),
+      species: Space::new(Keyspace::Species, descriptions_size),
+      profiles: Space::new(Keyspace::Profiles, descriptions_size),
+      texts: Space::new(Keyspace::Texts, 0),
+      checkpoints: Space::new(Keyspace::Checkpoints, CHECKPOINTS_SIZE),
+      stale_grace: Duration::ZERO
+    }
+  }
+
+  /// Sets the capacity of the [`Texts`](Keyspace::Texts) keyspace.
+  pub fn with_texts_size(mut self, size: usize) -> Self {
+    self.texts = Space::new(Keyspace::Texts, size);
+    self
+  }
+
+  /// Keeps the expired entries for `grace` more, so that they can be retrieved with [`get_stale`](MemoryCache::get_stale).
+  pub fn with_stale_grace(mut self, grace: Duration) -> Self {
+    self.stale_grace = grace;
+    self
+  }
+
+  fn keyspace(&self, keyspace: Keyspace) -> &Mutex<Space> {
+    match keyspace {
+      Keyspace::Descriptions => &self.descriptions,
+      Keyspace::Translations => &self.translations,
+      Keyspace::Stats => &self.stats,
+      Keyspace::Species => &self.species,
+      Keyspace::Profiles => &self.profiles,
+      Keyspace::Texts => &self.texts,
+      Keyspace::Checkpoints => &self.checkpoints
+    }
+  }
+
+  /// Returns the number of entries stored in the given keyspace, and its capacity.
+  pub fn usage(&self, keyspace: Keyspace) -> (usize, usize) {
+    let space = self.keyspace(keyspace).lock().unwrap();
+    (space.entries.len(), space.entries.cap())
+  }
+
+  /// Returns the approximate number of bytes taken by the entries of the given keyspace.
+  pub fn memory_usage(&self, keyspace: Keyspace) -> usize {
+    self.keyspace(keyspace).lock().unwrap().bytes
+  }
+
+  /// Returns a snapshot of all the live entries of the given keyspace, from the most recently used.
+  pub fn entries(&self, keyspace: Keyspace) -> Vec<(String, CacheEntry)> {
+    let now = Instant::now();
+    let space = self.keyspace(keyspace).lock().unwrap();
+    space.entries.iter()
+      .filter(|(_, slot)| slot.expires_at.is_none_or(|t| now < t) && slot.entry.is_compatible())
+      .map(|(key, slot)| (key.clone(), slot.entry.clone()))
+      .collect()
+  }
+
+  /// Returns the keys of all the entries of the given keyspace, even the expired ones.
+  pub fn keys(&self, keyspace: Keyspace) -> Vec<String> {
+    self.keyspace(keyspace).lock().unwrap().entries.iter().map(|(key, _)| key.clone()).collect()
+  }
+
+  /// Retrieves an entry from the given keyspace.
+  /// Expired entries and entries with an incompatible schema are treated as missing,
+  /// and discarded unless they are in their stale grace period.
+  pub fn get(&self, keyspace: Keyspace, key: &str) -> Option<CacheEntry> {
+    let mut space = self.keyspace(keyspace).lock().unwrap();
+    let key = key.to_string();
+    let now = Instant::now();
+    match space.entries.get(&key) {
+      Some(slot) if slot.expires_at.is_some_and(|t| now >= t && self.is_in_grace(t, now)) => None,
+      Some(slot) if slot.expires_at.is_some_and(|t| now >= t) => {
+        debug!(key = %key, "Discarding expired cache entry");
+        metrics::CACHE_EXPIRED.with_label_values(&[keyspace.name()]).inc();
+        space.remove(&key);
+        None
+      },
+      Some(slot) if !slot.entry.is_compatible() => {
+        debug!(key = %key, schema_version = slot.entry.schema_version, "Discarding incompatible cache entry");
+        space.remove(&key);
+        None
+      },
+      Some(slot) => Some(slot.entry.clone()),
+      None => None
+    }
+  }
+
+  /// Whether an entry expiring at `expires_at` is still within its stale grace period.
+  /// Grace periods too long to be represented never end.
+  fn is_in_grace(&self, expires_at: Instant, now: Instant) -> bool {
+    expires_at.checked_add(self.stale_grace).is_none_or(|end| now < end)
+  }
+
+  /// Retrieves an entry from the given keyspace, even if it expired less than the stale grace period ago.
+  pub fn get_stale(&self, keyspace: Keyspace, key: &str) -> Option<CacheEntry> {
+    let mut space = self.keyspace(keyspace).lock().unwrap();
+    let now = Instant::now();
+    space.entries.get(&key.to_string())
+      .filter(|slot| slot.expires_at.is_none_or(|t| self.is_in_grace(t, now)) && slot.entry.is_compatible())
+      .map(|slot| slot.entry.clone())
+  }
+
+  /// Stores an entry in the given keyspace, expiring after `ttl` if given.
+  pub fn put(&self, keyspace: Keyspace, key: String, entry: CacheEntry, ttl: Option<Duration>) {
+    // TTLs too long to be represented never expire
+    let expires_at = ttl.and_then(|ttl| Instant::now().checked_add(ttl));
+    let mut space = self.keyspace(keyspace).lock().unwrap();
+    if space.insert(key, Slot { entry, expires_at }) {
+      metrics::CACHE_EVICTIONS.with_label_values(&[keyspace.name()]).inc();
+    }
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_keyspaces_are_independent() {
+    let cache = MemoryCache::new(1, 1);
+    cache.put(Keyspace::Descriptions, "pikachu".to_string(), CacheEntry::original("en", "Original".to_string()), None);
+    cache.put(Keyspace::Translations, "pikachu".to_string(), CacheEntry::translated("shakespeare", "en", "Translated".to_string()), None);
+
+    assert_eq!(cache.get(Keyspace::Descriptions, "pikachu").unwrap().text, "Original");
+    assert_eq!(cache.get(Keyspace::Translations, "pikachu").unwrap().translator.as_deref(), Some("shakespeare"));
+  }
+
+  #[test]
+  fn test_entries_snapshot() {
+    let cache = MemoryCache::new(2, 2);
+    cache.put(Keyspace::Translations, "pikachu".to_string(), CacheEntry::translated("shakespeare", "en", "Live".to_string()), None);
+    cache.put(Keyspace::Translations, "ditto".to_string(), CacheEntry::translated("shakespeare", "en", "Expired".to_string()), Some(Duration::from_secs(0)));
+
+    let entries = cache.entries(Keyspace::Translations);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].0, "pikachu");
+  }
+
+  #[test]
+  fn test_incompatible_entries_are_discarded() {
+    let cache = MemoryCache::new(1, 1);
+    cache.put(Keyspace::Translations, "pikachu".to_string(), CacheEntry {
+      schema_version: 0,
+      ..CacheEntry::translated("shakespeare", "en", "Old".to_string())
+    }, None);
+
+    assert!(cache.get(Keyspace::Translations, "pikachu").is_none());
+  }
+
+  #[test]
+  fn test_expired_entries_are_discarded() {
+    let cache = MemoryCache::new(1, 1);
+    cache.put(Keyspace::Translations, "pikachu".to_string(), CacheEntry::translated("shakespeare", "en", "Expired".to_string()), Some(Duration::from_secs(0)));
+
+    assert!(cache.get(Keyspace::Translations, "pikachu").is_none());
+  }
+
+  #[test]
+  fn test_stale_entries() {
+    let cache = MemoryCache::new(1, 1).with_stale_grace(Duration::from_secs(60));
+    cache.put(Keyspace::Translations, "pikachu".to_string(), CacheEntry::translated("shakespeare", "en", "Stale".to_string()), Some(Duration::from_secs(0)));
+
+    assert!(cache.get(Keyspace::Translations, "pikachu").is_none());
+    assert_eq!(cache.get_stale(Keyspace::Translations, "pikachu").unwrap().text, "Stale");
+
+    // Without a grace period, expired entries are gone for good
+    let cache = MemoryCache::new(1, 1);
+    cache.put(Keyspace::Translations, "pikachu".to_string(), CacheEntry::translated("shakespeare", "en", "Stale".to_string()), Some(Duration::from_secs(0)));
+    assert!(cache.get_stale(Keyspace::Translations, "pikachu").is_none());
+  }
+
+  #[test]
+  fn test_huge_durations() {
+    let cache = MemoryCache::new(1, 1).with_stale_grace(Duration::MAX);
+    cache.put(Keyspace::Translations, "pikachu".to_string(), CacheEntry::translated("shakespeare", "en", "Stale".to_string()), Some(Duration::from_secs(0)));
+    assert!(cache.get(Keyspace::Translations, "pikachu").is_none());
+    assert_eq!(cache.get_stale(Keyspace::Translations, "pikachu").unwrap().text, "Stale");
+
+    cache.put(Keyspace::Translations, "pikachu".to_string(), CacheEntry::translated("shakespeare", "en", "Fresh".to_string()), Some(Duration::MAX));
+    assert_eq!(cache.get(Keyspace::Translations, "pikachu").unwrap().text, "Fresh");
+  }
+
+  #[test]
+  fn test_memory_usage() {
+    let cache = MemoryCache::new(1, 1);
+    assert_eq!(cache.memory_usage(Keyspace::Descriptions), 0);
+
+    let entry = CacheEntry::original("en", "First".to_string());
+    cache.put(Keyspace::Descriptions, "pikachu".to_string(), entry.clone(), None);
+    assert_eq!(cache.memory_usage(Keyspace::Descriptions), entry_size("pikachu", &entry));
+
+    // Replaced and evicted entries are not counted anymore
+    let entry = CacheEntry::original("en", "Replaced".to_string());
+    cache.put(Keyspace::Descriptions, "pikachu".to_string(), entry.clone(), None);
+    assert_eq!(cache.memory_usage(Keyspace::Descriptions), entry_size("pikachu", &entry));
+    let entry = CacheEntry::original("en", "Evicts".to_string());
+    cache.put(Keyspace::Descriptions, "bulbasaur".to_string(), entry.clone(), None);
+    assert_eq!(cache.memory_usage(Keyspace::Descriptions), entry_size("bulbasaur", &entry));
+
+    // And neither are the discarded ones
+    cache.put(Keyspace::Translations, "ditto".to_string(), CacheEntry::translated("shakespeare", "en", "Expired".to_string()), Some(Duration::from_secs(0)));
+    assert!(cache.get(Keyspace::Translations, "ditto").is_none());
+    assert_eq!(cache.memory_usage(Keyspace::Translations), 0);
+  }
+
+  #[test]
+  fn test_eviction_counter() {
+    let cache = MemoryCache::new(1, 1);
+    let evictions = metrics::CACHE_EVICTIONS.with_label_values(&[Keyspace::Descriptions.name()]);
+    let before = evictions.get();
+
+    cache.put(Keyspace::Descriptions, "pikachu".to_string(), CacheEntry::original("en", "First".to_string()), None);
+    cache.put(Keyspace::Descriptions, "bulbasaur".to_string(), CacheEntry::original("en", "Evicts".to_string()), None);
+
+    // Other tests might be evicting entries concurrently
+    assert!(evictions.get() > before);
+  }
+
+}
@@ -0,0 +1,842 @@
+pub mod memcached;
+pub mod memory;
+pub mod redis;
+pub mod singleflight;
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+use crate::cache::memcached::MemcachedCache;
+use crate::cache::memory::MemoryCache;
+use crate::cache::redis::RedisCache;
+use crate::cache::singleflight::SingleFlight;
+use crate::config::{CacheBackendKind, Config};
+use crate::metrics;
+use crate::request_stats;
+
+/// Version of the [`CacheEntry`](crate::cache::CacheEntry) schema.
+/// Bump it whenever the structure of the entries changes in an incompatible way:
+/// entries with a different version are discarded when read.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// How often to check whether another replica finished populating an entry.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Errors are shared between all the requests waiting for the same entry.
+pub type SharedError = Arc<anyhow::Error>;
+
+/// Result of the population of a cache entry. `None` means that there is nothing to cache,
+/// for example because the Pokemon does not exist.
+pub type PopulateResult = std::result::Result<Option<CacheEntry>, SharedError>;
+
+/// The independent keyspaces of the [`Cache`](crate::cache::Cache).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Keyspace {
+  /// Untranslated descriptions, as returned by the PokeAPI.
+  Descriptions,
+  /// Translated descriptions.
+  Translations,
+  /// Base stats of the Pokemon, encoded as JSON.
+  Stats,
+  /// Species of the forms and varieties, like `giratina` for `giratina-origin`, encoded as JSON.
+  /// They never change, so these entries do not expire.
+  Species,
+  /// Habitat and types of the Pokemon, encoded as JSON.
+  Profiles,
+  /// Translations of the arbitrary texts of `POST /translate`, keyed by a hash of the text and of the translator.
+  /// They have their own capacity and TTL, so that they cannot evict the Pokemon.
+  Texts,
+  /// Progress of the long-running jobs, and the switches set at runtime, encoded as JSON.
+  /// They are not cached data, so they are not part of [`ALL`](Keyspace::ALL), and they do not expire.
+  Checkpoints
+}
+
+impl Keyspace {
+
+  pub const ALL: [Keyspace; 6] = [Keyspace::Descriptions, Keyspace::Translations, Keyspace::Stats, Keyspace::Species, Keyspace::Profiles, Keyspace::Texts];
+
+  /// Returns the name of the keyspace, used to namespace the keys in shared backends.
+  pub fn name(&self) -> &'static str {
+    match self {
+      Keyspace::Descriptions => "descriptions",
+      Keyspace::Translations => "translations",
+      Keyspace::Stats => "stats",
+      Keyspace::Species => "species",
+      Keyspace::Profiles => "profiles",
+      Keyspace::Texts => "texts",
+      Keyspace::Checkpoints => "checkpoints"
+    }
+  }
+
+  /// Whether the entries of the keyspace are kept regardless of the TTL.
+  fn is_long_lived(&self) -> bool {
+    matches!(self, Keyspace::Species | Keyspace::Checkpoints)
+  }
+
+}
+
+/// A single cached text, together with the metadata describing how it was produced.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheEntry {
+  pub schema_version: u32,
+  /// Creation time of the entry, in seconds since the Unix epoch.
+  pub created_at: u64,
+  /// Name of the translator which produced the text, if any.
+  pub translator: Option<String>,
+  pub language: String,
+  pub text: String
+}
+
+impl CacheEntry {
+
+  /// Creates a new entry for an untranslated text.
+  pub fn original(language: &str, text: String) -> Self {
+    CacheEntry {
+      schema_version: SCHEMA_VERSION,
+      created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+      translator: None,
+      language: language.to_string(),
+      text
+    }
+  }
+
+  /// Creates a new entry for structured data, serialized as JSON.
+  /// Being no text, it has neither a translator nor a language.
+  pub fn data<T: Serialize>(data: &T) -> Result<Self> {
+    Ok(CacheEntry {
+      schema_version: SCHEMA_VERSION,
+      created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+      translator: None,
+      language: String::new(),
+      text: serde_json::to_string(data)?
+    })
+  }
+
+  /// Parses the data stored by [`CacheEntry::data`](crate::cache::CacheEntry::data).
+  pub fn parse_data<T: serde::de::DeserializeOwned>(&self) -> Result<T> {
+    Ok(serde_json::from_str(&self.text)?)
+  }
+
+  /// Creates a new entry for a text produced by the given translator.
+  pub fn translated(translator: &str, language: &str, text: String) -> Self {
+    CacheEntry {
+      translator: Some(translator.to_string()),
+      ..CacheEntry::original(language, text)
+    }
+  }
+
+  /// Returns whether this entry can be understood by the current version of the application.
+  pub fn is_compatible(&self) -> bool {
+    self.schema_version == SCHEMA_VERSION
+  }
+
+  /// Returns how long ago this entry has been created.
+  pub fn age(&self) -> Duration {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    Duration::from_secs(now.saturating_sub(self.created_at))
+  }
+
+  /// Checksum of the fields of this entry, stored with it in the external backends to detect the corrupt ones.
+  fn checksum(&self) -> Result<String> {
+    Ok(format!("{:x}", Sha256::digest(serde_json::to_vec(self)?)))
+  }
+
+  /// Serializes this entry to be stored in an external backend, together with its checksum.
+  pub fn encode(&self) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&Stored { entry: self.clone(), checksum: Some(self.checksum()?) })?)
+  }
+
+  /// Deserializes an entry read from an external backend, checking its schema version and its checksum.
+  /// The entries written before the checksums were introduced have none, and are trusted as they are.
+  pub fn verify(bytes: &[u8]) -> std::result::Result<CacheEntry, Untrusted> {
+
+    #[derive(Deserialize)]
+    struct Versioned {
+      schema_version: u32
+    }
+
+    let version = serde_json::from_slice::<Versioned>(bytes)
+      .map_err(Untrusted::malformed)?
+      .schema_version;
+    if version != SCHEMA_VERSION {
+      debug!(schema_version = version, "Discarding incompatible cache entry");
+      return Err(Untrusted::Incompatible);
+    }
+
+    let stored = serde_json::from_slice::<Stored>(bytes)
+      .map_err(Untrusted::malformed)?;
+    match stored.checksum {
+      Some(checksum) if stored.entry.checksum().ok().as_ref() != Some(&checksum) => {
+        debug!("Discarding corrupt cache entry");
+        Err(Untrusted::Corrupt)
+      },
+      _ => Ok(stored.entry)
+    }
+
+  }
+
+  /// Deserializes an entry read from an external backend.
+  /// Returns `None` if the entry cannot be trusted, counting it in the `pokechallenge_cache_discarded_entries_total` metric.
+  pub fn decode(bytes: &[u8]) -> Option<CacheEntry> {
+    CacheEntry::decode_counted(bytes, &mut Discarded::default())
+  }
+
+  /// Like [`decode`](CacheEntry::decode), but also counts the discarded entry in `discarded`.
+  pub fn decode_counted(bytes: &[u8], discarded: &mut Discarded) -> Option<CacheEntry> {
+    CacheEntry::verify(bytes).map_err(|reason| discarded.count(reason)).ok()
+  }
+
+}
+
+/// Form of the entries in the external backends.
+#[derive(Serialize, Deserialize)]
+struct Stored {
+  #[serde(flatten)]
+  entry: CacheEntry,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  checksum: Option<String>
+}
+
+/// Reasons why an entry read from an external backend cannot be trusted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Untrusted {
+  /// The entry cannot be parsed.
+  Malformed,
+  /// The entry has been written with another schema version, maybe by another version of the application.
+  Incompatible,
+  /// The entry does not match its checksum.
+  Corrupt
+}
+
+impl Untrusted {
+
+  fn malformed(e: serde_json::Error) -> Self {
+    debug!(error = %e, "Discarding malformed cache entry");
+    Untrusted::Malformed
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Untrusted::Malformed => "malformed",
+      Untrusted::Incompatible => "incompatible",
+      Untrusted::Corrupt => "corrupt"
+    }
+  }
+
+}
+
+/// Numbers of the entries read from a backend which could not be trusted and have been discarded.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Discarded {
+  pub malformed: usize,
+  pub incompatible: usize,
+  pub corrupt: usize
+}
+
+impl Discarded {
+
+  /// Counts a discarded entry, here and in the `pokechallenge_cache_discarded_entries_total` metric.
+  pub fn count(&mut self, reason: Untrusted) {
+    metrics::CACHE_DISCARDED_ENTRIES.with_label_values(&[reason.name()]).inc();
+    match reason {
+      Untrusted::Malformed => self.malformed += 1,
+      Untrusted::Incompatible => self.incompatible += 1,
+      Untrusted::Corrupt => self.corrupt += 1
+    }
+  }
+
+  fn add(&mut self, other: Discarded) {
+    self.malformed += other.malformed;
+    self.incompatible += other.incompatible;
+    self.corrupt += other.corrupt;
+  }
+
+}
+
+/// Outcome of the integrity check of the entries of a shared backend.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Integrity {
+  /// Number of entries checked.
+  pub checked: usize,
+  pub discarded: Discarded,
+  /// Number of discarded entries moved to the quarantine.
+  pub quarantined: usize
+}
+
+impl Integrity {
+
+  /// Checks a stored entry, returning whether it must be quarantined.
+  /// The entries of another schema version are left alone, as they may belong to the replicas of another version
+  /// of the application during a rolling deploy.
+  pub fn check(&mut self, bytes: &[u8]) -> bool {
+    self.checked += 1;
+    match CacheEntry::verify(bytes) {
+      Ok(_) => false,
+      Err(reason) => {
+        self.discarded.count(reason);
+        reason != Untrusted::Incompatible
+      }
+    }
+  }
+
+  fn add(&mut self, other: Integrity) {
+    self.checked += other.checked;
+    self.discarded.add(other.discarded);
+    self.quarantined += other.quarantined;
+  }
+
+}
+
+/// Time to live of the cached entries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ttl {
+  pub base: Duration,
+  /// Fraction of the base TTL randomly added or subtracted to each entry,
+  /// so that entries cached at the same time do not expire all together.
+  pub jitter: f64
+}
+
+impl Ttl {
+
+  /// Computes the effective TTL of a new entry, applying a random jitter to the base TTL.
+  pub fn jittered(&self) -> Duration {
+    let jitter = self.jitter.clamp(0.0, 1.0);
+    if jitter == 0.0 {
+      return self.base;
+    }
+    let factor = 1.0 + rand::thread_rng().gen_range(-jitter..=jitter);
+    self.base.mul_f64(factor)
+  }
+
+}
+
+/// A cache backend shared among all the replicas of the application.
+#[async_trait]
+pub trait CacheBackend: Send + Sync {
+
+  /// Name of the backend, used for logging.
+  fn name(&self) -> &'static str;
+
+  /// Retrieves an entry from the given keyspace.
+  async fn get(&self, keyspace: Keyspace, key: &str) -> Result<Option<CacheEntry>>;
+
+  /// Stores an entry in the given keyspace, expiring after `ttl` if given.
+  async fn put(&self, keyspace: Keyspace, key: &str, entry: &CacheEntry, ttl: Option<Duration>) -> Result<()>;
+
+  /// Checks that the backend is reachable.
+  async fn ping(&self) -> Result<()>;
+
+  /// Returns up to `limit` entries of the given keyspace, the most recently created first,
+  /// together with the numbers of the entries which have been discarded while reading them.
+  /// Backends which cannot enumerate their keys answer with an error.
+  async fn recent(&self, _keyspace: Keyspace, _limit: usize) -> Result<(Vec<(String, CacheEntry)>, Discarded)> {
+    Err(anyhow!("The {} cache cannot list its entries", self.name()))
+  }
+
+  /// Checks the entries of the given keyspace, moving the corrupt and malformed ones to a quarantine
+  /// where they can be inspected without being served.
+  /// Backends which cannot enumerate their keys skip the check, answering with `None`.
+  async fn check_integrity(&self, _keyspace: Keyspace) -> Result<Option<Integrity>> {
+    Ok(None)
+  }
+
+}
+
+/// Distributed lock used to make sure that only one replica populates a cold entry.
+struct DistributedLock {
+  redis: RedisCache,
+  ttl: Duration
+}
+
+/// The cache of the application.
+///
+/// Entries are always kept in a local in-memory tier, optionally backed by a shared [`CacheBackend`](crate::cache::CacheBackend).
+/// Concurrent requests for the same missing entry are collapsed into a single population,
+/// locally and, if a distributed lock is configured, across replicas.
+pub struct Cache {
+  local: MemoryCache,
+  shared: Option<Arc<dyn CacheBackend>>,
+  lock: Option<DistributedLock>,
+  ttl: Option<Ttl>,
+  /// TTL of the [`Texts`](Keyspace::Texts), which do not follow the one of the Pokemon.
+  texts_ttl: Option<Duration>,
+  flights: SingleFlight<PopulateResult>
+}
+
+impl Cache {
+
+  /// Creates a new cache with only the given in-memory tier.
+  pub fn new(local: MemoryCache) -> Self {
+    Cache {
+      local,
+      shared: None,
+      lock: None,
+      ttl: None,
+      texts_ttl: None,
+      flights: SingleFlight::default()
+    }
+  }
+
+  /// Builds the cache described by the given configuration, connecting to the shared backends if needed.
+  pub async fn from_config(config: &Config) -> Result<Self> {
+    let local = MemoryCache::new(config.descriptions_cache_size, config.pokemon_cache_size)
+      .with_texts_size(config.translate_cache_size)
+      .with_stale_grace(config.cache_stale_if_error.unwrap_or_default());
+    let mut cache = Cache::new(local)
+      .with_texts_ttl(config.translate_cache_ttl)
+      .with_max_waiters(config.max_waiters_per_key);
+    if let Some(ttl) = config.cache_ttl {
+      cache = cache.with_ttl(ttl);
+    }
+
+    match config.cache_backend {
+      CacheBackendKind::Memory => {},
+      CacheBackendKind::Redis => {
+        let redis = RedisCache::connect(config.redis_url.as_deref().unwrap_or_default()).await?;
+        if let Some(ttl) = config.distributed_lock_ttl {
+          cache.lock = Some(DistributedLock { redis: redis.clone(), ttl });
+        }
+        cache = cache.with_shared(Arc::new(redis));
+      },
+      CacheBackendKind::Memcached => {
+        let memcached = MemcachedCache::connect(config.memcached_servers.clone()).await?;
+        cache = cache.with_shared(Arc::new(memcached));
+      }
+    }
+
+    Ok(cache)
+  }
+
+  /// Makes the entries of this cache expire after the given TTL.
+  pub fn with_ttl(mut self, ttl: Ttl) -> Self {
+    self.ttl = Some(ttl);
+    self
+  }
+
+  /// Makes the [`Texts`](Keyspace::Texts) expire after the given TTL, if any.
+  pub fn with_texts_ttl(mut self, ttl: Option<Duration>) -> Self {
+    self.texts_ttl = ttl;
+    self
+  }
+
+  /// Caps the number of callers waiting for the population of the same entry.
+  pub fn with_max_waiters(mut self, max_waiters: Option<usize>) -> Self {
+    self.flights = SingleFlight::default().with_max_waiters(max_waiters);
+    self
+  }
+
+  /// Backs the local tier with the given shared backend.
+  pub fn with_shared(mut self, backend: Arc<dyn CacheBackend>) -> Self {
+    self.shared = Some(backend);
+    self
+  }
+
+  /// Returns the number of entries stored in the local tier for the given keyspace, and its capacity.
+  pub fn local_usage(&self, keyspace: Keyspace) -> (usize, usize) {
+    self.local.usage(keyspace)
+  }
+
+  /// Returns the approximate number of bytes taken by the local tier for the given keyspace.
+  pub fn local_memory_usage(&self, keyspace: Keyspace) -> usize {
+    self.local.memory_usage(keyspace)
+  }
+
+  /// Returns a snapshot of the entries stored in the local tier for the given keyspace.
+  pub fn local_entries(&self, keyspace: Keyspace) -> Vec<(String, CacheEntry)> {
+    self.local.entries(keyspace)
+  }
+
+  /// Returns the keys stored in the local tier for the given keyspace, even the expired ones.
+  pub fn local_keys(&self, keyspace: Keyspace) -> Vec<String> {
+    self.local.keys(keyspace)
+  }
+
+  /// Returns the shared backend, if any.
+  pub fn shared(&self) -> Option<&Arc<dyn CacheBackend>> {
+    self.shared.as_ref()
+  }
+
+  /// Retrieves an entry from the given keyspace, looking in the local tier first and then in the shared one.
+  /// Errors of the shared backend are logged and treated as misses.
+  pub async fn get(&self, keyspace: Keyspace, key: &str) -> Option<CacheEntry> {
+    let started = Instant::now();
+    let entry = self.lookup(keyspace, key).await;
+    request_stats::record(|stats| stats.cache_time += started.elapsed());
+    entry
+  }
+
+  async fn lookup(&self, keyspace: Keyspace, key: &str) -> Option<CacheEntry> {
+    let started = Instant::now();
+    let local = self.local.get(keyspace, key);
+    metrics::observe_cache_operation("memory", "get", started);
+    let entry = match local {
+      Some(entry) => Some(entry),
+      None => {
+        let shared = self.shared.as_ref()?;
+        let started = Instant::now();
+        let res = shared.get(keyspace, key).await;
+        metrics::observe_cache_operation(shared.name(), "get", started);
+        match res {
+          Ok(Some(entry)) => {
+            // The copy in the local tier must not outlive the one in the shared tier
+            let ttl = self.remaining_ttl(keyspace, key, &entry);
+            if !ttl.is_some_and(|ttl| ttl.is_zero()) {
+              self.local.put(keyspace, key.to_string(), entry.clone(), ttl);
+            }
+            Some(entry)
+          },
+          Ok(None) => None,
+          Err(e) => {
+            warn!(error = %e, backend = shared.name(), "Cannot read from the shared cache");
+            None
+          }
+        }
+      }
+    }?;
+
+    debug!(keyspace = keyspace.name(), age = ?entry.age(), "Cache hit");
+    match keyspace {
+      Keyspace::Translations => metrics::CACHE_HITS.inc(),
+      Keyspace::Descriptions => metrics::DESCRIPTION_CACHE_HITS.inc(),
+      Keyspace::Stats => metrics::STATS_CACHE_HITS.inc(),
+      Keyspace::Species => metrics::SPECIES_CACHE_HITS.inc(),
+      Keyspace::Profiles => metrics::PROFILE_CACHE_HITS.inc(),
+      Keyspace::Texts => metrics::TEXT_CACHE_HITS.inc(),
+      Keyspace::Checkpoints => {}
+    }
+    Some(entry)
+  }
+
+  /// Retrieves an entry from the local tier, even if it expired recently.
+  /// Meant to serve something when the entry cannot be refreshed.
+  pub fn get_stale(&self, keyspace: Keyspace, key: &str) -> Option<CacheEntry> {
+    self.local.get_stale(keyspace, key)
+  }
+
+  /// Stores an entry in the given keyspace, in all the tiers.
+  pub async fn put(&self, keyspace: Keyspace, key: String, entry: CacheEntry) {
+    let ttl = self.effective_ttl(keyspace, &key);
+    if let Some(shared) = &self.shared {
+      let started = Instant::now();
+      if let Err(e) = shared.put(keyspace, &key, &entry, ttl).await {
+        warn!(error = %e, backend = shared.name(), "Cannot write to the shared cache");
+      }
+      metrics::observe_cache_operation(shared.name(), "put", started);
+    }
+    let started = Instant::now();
+    self.local.put(keyspace, key, entry, ttl);
+    metrics::observe_cache_operation("memory", "put", started);
+  }
+
+  /// Copies up to `limit` of the most recent entries of each keyspace from the shared tier to the local one,
+  /// to avoid the latency of a cold local tier after a deploy. Returns how many entries were copied for each keyspace.
+  pub async fn rehydrate(&self, limit: usize) -> Result<Vec<(Keyspace, usize)>> {
+
+    let shared = self.shared.as_ref().ok_or_else(|| anyhow!("No shared cache to rehydrate from"))?;
+    let mut copied = Vec::new();
+    let mut discarded = Discarded::default();
+    for keyspace in Keyspace::ALL.iter().copied() {
+
+      // The entries only live for what is left of their TTL.
+      // The ones which cannot be decoded are already left out, and counted, by the backend.
+      let mut count = 0;
+      let (entries, skipped) = shared.recent(keyspace, limit).await?;
+      discarded.add(skipped);
+      for (key, entry) in entries {
+        if !entry.is_compatible() {
+          discarded.count(Untrusted::Incompatible);
+          continue;
+        }
+        let ttl = self.remaining_ttl(keyspace, &key, &entry);
+        if ttl.is_some_and(|ttl| ttl.is_zero()) {
+          continue;
+        }
+        self.local.put(keyspace, key, entry, ttl);
+        count += 1;
+      }
+      copied.push((keyspace, count));
+
+    }
+
+    // Report the entries of this rehydration which could not be trusted, not to fail the whole of it for a few of them
+    info!(?copied, malformed = discarded.malformed, incompatible = discarded.incompatible, corrupt = discarded.corrupt, backend = shared.name(), "Local cache rehydrated");
+    Ok(copied)
+
+  }
+
+  /// Checks the entries persisted in the shared tier, quarantining the ones which cannot be trusted,
+  /// so that a corrupt backend is reported at startup instead of failing the requests or the startup itself.
+  /// Returns `None` if there is no shared tier, or if it cannot be checked.
+  pub async fn check_integrity(&self) -> Result<Option<Integrity>> {
+
+    let shared = match &self.shared {
+      Some(shared) => shared,
+      None => return Ok(None)
+    };
+    let mut integrity = Integrity::default();
+    for keyspace in Keyspace::ALL.iter().copied() {
+      match shared.check_integrity(keyspace).await? {
+        Some(checked) => integrity.add(checked),
+        None => return Ok(None)
+      }
+    }
+
+    let Integrity { checked, discarded, quarantined } = integrity;
+    info!(checked, malformed = discarded.malformed, incompatible = discarded.incompatible, corrupt = discarded.corrupt, quarantined, backend = shared.name(), "Shared cache checked");
+    Ok(Some(integrity))
+
+  }
+
+  /// Retrieves an entry from the cache, populating it with `populate` if it is missing.
+  pub async fn get_or_populate<F, Fut>(&self, keyspace: Keyspace, key: &str, populate: F) -> PopulateResult
+    where F: FnOnce() -> Fut, Fut: Future<Output = PopulateResult>
+  {
+    let entry = self.get(keyspace, key).await;
+    request_stats::record(|stats| { stats.cache_hit.get_or_insert(entry.is_some()); });
+    if entry.is_some() {
+      return Ok(entry);
+    }
+
+    let flight_key = format!("{}:{}", keyspace.name(), key);
+    self.flights.run(&flight_key, || async {
+      match &self.lock {
+        Some(lock) => self.populate_locked(lock, keyspace, key, populate).await,
+        None => self.populate(keyspace, key, populate).await
+      }
+    }).await.unwrap_or_else(|e| {
+      metrics::SHED_REQUESTS.with_label_values(&["hot_key"]).inc();
+      Err(Arc::new(e.into()))
+    })
+  }
+
+  async fn populate<F, Fut>(&self, keyspace: Keyspace, key: &str, populate: F) -> PopulateResult
+    where F: FnOnce() -> Fut, Fut: Future<Output = PopulateResult>
+  {
+    let entry = populate().await?;
+    if let Some(entry) = &entry {
+      self.put(keyspace, key.to_string(), entry.clone()).await;
+    }
+    Ok(entry)
+  }
+
+  /// Populates an entry while holding the distributed lock for its key.
+  /// If another replica holds the lock, waits for it to publish the entry in the shared backend.
+  async fn populate_locked<F, Fut>(&self, lock: &DistributedLock, keyspace: Keyspace, key: &str, populate: F) -> PopulateResult
+    where F: FnOnce() -> Fut, Fut: Future<Output = PopulateResult>
+  {
+    let lock_key = format!("{}:{}", keyspace.name(), key);
+    match lock.redis.try_lock(&lock_key, lock.ttl).await {
+      Ok(Some(token)) => {
+        let res = self.populate(keyspace, key, populate).await;
+        if let Err(e) = lock.redis.unlock(&lock_key, &token).await {
+          warn!(error = %e, "Cannot release distributed lock");
+        }
+        res
+      },
+      Ok(None) => {
+        debug!(key = %lock_key, "Entry is being populated by another replica");
+        let deadline = Instant::now() + lock.ttl;
+        while Instant::now() < deadline {
+          tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+          if let Some(entry) = self.get(keyspace, key).await {
+            return Ok(Some(entry));
+          }
+          if !lock.redis.is_locked(&lock_key).await.unwrap_or(false) {
+            break;
+          }
+        }
+
+        // The other replica either finished without publishing anything or died:
+        // have a last look and then do the work ourselves
+        match self.get(keyspace, key).await {
+          Some(entry) => Ok(Some(entry)),
+          None => self.populate(keyspace, key, populate).await
+        }
+      },
+      Err(e) => {
+        warn!(error = %e, "Cannot acquire distributed lock");
+        self.populate(keyspace, key, populate).await
+      }
+    }
+  }
+
+  /// TTL left to an entry copied from the shared tier, given the time it has already spent there.
+  fn remaining_ttl(&self, keyspace: Keyspace, key: &str, entry: &CacheEntry) -> Option<Duration> {
+    self.effective_ttl(keyspace, key).map(|ttl| ttl.saturating_sub(entry.age()))
+  }
+
+  fn effective_ttl(&self, keyspace: Keyspace, key: &str) -> Option<Duration> {
+    if keyspace.is_long_lived() {
+      return None;
+    }
+    if matches!(keyspace, Keyspace::Texts) {
+      return self.texts_ttl;
+    }
+    self.ttl.map(|ttl| {
+      let effective = ttl.jittered();
+      debug!(key = %key, base_ttl_secs = ttl.base.as_secs(), effective_ttl_secs = effective.as_secs(), "Computed entry TTL");
+      effective
+    })
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  #[test]
+  fn test_entry_encoding() {
+    let entry = CacheEntry::translated("shakespeare", "en", "Translated".to_string());
+    assert_eq!(CacheEntry::decode(&entry.encode().unwrap()), Some(entry));
+  }
+
+  #[test]
+  fn test_incompatible_entries_are_not_decoded() {
+    let old = CacheEntry {
+      schema_version: 0,
+      ..CacheEntry::translated("shakespeare", "en", "Old".to_string())
+    };
+    let discarded = |reason| metrics::CACHE_DISCARDED_ENTRIES.with_label_values(&[reason]).get();
+    let (incompatible, malformed) = (discarded("incompatible"), discarded("malformed"));
+    assert_eq!(CacheEntry::decode(&old.encode().unwrap()), None);
+    assert_eq!(CacheEntry::decode(br#"{ "text": "no version" }"#), None);
+    assert!(discarded("incompatible") > incompatible);
+    assert!(discarded("malformed") > malformed);
+  }
+
+  #[test]
+  fn test_discarded_entries_are_counted_locally() {
+    let old = CacheEntry {
+      schema_version: 0,
+      ..CacheEntry::translated("shakespeare", "en", "Old".to_string())
+    };
+    let mut discarded = Discarded::default();
+    assert_eq!(CacheEntry::decode_counted(&old.encode().unwrap(), &mut discarded), None);
+    assert_eq!(CacheEntry::decode_counted(b"not json", &mut discarded), None);
+    assert_eq!(CacheEntry::decode_counted(br#"{ "schema_version": 1 }"#, &mut discarded), None);
+    assert_eq!(discarded, Discarded { malformed: 2, incompatible: 1, corrupt: 0 });
+  }
+
+  #[test]
+  fn test_checksums() {
+    let entry = CacheEntry::translated("shakespeare", "en", "Translated".to_string());
+    let encoded = String::from_utf8(entry.encode().unwrap()).unwrap();
+    assert_eq!(CacheEntry::verify(encoded.as_bytes()), Ok(entry.clone()));
+
+    // A bit flipped in the backend does not go unnoticed
+    let tampered = encoded.replace("Translated", "Translatex");
+    assert_eq!(CacheEntry::verify(tampered.as_bytes()), Err(Untrusted::Corrupt));
+
+    // The entries written before the checksums are still trusted
+    assert_eq!(CacheEntry::verify(&serde_json::to_vec(&entry).unwrap()), Ok(entry));
+  }
+
+  #[test]
+  fn test_integrity_check() {
+    let entry = CacheEntry::translated("shakespeare", "en", "Translated".to_string());
+    let old = CacheEntry { schema_version: 0, ..entry.clone() };
+    let corrupt_before = metrics::CACHE_DISCARDED_ENTRIES.with_label_values(&["corrupt"]).get();
+
+    // The entries of another schema version are counted, but not quarantined
+    let mut integrity = Integrity::default();
+    assert!(!integrity.check(&entry.encode().unwrap()));
+    assert!(!integrity.check(&old.encode().unwrap()));
+    assert!(integrity.check(b"not json"));
+    assert!(integrity.check(String::from_utf8(entry.encode().unwrap()).unwrap().replace("en", "it").as_bytes()));
+    assert_eq!(integrity.checked, 4);
+    assert_eq!(integrity.discarded, Discarded { malformed: 1, incompatible: 1, corrupt: 1 });
+    assert!(metrics::CACHE_DISCARDED_ENTRIES.with_label_values(&["corrupt"]).get() > corrupt_before);
+  }
+
+  #[test]
+  fn test_ttl_jitter() {
+    let ttl = Ttl { base: Duration::from_secs(100), jitter: 0.2 };
+    for _ in 0..100 {
+      let effective = ttl.jittered();
+      assert!(effective >= Duration::from_secs(80) && effective <= Duration::from_secs(120));
+    }
+    assert_eq!(Ttl { jitter: 0.0, ..ttl }.jittered(), Duration::from_secs(100));
+  }
+
+  #[tokio::test]
+  async fn test_operations_are_timed() {
+    let gets = metrics::CACHE_OPERATION_DURATION.with_label_values(&["memory", "get"]);
+    let puts = metrics::CACHE_OPERATION_DURATION.with_label_values(&["memory", "put"]);
+    let (gets_before, puts_before) = (gets.get_sample_count(), puts.get_sample_count());
+
+    let cache = Cache::new(MemoryCache::new(1, 1));
+    cache.put(Keyspace::Descriptions, "pikachu".to_string(), CacheEntry::original("en", "Text".to_string())).await;
+    cache.get(Keyspace::Descriptions, "pikachu").await;
+    assert!(gets.get_sample_count() > gets_before);
+    assert!(puts.get_sample_count() > puts_before);
+  }
+
+  #[tokio::test]
+  async fn test_get_or_populate() {
+    let cache = Cache::new(MemoryCache::new(1, 1));
+    let calls = AtomicUsize::new(0);
+    let populate = || async {
+      calls.fetch_add(1, Ordering::SeqCst);
+      Ok(Some(CacheEntry::original("en", "Populated".to_string())))
+    };
+
+    let (a, b) = futures::join!(
+      cache.get_or_populate(Keyspace::Descriptions, "pikachu", populate),
+      cache.get_or_populate(Keyspace::Descriptions, "pikachu", populate)
+    );
+    assert_eq!(a.unwrap().unwrap().text, "Populated");
+    assert_eq!(b.unwrap().unwrap().text, "Populated");
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // Now the entry is cached
+    cache.get_or_populate(Keyspace::Descriptions, "pikachu", populate).await.unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+  }
+
+  /// Shared backend holding a single description, created `age` ago.
+  struct AgedShared {
+    age: Duration
+  }
+
+  #[async_trait]
+  impl CacheBackend for AgedShared {
+    fn name(&self) -> &'static str {
+      "aged"
+    }
+    async fn get(&self, _keyspace: Keyspace, _key: &str) -> Result<Option<CacheEntry>> {
+      let entry = CacheEntry::original("en", "Shared".to_string());
+      Ok(Some(CacheEntry { created_at: entry.created_at - self.age.as_secs(), ..entry }))
+    }
+    async fn put(&self, _keyspace: Keyspace, _key: &str, _entry: &CacheEntry, _ttl: Option<Duration>) -> Result<()> {
+      Ok(())
+    }
+    async fn ping(&self) -> Result<()> {
+      Ok(())
+    }
+  }
+
+  #[tokio::test]
+  async fn test_backfill_keeps_the_age_of_the_entries() {
+    let ttl = Ttl { base: Duration::from_secs(100), jitter: 0.0 };
+
+    // An entry which would have already expired locally is served, but not copied in the local tier
+    let cache = Cache::new(MemoryCache::new(1, 1)).with_ttl(ttl).with_shared(Arc::new(AgedShared { age: Duration::from_secs(200) }));
+    assert_eq!(cache.get(Keyspace::Descriptions, "pikachu").await.unwrap().text, "Shared");
+    assert!(cache.local.get(Keyspace::Descriptions, "pikachu").is_none());
+
+    let cache = Cache::new(MemoryCache::new(1, 1)).with_ttl(ttl).with_shared(Arc::new(AgedShared { age: Duration::from_secs(50) }));
+    cache.get(Keyspace::Descriptions, "pikachu").await.unwrap();
+    assert!(cache.local.get(Keyspace::Descriptions, "pikachu").is_some());
+  }
+
+}
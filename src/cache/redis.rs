@@ -0,0 +1,234 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use rand::Rng;
+use redis::aio::ConnectionManager;
+
+use crate::cache::{CacheBackend, CacheEntry, Discarded, Integrity, Keyspace};
+use crate::metrics;
+
+/// Releases a lock only if it is still owned by the caller.
+const UNLOCK_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+  return redis.call("DEL", KEYS[1])
+else
+  return 0
+end
+"#;
+
+/// Extends a lock only if it is still owned by the caller.
+const RENEW_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+  return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+else
+  return 0
+end
+"#;
+
+/// Moves an entry to the quarantine only if it has not been overwritten since it was checked.
+const QUARANTINE_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+  redis.call("RENAME", KEYS[1], KEYS[2])
+  return redis.call("PEXPIRE", KEYS[2], ARGV[2])
+else
+  return 0
+end
+"#;
+
+/// How long the quarantined entries are kept for inspection.
+const QUARANTINE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Maximum number of keys scanned when listing the entries of a keyspace, so that a huge keyspace cannot stall Redis.
+const MAX_SCANNED_KEYS: usize = 10_000;
+
+/// A [`CacheBackend`](crate::cache::CacheBackend) storing entries in Redis,
+/// shared among all the replicas of the application.
+#[derive(Clone)]
+pub struct RedisCache {
+  conn: ConnectionManager
+}
+
+impl RedisCache {
+
+  /// Connects to the Redis instance at the given url.
+  pub async fn connect(url: &str) -> Result<Self> {
+    let client = redis::Client::open(url).context("Invalid Redis URL")?;
+    let conn = client.get_connection_manager().await.context("Cannot connect to Redis")?;
+    Ok(RedisCache { conn })
+  }
+
+  fn entry_key(keyspace: Keyspace, key: &str) -> String {
+    format!("pokechallenge:{}:{}", keyspace.name(), key)
+  }
+
+  fn quarantine_key(keyspace: Keyspace, key: &str) -> String {
+    format!("pokechallenge:quarantine:{}:{}", keyspace.name(), key)
+  }
+
+  fn lock_key(key: &str) -> String {
+    format!("pokechallenge:lock:{}", key)
+  }
+
+  /// Tries to acquire the lock with the given name, expiring automatically after `ttl`.
+  /// Returns the token needed to release the lock, or `None` if the lock is already held by someone else.
+  pub async fn try_lock(&self, key: &str, ttl: Duration) -> Result<Option<String>> {
+    let token = format!("{:016x}", rand::thread_rng().gen::<u64>());
+    let res: Option<String> = redis::cmd("SET")
+      .arg(RedisCache::lock_key(key))
+      .arg(&token)
+      .arg("NX")
+      .arg("PX")
+      .arg(ttl.as_millis() as u64)
+      .query_async(&mut self.conn.clone())
+      .await?;
+    Ok(res.map(|_| token))
+  }
+
+  /// Returns whether the lock with the given name is currently held by anyone.
+  pub async fn is_locked(&self, key: &str) -> Result<bool> {
+    let exists: bool = redis::cmd("EXISTS")
+      .arg(RedisCache::lock_key(key))
+      .query_async(&mut self.conn.clone())
+      .await?;
+    Ok(exists)
+  }
+
+  /// Extends the expiration of a lock previously acquired with [`try_lock`](crate::cache::redis::RedisCache::try_lock).
+  /// Returns `false` if the lock expired in the meantime, and is not owned by the caller anymore.
+  pub async fn renew_lock(&self, key: &str, token: &str, ttl: Duration) -> Result<bool> {
+    let renewed = redis::Script::new(RENEW_SCRIPT)
+      .key(RedisCache::lock_key(key))
+      .arg(token)
+      .arg(ttl.as_millis() as u64)
+      .invoke_async::<i64>(&mut self.conn.clone())
+      .await?;
+    Ok(renewed == 1)
+  }
+
+  /// Releases a lock previously acquired with [`try_lock`](crate::cache::redis::RedisCache::try_lock).
+  pub async fn unlock(&self, key: &str, token: &str) -> Result<()> {
+    redis::Script::new(UNLOCK_SCRIPT)
+      .key(RedisCache::lock_key(key))
+      .arg(token)
+      .invoke_async::<i64>(&mut self.conn.clone())
+      .await?;
+    Ok(())
+  }
+
+  /// Incrementally scans the keys starting with the given prefix, up to [`MAX_SCANNED_KEYS`].
+  async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut cursor = 0u64;
+    loop {
+      let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+        .arg(cursor)
+        .arg("MATCH")
+        .arg(format!("{}*", prefix))
+        .arg("COUNT")
+        .arg(1000)
+        .query_async(&mut self.conn.clone())
+        .await?;
+      keys.extend(batch);
+      cursor = next;
+      if cursor == 0 || keys.len() >= MAX_SCANNED_KEYS {
+        return Ok(keys);
+      }
+    }
+  }
+
+}
+
+#[async_trait]
+impl CacheBackend for RedisCache {
+
+  fn name(&self) -> &'static str {
+    "redis"
+  }
+
+  async fn get(&self, keyspace: Keyspace, key: &str) -> Result<Option<CacheEntry>> {
+    let value: Option<Vec<u8>> = redis::cmd("GET")
+      .arg(RedisCache::entry_key(keyspace, key))
+      .query_async(&mut self.conn.clone())
+      .await?;
+    Ok(value.and_then(|value| CacheEntry::decode(&value)))
+  }
+
+  async fn put(&self, keyspace: Keyspace, key: &str, entry: &CacheEntry, ttl: Option<Duration>) -> Result<()> {
+    let mut cmd = redis::cmd("SET");
+    cmd.arg(RedisCache::entry_key(keyspace, key)).arg(entry.encode()?);
+    if let Some(ttl) = ttl {
+      cmd.arg("PX").arg((ttl.as_millis() as u64).max(1));
+    }
+    cmd.query_async::<()>(&mut self.conn.clone()).await?;
+    Ok(())
+  }
+
+  async fn ping(&self) -> Result<()> {
+    redis::cmd("PING").query_async::<String>(&mut self.conn.clone()).await?;
+    Ok(())
+  }
+
+  async fn recent(&self, keyspace: Keyspace, limit: usize) -> Result<(Vec<(String, CacheEntry)>, Discarded)> {
+
+    let prefix = RedisCache::entry_key(keyspace, "");
+    let keys = self.scan_keys(&prefix).await?;
+    if keys.is_empty() {
+      return Ok((Vec::new(), Discarded::default()));
+    }
+
+    // Fetch them all at once, and keep the most recent ones
+    let values: Vec<Option<Vec<u8>>> = redis::cmd("MGET")
+      .arg(&keys)
+      .query_async(&mut self.conn.clone())
+      .await?;
+    let mut discarded = Discarded::default();
+    let mut entries = keys.iter()
+      .zip(values)
+      .filter_map(|(key, value)| Some((key.strip_prefix(&prefix)?.to_string(), CacheEntry::decode_counted(&value?, &mut discarded)?)))
+      .collect::<Vec<_>>();
+    entries.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.created_at));
+    entries.truncate(limit);
+    Ok((entries, discarded))
+
+  }
+
+  async fn check_integrity(&self, keyspace: Keyspace) -> Result<Option<Integrity>> {
+
+    let prefix = RedisCache::entry_key(keyspace, "");
+    let keys = self.scan_keys(&prefix).await?;
+    let mut integrity = Integrity::default();
+    if keys.is_empty() {
+      return Ok(Some(integrity));
+    }
+
+    let values: Vec<Option<Vec<u8>>> = redis::cmd("MGET")
+      .arg(&keys)
+      .query_async(&mut self.conn.clone())
+      .await?;
+    for (key, value) in keys.iter().zip(values) {
+      // Skip the entries which expired while scanning
+      let (key, value) = match (key.strip_prefix(&prefix), value) {
+        (Some(key), Some(value)) => (key, value),
+        _ => continue
+      };
+      if !integrity.check(&value) {
+        continue;
+      }
+      let moved: i64 = redis::Script::new(QUARANTINE_SCRIPT)
+        .key(RedisCache::entry_key(keyspace, key))
+        .key(RedisCache::quarantine_key(keyspace, key))
+        .arg(value)
+        .arg(QUARANTINE_TTL.as_millis() as u64)
+        .invoke_async(&mut self.conn.clone())
+        .await?;
+      if moved == 1 {
+        metrics::CACHE_QUARANTINED_ENTRIES.with_label_values(&[keyspace.name()]).inc();
+        integrity.quarantined += 1;
+      }
+    }
+    Ok(Some(integrity))
+
+  }
+
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::Mutex;
+
+use tokio::sync::broadcast;
+
+/// Error of the callers finding too many others already waiting for the same key.
+#[derive(Debug)]
+pub struct TooManyWaiters;
+
+impl fmt::Display for TooManyWaiters {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Too many requests waiting for the same entry")
+  }
+}
+
+impl std::error::Error for TooManyWaiters {}
+
+/// Deduplicates concurrent computations for the same key:
+/// while a computation is in flight, other callers with the same key wait for its result
+/// instead of starting their own.
+pub struct SingleFlight<T> {
+  inflight: Mutex<HashMap<String, broadcast::Sender<T>>>,
+  /// Maximum number of callers waiting for each computation, if capped.
+  max_waiters: Option<usize>
+}
+
+/// Removes the in-flight marker even if the leader future gets cancelled,
+/// so that waiters do not hang forever.
+struct FlightGuard<'a, T> {
+  flights: &'a SingleFlight<T>,
+  key: &'a str,
+  armed: bool
+}
+
+impl<'a, T> Drop for FlightGuard<'a, T> {
+  fn drop(&mut self) {
+    if self.armed {
+      self.flights.inflight.lock().unwrap().remove(self.key);
+    }
+  }
+}
+
+impl<T: Clone> Default for SingleFlight<T> {
+  fn default() -> Self {
+    SingleFlight {
+      inflight: Mutex::new(HashMap::new()),
+      max_waiters: None
+    }
+  }
+}
+
+impl<T: Clone> SingleFlight<T> {
+
+  /// Caps the number of callers waiting for each computation,
+  /// so that a single hot key cannot hold all the requests being served.
+  pub fn with_max_waiters(mut self, max_waiters: Option<usize>) -> Self {
+    self.max_waiters = max_waiters;
+    self
+  }
+
+  /// Runs `f`, unless a computation for the same key is already running,
+  /// in which case its result is awaited and returned.
+  /// If the running computation is cancelled, one of the waiters takes over running `f`, and the others wait for it.
+  /// Fails without waiting if the computation already has the maximum number of waiters.
+  pub async fn run<F, Fut>(&self, key: &str, f: F) -> Result<T, TooManyWaiters>
+    where F: FnOnce() -> Fut, Fut: Future<Output = T>
+  {
+    loop {
+      let waiter = {
+        let mut inflight = self.inflight.lock().unwrap();
+        match inflight.get(key) {
+          Some(tx) if self.max_waiters.is_some_and(|max| tx.receiver_count() >= max) => return Err(TooManyWaiters),
+          Some(tx) => Some(tx.subscribe()),
+          None => {
+            let (tx, _) = broadcast::channel(1);
+            inflight.insert(key.to_string(), tx);
+            None
+          }
+        }
+      };
+
+      // The channel is closed without a result only when the leader is cancelled:
+      // the first waiter to get here again becomes the new leader
+      match waiter {
+        Some(mut rx) => if let Ok(res) = rx.recv().await {
+          return Ok(res);
+        },
+        None => break
+      }
+    }
+
+    let mut guard = FlightGuard { flights: self, key, armed: true };
+    let res = f().await;
+    guard.armed = false;
+    let tx = self.inflight.lock().unwrap().remove(key);
+    if let Some(tx) = tx {
+      let _ = tx.send(res.clone());
+    }
+    Ok(res)
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::time::Duration;
+
+  #[tokio::test]
+  async fn test_concurrent_calls_are_deduplicated() {
+    let flights = Arc::new(SingleFlight::<usize>::default());
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    let tasks = (0..10).map(|_| {
+      let flights = flights.clone();
+      let calls = calls.clone();
+      tokio::spawn(async move {
+        flights.run("pikachu", || async {
+          tokio::time::sleep(Duration::from_millis(50)).await;
+          calls.fetch_add(1, Ordering::SeqCst) + 42
+        }).await
+      })
+    }).collect::<Vec<_>>();
+
+    for task in tasks {
+      assert_eq!(task.await.unwrap().unwrap(), 42);
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+  }
+
+  #[tokio::test]
+  async fn test_cancelled_leader_is_taken_over() {
+    let flights = Arc::new(SingleFlight::<usize>::default());
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    let spawn = || {
+      let flights = flights.clone();
+      let calls = calls.clone();
+      tokio::spawn(async move {
+        flights.run("pikachu", || async {
+          calls.fetch_add(1, Ordering::SeqCst);
+          tokio::time::sleep(Duration::from_millis(50)).await;
+          42
+        }).await
+      })
+    };
+    let leader = spawn();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let waiters = (0..5).map(|_| spawn()).collect::<Vec<_>>();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    leader.abort();
+
+    // A single waiter runs the computation again, for all the others
+    for waiter in waiters {
+      assert_eq!(waiter.await.unwrap().unwrap(), 42);
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+  }
+
+  #[tokio::test]
+  async fn test_different_keys_are_independent() {
+    let flights = SingleFlight::<&str>::default();
+    let (a, b) = futures::join!(
+      flights.run("pikachu", || async { "pikachu" }),
+      flights.run("bulbasaur", || async { "bulbasaur" })
+    );
+    assert_eq!((a.unwrap(), b.unwrap()), ("pikachu", "bulbasaur"));
+  }
+
+  #[tokio::test]
+  async fn test_max_waiters() {
+    let flights = Arc::new(SingleFlight::<usize>::default().with_max_waiters(Some(2)));
+
+    let tasks = (0..5).map(|_| {
+      let flights = flights.clone();
+      tokio::spawn(async move {
+        flights.run("pikachu", || async {
+          tokio::time::sleep(Duration::from_millis(50)).await;
+          42
+        }).await
+      })
+    }).collect::<Vec<_>>();
+
+    // The leader and two waiters get the result, the others are turned away
+    let mut served = 0;
+    for task in tasks {
+      if task.await.unwrap().is_ok() {
+        served += 1;
+      }
+    }
+    assert_eq!(served, 3);
+  }
+
+}
@@ -0,0 +1,99 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::cache::{Cache, CacheEntry, Keyspace};
+
+/// Where the long-running jobs keep their progress, so that an interrupted run resumes instead of starting over.
+pub enum CheckpointStore {
+  /// A local file, for the runs on a single machine.
+  File(PathBuf),
+  /// The shared cache, so that the run resumes wherever it is started again, like in a new container.
+  Shared(Arc<Cache>)
+}
+
+/// Writes a file atomically, so that an interrupted write does not leave it truncated.
+pub fn write_atomically(path: &Path, content: &[u8]) -> Result<()> {
+  let mut tmp = path.as_os_str().to_owned();
+  tmp.push(".tmp");
+  fs::write(&tmp, content).with_context(|| format!("Cannot write {}", path.display()))?;
+  fs::rename(&tmp, path).with_context(|| format!("Cannot write {}", path.display()))?;
+  Ok(())
+}
+
+impl CheckpointStore {
+
+  /// Describes where the progress is kept, for the logs.
+  pub fn describe(&self) -> String {
+    match self {
+      CheckpointStore::File(path) => path.display().to_string(),
+      CheckpointStore::Shared(cache) => format!("the {} cache", cache.shared().map(|shared| shared.name()).unwrap_or("local"))
+    }
+  }
+
+  /// Loads the progress of the given job, if it was interrupted.
+  pub async fn load<T: DeserializeOwned>(&self, job: &str) -> Result<Option<T>> {
+    match self {
+      CheckpointStore::File(path) => match fs::read(path) {
+        Ok(content) => Ok(Some(serde_json::from_slice(&content).with_context(|| format!("Invalid checkpoint {}", path.display()))?)),
+        Err(_) => Ok(None)
+      },
+      // Completed jobs leave an empty checkpoint behind, as entries cannot be removed from all the backends
+      CheckpointStore::Shared(cache) => match cache.get(Keyspace::Checkpoints, job).await {
+        Some(entry) => Ok(entry.parse_data::<Option<T>>().with_context(|| format!("Invalid checkpoint of {}", job))?),
+        None => Ok(None)
+      }
+    }
+  }
+
+  /// Saves the progress of the given job.
+  pub async fn save<T: Serialize>(&self, job: &str, progress: &T) -> Result<()> {
+    match self {
+      CheckpointStore::File(path) => write_atomically(path, &serde_json::to_vec(progress)?),
+      CheckpointStore::Shared(cache) => {
+        cache.put(Keyspace::Checkpoints, job.to_string(), CacheEntry::data(&Some(progress))?).await;
+        Ok(())
+      }
+    }
+  }
+
+  /// Forgets the progress of a completed job, so that the next run starts from scratch.
+  pub async fn clear(&self, job: &str) -> Result<()> {
+    match self {
+      CheckpointStore::File(path) => {
+        if path.exists() {
+          fs::remove_file(path).with_context(|| format!("Cannot remove {}", path.display()))?;
+        }
+        Ok(())
+      },
+      CheckpointStore::Shared(cache) => {
+        cache.put(Keyspace::Checkpoints, job.to_string(), CacheEntry::data(&None::<()>)?).await;
+        Ok(())
+      }
+    }
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::cache::memory::MemoryCache;
+
+  #[tokio::test]
+  async fn test_shared_checkpoints() {
+    let store = CheckpointStore::Shared(Arc::new(Cache::new(MemoryCache::new(2, 2))));
+    assert_eq!(store.load::<u64>("export").await.unwrap(), None);
+
+    store.save("export", &42u64).await.unwrap();
+    assert_eq!(store.load::<u64>("export").await.unwrap(), Some(42));
+
+    store.clear("export").await.unwrap();
+    assert_eq!(store.load::<u64>("export").await.unwrap(), None);
+  }
+
+}
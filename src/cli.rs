@@ -0,0 +1,140 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use clap::{Parser, Subcommand, Args, ValueEnum};
+
+use crate::build_info;
+
+/// Maximum rate of the load tests, keeping the interval between two requests well above the timer resolution.
+pub const MAX_RPS: u32 = 100_000;
+
+/// Shakespearean descriptions of Pokemons.
+#[derive(Parser)]
+#[command(version = build_info::LONG_VERSION)]
+pub struct Cli {
+  #[command(subcommand)]
+  pub command: Option<Command>
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+  /// Starts the HTTP server (the default when no command is given).
+  Serve,
+  /// Sends a steady stream of requests to a running instance and reports the latencies.
+  Loadtest(LoadtestArgs),
+  /// Starts a local server emulating the PokeAPI and the Shakespeare Translator, for development.
+  MockUpstreams(MockUpstreamsArgs),
+  /// Validates the configuration and prints it, with the secrets masked, without starting the server.
+  CheckConfig,
+  /// Writes the OpenAPI document of the API.
+  #[command(name = "export-openapi")]
+  ExportOpenApi(ExportOpenApiArgs),
+  /// Writes the translated description of all the species, resuming the previous run if it was interrupted.
+  ExportPokedex(ExportPokedexArgs)
+}
+
+#[derive(Args)]
+pub struct LoadtestArgs {
+  /// Base url of the instance to test.
+  #[arg(long)]
+  pub target: String,
+  /// Number of requests per second to send, between 1 and 100000.
+  #[arg(long, default_value_t = 10, value_parser = parse_rps)]
+  pub rps: u32,
+  /// Duration of the test, like `60s`, `2m` or `500ms`.
+  #[arg(long, default_value = "10s", value_parser = parse_duration)]
+  pub duration: Duration,
+  /// Weighted list of Pokemon names to request, like `pikachu:10,ditto:1`.
+  /// Defaults to a small built-in distribution skewed towards popular Pokemons.
+  #[arg(long)]
+  pub names: Option<String>
+}
+
+#[derive(Args)]
+pub struct MockUpstreamsArgs {
+  /// Port to bind the server to.
+  #[arg(long, default_value_t = 8081)]
+  pub port: u16,
+  /// Number of translations allowed in each window before answering with a 429, like the public API does.
+  #[arg(long, default_value_t = 5)]
+  pub translator_limit: u32,
+  /// Length of the rate limiting window of the translator.
+  #[arg(long, default_value = "1h", value_parser = parse_duration)]
+  pub translator_window: Duration
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum OpenApiFormat {
+  Json,
+  Yaml
+}
+
+#[derive(Args)]
+pub struct ExportOpenApiArgs {
+  /// Serialization format of the document.
+  #[arg(long, value_enum, default_value_t = OpenApiFormat::Json)]
+  pub format: OpenApiFormat,
+  /// File to write the document to. Defaults to the standard output.
+  #[arg(long)]
+  pub output: Option<PathBuf>
+}
+
+#[derive(Args)]
+pub struct ExportPokedexArgs {
+  /// File to write the Pokedex to.
+  #[arg(long, default_value = "pokedex.json")]
+  pub out: PathBuf,
+  /// File keeping the progress of the export, removed when it completes. Defaults to the output file with a `.checkpoint` suffix.
+  #[arg(long)]
+  pub checkpoint: Option<PathBuf>
+}
+
+/// Parses a request rate, rejecting the ones which cannot be paced.
+pub fn parse_rps(s: &str) -> Result<u32> {
+  let rps = s.trim().parse::<u32>().map_err(|_| anyhow!("Invalid rate: {}", s))?;
+  if rps == 0 || rps > MAX_RPS {
+    return Err(anyhow!("The rate must be between 1 and {}", MAX_RPS));
+  }
+  Ok(rps)
+}
+
+/// Parses a duration with an optional `ms`, `s`, `m` or `h` unit. Durations without a unit are in seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+  let s = s.trim();
+  let (value, unit) = s.find(|c: char| !c.is_ascii_digit())
+    .map(|idx| s.split_at(idx))
+    .unwrap_or((s, "s"));
+  let value = value.parse::<u64>().map_err(|_| anyhow!("Invalid duration: {}", s))?;
+  match unit {
+    "ms" => Ok(Duration::from_millis(value)),
+    "s" => Ok(Duration::from_secs(value)),
+    "m" => Ok(Duration::from_secs(value * 60)),
+    "h" => Ok(Duration::from_secs(value * 60 * 60)),
+    _ => Err(anyhow!("Invalid duration unit: {}", unit))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse_duration() {
+    assert_eq!(parse_duration("60s").unwrap(), Duration::from_secs(60));
+    assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+    assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+    assert_eq!(parse_duration("15").unwrap(), Duration::from_secs(15));
+    assert!(parse_duration("1d").is_err());
+    assert!(parse_duration("s").is_err());
+  }
+
+  #[test]
+  fn test_parse_rps() {
+    assert_eq!(parse_rps("10").unwrap(), 10);
+    assert!(parse_rps("0").is_err());
+    assert!(parse_rps("2000000000").is_err());
+    assert!(parse_rps("fast").is_err());
+  }
+
+}
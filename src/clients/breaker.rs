@@ -0,0 +1,212 @@
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tracing::{info, warn};
+
+use crate::metrics;
+
+/// Error of the calls rejected without contacting the upstream, because its circuit breaker is open.
+#[derive(Debug)]
+pub struct BreakerOpen;
+
+impl std::fmt::Display for BreakerOpen {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Circuit breaker open")
+  }
+}
+
+impl std::error::Error for BreakerOpen {}
+
+enum BreakerState {
+  Closed { failures: u32 },
+  Open { until: Instant },
+  /// A single probe has been let through, and the other calls are rejected until it completes,
+  /// or until the given time if it never does.
+  HalfOpen { until: Instant }
+}
+
+/// Stops calling an upstream after a number of consecutive failures.
+///
+/// Once open, the calls fail fast for the cooldown, after which a single probe is let through:
+/// the breaker closes again if it succeeds, and opens for another cooldown otherwise.
+pub struct CircuitBreaker {
+  name: &'static str,
+  threshold: u32,
+  cooldown: Duration,
+  state: Mutex<BreakerState>
+}
+
+impl CircuitBreaker {
+
+  /// Creates a breaker opening after `threshold` consecutive failures.
+  pub fn new(name: &'static str, threshold: u32, cooldown: Duration) -> Self {
+    metrics::CIRCUIT_BREAKER_STATE.with_label_values(&[name]).set(0);
+    CircuitBreaker {
+      name,
+      threshold: threshold.max(1),
+      cooldown,
+      state: Mutex::new(BreakerState::Closed { failures: 0 })
+    }
+  }
+
+  /// Whether the calls reach the upstream, as opposed to the breaker being open or probing it.
+  pub fn is_closed(&self) -> bool {
+    matches!(*self.state.lock().unwrap(), BreakerState::Closed { .. })
+  }
+
+  /// Name of the current state, `closed`, `open` or `half_open`, as exported by the metrics.
+  pub fn state(&self) -> &'static str {
+    match *self.state.lock().unwrap() {
+      BreakerState::Closed { .. } => "closed",
+      BreakerState::Open { .. } => "open",
+      BreakerState::HalfOpen { .. } => "half_open"
+    }
+  }
+
+  /// Runs the call, unless the breaker is open.
+  pub async fn call<T>(&self, fut: impl Future<Output = Result<T>>) -> Result<T> {
+    self.call_with(fut, Result::is_err).await
+  }
+
+  /// Runs the call, unless the breaker is open, counting as failures only the outcomes for which `is_failure` holds,
+  /// like the server errors of an upstream as opposed to its client errors.
+  pub async fn call_with<T>(&self, fut: impl Future<Output = Result<T>>, is_failure: impl FnOnce(&Result<T>) -> bool) -> Result<T> {
+    self.acquire()?;
+    let res = fut.await;
+    self.record(!is_failure(&res));
+    res
+  }
+
+  fn acquire(&self) -> std::result::Result<(), BreakerOpen> {
+    let mut state = self.state.lock().unwrap();
+    let now = Instant::now();
+    match *state {
+      BreakerState::Closed { .. } => Ok(()),
+      // A probe which never completed, like a cancelled one, does not keep the breaker half-open forever
+      BreakerState::Open { until } | BreakerState::HalfOpen { until } if now >= until => {
+        *state = BreakerState::HalfOpen { until: now + self.cooldown };
+        self.transition("half_open");
+        Ok(())
+      },
+      _ => Err(BreakerOpen)
+    }
+  }
+
+  fn record(&self, success: bool) {
+    let mut state = self.state.lock().unwrap();
+    match (&*state, success) {
+      (BreakerState::HalfOpen { .. }, true) => {
+        *state = BreakerState::Closed { failures: 0 };
+        self.transition("closed");
+      },
+      (BreakerState::HalfOpen { .. }, false) => {
+        *state = BreakerState::Open { until: Instant::now() + self.cooldown };
+        self.transition("open");
+      },
+      (BreakerState::Closed { .. }, true) => *state = BreakerState::Closed { failures: 0 },
+      (BreakerState::Closed { failures }, false) => {
+        let failures = failures + 1;
+        if failures >= self.threshold {
+          *state = BreakerState::Open { until: Instant::now() + self.cooldown };
+          self.transition("open");
+        } else {
+          *state = BreakerState::Closed { failures };
+        }
+      },
+      // Calls started before the breaker opened do not change its state
+      (BreakerState::Open { .. }, _) => {}
+    }
+  }
+
+  /// Records a change of state, with an event on the span of the call which caused it.
+  fn transition(&self, state: &str) {
+    match state {
+      "open" => warn!(breaker = self.name, state, cooldown_ms = self.cooldown.as_millis() as u64, "Circuit breaker opened"),
+      "half_open" => info!(breaker = self.name, state, "Circuit breaker half-open, probing the upstream"),
+      _ => info!(breaker = self.name, state, "Circuit breaker closed")
+    }
+    metrics::CIRCUIT_BREAKER_TRANSITIONS.with_label_values(&[self.name, state]).inc();
+    let value = match state {
+      "closed" => 0,
+      "half_open" => 1,
+      _ => 2
+    };
+    metrics::CIRCUIT_BREAKER_STATE.with_label_values(&[self.name]).set(value);
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use anyhow::anyhow;
+
+  #[tokio::test]
+  async fn test_open_and_close() {
+
+    let breaker = CircuitBreaker::new("test_open", 2, Duration::from_millis(20));
+    let fail = || async { Err::<(), _>(anyhow!("Down")) };
+
+    // Opens after two consecutive failures, and then fails fast
+    assert!(breaker.call(fail()).await.is_err());
+    assert!(breaker.is_closed());
+    assert!(breaker.call(fail()).await.is_err());
+    assert!(!breaker.is_closed());
+    assert_eq!(breaker.state(), "open");
+    assert!(breaker.call(async { Ok(()) }).await.unwrap_err().is::<BreakerOpen>());
+
+    // A failed probe opens it again
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert!(!breaker.call(fail()).await.unwrap_err().is::<BreakerOpen>());
+    assert!(breaker.call(async { Ok(()) }).await.unwrap_err().is::<BreakerOpen>());
+
+    // A successful probe closes it
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    breaker.call(async { Ok(()) }).await.unwrap();
+    assert!(breaker.is_closed());
+    assert_eq!(breaker.state(), "closed");
+    assert_eq!(metrics::CIRCUIT_BREAKER_STATE.with_label_values(&["test_open"]).get(), 0);
+
+  }
+
+  #[tokio::test]
+  async fn test_ignored_failures() {
+
+    let breaker = CircuitBreaker::new("test_ignored", 1, Duration::from_secs(30));
+    let not_found = || async { Err::<(), _>(anyhow!("Not found")) };
+
+    // Only the failures passing the check open the breaker
+    assert!(breaker.call_with(not_found(), |_| false).await.is_err());
+    assert!(breaker.is_closed());
+    assert!(breaker.call_with(not_found(), |_| true).await.is_err());
+    assert!(!breaker.is_closed());
+    assert_eq!(metrics::CIRCUIT_BREAKER_STATE.with_label_values(&["test_ignored"]).get(), 2);
+
+  }
+
+  #[tokio::test]
+  async fn test_single_probe() {
+
+    let breaker = CircuitBreaker::new("test", 1, Duration::from_millis(100));
+    assert!(breaker.call(async { Err::<(), _>(anyhow!("Down")) }).await.is_err());
+    tokio::time::sleep(Duration::from_millis(120)).await;
+
+    // While the probe is in flight, the other calls are rejected
+    let probe = breaker.call(async {
+      tokio::time::sleep(Duration::from_millis(50)).await;
+      Ok(())
+    });
+    let other = async {
+      tokio::time::sleep(Duration::from_millis(10)).await;
+      breaker.call(async { Ok(()) }).await
+    };
+    let (probe, other) = futures::join!(probe, other);
+    probe.unwrap();
+    assert!(other.unwrap_err().is::<BreakerOpen>());
+    assert!(breaker.is_closed());
+
+  }
+
+}
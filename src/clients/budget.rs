@@ -0,0 +1,318 @@
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use prometheus::IntGauge;
+use reqwest::header::HeaderMap;
+
+use crate::metrics;
+
+tokio::task_local! {
+  static PRIORITY: Priority;
+}
+
+/// Priority of the calls competing for the budget.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+  /// Calls made on behalf of a user waiting for the response.
+  Interactive,
+  /// Calls made by background jobs, like prewarming or refreshing the cache.
+  Background
+}
+
+impl Priority {
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Priority::Interactive => "interactive",
+      Priority::Background => "background"
+    }
+  }
+
+}
+
+/// Runs a future with the [`Background`](Priority::Background) priority,
+/// so that its calls never take the budget from the interactive requests.
+pub async fn background<F: Future>(fut: F) -> F::Output {
+  PRIORITY.scope(Priority::Background, fut).await
+}
+
+/// Priority of the task being run. Anything which has not been marked as background is interactive.
+fn current_priority() -> Priority {
+  PRIORITY.try_with(|priority| *priority).unwrap_or(Priority::Interactive)
+}
+
+/// Token bucket tracking the remaining quota of calls to a rate limited upstream, like the free tier of FunTranslations.
+///
+/// Tokens are refilled continuously, reaching `capacity` once every `period`,
+/// and corrected with the remaining quota advertised by the upstream in its responses.
+/// Once the upstream reports the quota exhausted, nothing is refilled until the reset it advertises, if any.
+/// When the bucket is empty, callers queue for the next tokens, and interactive callers are always served first.
+pub struct Budget {
+  capacity: f64,
+  period: Duration,
+  /// Maximum time an interactive caller waits for the budget. Background callers wait up to a whole period.
+  max_wait: Duration,
+  state: Mutex<BudgetState>
+}
+
+struct BudgetState {
+  tokens: f64,
+  updated_at: Instant,
+  /// Number of interactive callers waiting for the budget.
+  interactive_waiting: usize,
+  /// When the quota of the upstream is reset, if it was exhausted.
+  paused_until: Option<Instant>
+}
+
+impl BudgetState {
+
+  fn refill(&mut self, capacity: f64, period: Duration) {
+    let now = Instant::now();
+    match self.paused_until {
+      Some(reset) if now < reset => {},
+      Some(_) => {
+        self.tokens = capacity;
+        self.paused_until = None;
+      },
+      None => {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + capacity * elapsed / period.as_secs_f64()).min(capacity);
+      }
+    }
+    self.updated_at = now;
+  }
+
+}
+
+impl Budget {
+
+  /// Creates a full budget of `capacity` calls every `period`.
+  pub fn new(capacity: u32, period: Duration, max_wait: Duration) -> Self {
+    let budget = Budget {
+      capacity: capacity as f64,
+      period,
+      max_wait,
+      state: Mutex::new(BudgetState { tokens: capacity as f64, updated_at: Instant::now(), interactive_waiting: 0, paused_until: None })
+    };
+    metrics::TRANSLATOR_BUDGET_REMAINING.set(capacity as i64);
+    budget
+  }
+
+  /// Consumes the budget for `calls` calls, with the priority of the current task.
+  /// If there is not enough budget, waits for it to be refilled, and gives up returning `false` after the maximum wait.
+  pub async fn acquire(&self, calls: u32) -> bool {
+
+    let priority = current_priority();
+    let max_wait = match priority {
+      Priority::Interactive => self.max_wait,
+      Priority::Background => self.period
+    };
+    let deadline = Instant::now() + max_wait;
+    let mut queued = None;
+
+    let acquired = loop {
+
+      // Background callers only get the tokens nobody else is waiting for
+      let wait = {
+        let mut state = self.state.lock().unwrap();
+        state.refill(self.capacity, self.period);
+        let can_take = priority == Priority::Interactive || state.interactive_waiting == 0;
+        if can_take && state.tokens >= calls as f64 {
+          state.tokens -= calls as f64;
+          metrics::TRANSLATOR_BUDGET_REMAINING.set(state.tokens as i64);
+          break true;
+        }
+
+        // No point in waiting for a reset coming after the deadline
+        let now = Instant::now();
+        if now >= deadline || calls as f64 > self.capacity || state.paused_until.is_some_and(|reset| reset > deadline) {
+          break false;
+        }
+        if queued.is_none() {
+          queued = Some(Waiter::new(self, &mut state, priority));
+        }
+
+        // Sleep until the missing tokens are refilled, checking now and then
+        // in case the upstream reported a different budget in the meantime
+        let missing = (calls as f64 - state.tokens).max(0.0);
+        let refill = match state.paused_until {
+          Some(reset) => reset.saturating_duration_since(now),
+          None => Duration::from_secs_f64(missing * self.period.as_secs_f64() / self.capacity)
+        };
+        refill.clamp(Duration::from_millis(10), Duration::from_secs(1)).min(deadline - now)
+      };
+
+      tokio::time::sleep(wait).await;
+
+    };
+
+    drop(queued);
+    acquired
+
+  }
+
+  /// Aligns the budget with the response of the upstream:
+  /// the `X-RateLimit-Remaining` header wins over our estimate, and a `429 Too Many Requests` empties the bucket.
+  /// When the bucket is empty, the calls are paused until the `X-RateLimit-Reset` advertised by the upstream.
+  pub fn observe(&self, status: u16, headers: &HeaderMap) {
+    let remaining = header(headers, "x-ratelimit-remaining");
+    let reset = header(headers, "x-ratelimit-reset").map(reset_delay);
+    let mut state = self.state.lock().unwrap();
+    state.refill(self.capacity, self.period);
+    if status == 429 {
+      state.tokens = 0.0;
+    } else if let Some(remaining) = remaining {
+      state.tokens = remaining.min(self.capacity);
+      if remaining >= 1.0 {
+        state.paused_until = None;
+      }
+    }
+    if state.tokens < 1.0 {
+      if let Some(reset) = reset {
+        state.paused_until = Some(Instant::now() + reset);
+      }
+    }
+    metrics::TRANSLATOR_BUDGET_REMAINING.set(state.tokens as i64);
+  }
+
+}
+
+/// A caller queued for the budget, counted as waiting until dropped,
+/// including when its future is dropped before getting the budget, like on a client disconnect or a timeout.
+struct Waiter<'a> {
+  budget: &'a Budget,
+  priority: Priority,
+  gauge: IntGauge
+}
+
+impl<'a> Waiter<'a> {
+
+  fn new(budget: &'a Budget, state: &mut BudgetState, priority: Priority) -> Self {
+    if priority == Priority::Interactive {
+      state.interactive_waiting += 1;
+    }
+    let gauge = metrics::TRANSLATOR_BUDGET_WAITING.with_label_values(&[priority.name()]);
+    gauge.inc();
+    Waiter { budget, priority, gauge }
+  }
+
+}
+
+impl Drop for Waiter<'_> {
+  fn drop(&mut self) {
+    if self.priority == Priority::Interactive {
+      self.budget.state.lock().unwrap().interactive_waiting -= 1;
+    }
+    self.gauge.dec();
+  }
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Option<f64> {
+  headers.get(name)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.trim().parse::<f64>().ok())
+    .filter(|value| value.is_finite() && *value >= 0.0)
+}
+
+/// Time until the reset of the quota. Values large enough to be Unix timestamps are taken as such,
+/// the others as seconds from now, as the upstreams do not agree on the format.
+fn reset_delay(reset: f64) -> Duration {
+  const TIMESTAMP_THRESHOLD: f64 = 1_000_000_000.0;
+  if reset < TIMESTAMP_THRESHOLD {
+    return Duration::from_secs_f64(reset);
+  }
+  let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+  Duration::from_secs_f64((reset - now).max(0.0))
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::sync::Arc;
+
+  #[tokio::test]
+  async fn test_budget() {
+    let budget = Budget::new(5, Duration::from_secs(3600), Duration::ZERO);
+
+    assert!(budget.acquire(3).await);
+    assert!(!budget.acquire(3).await);
+    assert!(budget.acquire(2).await);
+    assert!(!budget.acquire(1).await);
+
+    // The upstream knows better
+    let mut headers = HeaderMap::new();
+    headers.insert("x-ratelimit-remaining", "4".parse().unwrap());
+    budget.observe(200, &headers);
+    assert!(budget.acquire(4).await);
+
+    // Tokens are refilled over time
+    let budget = Budget::new(1000, Duration::from_millis(100), Duration::ZERO);
+    budget.observe(429, &HeaderMap::new());
+    assert!(!budget.acquire(1).await);
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(budget.acquire(1).await);
+  }
+
+  #[tokio::test]
+  async fn test_reset() {
+    let budget = Budget::new(1000, Duration::from_millis(100), Duration::from_secs(1));
+
+    // Nothing is refilled before the advertised reset, and then the whole quota is back
+    let mut headers = HeaderMap::new();
+    headers.insert("x-ratelimit-remaining", "0".parse().unwrap());
+    headers.insert("x-ratelimit-reset", "0.2".parse().unwrap());
+    budget.observe(200, &headers);
+    let started = Instant::now();
+    assert!(budget.acquire(1000).await);
+    assert!(started.elapsed() >= Duration::from_millis(200));
+
+    // Callers do not wait for a reset coming after their deadline
+    let budget = Budget::new(10, Duration::from_millis(100), Duration::from_millis(100));
+    headers.insert("x-ratelimit-reset", "3600".parse().unwrap());
+    budget.observe(429, &headers);
+    let started = Instant::now();
+    assert!(!budget.acquire(1).await);
+    assert!(started.elapsed() < Duration::from_millis(50));
+  }
+
+  #[test]
+  fn test_reset_delay() {
+    assert_eq!(reset_delay(30.0), Duration::from_secs(30));
+    let in_a_minute = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64() + 60.0;
+    let delay = reset_delay(in_a_minute);
+    assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60));
+    assert_eq!(reset_delay(1_000_000_000.0), Duration::ZERO);
+  }
+
+  #[tokio::test]
+  async fn test_interactive_calls_win() {
+    let budget = Arc::new(Budget::new(10, Duration::from_millis(500), Duration::from_secs(5)));
+    budget.observe(429, &HeaderMap::new());
+
+    // A background job queues first, but the interactive request gets the first token
+    let background_budget = budget.clone();
+    let background_call = tokio::spawn(background(async move {
+      background_budget.acquire(1).await;
+      Instant::now()
+    }));
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    assert!(budget.acquire(1).await);
+    let interactive_done = Instant::now();
+    assert!(background_call.await.unwrap() > interactive_done);
+  }
+
+  #[tokio::test]
+  async fn test_cancelled_waiter() {
+    let budget = Budget::new(10, Duration::from_millis(200), Duration::from_secs(5));
+    budget.observe(429, &HeaderMap::new());
+
+    // An interactive caller gives up while queued, like when the client disconnects
+    assert!(tokio::time::timeout(Duration::from_millis(5), budget.acquire(1)).await.is_err());
+
+    // The background callers are not kept waiting for it
+    assert!(background(budget.acquire(1)).await);
+  }
+
+}
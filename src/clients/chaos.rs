@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use rand::Rng;
+use tracing::{debug, warn};
+
+use crate::env_vars::optional_env;
+
+/// Fault injection for the upstream clients, used to exercise the resilience features locally.
+///
+/// Only available when the application is built with the `chaos` feature,
+/// so that it can never be turned on by mistake in a production build.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chaos {
+  /// Maximum latency added to each request. The actual latency is picked uniformly in `[0, latency]`.
+  pub latency: Duration,
+  /// Probability for a request to fail without being sent.
+  pub error_rate: f64
+}
+
+impl Chaos {
+
+  /// Reads the chaos configuration for an upstream from `CHAOS_<UPSTREAM>_LATENCY_MS` and `CHAOS_<UPSTREAM>_ERROR_RATE`.
+  /// Returns `None` if neither is set.
+  pub fn from_env(upstream: &str) -> Result<Option<Self>> {
+    let latency = optional_env::<u64>(&format!("CHAOS_{}_LATENCY_MS", upstream))?;
+    let error_rate = optional_env::<f64>(&format!("CHAOS_{}_ERROR_RATE", upstream))?;
+    if latency.is_none() && error_rate.is_none() {
+      return Ok(None);
+    }
+
+    let chaos = Chaos {
+      latency: Duration::from_millis(latency.unwrap_or(0)),
+      error_rate: error_rate.unwrap_or(0.0).clamp(0.0, 1.0)
+    };
+    warn!(upstream, latency = ?chaos.latency, error_rate = %chaos.error_rate, "Chaos mode enabled");
+    Ok(Some(chaos))
+  }
+
+  /// Delays the caller and randomly fails according to the configuration.
+  pub async fn inject(&self) -> Result<()> {
+    let (delay, fail) = {
+      let mut rng = rand::thread_rng();
+      (self.latency.mul_f64(rng.gen::<f64>()), rng.gen::<f64>() < self.error_rate)
+    };
+
+    if !delay.is_zero() {
+      debug!(delay = ?delay, "Chaos: injecting latency");
+      tokio::time::sleep(delay).await;
+    }
+    if fail {
+      debug!("Chaos: injecting failure");
+      return Err(anyhow!("Chaos: injected upstream failure"));
+    }
+    Ok(())
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_error_rate() {
+    let always = Chaos { latency: Duration::default(), error_rate: 1.0 };
+    let never = Chaos { latency: Duration::default(), error_rate: 0.0 };
+    for _ in 0..20 {
+      assert!(always.inject().await.is_err());
+      assert!(never.inject().await.is_ok());
+    }
+  }
+
+  #[tokio::test]
+  async fn test_latency() {
+    let chaos = Chaos { latency: Duration::from_millis(20), error_rate: 0.0 };
+    let start = std::time::Instant::now();
+    chaos.inject().await.unwrap();
+    assert!(start.elapsed() < Duration::from_millis(200));
+  }
+
+}
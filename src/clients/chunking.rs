@@ -0,0 +1,104 @@
+/// Splits a text in chunks of at most `max_chars` characters, trying to break it on sentence boundaries.
+/// Sentences longer than `max_chars` are split on word boundaries and, as a last resort, in the middle of a word.
+pub fn split_text(text: &str, max_chars: usize) -> Vec<String> {
+  let max_chars = max_chars.max(1);
+  let mut chunks = Vec::new();
+  let mut current = String::new();
+
+  for piece in sentences(text).into_iter().flat_map(|s| split_long(s, max_chars)) {
+    if !current.is_empty() && current.chars().count() + 1 + piece.chars().count() > max_chars {
+      chunks.push(std::mem::take(&mut current));
+    }
+    if !current.is_empty() {
+      current.push(' ');
+    }
+    current.push_str(&piece);
+  }
+  if !current.is_empty() {
+    chunks.push(current);
+  }
+
+  chunks
+}
+
+/// Splits a text into sentences, each one keeping its terminating punctuation.
+fn sentences(text: &str) -> Vec<&str> {
+  let mut res = Vec::new();
+  let mut start = 0;
+  let mut chars = text.char_indices().peekable();
+  while let Some((idx, c)) = chars.next() {
+    let at_boundary = chars.peek().is_none_or(|(_, next)| next.is_whitespace());
+    if matches!(c, '.' | '!' | '?') && at_boundary {
+      let end = idx + c.len_utf8();
+      res.push(text[start..end].trim());
+      start = end;
+    }
+  }
+  res.push(text[start..].trim());
+  res.retain(|s| !s.is_empty());
+  res
+}
+
+/// Splits a single sentence on word boundaries so that each piece fits in `max_chars`.
+fn split_long(sentence: &str, max_chars: usize) -> Vec<String> {
+  if sentence.chars().count() <= max_chars {
+    return vec![ sentence.to_string() ];
+  }
+
+  let mut res = Vec::new();
+  let mut current = String::new();
+  for word in sentence.split_whitespace() {
+    let word_len = word.chars().count();
+    if !current.is_empty() && current.chars().count() + 1 + word_len > max_chars {
+      res.push(std::mem::take(&mut current));
+    }
+    if word_len > max_chars {
+      // A single word longer than the limit: no choice but to break it
+      let chars = word.chars().collect::<Vec<_>>();
+      let mut pieces = chars.chunks(max_chars).map(|c| c.iter().collect::<String>()).collect::<Vec<_>>();
+      current = pieces.pop().unwrap_or_default();
+      res.extend(pieces);
+      continue;
+    }
+    if !current.is_empty() {
+      current.push(' ');
+    }
+    current.push_str(word);
+  }
+  if !current.is_empty() {
+    res.push(current);
+  }
+  res
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_short_text() {
+    assert_eq!(split_text("Short one. Really.", 100), vec![ "Short one. Really." ]);
+  }
+
+  #[test]
+  fn test_sentence_boundaries() {
+    assert_eq!(
+      split_text("First sentence. Second one! Third? Fourth.", 20),
+      vec![ "First sentence.", "Second one! Third?", "Fourth." ]
+    );
+  }
+
+  #[test]
+  fn test_long_sentence() {
+    assert_eq!(
+      split_text("This sentence is way too long. Ok.", 10),
+      vec![ "This", "sentence", "is way too", "long. Ok." ]
+    );
+  }
+
+  #[test]
+  fn test_long_word() {
+    assert_eq!(split_text("Supercalifragilistic", 8), vec![ "Supercal", "ifragili", "stic" ]);
+  }
+
+}
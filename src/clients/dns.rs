@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use hickory_resolver::TokioAsyncResolver;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use warp::hyper::client::connect::dns::Name;
+
+use crate::metrics;
+
+/// Default number of records kept by the caching resolver.
+pub const DEFAULT_CACHE_SIZE: usize = 256;
+
+/// Address family used to connect to the upstreams.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+  /// Addresses are tried in the order given by the DNS.
+  #[default]
+  Any,
+  /// IPv4 addresses are tried first, falling back to IPv6 ones.
+  PreferIpv4,
+  /// IPv6 addresses are tried first, falling back to IPv4 ones.
+  PreferIpv6,
+  /// Only IPv4 addresses are used.
+  Ipv4,
+  /// Only IPv6 addresses are used.
+  Ipv6
+}
+
+impl std::str::FromStr for AddressFamily {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "any" => Ok(AddressFamily::Any),
+      "prefer-ipv4" => Ok(AddressFamily::PreferIpv4),
+      "prefer-ipv6" => Ok(AddressFamily::PreferIpv6),
+      "ipv4" => Ok(AddressFamily::Ipv4),
+      "ipv6" => Ok(AddressFamily::Ipv6),
+      other => Err(anyhow!("Unknown address family: {}", other))
+    }
+  }
+}
+
+impl AddressFamily {
+
+  /// Filters and sorts the resolved addresses according to this preference.
+  /// The connector tries the addresses of the family of the first one before falling back to the others.
+  fn apply(&self, mut addrs: Vec<IpAddr>) -> Vec<IpAddr> {
+    match self {
+      AddressFamily::Any => {},
+      AddressFamily::PreferIpv4 => addrs.sort_by_key(|addr| addr.is_ipv6()),
+      AddressFamily::PreferIpv6 => addrs.sort_by_key(|addr| addr.is_ipv4()),
+      AddressFamily::Ipv4 => addrs.retain(|addr| addr.is_ipv4()),
+      AddressFamily::Ipv6 => addrs.retain(|addr| addr.is_ipv6())
+    }
+    addrs
+  }
+
+}
+
+/// Settings of the in-process DNS cache.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DnsCacheConfig {
+  /// Maximum number of records kept in the cache.
+  pub cache_size: usize,
+  /// Lower bound of the time records are cached for, overriding shorter TTLs.
+  pub min_ttl: Option<Duration>,
+  /// Upper bound of the time records are cached for, overriding longer TTLs.
+  pub max_ttl: Option<Duration>
+}
+
+/// Configuration of the resolution of the upstream hosts.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DnsConfig {
+  /// Settings of the in-process cache, if enabled. Otherwise hosts are resolved by the system every time.
+  pub cache: Option<DnsCacheConfig>,
+  /// Hosts resolved to fixed addresses, without querying the DNS at all.
+  pub static_hosts: HashMap<String, Vec<IpAddr>>,
+  pub family: AddressFamily
+}
+
+/// Parses a list of static host mappings, like `pokeapi.co=1.2.3.4|5.6.7.8,localhost=127.0.0.1`.
+pub fn parse_static_hosts(s: &str) -> Result<HashMap<String, Vec<IpAddr>>> {
+  s.split(',')
+    .map(str::trim)
+    .filter(|mapping| !mapping.is_empty())
+    .map(|mapping| {
+      let (host, addrs) = mapping.split_once('=')
+        .ok_or_else(|| anyhow!("Invalid host mapping: {}", mapping))?;
+      let addrs = addrs.split('|')
+        .map(|addr| addr.trim().parse::<IpAddr>().with_context(|| format!("Invalid address for {}: {}", host, addr)))
+        .collect::<Result<Vec<_>>>()?;
+      Ok((host.trim().to_lowercase(), addrs))
+    })
+    .collect()
+}
+
+/// Resolver of the upstream hosts, optionally caching the resolved records in process,
+/// so that a flaky DNS server does not turn into failed requests as long as the records are cached.
+pub struct Resolver {
+  /// `None` when the hosts are resolved by the system.
+  caching: Option<TokioAsyncResolver>,
+  static_hosts: Arc<HashMap<String, Vec<IpAddr>>>,
+  family: AddressFamily
+}
+
+impl Resolver {
+
+  /// Creates a resolver according to the given configuration.
+  /// The caching resolver queries the name servers of the system.
+  pub fn new(config: &DnsConfig) -> Result<Self> {
+    let caching = match &config.cache {
+      Some(cache) => {
+        let (resolver_config, mut options) = hickory_resolver::system_conf::read_system_conf()
+          .context("Cannot read the system DNS configuration")?;
+        options.cache_size = cache.cache_size;
+        options.positive_min_ttl = cache.min_ttl;
+        options.positive_max_ttl = cache.max_ttl;
+        Some(TokioAsyncResolver::tokio(resolver_config, options))
+      },
+      None => None
+    };
+    Ok(Resolver {
+      caching,
+      static_hosts: Arc::new(config.static_hosts.clone()),
+      family: config.family
+    })
+  }
+
+}
+
+impl Resolve for Resolver {
+  fn resolve(&self, name: Name) -> Resolving {
+    let caching = self.caching.clone();
+    let static_hosts = self.static_hosts.clone();
+    let family = self.family;
+    Box::pin(async move {
+
+      let host = name.as_str().to_lowercase();
+      let started = std::time::Instant::now();
+      let addrs = match (static_hosts.get(&host), caching) {
+        (Some(addrs), _) => addrs.clone(),
+        (None, Some(resolver)) => resolver.lookup_ip(host.as_str()).await?.into_iter().collect(),
+        (None, None) => tokio::net::lookup_host((host.as_str(), 0)).await?.map(|addr| addr.ip()).collect()
+      };
+      metrics::DNS_RESOLUTION_DURATION.observe(started.elapsed().as_secs_f64());
+
+      let addrs = family.apply(addrs);
+      if addrs.is_empty() {
+        return Err(anyhow!("No address of the requested family for {}", host).into());
+      }
+
+      // The port is filled in by the connector
+      let addrs: Addrs = Box::new(addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)).collect::<Vec<_>>().into_iter());
+      Ok(addrs)
+
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse_static_hosts() {
+    let hosts = parse_static_hosts("PokeAPI.co=1.2.3.4|::1, localhost=127.0.0.1").unwrap();
+    assert_eq!(hosts["pokeapi.co"], vec!["1.2.3.4".parse::<IpAddr>().unwrap(), "::1".parse().unwrap()]);
+    assert_eq!(hosts["localhost"], vec!["127.0.0.1".parse::<IpAddr>().unwrap()]);
+
+    assert!(parse_static_hosts("pokeapi.co").is_err());
+    assert!(parse_static_hosts("pokeapi.co=not-an-ip").is_err());
+  }
+
+  #[test]
+  fn test_address_family() {
+    let addrs = || vec!["::1".parse::<IpAddr>().unwrap(), "1.2.3.4".parse().unwrap(), "::2".parse().unwrap()];
+    assert_eq!(AddressFamily::Any.apply(addrs()), addrs());
+    assert_eq!(AddressFamily::PreferIpv4.apply(addrs())[0], "1.2.3.4".parse::<IpAddr>().unwrap());
+    assert_eq!(AddressFamily::PreferIpv6.apply(addrs()).len(), 3);
+    assert_eq!(AddressFamily::Ipv4.apply(addrs()), vec!["1.2.3.4".parse::<IpAddr>().unwrap()]);
+    assert_eq!(AddressFamily::Ipv6.apply(addrs()).len(), 2);
+  }
+
+  #[tokio::test]
+  async fn test_forced_family_without_addresses() {
+    let resolver = Resolver::new(&DnsConfig {
+      static_hosts: parse_static_hosts("upstream.invalid=::1").unwrap(),
+      family: AddressFamily::Ipv4,
+      ..Default::default()
+    }).unwrap();
+    assert!(resolver.resolve("upstream.invalid".parse().unwrap()).await.is_err());
+  }
+
+}
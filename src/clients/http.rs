@@ -0,0 +1,580 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow};
+use bytes::Bytes;
+use reqwest::header::HeaderMap;
+use reqwest::{Client, Request, RequestBuilder, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use tracing::{info, warn};
+
+use crate::clients::breaker::CircuitBreaker;
+use crate::clients::budget::Budget;
+use crate::clients::dns::Resolver;
+use crate::clients::recording::{self, Cassette};
+use crate::clients::retry::{RetryBudget, RetryPolicy};
+use crate::metrics;
+use crate::request_stats;
+use crate::trace_context;
+
+/// A fully buffered response received from an upstream.
+pub struct UpstreamResponse {
+  status: StatusCode,
+  headers: HeaderMap,
+  body: Bytes
+}
+
+impl UpstreamResponse {
+
+  pub fn new(status: StatusCode, headers: HeaderMap, body: Bytes) -> Self {
+    UpstreamResponse { status, headers, body }
+  }
+
+  pub fn status(&self) -> StatusCode {
+    self.status
+  }
+
+  pub fn headers(&self) -> &HeaderMap {
+    &self.headers
+  }
+
+  pub fn body(&self) -> &[u8] {
+    &self.body
+  }
+
+  /// Parses the body of the response as JSON.
+  pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+    Ok(serde_json::from_slice(&self.body)?)
+  }
+
+}
+
+/// Maximum number of redirects followed for a single request.
+const MAX_REDIRECTS: usize = 5;
+
+/// `User-Agent` sent to the upstreams, unless the clients set another one.
+pub const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Parses the base url of an upstream, making sure that it ends with a slash, so that the paths joined to it
+/// are appended to its path rather than replacing its last segment (`https://host/api/v2` + `pokemon/` is `https://host/api/v2/pokemon/`).
+/// The query and the fragment, which would be lost when joining paths, are not accepted.
+pub fn parse_base_url(url: &str) -> Result<Url> {
+  let mut url = Url::parse(url.trim())?;
+  if url.scheme() != "http" && url.scheme() != "https" {
+    return Err(anyhow!("Unsupported scheme: {}", url.scheme()));
+  }
+  if url.query().is_some() || url.fragment().is_some() {
+    return Err(anyhow!("Base URLs cannot have a query or a fragment"));
+  }
+  if !url.path().ends_with('/') {
+    let path = format!("{}/", url.path());
+    url.set_path(&path);
+  }
+  Ok(url)
+}
+
+/// Returns whether the error is caused by an upstream which could not be reached at all,
+/// like a connection refused or a failed DNS resolution, rather than by a response of the upstream.
+pub fn is_connection_error(e: &anyhow::Error) -> bool {
+  e.chain().any(|cause| cause.downcast_ref::<reqwest::Error>().is_some_and(reqwest::Error::is_connect))
+}
+
+/// Returns whether the outcome of a request is worth retrying: a server error, or an upstream
+/// which could not be reached or did not answer in time.
+pub fn is_transient(res: &Result<UpstreamResponse>) -> bool {
+  match res {
+    Ok(response) => response.status().is_server_error(),
+    Err(e) => is_connection_error(e) || e.chain().any(|cause| cause.downcast_ref::<reqwest::Error>().is_some_and(reqwest::Error::is_timeout))
+  }
+}
+
+/// HTTP client shared by all the upstream clients, implementing the behaviours common to all of them
+/// (record-and-replay, fault injection, trace context propagation, response compression, retries).
+#[derive(Clone)]
+pub struct HttpClient {
+  client: Client,
+  /// Short name of the upstream, like `pokeapi`.
+  upstream: &'static str,
+  resolver: Option<Arc<Resolver>>,
+  timeout: Option<Duration>,
+  user_agent: String,
+  retry: RetryPolicy,
+  retry_budget: Option<Arc<RetryBudget>>,
+  quota: Option<Arc<Budget>>,
+  breaker: Option<Arc<CircuitBreaker>>,
+  cassette: Option<Cassette>,
+  #[cfg(feature = "chaos")]
+  chaos: Option<crate::clients::chaos::Chaos>
+}
+
+fn build_client(resolver: Option<Arc<Resolver>>, timeout: Option<Duration>, user_agent: &str) -> Result<Client> {
+
+  // Ask for compressed responses, the species payloads of the PokeAPI are quite large
+  let mut builder = Client::builder()
+    .user_agent(user_agent)
+    .gzip(true)
+    .brotli(true)
+    .redirect(reqwest::redirect::Policy::limited(MAX_REDIRECTS));
+  if let Some(resolver) = resolver {
+    builder = builder.dns_resolver(resolver);
+  }
+  if let Some(timeout) = timeout {
+    builder = builder.timeout(timeout);
+  }
+
+  builder.build().context("Cannot build HTTP client")
+
+}
+
+impl HttpClient {
+
+  /// Creates a new client for the upstream with the given name.
+  pub fn new(upstream: &'static str) -> Result<Self> {
+    Ok(HttpClient {
+      client: build_client(None, None, DEFAULT_USER_AGENT)?,
+      upstream,
+      resolver: None,
+      timeout: None,
+      user_agent: DEFAULT_USER_AGENT.to_string(),
+      retry: RetryPolicy::default(),
+      retry_budget: None,
+      quota: None,
+      breaker: None,
+      cassette: None,
+      #[cfg(feature = "chaos")]
+      chaos: None
+    })
+  }
+
+  /// Resolves the upstream hosts with the given resolver, instead of the one of the system.
+  pub fn with_resolver(mut self, resolver: Option<Arc<Resolver>>) -> Result<Self> {
+    self.resolver = resolver;
+    self.rebuild()
+  }
+
+  /// Fails the requests which are not completed within `timeout`, body included, if any.
+  pub fn with_timeout(mut self, timeout: Option<Duration>) -> Result<Self> {
+    self.timeout = timeout;
+    self.rebuild()
+  }
+
+  /// Sends the given `User-Agent` instead of the default one.
+  pub fn with_user_agent(mut self, user_agent: &str) -> Result<Self> {
+    self.user_agent = user_agent.to_string();
+    self.rebuild()
+  }
+
+  fn rebuild(mut self) -> Result<Self> {
+    self.client = build_client(self.resolver.clone(), self.timeout, &self.user_agent)?;
+    Ok(self)
+  }
+
+  /// Retries the requests failing with a transient error as described by the policy.
+  pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+    self.retry = retry;
+    self
+  }
+
+  /// Only retries the requests as long as the given budget allows it.
+  pub fn with_retry_budget(mut self, retry_budget: Arc<RetryBudget>) -> Self {
+    self.retry_budget = Some(retry_budget);
+    self
+  }
+
+  /// Takes a call from the quota of the upstream for each retry, as they count against it like the first attempts,
+  /// not retrying the requests once it is exhausted.
+  pub fn with_quota(mut self, quota: Arc<Budget>) -> Self {
+    self.quota = Some(quota);
+    self
+  }
+
+  /// Stops calling the upstream for `cooldown` after `threshold` consecutive transient failures,
+  /// failing fast with [`BreakerOpen`](crate::clients::breaker::BreakerOpen) instead.
+  pub fn with_circuit_breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+    self.breaker = Some(Arc::new(CircuitBreaker::new(self.upstream, threshold, cooldown)));
+    self
+  }
+
+  /// The circuit breaker of the upstream, if any, shared by all the clones of this client.
+  pub fn circuit_breaker(&self) -> Option<Arc<CircuitBreaker>> {
+    self.breaker.clone()
+  }
+
+  /// Records or replays all the exchanges with the upstream.
+  pub fn with_cassette(mut self, cassette: Option<Cassette>) -> Self {
+    self.cassette = cassette;
+    self
+  }
+
+  /// Injects faults in all the requests performed by this client.
+  #[cfg(feature = "chaos")]
+  pub fn with_chaos(mut self, chaos: Option<crate::clients::chaos::Chaos>) -> Self {
+    self.chaos = chaos;
+    self
+  }
+
+  pub fn get(&self, url: Url) -> RequestBuilder {
+    self.client.get(url)
+  }
+
+  pub fn post(&self, url: Url) -> RequestBuilder {
+    self.client.post(url)
+  }
+
+  /// Checks that the upstream is reachable, considering any response other than a server error as a success.
+  /// Nothing is recorded, and replayed upstreams are always reachable.
+  pub async fn ping(&self, url: Url) -> Result<()> {
+    if let Some(Cassette::Replay(_)) = &self.cassette {
+      return Ok(());
+    }
+    let res = self.client.get(url).send().await?;
+    if res.status().is_server_error() {
+      return Err(anyhow!("HTTP error: {}", res.status().as_u16()));
+    }
+    Ok(())
+  }
+
+  /// Sends a request and buffers the whole response.
+  pub async fn send(&self, request: RequestBuilder) -> Result<UpstreamResponse> {
+    match &self.breaker {
+      Some(breaker) => breaker.call_with(self.send_without_breaker(request), is_transient).await,
+      None => self.send_without_breaker(request).await
+    }
+  }
+
+  /// Sends a request like [`send`](HttpClient::send), leaving the circuit breaker to the caller,
+  /// so that a call made of several requests, like a failover across mirrors, is recorded once.
+  pub async fn send_without_breaker(&self, mut request: RequestBuilder) -> Result<UpstreamResponse> {
+
+    // Propagate the trace context of the request being handled
+    for (name, value) in trace_context::outgoing_headers() {
+      request = request.header(name, value);
+    }
+
+    let request = request.build()?;
+    let started = Instant::now();
+    let res = self.execute_with_retries(request).await;
+    request_stats::record(|stats| *stats.upstream_time.entry(self.upstream).or_default() += started.elapsed());
+    res
+
+  }
+
+  /// Sends a request, retrying it with an exponential backoff while it fails with a transient error.
+  /// Requests with a streaming body cannot be cloned, and are never retried.
+  async fn execute_with_retries(&self, request: Request) -> Result<UpstreamResponse> {
+
+    let mut request = request;
+    let mut retries = 0;
+    loop {
+      let next = if retries + 1 < self.retry.attempts { request.try_clone() } else { None };
+      request_stats::record(|stats| stats.upstream_requests += 1);
+      let res = self.execute(request).await;
+
+      // Stop at the first outcome which is not worth retrying, or when out of attempts or budget
+      request = match next {
+        Some(next) if is_transient(&res) => next,
+        _ => return res
+      };
+      if !self.try_retry().await {
+        return res;
+      }
+      retries += 1;
+      let delay = self.retry.delay(retries);
+      info!(upstream = self.upstream, attempt = retries + 1, backoff_ms = delay.as_millis() as u64, "Retrying the upstream call after a transient failure");
+      tokio::time::sleep(delay).await;
+    }
+
+  }
+
+  /// Returns whether a retry can be performed, withdrawing from the budget and taking from the quota if any.
+  async fn try_retry(&self) -> bool {
+    let allowed = match &self.retry_budget {
+      Some(budget) => budget.try_retry(),
+      None => {
+        metrics::UPSTREAM_RETRIES.with_label_values(&[metrics::upstream_label(self.upstream)]).inc();
+        true
+      }
+    };
+    if let (true, Some(quota)) = (allowed, &self.quota) {
+      if !quota.acquire(1).await {
+        warn!(upstream = self.upstream, "Quota of the upstream exhausted, not retrying the call");
+        return false;
+      }
+    }
+    allowed
+  }
+
+  /// Sends a request, going through fault injection and record-and-replay.
+  async fn execute(&self, request: Request) -> Result<UpstreamResponse> {
+
+    #[cfg(feature = "chaos")]
+    if let Some(chaos) = &self.chaos {
+      chaos.inject().await?;
+    }
+
+    if let Some(Cassette::Replay(dir)) = &self.cassette {
+      return recording::replay(dir, self.upstream, &request);
+    }
+
+    // Keep a copy of the request around in case we need to record it
+    let recorded_request = match &self.cassette {
+      Some(Cassette::Record(_)) => request.try_clone(),
+      _ => None
+    };
+
+    let res = self.client.execute(request).await?;
+    let res = UpstreamResponse::new(res.status(), res.headers().clone(), res.bytes().await?);
+
+    if let (Some(Cassette::Record(dir)), Some(request)) = (&self.cassette, recorded_request) {
+      recording::record(dir, self.upstream, &request, &res).context("Cannot record upstream response")?;
+    }
+
+    Ok(res)
+
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use httpmock::{MockServer, Method};
+  use rand::Rng;
+
+  #[test]
+  fn test_parse_base_url() {
+    let cases = [
+      ("https://pokeapi.co", "https://pokeapi.co/"),
+      ("https://pokeapi.co/api/v2", "https://pokeapi.co/api/v2/"),
+      ("https://pokeapi.co/api/v2/", "https://pokeapi.co/api/v2/"),
+      (" http://localhost:8081/mirror ", "http://localhost:8081/mirror/")
+    ];
+    for (url, expected) in &cases {
+      assert_eq!(parse_base_url(url).unwrap().as_str(), *expected);
+      assert_eq!(parse_base_url(url).unwrap().join("pokemon/").unwrap().as_str(), format!("{}pokemon/", expected));
+    }
+
+    assert!(parse_base_url("pokeapi.co/api/v2").is_err());
+    assert!(parse_base_url("ftp://pokeapi.co/").is_err());
+    assert!(parse_base_url("https://pokeapi.co/api?key=secret").is_err());
+  }
+
+  #[tokio::test]
+  async fn test_timeout() {
+
+    let server = MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.method(Method::GET).path("/slow");
+      then.status(200).delay(std::time::Duration::from_millis(500));
+    }).await;
+    let url = Url::parse(&server.base_url()).unwrap().join("slow").unwrap();
+
+    let client = HttpClient::new("test").unwrap().with_timeout(Some(Duration::from_millis(50))).unwrap();
+    assert!(client.send(client.get(url.clone())).await.is_err());
+    let client = client.with_timeout(None).unwrap();
+    assert!(client.send(client.get(url)).await.is_ok());
+
+  }
+
+  #[tokio::test]
+  async fn test_retries() {
+
+    let server = MockServer::start_async().await;
+    let failing = server.mock_async(|when, then| {
+      when.method(Method::GET).path("/failing");
+      then.status(503);
+    }).await;
+    let missing = server.mock_async(|when, then| {
+      when.method(Method::GET).path("/missing");
+      then.status(404);
+    }).await;
+    let base_url = Url::parse(&server.base_url()).unwrap();
+    let client = HttpClient::new("test").unwrap()
+      .with_retry(RetryPolicy { attempts: 3, base_delay: Duration::from_millis(1), jitter: 0.5 });
+
+    // Server errors are retried until the attempts run out, while the other errors are not
+    let res = client.send(client.get(base_url.join("failing").unwrap())).await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    failing.assert_hits(3);
+    client.send(client.get(base_url.join("missing").unwrap())).await.unwrap();
+    missing.assert_hits(1);
+
+    // Retries stop when the budget is exhausted
+    let budget = Arc::new(RetryBudget::new("test", 0.0));
+    while budget.try_retry() {}
+    let client = client.with_retry_budget(budget);
+    client.send(client.get(base_url.join("failing").unwrap())).await.unwrap();
+    failing.assert_hits(4);
+
+  }
+
+  #[tokio::test]
+  async fn test_circuit_breaker() {
+
+    let server = MockServer::start_async().await;
+    let failing = server.mock_async(|when, then| {
+      when.method(Method::GET).path("/failing");
+      then.status(503);
+    }).await;
+    let missing = server.mock_async(|when, then| {
+      when.method(Method::GET).path("/missing");
+      then.status(404);
+    }).await;
+    let base_url = Url::parse(&server.base_url()).unwrap();
+    let client = HttpClient::new("test_breaker").unwrap().with_circuit_breaker(2, Duration::from_secs(30));
+
+    // The client errors do not open the breaker
+    for _ in 0..3 {
+      client.send(client.get(base_url.join("missing").unwrap())).await.unwrap();
+    }
+    missing.assert_hits(3);
+
+    // The server errors do, after which the upstream is not contacted anymore
+    for _ in 0..2 {
+      client.send(client.get(base_url.join("failing").unwrap())).await.unwrap();
+    }
+    let err = client.send(client.get(base_url.join("missing").unwrap())).await.err().unwrap();
+    assert!(err.is::<crate::clients::breaker::BreakerOpen>());
+    failing.assert_hits(2);
+    missing.assert_hits(3);
+
+  }
+
+  #[tokio::test]
+  async fn test_record_and_replay() {
+
+    let dir = std::env::temp_dir().join(format!("pokechallenge-recordings-{:016x}", rand::thread_rng().gen::<u64>()));
+    let server = MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.method(Method::POST)
+        .path("/echo")
+        .body("hello");
+      then.status(201)
+        .header("x-custom", "value")
+        .body("recorded body");
+    }).await;
+    let url = Url::parse(&server.base_url()).unwrap().join("echo").unwrap();
+
+    // Record a live response
+    let recorder = HttpClient::new("test").unwrap().with_cassette(Some(Cassette::Record(dir.clone())));
+    let recorded = recorder.send(recorder.post(url.clone()).body("hello")).await.unwrap();
+    assert_eq!(recorded.status(), StatusCode::CREATED);
+    mock.assert();
+
+    // Replay it without touching the network
+    mock.delete_async().await;
+    let player = HttpClient::new("test").unwrap().with_cassette(Some(Cassette::Replay(dir.clone())));
+    let replayed = player.send(player.post(url.clone()).body("hello")).await.unwrap();
+    assert_eq!(replayed.status(), StatusCode::CREATED);
+    assert_eq!(replayed.headers()["x-custom"], "value");
+    assert_eq!(replayed.body(), b"recorded body");
+
+    // Requests which have not been recorded fail
+    assert!(player.send(player.post(url).body("other")).await.is_err());
+
+    std::fs::remove_dir_all(dir).unwrap();
+
+  }
+
+  #[tokio::test]
+  async fn test_record_and_replay_binary() {
+
+    let dir = std::env::temp_dir().join(format!("pokechallenge-recordings-{:016x}", rand::thread_rng().gen::<u64>()));
+    let server = MockServer::start_async().await;
+    let body = vec![0x1f, 0x8b, 0x00, 0xff, 0xfe];
+    let mock = server.mock_async(|when, then| {
+      when.method(Method::GET).path("/binary");
+      then.status(200)
+        .header("set-cookie", "a=1")
+        .header("set-cookie", "b=2")
+        .body(body.clone());
+    }).await;
+    let url = Url::parse(&server.base_url()).unwrap().join("binary").unwrap();
+
+    let recorder = HttpClient::new("test").unwrap().with_cassette(Some(Cassette::Record(dir.clone())));
+    recorder.send(recorder.get(url.clone())).await.unwrap();
+    mock.delete_async().await;
+
+    // The body is replayed byte for byte, and the repeated headers are all kept
+    let player = HttpClient::new("test").unwrap().with_cassette(Some(Cassette::Replay(dir.clone())));
+    let replayed = player.send(player.get(url)).await.unwrap();
+    assert_eq!(replayed.body(), &body[..]);
+    let cookies = replayed.headers().get_all("set-cookie").iter().collect::<Vec<_>>();
+    assert_eq!(cookies, vec!["a=1", "b=2"]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+
+  }
+
+  #[tokio::test]
+  async fn test_trace_context_propagation() {
+
+    let server = MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/traced")
+        .header("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+        .header("tracestate", "congo=t61rcWkgMzE");
+      then.status(200);
+    }).await;
+    let url = Url::parse(&server.base_url()).unwrap().join("traced").unwrap();
+
+    let client = HttpClient::new("test").unwrap();
+    let context = trace_context::TraceContext::parse(
+      "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+      Some("congo=t61rcWkgMzE".to_string())
+    ).unwrap();
+    let res = trace_context::scope(context, &Default::default(), client.send(client.get(url))).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    mock.assert();
+
+  }
+
+  #[tokio::test]
+  async fn test_static_hosts() {
+
+    let server = MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.method(Method::GET).path("/resolved");
+      then.status(200);
+    }).await;
+
+    let resolver = Resolver::new(&crate::clients::dns::DnsConfig {
+      static_hosts: crate::clients::dns::parse_static_hosts("upstream.invalid=127.0.0.1").unwrap(),
+      ..Default::default()
+    }).unwrap();
+    let client = HttpClient::new("test").unwrap().with_resolver(Some(Arc::new(resolver))).unwrap();
+    let url = Url::parse(&format!("http://upstream.invalid:{}/resolved", server.port())).unwrap();
+    assert_eq!(client.send(client.get(url)).await.unwrap().status(), StatusCode::OK);
+    mock.assert();
+
+  }
+
+  #[tokio::test]
+  async fn test_compressed_responses() {
+
+    use std::io::Write;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(b"uncompressed body").unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let server = MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/compressed")
+        .header("accept-encoding", "gzip, br");
+      then.status(200)
+        .header("content-encoding", "gzip")
+        .body(compressed);
+    }).await;
+    let url = Url::parse(&server.base_url()).unwrap().join("compressed").unwrap();
+
+    let client = HttpClient::new("test").unwrap();
+    let res = client.send(client.get(url)).await.unwrap();
+    mock.assert();
+    assert_eq!(res.body(), b"uncompressed body");
+
+  }
+
+}
@@ -0,0 +1,358 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow};
+use reqwest::{RequestBuilder, Url};
+use tracing::{field, info, info_span, warn, Instrument};
+
+use crate::clients::http::{is_transient, parse_base_url, HttpClient, UpstreamResponse};
+use crate::clients::retry::RetryBudget;
+use crate::metrics;
+
+/// How long a mirror is tried last after a failure.
+const UNHEALTHY_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Weight of the latest sample in the rolling latency of a mirror.
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+/// How the requests are spread across the healthy mirrors.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Balancing {
+  /// All the requests go to the first healthy mirror, in configuration order.
+  #[default]
+  Failover,
+  /// Each request starts from the next healthy mirror.
+  RoundRobin,
+  /// Each request starts from the healthy mirror with the lowest rolling latency.
+  LeastLatency
+}
+
+impl std::str::FromStr for Balancing {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "failover" => Ok(Balancing::Failover),
+      "round-robin" => Ok(Balancing::RoundRobin),
+      "least-latency" => Ok(Balancing::LeastLatency),
+      other => Err(anyhow!("Unknown balancing strategy: {}", other))
+    }
+  }
+}
+
+struct Mirror {
+  url: Url,
+  /// Label of the mirror in the metrics.
+  label: String,
+  /// Set when the mirror failed, until it is tried again before the others.
+  unhealthy_until: Mutex<Option<Instant>>,
+  /// Rolling average of the latency of the successful responses, in microseconds, `0` if unknown.
+  latency_micros: AtomicU64
+}
+
+impl Mirror {
+
+  fn is_healthy(&self, now: Instant) -> bool {
+    self.unhealthy_until.lock().unwrap().is_none_or(|t| now >= t)
+  }
+
+  fn mark(&self, healthy: bool) {
+    *self.unhealthy_until.lock().unwrap() = if healthy { None } else { Some(Instant::now() + UNHEALTHY_COOLDOWN) };
+    metrics::UPSTREAM_MIRROR_HEALTHY.with_label_values(&[&self.label]).set(healthy as i64);
+  }
+
+  fn observe_latency(&self, latency: Duration) {
+    let sample = latency.as_micros() as f64;
+    let _ = self.latency_micros.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+      Some(match current {
+        0 => sample as u64,
+        current => (current as f64 * (1.0 - LATENCY_SMOOTHING) + sample * LATENCY_SMOOTHING) as u64
+      }.max(1))
+    });
+  }
+
+}
+
+/// A list of equivalent base urls of an upstream, tried in order until one of them responds.
+///
+/// Mirrors failing with a connection error or a server error are tried last for a while,
+/// so that a dead primary does not slow down every request.
+#[derive(Clone)]
+pub struct Mirrors {
+  mirrors: Arc<Vec<Mirror>>,
+  balancing: Balancing,
+  /// Position of the next mirror for the round robin balancing.
+  next: Arc<AtomicUsize>,
+  /// Limits the requests retried against another mirror.
+  retry_budget: Option<Arc<RetryBudget>>
+}
+
+impl Mirrors {
+
+  /// Parses a comma-separated list of base urls, the first one being the primary.
+  pub fn parse(urls: &str) -> Result<Self> {
+    let mirrors = urls.split(',')
+      .map(str::trim)
+      .filter(|url| !url.is_empty())
+      .map(|url| {
+        let url = parse_base_url(url).with_context(|| format!("Invalid base URL: {}", url))?;
+        let label = metrics::MIRROR_LABELS.label(url.as_str());
+        metrics::UPSTREAM_MIRROR_HEALTHY.with_label_values(&[&label]).set(1);
+        Ok(Mirror { url, label, unhealthy_until: Mutex::new(None), latency_micros: AtomicU64::new(0) })
+      })
+      .collect::<Result<Vec<_>>>()?;
+    if mirrors.is_empty() {
+      return Err(anyhow!("No base URL given"));
+    }
+    Ok(Mirrors {
+      mirrors: Arc::new(mirrors),
+      balancing: Balancing::default(),
+      next: Arc::new(AtomicUsize::new(0)),
+      retry_budget: None
+    })
+  }
+
+  /// Spreads the requests across the healthy mirrors with the given strategy.
+  pub fn with_balancing(mut self, balancing: Balancing) -> Self {
+    self.balancing = balancing;
+    self
+  }
+
+  /// Only retries against another mirror as long as the given budget allows it.
+  pub fn with_retry_budget(mut self, retry_budget: Arc<RetryBudget>) -> Self {
+    self.retry_budget = Some(retry_budget);
+    self
+  }
+
+  /// Returns the mirrors in the order they should be tried: the healthy ones first, as picked by the balancing strategy,
+  /// then the unhealthy ones in configuration order.
+  fn candidates(&self) -> Vec<&Mirror> {
+    let now = Instant::now();
+    let (mut healthy, unhealthy): (Vec<_>, Vec<_>) = self.mirrors.iter().partition(|mirror| mirror.is_healthy(now));
+    match self.balancing {
+      Balancing::Failover => {},
+      Balancing::RoundRobin if !healthy.is_empty() => {
+        let len = healthy.len();
+        healthy.rotate_left(self.next.fetch_add(1, Ordering::Relaxed) % len);
+      },
+      Balancing::RoundRobin => {},
+      // Mirrors without samples are tried first, to measure them
+      Balancing::LeastLatency => healthy.sort_by_key(|mirror| mirror.latency_micros.load(Ordering::Relaxed))
+    }
+    healthy.extend(unhealthy);
+    healthy
+  }
+
+  /// Sends the request built by `build` for the base url of each mirror, until one does not fail.
+  /// If all the mirrors fail, the outcome of the last one is returned.
+  ///
+  /// The attempts run in an `upstream_call` span, recording how many were made, with an event for each retry,
+  /// so that the traces of the slow requests show what happened.
+  /// The circuit breaker of the client, if any, records the outcome of the whole call, once all the mirrors have been tried.
+  pub async fn send<F>(&self, http: &HttpClient, build: F) -> Result<UpstreamResponse>
+  where
+    F: Fn(&Url) -> Result<RequestBuilder>
+  {
+    let span = info_span!("upstream_call", attempts = field::Empty);
+    let attempts = self.send_attempts(http, build).instrument(span);
+    match http.circuit_breaker() {
+      Some(breaker) => breaker.call_with(attempts, is_transient).await,
+      None => attempts.await
+    }
+  }
+
+  async fn send_attempts<F>(&self, http: &HttpClient, build: F) -> Result<UpstreamResponse>
+  where
+    F: Fn(&Url) -> Result<RequestBuilder>
+  {
+
+    if let Some(budget) = &self.retry_budget {
+      budget.deposit();
+    }
+
+    let mut last = Err(anyhow!("No mirror available"));
+    for (attempt, mirror) in self.candidates().into_iter().enumerate() {
+      if attempt > 0 && self.retry_budget.as_ref().is_some_and(|budget| !budget.try_retry()) {
+        warn!(mirror = %mirror.url, "Retry budget exhausted, not trying the other mirrors");
+        break;
+      }
+      if attempt > 0 {
+        info!(attempt, mirror = %mirror.url, "Retrying the upstream call on the next mirror");
+      }
+      tracing::Span::current().record("attempts", attempt + 1);
+      let started = Instant::now();
+      let res = http.send_without_breaker(build(&mirror.url)?).await;
+      match &res {
+        Ok(response) if !response.status().is_server_error() => {
+          mirror.mark(true);
+          mirror.observe_latency(started.elapsed());
+          metrics::UPSTREAM_MIRROR_RESPONSES.with_label_values(&[&mirror.label]).inc();
+          return res;
+        },
+        Ok(response) => warn!(mirror = %mirror.url, status = response.status().as_u16(), "Mirror failed, trying the next one"),
+        Err(e) => warn!(mirror = %mirror.url, error = %e, "Mirror failed, trying the next one")
+      }
+      mirror.mark(false);
+      last = res;
+    }
+    last
+
+  }
+
+  /// Checks that at least one of the mirrors is reachable.
+  pub async fn ping<F>(&self, http: &HttpClient, url: F) -> Result<()>
+  where
+    F: Fn(&Url) -> Result<Url>
+  {
+    let mut last = Err(anyhow!("No mirror available"));
+    for mirror in self.candidates() {
+      last = http.ping(url(&mirror.url)?).await;
+      if last.is_ok() {
+        break;
+      }
+    }
+    last
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use httpmock::{MockServer, Method};
+
+  #[tokio::test]
+  async fn test_failover() {
+
+    let primary = MockServer::start_async().await;
+    let primary_mock = primary.mock_async(|when, then| {
+      when.method(Method::GET).path("/resource");
+      then.status(503);
+    }).await;
+    let mirror = MockServer::start_async().await;
+    let mirror_mock = mirror.mock_async(|when, then| {
+      when.method(Method::GET).path("/resource");
+      then.status(200).body("from mirror");
+    }).await;
+
+    // Include a mirror refusing connections
+    let mirrors = Mirrors::parse(&format!("{}/,http://127.0.0.1:1/,{}/", primary.base_url(), mirror.base_url())).unwrap();
+    let http = HttpClient::new("test").unwrap();
+    let send = || mirrors.send(&http, |base_url| Ok(http.get(base_url.join("resource")?)));
+
+    let res = send().await.unwrap();
+    assert_eq!(res.body(), b"from mirror");
+    primary_mock.assert_hits(1);
+    mirror_mock.assert_hits(1);
+
+    // The failed mirrors are now tried last
+    assert_eq!(send().await.unwrap().body(), b"from mirror");
+    primary_mock.assert_hits(1);
+    mirror_mock.assert_hits(2);
+
+  }
+
+  #[tokio::test]
+  async fn test_balancing() {
+
+    let first = MockServer::start_async().await;
+    let first_mock = first.mock_async(|when, then| {
+      when.method(Method::GET);
+      then.status(200).delay(Duration::from_millis(50));
+    }).await;
+    let second = MockServer::start_async().await;
+    let second_mock = second.mock_async(|when, then| {
+      when.method(Method::GET);
+      then.status(200);
+    }).await;
+    let urls = format!("{}/,{}/", first.base_url(), second.base_url());
+    let http = HttpClient::new("test").unwrap();
+
+    // Round robin alternates between the mirrors
+    let mirrors = Mirrors::parse(&urls).unwrap().with_balancing(Balancing::RoundRobin);
+    for _ in 0..4 {
+      mirrors.send(&http, |base_url| Ok(http.get(base_url.clone()))).await.unwrap();
+    }
+    first_mock.assert_hits(2);
+    second_mock.assert_hits(2);
+
+    // Least latency sticks to the fastest one, once both have been measured
+    let mirrors = Mirrors::parse(&urls).unwrap().with_balancing(Balancing::LeastLatency);
+    for _ in 0..4 {
+      mirrors.send(&http, |base_url| Ok(http.get(base_url.clone()))).await.unwrap();
+    }
+    first_mock.assert_hits(3);
+    second_mock.assert_hits(5);
+
+  }
+
+  #[tokio::test]
+  async fn test_retry_budget() {
+
+    let mirror = MockServer::start_async().await;
+    let mirror_mock = mirror.mock_async(|when, then| {
+      when.method(Method::GET);
+      then.status(200);
+    }).await;
+
+    // Without any deposit, only the initial allowance of retries is available
+    let mirrors = Mirrors::parse(&format!("http://127.0.0.1:1/,{}/", mirror.base_url())).unwrap()
+      .with_retry_budget(Arc::new(RetryBudget::new("test", 0.0)));
+    let http = HttpClient::new("test").unwrap();
+    let mut results = Vec::new();
+    for _ in 0..12 {
+      // Keep the broken primary first
+      mirrors.mirrors[0].mark(true);
+      results.push(mirrors.send(&http, |base_url| Ok(http.get(base_url.clone()))).await.is_ok());
+    }
+    assert_eq!(results.iter().filter(|ok| **ok).count(), 10);
+    mirror_mock.assert_hits(10);
+
+  }
+
+  #[tokio::test]
+  async fn test_circuit_breaker_after_failover() {
+
+    let mirror = MockServer::start_async().await;
+    let mirror_mock = mirror.mock_async(|when, then| {
+      when.method(Method::GET);
+      then.status(200);
+    }).await;
+
+    // The dead primary does not count as a failure, as the secondary answers every call
+    let mirrors = Mirrors::parse(&format!("http://127.0.0.1:1/,{}/", mirror.base_url())).unwrap();
+    let http = HttpClient::new("test_mirrors_breaker").unwrap().with_circuit_breaker(1, Duration::from_secs(30));
+    for _ in 0..3 {
+      mirrors.mirrors[0].mark(true);
+      assert!(mirrors.send(&http, |base_url| Ok(http.get(base_url.clone()))).await.is_ok());
+    }
+    mirror_mock.assert_hits(3);
+    assert_eq!(http.circuit_breaker().unwrap().state(), "closed");
+
+    // It opens once the whole call fails
+    let mirrors = Mirrors::parse("http://127.0.0.1:1/").unwrap();
+    assert!(mirrors.send(&http, |base_url| Ok(http.get(base_url.clone()))).await.is_err());
+    assert_eq!(http.circuit_breaker().unwrap().state(), "open");
+
+  }
+
+  #[tokio::test]
+  async fn test_all_mirrors_failing() {
+
+    let primary = MockServer::start_async().await;
+    primary.mock_async(|when, then| {
+      when.method(Method::GET);
+      then.status(500);
+    }).await;
+
+    let mirrors = Mirrors::parse(&format!("http://127.0.0.1:1/, {}/", primary.base_url())).unwrap();
+    let http = HttpClient::new("test").unwrap();
+    let res = mirrors.send(&http, |base_url| Ok(http.get(base_url.clone()))).await.unwrap();
+    assert_eq!(res.status(), 500);
+
+    assert!(Mirrors::parse(" , ").is_err());
+
+  }
+
+}
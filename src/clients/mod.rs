@@ -1,5 +1,47 @@
+//! Clients of the upstream APIs, also usable on their own by the services calling them directly.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use truelayer_pokemon_challenge::clients::{PokemonApi, PokemonClient, ShakespeareClient, Translator};
+//! use truelayer_pokemon_challenge::clients::retry::RetryPolicy;
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let pokemon = PokemonClient::builder()
+//!   .base_url("https://pokeapi.co/api/v2/")
+//!   .timeout(Duration::from_secs(5))
+//!   .build()?;
+//! let translator = ShakespeareClient::builder()
+//!   .base_url("https://api.funtranslations.com/")
+//!   .timeout(Duration::from_secs(10))
+//!   .retries(RetryPolicy { attempts: 3, ..RetryPolicy::default() })
+//!   .build()?;
+//!
+//! if let Some(description) = pokemon.get_pokemon_description("pikachu").await? {
+//!   println!("{}", Translator::translate(&translator, &description).await?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Their metrics are registered in the default Prometheus registry, with the `pokechallenge_` prefix.
+
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod budget;
+pub mod breaker;
+pub mod chunking;
+pub mod dns;
+pub mod http;
+pub mod mirrors;
+pub mod openai;
+pub mod recording;
+pub mod retry;
 pub mod shakespeare;
 pub mod pokemon;
+pub mod pokemon_api;
+pub mod translator;
 
 pub use shakespeare::ShakespeareClient;
-pub use pokemon::PokemonClient;
\ No newline at end of file
+pub use pokemon::PokemonClient;
+pub use pokemon_api::PokemonApi;
+pub use translator::{Translator, Translators};
\ No newline at end of file
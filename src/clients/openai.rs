@@ -0,0 +1,244 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use reqwest::Url;
+use serde::{Serialize, Deserialize};
+use tracing::{instrument, debug};
+
+use crate::clients::breaker::CircuitBreaker;
+use crate::clients::dns::Resolver;
+use crate::clients::http::{parse_base_url, HttpClient};
+use crate::clients::recording::Cassette;
+use crate::clients::retry::{RetryBudget, RetryPolicy};
+use crate::clients::translator::Translator;
+use crate::metrics;
+
+/// Default model asked to translate the texts.
+pub const DEFAULT_MODEL: &str = "gpt-4o-mini";
+
+/// Instructions given to the model, asking it to behave like the Shakespeare Translator.
+const PROMPT: &str = "Rewrite the text sent by the user in the style of William Shakespeare, in Early Modern English. \
+  Keep the meaning and the length of the original text. Reply with the rewritten text only.";
+
+/// A translator backed by any OpenAI compatible chat completions API, like OpenAI itself or a local model server.
+#[derive(Clone)]
+pub struct OpenAiClient {
+  http: HttpClient,
+  base_url: Url,
+  api_key: Option<String>,
+  model: String,
+  retry_budget: Option<Arc<RetryBudget>>
+}
+
+#[derive(Serialize)]
+struct ChatRequest<'a> {
+  model: &'a str,
+  messages: Vec<ChatMessage<'a>>,
+  temperature: f32
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChatMessage<'a> {
+  role: &'a str,
+  content: &'a str
+}
+
+#[derive(Deserialize)]
+struct ChatResponse {
+  choices: Vec<ChatChoice>
+}
+
+#[derive(Deserialize)]
+struct ChatChoice {
+  message: ChatResponseMessage
+}
+
+#[derive(Deserialize)]
+struct ChatResponseMessage {
+  content: String
+}
+
+impl OpenAiClient {
+
+  /// Creates a new [`OpenAiClient`](crate::clients::openai::OpenAiClient) using the given base url, like `https://api.openai.com/v1/`.
+  ///
+  /// The requests will be performed against `<base_url>/chat/completions`.
+  pub fn new(base_url: &str) -> Result<Self> {
+    Ok(OpenAiClient {
+      http: HttpClient::new("openai")?,
+      base_url: parse_base_url(base_url).context("Invalid OpenAI API base URL")?,
+      api_key: None,
+      model: DEFAULT_MODEL.to_string(),
+      retry_budget: None
+    })
+  }
+
+  /// Sends the given key in the `Authorization` header of the requests.
+  pub fn with_api_key(mut self, api_key: Option<String>) -> Self {
+    self.api_key = api_key;
+    self
+  }
+
+  /// Sets the model asked to translate the texts.
+  pub fn with_model(mut self, model: &str) -> Self {
+    self.model = model.to_string();
+    self
+  }
+
+  /// Injects faults in all the requests performed by this client.
+  #[cfg(feature = "chaos")]
+  pub fn with_chaos(mut self, chaos: Option<crate::clients::chaos::Chaos>) -> Self {
+    self.http = self.http.with_chaos(chaos);
+    self
+  }
+
+  /// Resolves the host of the upstream with the given resolver, instead of the one of the system.
+  pub fn with_resolver(mut self, resolver: Option<Arc<Resolver>>) -> Result<Self> {
+    self.http = self.http.with_resolver(resolver)?;
+    Ok(self)
+  }
+
+  /// Retries the translations failing with a server error or a connection error, with an exponential backoff.
+  pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+    self.http = self.http.with_retry(retry);
+    self
+  }
+
+  /// Allows retrying `ratio` of the translation requests. By default the retries are not capped.
+  pub fn with_retry_budget(mut self, ratio: f64) -> Self {
+    let retry_budget = Arc::new(RetryBudget::new("openai", ratio));
+    self.http = self.http.with_retry_budget(retry_budget.clone());
+    self.retry_budget = Some(retry_budget);
+    self
+  }
+
+  /// Stops calling the upstream for `cooldown` after `threshold` consecutive server errors or connection errors,
+  /// failing fast instead.
+  pub fn with_circuit_breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+    self.http = self.http.with_circuit_breaker(threshold, cooldown);
+    self
+  }
+
+  /// Records or replays all the exchanges with the upstream.
+  pub fn with_cassette(mut self, cassette: Option<Cassette>) -> Self {
+    self.http = self.http.with_cassette(cassette);
+    self
+  }
+
+  /// Fails the requests which are not completed within `timeout`, body included, if any.
+  /// By default the requests never time out.
+  pub fn with_timeout(mut self, timeout: Option<Duration>) -> Result<Self> {
+    self.http = self.http.with_timeout(timeout)?;
+    Ok(self)
+  }
+
+}
+
+#[async_trait]
+impl Translator for OpenAiClient {
+
+  fn name(&self) -> &'static str {
+    "openai"
+  }
+
+  fn circuit_breaker(&self) -> Option<Arc<CircuitBreaker>> {
+    self.http.circuit_breaker()
+  }
+
+  #[instrument(skip(self))]
+  async fn translate(&self, text: &str) -> Result<String> {
+
+    debug!("Sending HTTP request");
+    metrics::OPENAI_REQUESTS.inc();
+
+    // Send the request
+    let mut req = self.http.post(self.base_url.join("chat/completions")?)
+      .json(&ChatRequest {
+        model: &self.model,
+        messages: vec![
+          ChatMessage { role: "system", content: PROMPT },
+          ChatMessage { role: "user", content: text }
+        ],
+        temperature: 0.0
+      });
+    if let Some(api_key) = &self.api_key {
+      req = req.bearer_auth(api_key);
+    }
+    if let Some(retry_budget) = &self.retry_budget {
+      retry_budget.deposit();
+    }
+    let res = self.http.send(req)
+      .await
+      .context("Cannot send request to OpenAI API")?;
+
+    debug!(status = res.status().as_u16(), "Got HTTP response: {}", res.status().as_u16());
+
+    if !res.status().is_success() {
+      return Err(anyhow!("HTTP error: {}", res.status().as_u16()));
+    }
+
+    // The translation is the first choice of the model
+    let body = res
+      .json::<ChatResponse>()
+      .context("Cannot parse response from OpenAI API")?;
+    body.choices
+      .into_iter()
+      .next()
+      .map(|choice| choice.message.content.trim().to_string())
+      .ok_or_else(|| anyhow!("OpenAI API returned no choices"))
+
+  }
+
+  async fn ping(&self) -> Result<()> {
+    self.http.ping(self.base_url.join("models")?).await
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use httpmock::{MockServer, Method};
+  use serde_json::json;
+
+  #[tokio::test]
+  async fn test_translation() {
+
+    let server = MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.method(Method::POST)
+        .path("/v1/chat/completions")
+        .header("Authorization", "Bearer key")
+        .body_contains("\"model\":\"local-model\"")
+        .body_contains("\"content\":\"Hello world\"");
+      then.status(200)
+        .json_body(json!({
+          "choices": [{ "index": 0, "message": { "role": "assistant", "content": " Good morrow, world \n" } }]
+        }));
+    }).await;
+
+    let client = OpenAiClient::new(&format!("{}/v1/", server.base_url())).unwrap()
+      .with_api_key(Some("key".to_string()))
+      .with_model("local-model");
+    assert_eq!(client.translate("Hello world").await.unwrap(), "Good morrow, world");
+    mock.assert();
+
+  }
+
+  #[tokio::test]
+  async fn test_error_response() {
+
+    let server = MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.method(Method::POST).path("/chat/completions");
+      then.status(401).json_body(json!({ "error": { "message": "Invalid API key" } }));
+    }).await;
+
+    let client = OpenAiClient::new(&server.base_url()).unwrap();
+    assert!(client.translate("Hello world").await.unwrap_err().to_string().contains("HTTP error: 401"));
+
+  }
+
+}
@@ -1,21 +1,58 @@
-use anyhow::{Context, Result, anyhow};
-use reqwest::{Client, Url};
-use serde::{Serialize, Deserialize};
-use tracing::{instrument, debug};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
 
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use reqwest::{StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use tracing::{instrument, debug, warn};
+
+use crate::clients::breaker::CircuitBreaker;
+use crate::clients::dns::Resolver;
+use crate::clients::http::HttpClient;
+use crate::clients::mirrors::{Balancing, Mirrors};
+use crate::clients::pokemon_api::PokemonApi;
+use crate::clients::recording::Cassette;
+use crate::clients::retry::{RetryBudget, RetryPolicy};
 use crate::metrics;
 
 /// A client for the Pokemon APIs.
 #[derive(Clone)]
 pub struct PokemonClient {
-  client: Client,
-  endpoint_url: Url
+  http: HttpClient,
+  mirrors: Mirrors,
+  species_path: String,
+  pokemon_path: String
 }
 
+/// Default path of the species resources, relative to the base url.
+pub const DEFAULT_SPECIES_PATH: &str = "pokemon-species/";
+
+/// Default path of the Pokemon resources, relative to the base url.
+pub const DEFAULT_POKEMON_PATH: &str = "pokemon/";
+
 /// The response from the Pokemon API.
 #[derive(Serialize, Deserialize)]
 struct PokemonSpecies {
-  flavor_text_entries: Vec<PokemonFlavorTextEntry>
+  #[serde(default, deserialize_with = "skip_invalid")]
+  flavor_text_entries: Vec<PokemonFlavorTextEntry>,
+  #[serde(default, deserialize_with = "skip_invalid")]
+  genera: Vec<PokemonGenus>,
+  #[serde(default)]
+  habitat: Option<PokemonHabitat>
+}
+
+#[derive(Serialize, Deserialize)]
+struct PokemonGenus {
+  genus: String,
+  language: PokemonLanguage
+}
+
+#[derive(Serialize, Deserialize)]
+struct PokemonHabitat {
+  name: String
 }
 
 #[derive(Serialize, Deserialize)]
@@ -29,29 +66,388 @@ struct PokemonLanguage {
   name: String
 }
 
-impl PokemonClient {
+/// The response from the `/pokemon/{name}` Pokemon API, with the base stats of the Pokemon.
+#[derive(Serialize, Deserialize)]
+struct PokemonDetails {
+  #[serde(default, deserialize_with = "skip_invalid")]
+  stats: Vec<PokemonStat>,
+  #[serde(default, deserialize_with = "skip_invalid")]
+  types: Vec<PokemonType>,
+  /// The species the Pokemon is a form or a variety of.
+  #[serde(default)]
+  species: Option<PokemonSpeciesLink>
+}
+
+#[derive(Serialize, Deserialize)]
+struct PokemonStat {
+  base_stat: u32,
+  stat: PokemonStatName
+}
+
+#[derive(Serialize, Deserialize)]
+struct PokemonStatName {
+  name: String
+}
+
+#[derive(Serialize, Deserialize)]
+struct PokemonType {
+  slot: u32,
+  #[serde(rename = "type")]
+  kind: PokemonTypeName
+}
+
+#[derive(Serialize, Deserialize)]
+struct PokemonTypeName {
+  name: String
+}
+
+/// A page of the `/pokemon-species/` listing of the Pokemon API.
+#[derive(Serialize, Deserialize)]
+struct PokemonSpeciesPage {
+  count: u64,
+  #[serde(default, deserialize_with = "skip_invalid")]
+  results: Vec<PokemonSpeciesLink>
+}
+
+#[derive(Serialize, Deserialize)]
+struct PokemonSpeciesLink {
+  name: String
+}
+
+/// Deserializes a list, skipping the items which cannot be deserialized, so that a single malformed entry
+/// does not fail the whole response. Anything other than a list is treated as an empty one.
+fn skip_invalid<'de, D, T>(deserializer: D) -> std::result::Result<Vec<T>, D::Error>
+where
+  D: Deserializer<'de>,
+  T: DeserializeOwned
+{
+  let items = match serde_json::Value::deserialize(deserializer)? {
+    serde_json::Value::Array(items) => items,
+    serde_json::Value::Null => vec![],
+    other => {
+      warn!(value = %other, "Expected a list from the Pokemon API");
+      vec![]
+    }
+  };
+  Ok(items.into_iter()
+    .filter_map(|item| match serde_json::from_value(item) {
+      Ok(item) => Some(item),
+      Err(e) => {
+        warn!(error = %e, "Skipping malformed entry from the Pokemon API");
+        None
+      }
+    })
+    .collect())
+}
+
+/// Base stats of a Pokemon, like `hp` or `attack`, by name.
+pub type PokemonStats = BTreeMap<String, u32>;
+
+/// Where a Pokemon lives and what it is made of, like `forest` and `[grass, poison]`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PokemonProfile {
+  /// Habitat of the species, if known. Recent species have none.
+  pub habitat: Option<String>,
+  /// English genus of the species, like `Seed Pokémon`.
+  #[serde(default)]
+  pub genus: Option<String>,
+  /// Types of the Pokemon, primary first.
+  pub types: Vec<String>
+}
+
+/// Builder of a [`PokemonClient`](PokemonClient), only requiring the base url.
+#[derive(Default)]
+pub struct PokemonClientBuilder {
+  base_url: Option<String>,
+  species_path: Option<String>,
+  pokemon_path: Option<String>,
+  balancing: Balancing,
+  retry_budget: Option<f64>,
+  retries: RetryPolicy,
+  breaker: Option<(u32, Duration)>,
+  timeout: Option<Duration>,
+  user_agent: Option<String>,
+  resolver: Option<Arc<Resolver>>,
+  cassette: Option<Cassette>,
+  #[cfg(feature = "chaos")]
+  chaos: Option<crate::clients::chaos::Chaos>
+}
+
+impl PokemonClientBuilder {
+
+  /// Sends the requests to the given base url, like `https://pokeapi.co/api/v2/`.
+  /// Multiple comma-separated base urls can be given, the others being mirrors used when the first one fails.
+  pub fn base_url(mut self, base_url: &str) -> Self {
+    self.base_url = Some(base_url.to_string());
+    self
+  }
+
+  /// Sets the paths of the species and of the Pokemon resources, relative to the base url,
+  /// for mirrors mounting the API under a different prefix.
+  pub fn paths(mut self, species_path: &str, pokemon_path: &str) -> Self {
+    self.species_path = Some(species_path.to_string());
+    self.pokemon_path = Some(pokemon_path.to_string());
+    self
+  }
+
+  /// Spreads the requests across the mirrors with the given strategy.
+  pub fn balancing(mut self, balancing: Balancing) -> Self {
+    self.balancing = balancing;
+    self
+  }
+
+  /// Allows retrying `ratio` of the requests, against the same mirror or another one.
+  pub fn retry_budget(mut self, ratio: f64) -> Self {
+    self.retry_budget = Some(ratio);
+    self
+  }
+
+  /// Retries the requests failing with a server error or a connection error against the same mirror,
+  /// with an exponential backoff, before trying the next one. By default the requests are not retried.
+  pub fn retries(mut self, retries: RetryPolicy) -> Self {
+    self.retries = retries;
+    self
+  }
+
+  /// Stops calling the upstream for `cooldown` after `threshold` consecutive server errors or connection errors,
+  /// failing fast instead. By default the upstream is always called.
+  pub fn circuit_breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+    self.breaker = Some((threshold, cooldown));
+    self
+  }
+
+  /// Fails the requests which are not completed within `timeout`, body included.
+  /// By default the requests never time out.
+  pub fn timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = Some(timeout);
+    self
+  }
+
+  /// Sends the given `User-Agent`, instead of the name and the version of this crate.
+  pub fn user_agent(mut self, user_agent: &str) -> Self {
+    self.user_agent = Some(user_agent.to_string());
+    self
+  }
+
+  /// Resolves the host of the upstream with the given resolver, instead of the one of the system.
+  pub fn resolver(mut self, resolver: Option<Arc<Resolver>>) -> Self {
+    self.resolver = resolver;
+    self
+  }
+
+  /// Records or replays all the exchanges with the upstream.
+  pub fn cassette(mut self, cassette: Option<Cassette>) -> Self {
+    self.cassette = cassette;
+    self
+  }
+
+  /// Injects faults in all the requests performed by this client.
+  #[cfg(feature = "chaos")]
+  pub fn chaos(mut self, chaos: Option<crate::clients::chaos::Chaos>) -> Self {
+    self.chaos = chaos;
+    self
+  }
+
+  /// Builds the client, failing if the base url is missing or invalid, or if the HTTP client cannot be built.
+  pub fn build(self) -> Result<PokemonClient> {
+
+    let base_url = self.base_url.ok_or_else(|| anyhow!("Missing Pokemon API base URL"))?;
+    let mut mirrors = Mirrors::parse(&base_url).context("Invalid Pokemon API base URL")?
+      .with_balancing(self.balancing);
+    let mut http = HttpClient::new("pokeapi")?
+      .with_timeout(self.timeout)?
+      .with_resolver(self.resolver)?
+      .with_retry(self.retries)
+      .with_cassette(self.cassette);
+    if let Some(user_agent) = &self.user_agent {
+      http = http.with_user_agent(user_agent)?;
+    }
+    if let Some((threshold, cooldown)) = self.breaker {
+      http = http.with_circuit_breaker(threshold, cooldown);
+    }
+    #[cfg(feature = "chaos")]
+    let mut http = http.with_chaos(self.chaos);
+
+    // The same budget caps the retries against the same mirror and against the others
+    if let Some(ratio) = self.retry_budget {
+      let budget = Arc::new(RetryBudget::new("pokeapi", ratio));
+      http = http.with_retry_budget(budget.clone());
+      mirrors = mirrors.with_retry_budget(budget);
+    }
 
-  /// Creates a new [`PokemonClient`](crate::clients::PokemonClient) using the given base url.
-  pub fn new(base_url: &str) -> Result<Self> {
     Ok(PokemonClient {
-      client: Client::new(),
-      endpoint_url: Url::parse(base_url).context("Invalid Pokemon API base URL")?
+      http,
+      mirrors,
+      species_path: relative_dir(self.species_path.as_deref().unwrap_or(DEFAULT_SPECIES_PATH)),
+      pokemon_path: relative_dir(self.pokemon_path.as_deref().unwrap_or(DEFAULT_POKEMON_PATH))
     })
+
+  }
+
+}
+
+impl PokemonClient {
+
+  /// Starts building a new [`PokemonClient`](crate::clients::PokemonClient).
+  pub fn builder() -> PokemonClientBuilder {
+    PokemonClientBuilder::default()
+  }
+
+  /// The circuit breaker of the Pokemon API, if any.
+  pub fn circuit_breaker(&self) -> Option<Arc<CircuitBreaker>> {
+    self.http.circuit_breaker()
+  }
+
+  /// Checks that the Pokemon API is reachable.
+  pub async fn ping(&self) -> Result<()> {
+    self.mirrors.ping(&self.http, |base_url| Ok(base_url.join(&self.species_path)?)).await
+  }
+
+  /// Builds the url of a resource, escaping any reserved character in the name.
+  fn resource_url(base_url: &Url, resource: &str, name: &str) -> Result<Url> {
+    let mut url = base_url.join(resource)?;
+    url.path_segments_mut()
+      .map_err(|_| anyhow!("Invalid Pokemon API base URL"))?
+      .pop_if_empty()
+      .push(name);
+    Ok(url)
+  }
+
+  /// Retrieves the total number of species, and the name of the species at the given position of the listing.
+  /// The name is `None` if the position is past the end of the listing.
+  #[instrument(skip(self))]
+  pub async fn get_species_at(&self, index: u64) -> Result<(u64, Option<String>)> {
+
+    debug!("Sending HTTP request");
+    metrics::POKEAPI_REQUESTS.inc();
+
+    // Send the request for a single item of the listing
+    let res = self.mirrors
+      .send(&self.http, |base_url| {
+        let mut url = base_url.join(&self.species_path)?;
+        url.query_pairs_mut()
+          .append_pair("limit", "1")
+          .append_pair("offset", &index.to_string());
+        Ok(self.http.get(url))
+      })
+      .await
+      .context("Cannot send request to Pokemon API")?;
+
+    debug!(status = res.status().as_u16(), "Got HTTP response: {}", res.status().as_u16());
+
+    check_status(res.status())?;
+
+    let body = res
+      .json::<PokemonSpeciesPage>()
+      .context("Cannot parse response from Pokemon API")?;
+    Ok((body.count, body.results.into_iter().next().map(|species| species.name)))
+
+  }
+
+  /// Retrieves the details of the Pokemon with the given name, from the resources of the Pokemon rather than of the species.
+  async fn get_pokemon_details(&self, name: &str) -> Result<Option<PokemonDetails>> {
+
+    debug!("Sending HTTP request");
+    metrics::POKEAPI_REQUESTS.inc();
+
+    // Send the request
+    let name = name.to_lowercase();
+    let res = self.mirrors
+      .send(&self.http, |base_url| Ok(self.http.get(Self::resource_url(base_url, &self.pokemon_path, &name)?)))
+      .await
+      .context("Cannot send request to Pokemon API")?;
+
+    debug!(status = res.status().as_u16(), "Got HTTP response: {}", res.status().as_u16());
+
+    if res.status().as_u16() == 404 {
+      return Ok(None);
+    }
+    check_status(res.status())?;
+
+    let body = res
+      .json::<PokemonDetails>()
+      .context("Cannot parse response from Pokemon API")?;
+    Ok(Some(body))
+
+  }
+
+  /// Retrieves the base stats of the Pokemon with the given name.
+  /// If no Pokemon can be found, `None` is returned.
+  #[instrument(skip(self))]
+  pub async fn get_pokemon_stats(&self, name: &str) -> Result<Option<PokemonStats>> {
+    Ok(self.get_pokemon_details(name).await?
+      .map(|details| details.stats.into_iter().map(|stat| (stat.stat.name, stat.base_stat)).collect()))
+  }
+
+  /// Retrieves the name of the species of the Pokemon with the given name,
+  /// like `giratina` for the `giratina-origin` form.
+  /// If no Pokemon can be found, `None` is returned.
+  #[instrument(skip(self))]
+  pub async fn get_species_name(&self, name: &str) -> Result<Option<String>> {
+    Ok(self.get_pokemon_details(name).await?
+      .and_then(|details| details.species)
+      .map(|species| species.name))
+  }
+
+  /// Retrieves the habitat and the types of the Pokemon with the given name, together with its base stats
+  /// which come from the same resource. Forms and varieties get the habitat of their species.
+  /// If no Pokemon can be found, `None` is returned.
+  #[instrument(skip(self))]
+  pub async fn get_pokemon_profile(&self, name: &str) -> Result<Option<(PokemonProfile, PokemonStats)>> {
+
+    let details = match self.get_pokemon_details(name).await? {
+      Some(details) => details,
+      None => return Ok(None)
+    };
+    let species = details.species.map(|species| species.name).unwrap_or_else(|| name.to_lowercase());
+    let (habitat, genus) = match self.get_species(&species).await? {
+      Some(species) => (
+        species.habitat.map(|habitat| habitat.name),
+        // Select the first english genus available, like for the descriptions
+        species.genera.into_iter().find(|genus| genus.language.name == "en").map(|genus| genus.genus)
+      ),
+      None => (None, None)
+    };
+
+    let mut types = details.types;
+    types.sort_by_key(|t| t.slot);
+    let profile = PokemonProfile {
+      habitat,
+      genus,
+      types: types.into_iter().map(|t| t.kind.name).collect()
+    };
+    Ok(Some((profile, details.stats.into_iter().map(|stat| (stat.stat.name, stat.base_stat)).collect())))
+
   }
 
   /// Retrieves the description of the Pokemon with the given name.
   /// If no Pokemon can be found, `None` is returned.
-  #[instrument(skip(self), err)]
+  #[instrument(skip(self))]
   pub async fn get_pokemon_description(&self, name: &str) -> Result<Option<String>> {
 
+    // Select the first english description available
+    match self.get_species(name).await? {
+      Some(species) => species.flavor_text_entries
+        .into_iter()
+        .find(|entry| entry.language.name == "en")
+        .map(|entry| Some(entry.flavor_text))
+        .ok_or_else(|| anyhow!("No english description is available")),
+      None => Ok(None)
+    }
+
+  }
+
+  /// Retrieves the species resource with the given name.
+  async fn get_species(&self, name: &str) -> Result<Option<PokemonSpecies>> {
+
     let name = name.to_lowercase();
 
     debug!("Sending HTTP request");
     metrics::POKEAPI_REQUESTS.inc();
 
     // Send the request
-    let res = self.client.get(self.endpoint_url.join("pokemon-species/")?.join(&name)?)
-      .send()
+    let res = self.mirrors
+      .send(&self.http, |base_url| Ok(self.http.get(Self::resource_url(base_url, &self.species_path, &name)?)))
       .await
       .context("Cannot send request to Pokemon API")?;
 
@@ -60,23 +456,57 @@ impl PokemonClient {
     // If the pokemon has not been found, exit immediately
     if res.status().as_u16() == 404 {
       return Ok(None);
-    } else if res.status().is_server_error() {
-      return Err(anyhow!("HTTP error: {}", res.status().as_u16()));
     }
+    check_status(res.status())?;
 
     // Parse the body of the response
     let body = res
       .json::<PokemonSpecies>()
-      .await
       .context("Cannot parse response from Pokemon API")?;
+    Ok(Some(body))
 
-    // Select the first english description available
-    body.flavor_text_entries
-      .into_iter()
-      .find(|entry| entry.language.name == "en")
-      .map(|entry| Some(entry.flavor_text))
-      .ok_or_else(|| anyhow!("No english description is available"))
+  }
+
+}
+
+/// Fails on the responses which do not carry the requested resource,
+/// like redirects which could not be followed and any error but the not found ones.
+fn check_status(status: StatusCode) -> Result<()> {
+  if status.is_redirection() {
+    Err(anyhow!("Unexpected redirect from Pokemon API: {}", status.as_u16()))
+  } else if !status.is_success() {
+    Err(anyhow!("HTTP error: {}", status.as_u16()))
+  } else {
+    Ok(())
+  }
+}
+
+/// Normalizes a path to be joined to the base url as a directory, like `api/v2/pokemon/` for `/api/v2/pokemon`.
+fn relative_dir(path: &str) -> String {
+  format!("{}/", path.trim_matches('/'))
+}
+
+#[async_trait]
+impl PokemonApi for PokemonClient {
+
+  async fn get_pokemon_description(&self, name: &str) -> Result<Option<String>> {
+    PokemonClient::get_pokemon_description(self, name).await
+  }
 
+  async fn get_species_name(&self, name: &str) -> Result<Option<String>> {
+    PokemonClient::get_species_name(self, name).await
+  }
+
+  async fn get_pokemon_stats(&self, name: &str) -> Result<Option<PokemonStats>> {
+    PokemonClient::get_pokemon_stats(self, name).await
+  }
+
+  async fn get_pokemon_profile(&self, name: &str) -> Result<Option<(PokemonProfile, PokemonStats)>> {
+    PokemonClient::get_pokemon_profile(self, name).await
+  }
+
+  async fn ping(&self) -> Result<()> {
+    PokemonClient::ping(self).await
   }
 
 }
@@ -95,12 +525,14 @@ mod test {
         .path(format!("/pokemon-species/{}", name));
       then.status(200)
         .json_body_obj(&PokemonSpecies {
-          flavor_text_entries: entries
+          flavor_text_entries: entries,
+          genera: vec![],
+          habitat: None
         });
     }).await;
 
     // Build a new client and perform the request
-    let client = PokemonClient::new(&server.base_url()).unwrap();
+    let client = PokemonClient::builder().base_url(&server.base_url()).build().unwrap();
     let res = client.get_pokemon_description(name).await;
 
     // Assert that the mock matched
@@ -123,7 +555,7 @@ mod test {
     }).await;
 
     // Build a new client and perform the request
-    let client = PokemonClient::new(&server.base_url()).unwrap();
+    let client = PokemonClient::builder().base_url(&server.base_url()).build().unwrap();
     let res = client.get_pokemon_description(name).await;
 
     // Assert that the mock matched
@@ -146,7 +578,7 @@ mod test {
     }).await;
 
     // Build a new client and perform the request
-    let client = PokemonClient::new(&server.base_url()).unwrap();
+    let client = PokemonClient::builder().base_url(&server.base_url()).build().unwrap();
     let res = client.get_pokemon_description(name).await;
 
     // Assert that the mock matched
@@ -222,6 +654,45 @@ mod test {
 
   }
 
+  #[tokio::test]
+  async fn test_malformed_entries_are_skipped() {
+
+    let server = MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon-species/pikachu");
+      then.status(200)
+        .json_body(serde_json::json!({
+          "flavor_text_entries": [
+            { "language": { "name": "en" } },
+            { "flavor_text": "Unknown language", "language": "xx" },
+            { "flavor_text": 42, "language": { "name": "en" } },
+            { "flavor_text": "This one!", "language": { "name": "en", "url": "https://pokeapi.co/api/v2/language/9/" }, "version": {} }
+          ]
+        }));
+    }).await;
+    server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon/pikachu");
+      then.status(200)
+        .json_body(serde_json::json!({
+          "stats": [{ "base_stat": "high", "stat": { "name": "hp" } }, { "base_stat": 55, "stat": { "name": "attack" } }]
+        }));
+    }).await;
+    server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon-species/ditto");
+      then.status(200)
+        .json_body(serde_json::json!({ "flavor_text_entries": { "unexpected": true } }));
+    }).await;
+
+    let client = PokemonClient::builder().base_url(&server.base_url()).build().unwrap();
+    assert_eq!(client.get_pokemon_description("pikachu").await.unwrap().as_deref(), Some("This one!"));
+    assert_eq!(client.get_pokemon_stats("pikachu").await.unwrap().unwrap().len(), 1);
+    assert!(client.get_pokemon_description("ditto").await.unwrap_err().to_string().contains("No english description"));
+
+  }
+
   #[tokio::test]
   async fn test_pokemon_not_found() {
     
@@ -241,4 +712,223 @@ mod test {
 
   }
 
-}
\ No newline at end of file
+  #[tokio::test]
+  async fn test_reserved_characters_are_escaped() {
+
+    let server = MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon-species/a%2Fb%3Fc");
+      then.status(404);
+    }).await;
+
+    let client = PokemonClient::builder().base_url(&server.base_url()).build().unwrap();
+    assert!(client.get_pokemon_description("a/b?c").await.unwrap().is_none());
+    mock.assert();
+
+  }
+
+  #[tokio::test]
+  async fn test_stats() {
+
+    let server = MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon/pikachu");
+      then.status(200)
+        .json_body(serde_json::json!({
+          "stats": [
+            { "base_stat": 35, "stat": { "name": "hp" } },
+            { "base_stat": 55, "stat": { "name": "attack" } }
+          ]
+        }));
+    }).await;
+
+    let client = PokemonClient::builder().base_url(&server.base_url()).build().unwrap();
+    let stats = client.get_pokemon_stats("pikachu").await.unwrap().unwrap();
+    mock.assert();
+    assert_eq!(stats["hp"], 35);
+    assert_eq!(stats["attack"], 55);
+
+  }
+
+  #[tokio::test]
+  async fn test_species_name() {
+
+    let server = MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon/giratina-origin");
+      then.status(200)
+        .json_body(serde_json::json!({
+          "stats": [],
+          "species": { "name": "giratina", "url": "https://pokeapi.co/api/v2/pokemon-species/487/" }
+        }));
+    }).await;
+    server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon/missingno");
+      then.status(404);
+    }).await;
+
+    let client = PokemonClient::builder().base_url(&server.base_url()).build().unwrap();
+    assert_eq!(client.get_species_name("giratina-origin").await.unwrap().as_deref(), Some("giratina"));
+    assert!(client.get_species_name("missingno").await.unwrap().is_none());
+
+  }
+
+  #[tokio::test]
+  async fn test_profile() {
+
+    let server = MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon/bulbasaur");
+      then.status(200)
+        .json_body(serde_json::json!({
+          "stats": [{ "base_stat": 45, "stat": { "name": "hp" } }],
+          "types": [
+            { "slot": 2, "type": { "name": "poison" } },
+            { "slot": 1, "type": { "name": "grass" } }
+          ],
+          "species": { "name": "bulbasaur" }
+        }));
+    }).await;
+    server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon-species/bulbasaur");
+      then.status(200)
+        .json_body(serde_json::json!({
+          "flavor_text_entries": [],
+          "genera": [
+            { "genus": "Pokémon Graine", "language": { "name": "fr" } },
+            { "genus": "Seed Pokémon", "language": { "name": "en" } }
+          ],
+          "habitat": { "name": "grassland" }
+        }));
+    }).await;
+
+    let client = PokemonClient::builder().base_url(&server.base_url()).build().unwrap();
+    let (profile, stats) = client.get_pokemon_profile("bulbasaur").await.unwrap().unwrap();
+    assert_eq!(profile.habitat.as_deref(), Some("grassland"));
+    assert_eq!(profile.genus.as_deref(), Some("Seed Pokémon"));
+    assert_eq!(profile.types, vec!["grass", "poison"]);
+    assert_eq!(stats["hp"], 45);
+
+  }
+
+  #[tokio::test]
+  async fn test_species_at() {
+
+    let server = MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon-species/")
+        .query_param("limit", "1")
+        .query_param("offset", "24");
+      then.status(200)
+        .json_body(serde_json::json!({
+          "count": 1025,
+          "results": [{ "name": "pikachu", "url": "https://pokeapi.co/api/v2/pokemon-species/25/" }]
+        }));
+    }).await;
+
+    let client = PokemonClient::builder().base_url(&server.base_url()).build().unwrap();
+    let (count, name) = client.get_species_at(24).await.unwrap();
+    mock.assert();
+    assert_eq!(count, 1025);
+    assert_eq!(name.as_deref(), Some("pikachu"));
+
+  }
+
+  #[tokio::test]
+  async fn test_base_url_with_path_prefix() {
+
+    let server = MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/api/v2/pokemon-species/pikachu");
+      then.status(404);
+    }).await;
+
+    // Without the trailing slash, the last segment of the base url would be replaced
+    let client = PokemonClient::builder().base_url(&format!("{}/api/v2", server.base_url())).build().unwrap();
+    assert!(client.get_pokemon_description("pikachu").await.unwrap().is_none());
+    mock.assert();
+
+  }
+
+  #[tokio::test]
+  async fn test_redirects() {
+
+    let server = MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon-species/pikachu");
+      then.status(301)
+        .header("location", "/pokemon-species/25/");
+    }).await;
+    let target = server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon-species/25/");
+      then.status(404);
+    }).await;
+    server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon-species/ditto");
+      then.status(302);
+    }).await;
+
+    // Redirects are followed
+    let client = PokemonClient::builder().base_url(&server.base_url()).build().unwrap();
+    assert!(client.get_pokemon_description("pikachu").await.unwrap().is_none());
+    target.assert();
+
+    // Redirects without a location are errors, and not parsed as a species
+    let res = client.get_pokemon_description("ditto").await;
+    assert!(res.unwrap_err().to_string().contains("Unexpected redirect from Pokemon API: 302"));
+
+  }
+
+  #[tokio::test]
+  async fn test_custom_paths() {
+
+    let server = MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/mirror/species/pikachu");
+      then.status(404);
+    }).await;
+
+    let client = PokemonClient::builder().base_url(&server.base_url()).paths("/mirror/species", "mirror/pokemon/").build().unwrap();
+    assert!(client.get_pokemon_description("pikachu").await.unwrap().is_none());
+    mock.assert();
+
+  }
+
+  #[tokio::test]
+  async fn test_builder() {
+
+    assert!(PokemonClient::builder().build().is_err());
+    assert!(PokemonClient::builder().base_url("pokeapi.co/api/v2").build().is_err());
+
+    let server = MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon-species/pikachu")
+        .header("user-agent", "pokedex/1.0");
+      then.status(404);
+    }).await;
+
+    let client = PokemonClient::builder()
+      .base_url(&server.base_url())
+      .user_agent("pokedex/1.0")
+      .timeout(Duration::from_secs(5))
+      .build()
+      .unwrap();
+    assert!(client.get_pokemon_description("pikachu").await.unwrap().is_none());
+    mock.assert();
+
+  }
+
+}
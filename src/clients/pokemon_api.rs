@@ -0,0 +1,26 @@
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::clients::pokemon::{PokemonProfile, PokemonStats};
+
+/// A provider of the data of the Pokemon, like the PokeAPI.
+/// Lookups of Pokemon which do not exist succeed with `None`.
+#[async_trait]
+pub trait PokemonApi: Send + Sync {
+
+  /// Retrieves the english description of the species with the given name.
+  async fn get_pokemon_description(&self, name: &str) -> Result<Option<String>>;
+
+  /// Retrieves the name of the species of the Pokemon with the given name, like `giratina` for `giratina-origin`.
+  async fn get_species_name(&self, name: &str) -> Result<Option<String>>;
+
+  /// Retrieves the base stats of the Pokemon with the given name.
+  async fn get_pokemon_stats(&self, name: &str) -> Result<Option<PokemonStats>>;
+
+  /// Retrieves the habitat, the genus and the types of the Pokemon with the given name, together with its base stats.
+  async fn get_pokemon_profile(&self, name: &str) -> Result<Option<(PokemonProfile, PokemonStats)>>;
+
+  /// Checks that the provider is reachable.
+  async fn ping(&self) -> Result<()>;
+
+}
@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Request, StatusCode};
+use serde::{Serialize, Deserialize};
+use tracing::debug;
+
+use crate::clients::http::UpstreamResponse;
+
+/// Record-and-replay mode of the upstream clients.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Cassette {
+  /// Every response received from the upstreams is saved in the given directory.
+  Record(PathBuf),
+  /// Responses are served from the recordings in the given directory, without touching the network.
+  Replay(PathBuf)
+}
+
+/// A recorded exchange with an upstream, as saved on disk.
+#[derive(Serialize, Deserialize)]
+struct Recording {
+  method: String,
+  path: String,
+  request_body: String,
+  status: u16,
+  /// All the values of each header, in the order they were received.
+  headers: BTreeMap<String, Vec<String>>,
+  /// Base64 encoded body, which may well be binary.
+  body: String
+}
+
+/// Stable 64 bit FNV-1a hash, used to derive the file names of the recordings.
+fn fnv1a(bytes: &[u8]) -> u64 {
+  bytes.iter().fold(0xcbf29ce484222325, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Path of the recording for the given request.
+/// Only the path, query and body of the request are considered, so that recordings can be
+/// replayed against a different host than the one they have been recorded from.
+fn recording_path(dir: &Path, upstream: &str, request: &Request) -> PathBuf {
+  let mut key = format!("{} {}", request.method(), request.url().path()).into_bytes();
+  if let Some(query) = request.url().query() {
+    key.extend_from_slice(b"?");
+    key.extend_from_slice(query.as_bytes());
+  }
+  key.extend_from_slice(b"\n");
+  key.extend_from_slice(request_body(request));
+  dir.join(format!("{}-{:016x}.json", upstream, fnv1a(&key)))
+}
+
+fn request_body(request: &Request) -> &[u8] {
+  request.body().and_then(|b| b.as_bytes()).unwrap_or_default()
+}
+
+/// Saves the response to the given request in the recordings directory.
+pub fn record(dir: &Path, upstream: &str, request: &Request, response: &UpstreamResponse) -> Result<()> {
+  let recording = Recording {
+    method: request.method().to_string(),
+    path: request.url().path().to_string(),
+    request_body: String::from_utf8_lossy(request_body(request)).into_owned(),
+    status: response.status().as_u16(),
+    headers: response.headers().keys()
+      .map(|name| {
+        let values = response.headers().get_all(name).iter()
+          .filter_map(|value| Some(value.to_str().ok()?.to_string()))
+          .collect();
+        (name.to_string(), values)
+      })
+      .collect(),
+    body: BASE64.encode(response.body())
+  };
+
+  let path = recording_path(dir, upstream, request);
+  fs::create_dir_all(dir).with_context(|| format!("Cannot create recordings directory {}", dir.display()))?;
+  fs::write(&path, serde_json::to_vec_pretty(&recording)?)
+    .with_context(|| format!("Cannot write recording {}", path.display()))?;
+  debug!(path = %path.display(), "Recorded upstream response");
+  Ok(())
+}
+
+/// Loads the recorded response to the given request.
+pub fn replay(dir: &Path, upstream: &str, request: &Request) -> Result<UpstreamResponse> {
+  let path = recording_path(dir, upstream, request);
+  let contents = fs::read(&path)
+    .map_err(|_| anyhow!("No recorded response for {} {} ({})", request.method(), request.url().path(), path.display()))?;
+  let recording = serde_json::from_slice::<Recording>(&contents)
+    .with_context(|| format!("Invalid recording {}", path.display()))?;
+  debug!(path = %path.display(), "Replaying recorded upstream response");
+
+  let mut headers = HeaderMap::new();
+  for (name, values) in recording.headers {
+    let name = HeaderName::from_bytes(name.as_bytes())?;
+    for value in values {
+      headers.append(name.clone(), HeaderValue::from_str(&value)?);
+    }
+  }
+  let body = BASE64.decode(&recording.body)
+    .with_context(|| format!("Invalid body in recording {}", path.display()))?;
+
+  Ok(UpstreamResponse::new(
+    StatusCode::from_u16(recording.status)?,
+    headers,
+    body.into()
+  ))
+}
@@ -0,0 +1,140 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::metrics;
+
+/// Default fraction of the upstream calls which can be retries.
+pub const DEFAULT_RETRY_RATIO: f64 = 0.2;
+
+/// Longest wait before a retry, however many attempts are made.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// How the calls failing with a transient error, like a server error or a connection error, are retried.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+  /// Maximum number of attempts of each call, the first one included.
+  pub attempts: u32,
+  /// Wait before the first retry, doubled at each of the following ones.
+  pub base_delay: Duration,
+  /// Fraction of each wait, between `0` and `1`, which is randomly cut off,
+  /// so that the clients failing together do not retry together.
+  pub jitter: f64
+}
+
+// A single attempt, that is no retries
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    RetryPolicy {
+      attempts: 1,
+      base_delay: Duration::from_millis(100),
+      jitter: 0.5
+    }
+  }
+}
+
+impl RetryPolicy {
+
+  /// Returns how long to wait before the given retry, starting from `1`.
+  pub fn delay(&self, retry: u32) -> Duration {
+    let delay = self.base_delay.saturating_mul(2u32.saturating_pow(retry.saturating_sub(1))).min(MAX_RETRY_DELAY);
+    delay.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * rand::thread_rng().gen::<f64>())
+  }
+
+}
+
+/// Number of retries always allowed, so that a quiet instance can still retry.
+const MIN_RETRIES: f64 = 10.0;
+
+/// Number of calls whose deposits are kept, so that a long quiet period does not allow a burst of retries.
+const MAX_SAVED_CALLS: f64 = 100.0;
+
+/// Caps the fraction of the upstream calls which are retries, so that an outage does not turn into a retry storm.
+///
+/// Each first attempt deposits `ratio` tokens, and each retry withdraws a whole one.
+pub struct RetryBudget {
+  upstream: &'static str,
+  ratio: f64,
+  tokens: Mutex<f64>
+}
+
+impl RetryBudget {
+
+  /// Creates a budget allowing retries for `ratio` of the calls to the upstream with the given name.
+  pub fn new(upstream: &'static str, ratio: f64) -> Self {
+    RetryBudget {
+      upstream,
+      ratio,
+      tokens: Mutex::new(MIN_RETRIES)
+    }
+  }
+
+  /// Records a first attempt.
+  pub fn deposit(&self) {
+    let mut tokens = self.tokens.lock().unwrap();
+    *tokens = (*tokens + self.ratio).min(MIN_RETRIES.max(self.ratio * MAX_SAVED_CALLS));
+  }
+
+  /// Returns whether a retry can be performed, withdrawing from the budget if so.
+  pub fn try_retry(&self) -> bool {
+    let mut tokens = self.tokens.lock().unwrap();
+    if *tokens >= 1.0 {
+      *tokens -= 1.0;
+      metrics::UPSTREAM_RETRIES.with_label_values(&[metrics::upstream_label(self.upstream)]).inc();
+      true
+    } else {
+      metrics::RETRY_BUDGET_EXHAUSTED.with_label_values(&[metrics::upstream_label(self.upstream)]).inc();
+      false
+    }
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_retry_delay() {
+    let policy = RetryPolicy { attempts: 5, base_delay: Duration::from_millis(100), jitter: 0.0 };
+    assert_eq!(policy.delay(1), Duration::from_millis(100));
+    assert_eq!(policy.delay(3), Duration::from_millis(400));
+    assert_eq!(policy.delay(40), MAX_RETRY_DELAY);
+
+    // The jitter only shortens the waits
+    let policy = RetryPolicy { jitter: 0.5, ..policy };
+    for _ in 0..100 {
+      let delay = policy.delay(2);
+      assert!(delay > Duration::from_millis(100) && delay <= Duration::from_millis(200), "{:?}", delay);
+    }
+  }
+
+  #[test]
+  fn test_retry_budget() {
+    let budget = RetryBudget::new("test", 0.5);
+
+    // The initial allowance is spent first
+    for _ in 0..10 {
+      assert!(budget.try_retry());
+    }
+    assert!(!budget.try_retry());
+
+    // Then one retry every two calls
+    budget.deposit();
+    assert!(!budget.try_retry());
+    budget.deposit();
+    assert!(budget.try_retry());
+    assert!(!budget.try_retry());
+
+    // Only the deposits of the last calls are saved
+    for _ in 0..1000 {
+      budget.deposit();
+    }
+    for _ in 0..50 {
+      assert!(budget.try_retry());
+    }
+    assert!(!budget.try_retry());
+  }
+
+}
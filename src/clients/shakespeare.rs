@@ -1,12 +1,30 @@
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Context, Result, anyhow};
-use reqwest::{Client, Url};
+use async_trait::async_trait;
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::{RequestBuilder, Url};
 use serde::{Serialize, Deserialize};
 use tracing::{instrument, debug};
 
+use crate::clients::budget::Budget;
+use crate::clients::chunking;
+use crate::clients::dns::Resolver;
+use crate::clients::http::{parse_base_url, HttpClient};
+use crate::clients::recording::Cassette;
+use crate::clients::breaker::CircuitBreaker;
+use crate::clients::retry::{RetryBudget, RetryPolicy};
+use crate::clients::translator::Translator;
 use crate::metrics;
 
+/// Default path of the translation endpoint, relative to the base url.
+pub const DEFAULT_PATH: &str = "translate/shakespeare.json";
+
+/// Default maximum number of characters sent to the translator in a single request.
+pub const DEFAULT_MAX_CHUNK_CHARS: usize = 1000;
+
 /// A `ShakespeareString` represents a string converted to Shakespearean language.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct ShakespeareString(String);
@@ -25,11 +43,55 @@ impl ShakespeareString {
 
 }
 
+/// How the client authenticates to the translator.
+#[derive(Clone, PartialEq, Eq)]
+pub enum TranslatorAuth {
+  /// Anonymous requests, subject to the limits of the free tier.
+  None,
+  /// API secret of the public FunTranslations API, sent in the `X-Funtranslations-Api-Secret` header.
+  ApiSecret(String),
+  /// HTTP basic authentication, as used by some self-hosted installations.
+  Basic { username: String, password: String },
+  /// Arbitrary header, like the API keys required by corporate gateways.
+  Header { name: HeaderName, value: HeaderValue }
+}
+
+// Hand written to keep the secrets out of the logs
+impl std::fmt::Debug for TranslatorAuth {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      TranslatorAuth::None => write!(f, "None"),
+      TranslatorAuth::ApiSecret(_) => write!(f, "ApiSecret(***)"),
+      TranslatorAuth::Basic { username, .. } => write!(f, "Basic {{ username: {:?}, password: *** }}", username),
+      TranslatorAuth::Header { name, .. } => write!(f, "Header {{ name: {:?}, value: *** }}", name)
+    }
+  }
+}
+
+impl TranslatorAuth {
+
+  /// Adds the credentials to a request.
+  fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+    match self {
+      TranslatorAuth::None => request,
+      TranslatorAuth::ApiSecret(secret) => request.header("X-Funtranslations-Api-Secret", secret),
+      TranslatorAuth::Basic { username, password } => request.basic_auth(username, Some(password)),
+      TranslatorAuth::Header { name, value } => request.header(name, value)
+    }
+  }
+
+}
+
 /// A client for the Shakespeare Translator API.
 #[derive(Clone)]
 pub struct ShakespeareClient {
-  client: Client,
-  endpoint_url: String
+  http: HttpClient,
+  base_url: Url,
+  endpoint_url: Url,
+  max_chunk_chars: usize,
+  budget: Option<Arc<Budget>>,
+  retry_budget: Option<Arc<RetryBudget>>,
+  auth: TranslatorAuth
 }
 
 /// The response from the Shakespeare Translator API.
@@ -56,26 +118,213 @@ struct ShakespeareTranslatorContents {
   text: String
 }
 
-impl ShakespeareClient {
+/// Builder of a [`ShakespeareClient`](ShakespeareClient), only requiring the base url.
+#[derive(Default)]
+pub struct ShakespeareClientBuilder {
+  base_url: Option<String>,
+  path: Option<String>,
+  max_chunk_chars: Option<usize>,
+  auth: Option<TranslatorAuth>,
+  budget: Option<(u32, Duration, Duration)>,
+  retries: RetryPolicy,
+  retry_budget: Option<f64>,
+  breaker: Option<(u32, Duration)>,
+  timeout: Option<Duration>,
+  user_agent: Option<String>,
+  resolver: Option<Arc<Resolver>>,
+  cassette: Option<Cassette>,
+  #[cfg(feature = "chaos")]
+  chaos: Option<crate::clients::chaos::Chaos>
+}
+
+impl ShakespeareClientBuilder {
+
+  /// Sends the requests to the given base url, like `https://api.funtranslations.com/`.
+  /// The translations are requested to `<base_url>/translate/shakespeare.json`.
+  pub fn base_url(mut self, base_url: &str) -> Self {
+    self.base_url = Some(base_url.to_string());
+    self
+  }
+
+  /// Sends the requests to `<base_url>/<path>`, for mirrors mounting the translator under a different prefix.
+  pub fn path(mut self, path: &str) -> Self {
+    self.path = Some(path.to_string());
+    self
+  }
+
+  /// Sets the maximum number of characters sent to the translator in a single request.
+  /// Longer texts are split and translated one chunk at a time.
+  pub fn max_chunk_chars(mut self, max_chunk_chars: usize) -> Self {
+    self.max_chunk_chars = Some(max_chunk_chars);
+    self
+  }
+
+  /// Authenticates all the translation requests with the given credentials.
+  pub fn auth(mut self, auth: TranslatorAuth) -> Self {
+    self.auth = Some(auth);
+    self
+  }
+
+  /// Limits the translations to `calls` every `period`, instead of burning the quota of the upstream.
+  /// Once the budget is exhausted, interactive translations wait at most `max_wait` for it before failing.
+  pub fn budget(mut self, calls: u32, period: Duration, max_wait: Duration) -> Self {
+    self.budget = Some((calls, period, max_wait));
+    self
+  }
+
+  /// Retries the translations failing with a server error or a connection error, with an exponential backoff.
+  /// Each retry takes a call from the budget, if any, and is not made once it is exhausted.
+  /// By default the translations are not retried.
+  pub fn retries(mut self, retries: RetryPolicy) -> Self {
+    self.retries = retries;
+    self
+  }
+
+  /// Allows retrying `ratio` of the translation requests. By default the retries are only capped by the budget.
+  pub fn retry_budget(mut self, ratio: f64) -> Self {
+    self.retry_budget = Some(ratio);
+    self
+  }
+
+  /// Stops calling the upstream for `cooldown` after `threshold` consecutive server errors or connection errors,
+  /// failing fast instead. By default the upstream is always called.
+  pub fn circuit_breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+    self.breaker = Some((threshold, cooldown));
+    self
+  }
+
+  /// Fails the requests which are not completed within `timeout`, body included.
+  /// By default the requests never time out.
+  pub fn timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = Some(timeout);
+    self
+  }
+
+  /// Sends the given `User-Agent`, instead of the name and the version of this crate.
+  pub fn user_agent(mut self, user_agent: &str) -> Self {
+    self.user_agent = Some(user_agent.to_string());
+    self
+  }
+
+  /// Resolves the host of the upstream with the given resolver, instead of the one of the system.
+  pub fn resolver(mut self, resolver: Option<Arc<Resolver>>) -> Self {
+    self.resolver = resolver;
+    self
+  }
+
+  /// Records or replays all the exchanges with the upstream.
+  pub fn cassette(mut self, cassette: Option<Cassette>) -> Self {
+    self.cassette = cassette;
+    self
+  }
+
+  /// Injects faults in all the requests performed by this client.
+  #[cfg(feature = "chaos")]
+  pub fn chaos(mut self, chaos: Option<crate::clients::chaos::Chaos>) -> Self {
+    self.chaos = chaos;
+    self
+  }
+
+  /// Builds the client, failing if the base url is missing or invalid, or if the HTTP client cannot be built.
+  pub fn build(self) -> Result<ShakespeareClient> {
+
+    let base_url = self.base_url.ok_or_else(|| anyhow!("Missing Shakespeare Translator base URL"))?;
+    let base_url = parse_base_url(&base_url).context("Invalid Shakespeare Translator base URL")?;
+    let path = self.path.as_deref().unwrap_or(DEFAULT_PATH);
+    let endpoint_url = base_url.join(path.trim_start_matches('/'))
+      .context("Invalid Shakespeare Translator path")?;
+
+    let mut http = HttpClient::new("shakespeare")?
+      .with_timeout(self.timeout)?
+      .with_resolver(self.resolver)?
+      .with_retry(self.retries)
+      .with_cassette(self.cassette);
+    if let Some(user_agent) = &self.user_agent {
+      http = http.with_user_agent(user_agent)?;
+    }
+    if let Some((threshold, cooldown)) = self.breaker {
+      http = http.with_circuit_breaker(threshold, cooldown);
+    }
+    #[cfg(feature = "chaos")]
+    let mut http = http.with_chaos(self.chaos);
+
+    // The retries take from the same budget as the first attempts
+    let budget = self.budget.map(|(calls, period, max_wait)| Arc::new(Budget::new(calls, period, max_wait)));
+    if let Some(budget) = &budget {
+      http = http.with_quota(budget.clone());
+    }
+    let retry_budget = self.retry_budget.map(|ratio| Arc::new(RetryBudget::new("shakespeare", ratio)));
+    if let Some(retry_budget) = &retry_budget {
+      http = http.with_retry_budget(retry_budget.clone());
+    }
 
-  /// Creates a new [`ShakespeareClient`](crate::clients::ShakespeareClient) using the given base url.
-  /// 
-  /// The requests will be performed against `<base_url>/translate/shakespeare.json`.
-  pub fn new(base_url: &str) -> Result<Self> {
     Ok(ShakespeareClient {
-      client: Client::new(),
-      endpoint_url:
-        Url::parse(base_url)
-          .context("Invalid Shakespeare Translator base URL")?
-          .join("translate/shakespeare.json")?
-          .into()
+      http,
+      base_url,
+      endpoint_url,
+      max_chunk_chars: self.max_chunk_chars.unwrap_or(DEFAULT_MAX_CHUNK_CHARS),
+      budget,
+      retry_budget,
+      auth: self.auth.unwrap_or(TranslatorAuth::None)
     })
+
+  }
+
+}
+
+impl ShakespeareClient {
+
+  /// Starts building a new [`ShakespeareClient`](crate::clients::ShakespeareClient).
+  pub fn builder() -> ShakespeareClientBuilder {
+    ShakespeareClientBuilder::default()
+  }
+
+  /// Checks that the Shakespeare Translator is reachable, without consuming the translation quota.
+  ///
+  /// The base url is pinged instead of the translation endpoint, which the public API rate limits whatever the method.
+  pub async fn ping(&self) -> Result<()> {
+    self.http.ping(self.base_url.clone()).await
   }
 
   /// Requests the translation to Shakespearean language of the given string.
-  #[instrument(skip(self), err)]
+  ///
+  /// Texts longer than the configured chunk size are split on sentence boundaries,
+  /// translated sequentially and then joined back together.
+  /// If the translation of any of the chunks fails, the whole translation fails.
+  #[instrument(skip(self))]
   pub async fn translate(&self, text: &str) -> Result<ShakespeareString> {
 
+    let chunks = if text.chars().count() <= self.max_chunk_chars {
+      vec![text.to_string()]
+    } else {
+      chunking::split_text(text, self.max_chunk_chars)
+    };
+
+    // Do not start a translation which cannot be completed
+    if let Some(budget) = &self.budget {
+      if !budget.acquire(chunks.len() as u32).await {
+        return Err(anyhow!("Shakespeare Translator budget exhausted"));
+      }
+    }
+
+    if chunks.len() == 1 {
+      return self.translate_chunk(text).await.map(ShakespeareString);
+    }
+    debug!(chunks = chunks.len(), "Text too long, translating in chunks");
+
+    let mut translated = Vec::with_capacity(chunks.len());
+    for chunk in &chunks {
+      translated.push(self.translate_chunk(chunk).await?);
+    }
+
+    Ok(ShakespeareString(translated.join(" ")))
+
+  }
+
+  /// Sends a single translation request.
+  #[instrument(skip(self))]
+  async fn translate_chunk(&self, text: &str) -> Result<String> {
+
     debug!("Sending HTTP request");
     metrics::SHAKESPEARE_REQUESTS.inc();
 
@@ -83,13 +332,19 @@ impl ShakespeareClient {
     params.insert("text", text);
 
     // Send the request
-    let res = self.client.post(&self.endpoint_url)
-      .form(&params)
-      .send()
+    let req = self.auth.apply(self.http.post(self.endpoint_url.clone()))
+      .form(&params);
+    if let Some(retry_budget) = &self.retry_budget {
+      retry_budget.deposit();
+    }
+    let res = self.http.send(req)
       .await
       .context("Cannot send request to Shakespeare Translator")?;
 
     debug!(status = res.status().as_u16(), "Got HTTP response: {}", res.status().as_u16());
+    if let Some(budget) = &self.budget {
+      budget.observe(res.status().as_u16(), res.headers());
+    }
 
     // Handle error statuses
     if res.status().is_server_error() {
@@ -99,16 +354,15 @@ impl ShakespeareClient {
     // Parse the body of the response
     let body = res
       .json::<ShakespeareTranslatorResponse>()
-      .await
       .context("Cannot parse response from Shakespeare Translator")?;
 
     // Check if the server returned an error
-    return match body {
+    match body {
       ShakespeareTranslatorResponse::Error { error } => {
         Err(anyhow!("Shakespeare Translator error: {}", &error.message))
       },
       ShakespeareTranslatorResponse::Success { contents } => {
-        Ok(ShakespeareString(contents.translated))
+        Ok(contents.translated)
       }
     }
 
@@ -116,6 +370,27 @@ impl ShakespeareClient {
 
 }
 
+#[async_trait]
+impl Translator for ShakespeareClient {
+
+  fn name(&self) -> &'static str {
+    "shakespeare"
+  }
+
+  fn circuit_breaker(&self) -> Option<Arc<CircuitBreaker>> {
+    self.http.circuit_breaker()
+  }
+
+  async fn translate(&self, text: &str) -> Result<String> {
+    ShakespeareClient::translate(self, text).await.map(ShakespeareString::into_str)
+  }
+
+  async fn ping(&self) -> Result<()> {
+    ShakespeareClient::ping(self).await
+  }
+
+}
+
 #[cfg(test)]
 mod test {
   use super::*;
@@ -143,7 +418,7 @@ mod test {
     }).await;
 
     // Build a new client and perform the request
-    let client = ShakespeareClient::new(&server.base_url()).unwrap();
+    let client = ShakespeareClient::builder().base_url(&server.base_url()).build().unwrap();
     let res = client.translate(text).await;
 
     // Assert that the mock matched
@@ -171,7 +446,7 @@ mod test {
     }).await;
 
     // Build a new client and perform the request
-    let client = ShakespeareClient::new(&server.base_url()).unwrap();
+    let client = ShakespeareClient::builder().base_url(&server.base_url()).build().unwrap();
     let res = client.translate(text).await;
 
     // Assert that the mock matched
@@ -220,4 +495,197 @@ mod test {
     assert!(translated.unwrap_err().to_string().contains("HTTP error: 500"));
 
   }
-}
\ No newline at end of file
+
+  async fn mock_chunk<'a>(server: &'a MockServer, text: &str, translated: Option<&str>) -> httpmock::MockRef<'a> {
+    server.mock_async(|when, then| {
+      when.method(Method::POST)
+        .path("/translate/shakespeare.json")
+        .body(
+          form_urlencoded::Serializer::new(String::new())
+            .append_pair("text", text)
+            .finish()
+        );
+      match translated {
+        Some(translated) => then.status(200).json_body_obj(&ShakespeareTranslatorResponse::Success {
+          contents: ShakespeareTranslatorContents {
+            translated: translated.to_string(),
+            text: text.to_string()
+          }
+        }),
+        None => then.status(500).body("Internal server error")
+      };
+    }).await
+  }
+
+  #[tokio::test]
+  async fn test_chunked_translation() {
+
+    let server = MockServer::start_async().await;
+    let first = mock_chunk(&server, "First sentence.", Some("Translated first.")).await;
+    let second = mock_chunk(&server, "Second sentence.", Some("Translated second.")).await;
+
+    let client = ShakespeareClient::builder().base_url(&server.base_url()).max_chunk_chars(20).build().unwrap();
+    let translated = client.translate("First sentence. Second sentence.").await;
+
+    first.assert();
+    second.assert();
+    assert_eq!(translated.unwrap().into_str(), "Translated first. Translated second.");
+
+  }
+
+  #[tokio::test]
+  async fn test_chunked_translation_failure() {
+
+    let server = MockServer::start_async().await;
+    let first = mock_chunk(&server, "First sentence.", Some("Translated first.")).await;
+    let second = mock_chunk(&server, "Second sentence.", None).await;
+    let third = mock_chunk(&server, "Third sentence.", Some("Translated third.")).await;
+
+    let client = ShakespeareClient::builder().base_url(&server.base_url()).max_chunk_chars(20).build().unwrap();
+    let translated = client.translate("First sentence. Second sentence. Third sentence.").await;
+
+    // The translation stops at the first failed chunk
+    first.assert();
+    second.assert();
+    third.assert_hits(0);
+    assert!(translated.unwrap_err().to_string().contains("HTTP error: 500"));
+
+  }
+
+  #[tokio::test]
+  async fn test_budget_exhaustion() {
+
+    let server = MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.method(Method::POST)
+        .path("/translate/shakespeare.json");
+      then.status(200)
+        .header("X-RateLimit-Remaining", "0")
+        .json_body_obj(&ShakespeareTranslatorResponse::Success {
+          contents: ShakespeareTranslatorContents {
+            translated: "Mocked translation".to_string(),
+            text: "Hello world".to_string()
+          }
+        });
+    }).await;
+
+    // The upstream says that the quota is over, so the second call never leaves the client
+    let client = ShakespeareClient::builder().base_url(&server.base_url()).budget(5, Duration::from_secs(3600), Duration::ZERO).build().unwrap();
+    assert!(client.translate("Hello world").await.is_ok());
+    let translated = client.translate("Hello world").await;
+    assert!(translated.unwrap_err().to_string().contains("budget exhausted"));
+    mock.assert_hits(1);
+
+  }
+
+  #[tokio::test]
+  async fn test_retries_take_from_budget() {
+
+    let server = MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.method(Method::POST)
+        .path("/translate/shakespeare.json");
+      then.status(503);
+    }).await;
+
+    // The first attempt and the first retry spend the whole budget, so the last attempt is never made
+    let client = ShakespeareClient::builder()
+      .base_url(&server.base_url())
+      .budget(2, Duration::from_secs(3600), Duration::ZERO)
+      .retries(RetryPolicy { attempts: 3, base_delay: Duration::from_millis(1), jitter: 0.0 })
+      .retry_budget(0.5)
+      .build()
+      .unwrap();
+    let translated = client.translate("Hello world").await;
+    assert!(translated.unwrap_err().to_string().contains("HTTP error: 503"));
+    mock.assert_hits(2);
+
+  }
+
+  #[tokio::test]
+  async fn test_custom_path() {
+
+    let server = MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.method(Method::POST)
+        .path("/mirror/shakespeare");
+      then.status(200).json_body_obj(&ShakespeareTranslatorResponse::Success {
+        contents: ShakespeareTranslatorContents {
+          translated: "Mocked translation".to_string(),
+          text: "Hello world".to_string()
+        }
+      });
+    }).await;
+
+    let client = ShakespeareClient::builder().base_url(&server.base_url()).path("/mirror/shakespeare").build().unwrap();
+    assert_eq!(client.translate("Hello world").await.unwrap().into_str(), "Mocked translation");
+    mock.assert();
+
+  }
+
+  #[tokio::test]
+  async fn test_auth_modes() {
+
+    let server = MockServer::start_async().await;
+    let secret = server.mock_async(|when, then| {
+      when.method(Method::POST)
+        .path("/translate/shakespeare.json")
+        .header("X-Funtranslations-Api-Secret", "secret");
+      then.status(200).json_body_obj(&ShakespeareTranslatorResponse::Success {
+        contents: ShakespeareTranslatorContents { translated: "With secret".to_string(), text: "Hello".to_string() }
+      });
+    }).await;
+    let basic = server.mock_async(|when, then| {
+      when.method(Method::POST)
+        .path("/translate/shakespeare.json")
+        .header("Authorization", "Basic dXNlcjpwYXNz");
+      then.status(200).json_body_obj(&ShakespeareTranslatorResponse::Success {
+        contents: ShakespeareTranslatorContents { translated: "With basic".to_string(), text: "Hello".to_string() }
+      });
+    }).await;
+    let header = server.mock_async(|when, then| {
+      when.method(Method::POST)
+        .path("/translate/shakespeare.json")
+        .header("X-Gateway-Key", "key");
+      then.status(200).json_body_obj(&ShakespeareTranslatorResponse::Success {
+        contents: ShakespeareTranslatorContents { translated: "With header".to_string(), text: "Hello".to_string() }
+      });
+    }).await;
+
+    let translate = |auth| {
+      let client = ShakespeareClient::builder().base_url(&server.base_url()).auth(auth).build().unwrap();
+      async move { client.translate("Hello").await.unwrap().into_str() }
+    };
+    assert_eq!(translate(TranslatorAuth::ApiSecret("secret".to_string())).await, "With secret");
+    assert_eq!(translate(TranslatorAuth::Basic { username: "user".to_string(), password: "pass".to_string() }).await, "With basic");
+    assert_eq!(translate(TranslatorAuth::Header { name: HeaderName::from_static("x-gateway-key"), value: HeaderValue::from_static("key") }).await, "With header");
+    secret.assert();
+    basic.assert();
+    header.assert();
+
+    // Secrets do not end up in the logs
+    assert_eq!(format!("{:?}", TranslatorAuth::ApiSecret("secret".to_string())), "ApiSecret(***)");
+
+  }
+
+  #[tokio::test]
+  async fn test_ping() {
+
+    let server = MockServer::start_async().await;
+    let base = server.mock_async(|when, then| {
+      when.method(Method::GET).path("/");
+      then.status(404);
+    }).await;
+    let translate = server.mock_async(|when, then| {
+      when.path("/translate/shakespeare.json");
+      then.status(200);
+    }).await;
+
+    let client = ShakespeareClient::builder().base_url(&server.base_url()).build().unwrap();
+    client.ping().await.unwrap();
+    base.assert_async().await;
+    assert_eq!(translate.hits_async().await, 0);
+
+  }
+
+}
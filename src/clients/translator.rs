@@ -0,0 +1,101 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::clients::breaker::CircuitBreaker;
+
+/// A provider able to rewrite a text in Shakespearean English.
+#[async_trait]
+pub trait Translator: Send + Sync {
+
+  /// Name of the translator, recorded in the cached entries and accepted by the `POST /translate` route.
+  fn name(&self) -> &'static str;
+
+  /// The circuit breaker cutting off the calls to the provider while it is failing, if any.
+  fn circuit_breaker(&self) -> Option<Arc<CircuitBreaker>> {
+    None
+  }
+
+  /// Translates the given text.
+  async fn translate(&self, text: &str) -> Result<String>;
+
+  /// Checks that the provider is reachable, without consuming any quota.
+  async fn ping(&self) -> Result<()>;
+
+}
+
+/// The configured translators: the primary one translates the descriptions,
+/// unless a request selects another one by its name.
+#[derive(Clone)]
+pub struct Translators {
+  primary: Arc<dyn Translator>,
+  others: Vec<Arc<dyn Translator>>
+}
+
+impl Translators {
+
+  pub fn new(primary: Arc<dyn Translator>) -> Self {
+    Translators {
+      primary,
+      others: Vec::new()
+    }
+  }
+
+  /// Makes another translator available to the requests selecting it.
+  pub fn with(mut self, translator: Arc<dyn Translator>) -> Self {
+    if self.get(translator.name()).is_none() {
+      self.others.push(translator);
+    }
+    self
+  }
+
+  /// The translator used when a request does not select any.
+  pub fn primary(&self) -> &Arc<dyn Translator> {
+    &self.primary
+  }
+
+  /// Returns the translator with the given name, if configured.
+  pub fn get(&self, name: &str) -> Option<&Arc<dyn Translator>> {
+    self.iter().find(|translator| translator.name() == name)
+  }
+
+  /// All the configured translators, the primary one first.
+  pub fn iter(&self) -> impl Iterator<Item = &Arc<dyn Translator>> {
+    std::iter::once(&self.primary).chain(self.others.iter())
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  /// Translator doing nothing, just answering to its name.
+  struct Named(&'static str);
+
+  #[async_trait]
+  impl Translator for Named {
+    fn name(&self) -> &'static str {
+      self.0
+    }
+    async fn translate(&self, text: &str) -> Result<String> {
+      Ok(text.to_string())
+    }
+    async fn ping(&self) -> Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_translators() {
+    let translators = Translators::new(Arc::new(Named("shakespeare")))
+      .with(Arc::new(Named("openai")))
+      .with(Arc::new(Named("shakespeare")));
+    assert_eq!(translators.primary().name(), "shakespeare");
+    assert_eq!(translators.get("openai").unwrap().name(), "openai");
+    assert!(translators.get("yoda").is_none());
+    assert_eq!(translators.iter().map(|translator| translator.name()).collect::<Vec<_>>(), vec!["shakespeare", "openai"]);
+  }
+
+}
@@ -0,0 +1,27 @@
+//! Parsing of the env variables, shared by the configuration of the application and of the clients.
+
+use std::env;
+
+use anyhow::{Context, Result};
+
+/// Reads and parses a mandatory env variable.
+pub fn required_env<T>(name: &str) -> Result<T>
+  where T: std::str::FromStr, T::Err: std::error::Error + Send + Sync + 'static
+{
+  env::var(name)
+    .with_context(|| format!("Missing {}", name))?
+    .parse::<T>()
+    .with_context(|| format!("Invalid {}", name))
+}
+
+/// Reads and parses an optional env variable. Empty values are treated as missing.
+pub fn optional_env<T>(name: &str) -> Result<Option<T>>
+  where T: std::str::FromStr, T::Err: std::error::Error + Send + Sync + 'static
+{
+  match env::var(name) {
+    Ok(s) if !s.trim().is_empty() => s.trim().parse::<T>()
+      .map(Some)
+      .with_context(|| format!("Invalid {}", name)),
+    _ => Ok(None)
+  }
+}
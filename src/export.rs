@@ -0,0 +1,181 @@
+use std::path::PathBuf;
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::checkpoint::{CheckpointStore, write_atomically};
+use crate::cli::ExportPokedexArgs;
+use crate::clients::budget;
+use crate::routes::State;
+use crate::webhook::{self, JobEvent, Outcome, Webhook};
+use crate::routes::pokemons::get_translation;
+
+/// Number of species exported between two saves of the checkpoint.
+const CHECKPOINT_EVERY: u64 = 10;
+
+/// Name of the job in the checkpoint store.
+const JOB: &str = "export-pokedex";
+
+#[derive(Serialize, Deserialize)]
+struct ExportedPokemon {
+  name: String,
+  description: String
+}
+
+/// Progress of an export, saved so that an interrupted run does not translate everything again.
+#[derive(Serialize, Deserialize, Default)]
+struct Checkpoint {
+  /// Position of the next species in the listing of the PokeAPI.
+  next_offset: u64,
+  pokemon: Vec<ExportedPokemon>
+}
+
+/// Translates the species from the checkpoint onwards, updating it as it goes.
+async fn export(state: &State, checkpoint: &mut Checkpoint, store: &CheckpointStore) -> Result<()> {
+  loop {
+    let (count, name) = state.pokemon_client.get_species_at(checkpoint.next_offset).await?;
+    let name = match name {
+      Some(name) => name,
+      None => return Ok(())
+    };
+
+    // Species without a description are left out
+    match get_translation(&name, &**state.translators.primary(), state).await.map_err(|e| anyhow!("Cannot translate {}: {:#}", name, e))? {
+      Some(translated) => checkpoint.pokemon.push(ExportedPokemon { name, description: translated.text }),
+      None => warn!(pokemon = %name, "Species without a description, skipping it")
+    }
+
+    checkpoint.next_offset += 1;
+    if checkpoint.next_offset.is_multiple_of(CHECKPOINT_EVERY) {
+      store.save(JOB, checkpoint).await?;
+      info!(exported = checkpoint.next_offset, total = count, "Export in progress");
+    }
+  }
+}
+
+/// Exports the translated description of all the species listed by the PokeAPI as a JSON array.
+///
+/// The translations go through the cache and count against the translator budget as background calls, waiting for it if needed.
+/// If the export fails, the progress is kept in the checkpoint store, and the next run starts from there:
+/// the given checkpoint file, or else the shared cache if there is one, or else a file next to the output.
+/// The `webhook`, if any, is notified when the export ends.
+pub async fn run(args: ExportPokedexArgs, state: State, webhook: Option<&Webhook>) -> Result<()> {
+
+  let store = match args.checkpoint {
+    Some(path) => CheckpointStore::File(path),
+    None if state.cache.shared().is_some() => CheckpointStore::Shared(state.cache.clone()),
+    None => {
+      let mut path = args.out.clone().into_os_string();
+      path.push(".checkpoint");
+      CheckpointStore::File(PathBuf::from(path))
+    }
+  };
+
+  // Resume the previous run, if any
+  let mut checkpoint = match store.load::<Checkpoint>(JOB).await? {
+    Some(checkpoint) => {
+      info!(offset = checkpoint.next_offset, store = %store.describe(), "Resuming the export from the checkpoint");
+      checkpoint
+    },
+    None => Checkpoint::default()
+  };
+
+  let res = match budget::background(export(&state, &mut checkpoint, &store)).await {
+    Ok(()) => write_atomically(&args.out, &serde_json::to_vec_pretty(&checkpoint.pokemon)?),
+    Err(e) => Err(e)
+  };
+  if let Some(webhook) = webhook {
+    let (outcome, error) = match &res {
+      Ok(()) => (Outcome::Completed, None),
+      Err(e) => (Outcome::Failed, Some(format!("{:#}", e)))
+    };
+    webhook.notify(&JobEvent { job: JOB, kind: "export", outcome, done: checkpoint.next_offset, error, timestamp: webhook::now() }).await;
+  }
+  if let Err(e) = res {
+    store.save(JOB, &checkpoint).await?;
+    return Err(e.context(format!("Export interrupted, run it again to resume from {}", store.describe())));
+  }
+
+  store.clear(JOB).await?;
+  info!(exported = checkpoint.pokemon.len(), out = %args.out.display(), "Pokedex exported");
+  Ok(())
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::fs;
+  use std::sync::Arc;
+  use httpmock::{MockServer, Method};
+  use rand::Rng;
+  use serde_json::json;
+
+  use crate::cache::Cache;
+  use crate::cache::memory::MemoryCache;
+  use crate::clients::{PokemonClient, ShakespeareClient, Translators};
+  use crate::pipeline::TextPipeline;
+  use crate::prose::ProseTemplates;
+
+  #[tokio::test]
+  async fn test_export_resumes_from_checkpoint() {
+
+    let server = MockServer::start_async().await;
+    for (offset, name) in [("0", Some("bulbasaur")), ("1", Some("ivysaur")), ("2", None)] {
+      server.mock_async(|when, then| {
+        when.method(Method::GET)
+          .path("/pokemon-species/")
+          .query_param("offset", offset);
+        then.status(200)
+          .json_body(json!({ "count": 2, "results": name.map(|name| vec![json!({ "name": name })]).unwrap_or_default() }));
+      }).await;
+    }
+    let species_mock = server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon-species/ivysaur");
+      then.status(200)
+        .json_body(json!({ "flavor_text_entries": [{ "flavor_text": "It grows.", "language": { "name": "en" } }] }));
+    }).await;
+    server.mock_async(|when, then| {
+      when.method(Method::POST)
+        .path("/translate/shakespeare.json");
+      then.status(200)
+        .json_body(json!({ "contents": { "translated": "It groweth.", "text": "It grows." } }));
+    }).await;
+    let state = State {
+      pokemon_client: PokemonClient::builder().base_url(&server.base_url()).build().unwrap(),
+      translators: Translators::new(Arc::new(ShakespeareClient::builder().base_url(&server.base_url()).build().unwrap())),
+      cache: Arc::new(Cache::new(MemoryCache::new(2, 2))),
+      text_pipeline: TextPipeline::default(),
+      profanity_filter: None,
+      prose_templates: ProseTemplates::with_default_templates().unwrap(),
+      untranslated_fallback: false,
+      translate_genus: false,
+      degraded_mode: Default::default()
+    };
+
+    // The first species has already been exported by a previous run
+    let dir = std::env::temp_dir().join(format!("pokechallenge-export-{:016x}", rand::thread_rng().gen::<u64>()));
+    fs::create_dir_all(&dir).unwrap();
+    let out = dir.join("pokedex.json");
+    let checkpoint = Checkpoint {
+      next_offset: 1,
+      pokemon: vec![ExportedPokemon { name: "bulbasaur".to_string(), description: "A seed.".to_string() }]
+    };
+    CheckpointStore::File(dir.join("pokedex.json.checkpoint")).save(JOB, &checkpoint).await.unwrap();
+
+    run(ExportPokedexArgs { out: out.clone(), checkpoint: None }, state, None).await.unwrap();
+    let exported: serde_json::Value = serde_json::from_slice(&fs::read(&out).unwrap()).unwrap();
+    assert_eq!(exported, json!([
+      { "name": "bulbasaur", "description": "A seed." },
+      { "name": "ivysaur", "description": "It groweth." }
+    ]));
+    species_mock.assert_hits(1);
+    assert!(!dir.join("pokedex.json.checkpoint").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+
+  }
+
+}
@@ -0,0 +1,322 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::cache::{Cache, CacheEntry, Keyspace};
+use crate::clients::{PokemonClient, Translator};
+use crate::clients::breaker::{BreakerOpen, CircuitBreaker};
+
+/// Maximum time given to each dependency to answer a check.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Outcome of the check of a single dependency.
+#[derive(Clone, Debug, Serialize)]
+pub struct DependencyStatus {
+  pub name: &'static str,
+  pub up: bool,
+  /// Whether the application cannot serve the requests without this dependency.
+  /// The translator is not, since the Pokemon are then served in degraded mode.
+  pub required: bool,
+  pub latency_ms: u64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>
+}
+
+/// Outcome of the checks of all the dependencies.
+#[derive(Clone, Debug)]
+pub struct HealthReport {
+  pub checked_at: Instant,
+  pub dependencies: Vec<DependencyStatus>
+}
+
+impl HealthReport {
+
+  /// Whether all the required dependencies are up.
+  pub fn is_up(&self) -> bool {
+    self.dependencies.iter().all(|dependency| dependency.up || !dependency.required)
+  }
+
+}
+
+/// Set once the application starts shutting down, so that the readiness endpoint sends the load balancers away
+/// while the requests in flight are still served.
+#[derive(Default)]
+pub struct Draining(AtomicBool);
+
+impl Draining {
+
+  pub fn start(&self) {
+    self.0.store(true, Ordering::SeqCst);
+  }
+
+  pub fn is_draining(&self) -> bool {
+    self.0.load(Ordering::SeqCst)
+  }
+
+}
+
+/// Key of the degraded mode in the shared cache.
+const DEGRADED_MODE_KEY: &str = "degraded-mode";
+
+/// Error of the translations skipped because of the [`DegradedMode`](DegradedMode).
+#[derive(Debug)]
+pub struct DegradedModeEnabled;
+
+impl std::fmt::Display for DegradedModeEnabled {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Translations disabled by the degraded mode")
+  }
+}
+
+impl std::error::Error for DegradedModeEnabled {}
+
+/// Serves the untranslated descriptions without contacting the translator.
+/// It is forced by the operators, for example during a planned outage of the translator,
+/// or enabled automatically while the circuit breaker of the translator is open.
+#[derive(Default)]
+pub struct DegradedMode {
+  forced: AtomicBool,
+  breaker: Option<Arc<CircuitBreaker>>
+}
+
+impl DegradedMode {
+
+  /// Enables the degraded mode automatically while the given breaker of the translator is open, if any.
+  pub fn with_breaker(mut self, breaker: Option<Arc<CircuitBreaker>>) -> Self {
+    self.breaker = breaker;
+    self
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.is_forced() || self.is_breaker_open()
+  }
+
+  /// Whether the degraded mode is enabled by the breaker of the translator, open or probing the upstream.
+  pub fn is_breaker_open(&self) -> bool {
+    self.breaker.as_ref().is_some_and(|breaker| !breaker.is_closed())
+  }
+
+  /// Whether the degraded mode has been forced by the operators.
+  pub fn is_forced(&self) -> bool {
+    self.forced.load(Ordering::SeqCst)
+  }
+
+  /// Translates the text, unless the degraded mode is forced or the breaker of the translator is open.
+  pub async fn translate(&self, translator: &dyn Translator, text: &str) -> Result<String> {
+    if self.is_forced() {
+      return Err(DegradedModeEnabled.into());
+    }
+    translator.translate(text).await
+      .map_err(|e| if e.is::<BreakerOpen>() { DegradedModeEnabled.into() } else { e })
+  }
+
+  /// Forces the degraded mode, saving it in the cache so that it survives the restarts when the cache is shared.
+  pub async fn set(&self, enabled: bool, cache: &Cache) -> Result<()> {
+    self.forced.store(enabled, Ordering::SeqCst);
+    cache.put(Keyspace::Checkpoints, DEGRADED_MODE_KEY.to_string(), CacheEntry::data(&enabled)?).await;
+    Ok(())
+  }
+
+  /// Restores the degraded mode saved in the cache, if any.
+  pub async fn restore(&self, cache: &Cache) -> Result<()> {
+    if let Some(entry) = cache.get(Keyspace::Checkpoints, DEGRADED_MODE_KEY).await {
+      let enabled = entry.parse_data::<bool>()?;
+      if enabled {
+        info!("Degraded mode restored from the cache");
+      }
+      self.forced.store(enabled, Ordering::SeqCst);
+    }
+    Ok(())
+  }
+
+}
+
+/// Checks the dependencies of the application, caching the results so that frequent probes
+/// do not hammer the upstream APIs.
+pub struct HealthChecker {
+  pokemon_client: PokemonClient,
+  translator: Arc<dyn Translator>,
+  cache: Arc<Cache>,
+  interval: Duration,
+  report: RwLock<Option<HealthReport>>,
+  refreshing: AtomicBool
+}
+
+impl HealthChecker {
+
+  /// Creates a checker refreshing the status of the dependencies at most once every `interval`.
+  pub fn new(pokemon_client: PokemonClient, translator: Arc<dyn Translator>, cache: Arc<Cache>, interval: Duration) -> Self {
+    HealthChecker {
+      pokemon_client,
+      translator,
+      cache,
+      interval,
+      report: RwLock::new(None),
+      refreshing: AtomicBool::new(false)
+    }
+  }
+
+  /// Returns the last known status of the dependencies.
+  /// If it is stale, a refresh is started in the background, and the stale status is returned in the meantime.
+  /// Only the very first call waits for the checks to complete.
+  pub async fn report(self: &Arc<Self>) -> HealthReport {
+    let cached = self.report.read().unwrap().clone();
+    match cached {
+      None => self.refresh().await,
+      Some(report) => {
+        if report.checked_at.elapsed() >= self.interval && !self.refreshing.swap(true, Ordering::SeqCst) {
+          let checker = self.clone();
+          tokio::spawn(async move {
+            let _refreshing = ClearOnDrop(&checker.refreshing);
+            checker.refresh().await;
+          });
+        }
+        report
+      }
+    }
+  }
+
+  /// Checks all the dependencies and caches the results.
+  async fn refresh(&self) -> HealthReport {
+    debug!("Checking dependencies");
+
+    let shared = async {
+      match self.cache.shared() {
+        Some(shared) => Some(check(shared.name(), true, shared.ping()).await),
+        None => None
+      }
+    };
+    let (pokeapi, translator, shared) = futures::join!(
+      check("pokeapi", true, self.pokemon_client.ping()),
+      check(self.translator.name(), false, self.translator.ping()),
+      shared
+    );
+    let report = HealthReport {
+      checked_at: Instant::now(),
+      dependencies: vec![pokeapi, translator].into_iter().chain(shared).collect()
+    };
+
+    for dependency in report.dependencies.iter().filter(|dependency| !dependency.up) {
+      warn!(dependency = dependency.name, error = ?dependency.error, "Dependency check failed");
+    }
+
+    *self.report.write().unwrap() = Some(report.clone());
+    report
+  }
+
+}
+
+/// Clears a flag when dropped, so that a refresh which panics does not stop all the following ones.
+struct ClearOnDrop<'a>(&'a AtomicBool);
+
+impl Drop for ClearOnDrop<'_> {
+  fn drop(&mut self) {
+    self.0.store(false, Ordering::SeqCst);
+  }
+}
+
+/// Runs the check of a dependency, with a timeout.
+async fn check(name: &'static str, required: bool, f: impl std::future::Future<Output = Result<()>>) -> DependencyStatus {
+  let started = Instant::now();
+  let res = tokio::time::timeout(CHECK_TIMEOUT, f).await
+    .unwrap_or_else(|_| Err(anyhow!("Timed out")));
+  DependencyStatus {
+    name,
+    up: res.is_ok(),
+    required,
+    latency_ms: started.elapsed().as_millis() as u64,
+    error: res.err().map(|e| e.to_string())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use httpmock::{MockServer, Method};
+  use crate::cache::memory::MemoryCache;
+  use crate::clients::ShakespeareClient;
+
+  #[tokio::test]
+  async fn test_degraded_mode_is_restored() {
+    let cache = Cache::new(MemoryCache::new(1, 1));
+    DegradedMode::default().set(true, &cache).await.unwrap();
+
+    let restored = DegradedMode::default();
+    restored.restore(&cache).await.unwrap();
+    assert!(restored.is_enabled());
+  }
+
+  #[tokio::test]
+  async fn test_degraded_mode_follows_breaker() {
+
+    let server = MockServer::start_async().await;
+    let mut shakespeare = server.mock_async(|when, then| {
+      when.method(Method::POST).path("/translate/shakespeare.json");
+      then.status(503);
+    }).await;
+    let translator = ShakespeareClient::builder()
+      .base_url(&server.base_url())
+      .circuit_breaker(1, Duration::from_millis(20))
+      .build()
+      .unwrap();
+    let degraded_mode = DegradedMode::default().with_breaker(translator.circuit_breaker());
+
+    // Once the breaker opens, the translator is not contacted anymore
+    assert!(degraded_mode.translate(&translator, "Hello").await.is_err());
+    assert!(degraded_mode.is_enabled());
+    let err = degraded_mode.translate(&translator, "Hello").await.unwrap_err();
+    assert!(err.is::<DegradedModeEnabled>());
+    shakespeare.assert_hits(1);
+
+    // A successful probe disables it
+    shakespeare.delete();
+    server.mock_async(|when, then| {
+      when.method(Method::POST).path("/translate/shakespeare.json");
+      then.status(200).json_body(serde_json::json!({ "contents": { "translated": "Good morrow", "text": "Hello" } }));
+    }).await;
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(degraded_mode.translate(&translator, "Hello").await.unwrap(), "Good morrow");
+    assert!(!degraded_mode.is_enabled());
+
+  }
+
+  #[tokio::test]
+  async fn test_cached_report() {
+
+    // The PokeAPI is up, while the translator is failing, which does not make the application unready
+    let server = MockServer::start_async().await;
+    let pokeapi = server.mock_async(|when, then| {
+      when.method(Method::GET).path("/pokemon-species/");
+      then.status(200);
+    }).await;
+    let shakespeare = server.mock_async(|when, then| {
+      when.method(Method::GET).path("/");
+      then.status(503);
+    }).await;
+
+    let checker = Arc::new(HealthChecker::new(
+      PokemonClient::builder().base_url(&server.base_url()).build().unwrap(),
+      Arc::new(ShakespeareClient::builder().base_url(&server.base_url()).build().unwrap()),
+      Arc::new(Cache::new(MemoryCache::new(1, 1))),
+      Duration::from_secs(60)
+    ));
+
+    let report = checker.report().await;
+    assert!(report.is_up());
+    assert!(report.dependencies[0].up);
+    assert!(!report.dependencies[1].up);
+    assert!(!report.dependencies[1].required);
+    assert_eq!(report.dependencies[1].error.as_deref(), Some("HTTP error: 503"));
+
+    // The second probe is served from the cached status
+    checker.report().await;
+    pokeapi.assert_hits(1);
+    shakespeare.assert_hits(1);
+
+  }
+
+}
@@ -0,0 +1,277 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde::Serialize;
+use tokio::task::AbortHandle;
+use tracing::warn;
+
+use crate::leader::LeaderElection;
+use crate::metrics;
+use crate::webhook::{self, JobEvent, Outcome, Webhook};
+
+/// How long the leader of the singleton jobs holds its lease, which is renewed at each round of the jobs.
+pub const LEADER_LEASE: Duration = Duration::from_secs(600);
+
+/// Lifecycle of a background job.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+  Running,
+  Completed,
+  Failed,
+  Cancelled
+}
+
+/// State of a job, as reported by the `/admin/jobs` routes.
+#[derive(Clone, Debug, Serialize)]
+pub struct JobReport {
+  pub id: String,
+  pub kind: &'static str,
+  pub status: JobStatus,
+  /// Start time, in seconds since the Unix epoch.
+  pub started_at: u64,
+  /// Number of items or rounds processed so far.
+  pub done: u64,
+  /// Number of items to process, for the jobs which have an end.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub total: Option<u64>,
+  pub errors: u64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub last_error: Option<String>,
+  /// Estimated time to completion, from the pace so far.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub eta_seconds: Option<u64>
+}
+
+struct Job {
+  id: String,
+  kind: &'static str,
+  webhook: Option<Webhook>,
+  leader: Option<Arc<LeaderElection>>,
+  started: Instant,
+  report: Mutex<JobReport>,
+  abort: Mutex<Option<AbortHandle>>
+}
+
+impl Job {
+
+  fn report(&self) -> JobReport {
+    let mut report = self.report.lock().unwrap().clone();
+    if let (JobStatus::Running, Some(total)) = (report.status, report.total) {
+      if report.done > 0 {
+        let per_item = self.started.elapsed().as_secs_f64() / report.done as f64;
+        report.eta_seconds = Some((per_item * total.saturating_sub(report.done) as f64).round() as u64);
+      }
+    }
+    report
+  }
+
+  async fn notify(&self, outcome: Outcome, error: Option<String>) {
+    if let Some(webhook) = &self.webhook {
+      let done = self.report.lock().unwrap().done;
+      webhook.notify(&JobEvent { job: &self.id, kind: self.kind, outcome, done, error, timestamp: webhook::now() }).await;
+    }
+  }
+
+}
+
+/// Given to the jobs to report their progress.
+#[derive(Clone)]
+pub struct JobHandle(Arc<Job>);
+
+impl JobHandle {
+
+  /// Records that `done` items out of `total`, if known, have been processed.
+  pub fn progress(&self, done: u64, total: Option<u64>) {
+    let mut report = self.0.report.lock().unwrap();
+    report.done = done;
+    report.total = total;
+  }
+
+  /// Records an error which did not stop the job.
+  pub fn error(&self, error: &dyn Display) {
+    let mut report = self.0.report.lock().unwrap();
+    report.errors += 1;
+    report.last_error = Some(error.to_string());
+  }
+
+  /// Whether a round of a singleton job, which only one of the replicas should run, can be run by this replica.
+  /// Without leader election, every replica runs them.
+  pub async fn is_leader(&self) -> bool {
+    let is_leader = match &self.0.leader {
+      Some(leader) => leader.acquire().await,
+      None => true
+    };
+    metrics::JOBS_LEADER.set(is_leader as i64);
+    is_leader
+  }
+
+  /// Notifies the webhook that a round of a recurring job ended, as these jobs never complete.
+  pub async fn round_finished(&self, error: Option<String>) {
+    let outcome = if error.is_some() { Outcome::Failed } else { Outcome::Completed };
+    self.0.notify(outcome, error).await;
+  }
+
+}
+
+/// Registry of the background jobs of this instance.
+#[derive(Default)]
+pub struct Jobs {
+  jobs: Mutex<Vec<Arc<Job>>>,
+  next_id: AtomicU64,
+  webhook: Option<Webhook>,
+  leader: Option<Arc<LeaderElection>>
+}
+
+impl Jobs {
+
+  /// Notifies the given webhook when the jobs end.
+  pub fn with_webhook(mut self, webhook: Option<Webhook>) -> Self {
+    self.webhook = webhook;
+    self
+  }
+
+  /// Elects the replica running the singleton jobs.
+  pub fn with_leader(mut self, leader: Option<LeaderElection>) -> Self {
+    self.leader = leader.map(Arc::new);
+    self
+  }
+
+  /// Runs a job in the background, returning its id.
+  pub fn spawn<F, Fut>(&self, kind: &'static str, run: F) -> String
+    where F: FnOnce(JobHandle) -> Fut, Fut: Future<Output = Result<()>> + Send + 'static
+  {
+    let id = format!("{}-{}", kind, self.next_id.fetch_add(1, Ordering::Relaxed) + 1);
+    let job = Arc::new(Job {
+      id: id.clone(),
+      kind,
+      webhook: self.webhook.clone(),
+      leader: self.leader.clone(),
+      started: Instant::now(),
+      report: Mutex::new(JobReport {
+        id: id.clone(),
+        kind,
+        status: JobStatus::Running,
+        started_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        done: 0,
+        total: None,
+        errors: 0,
+        last_error: None,
+        eta_seconds: None
+      }),
+      abort: Mutex::new(None)
+    });
+
+    // Record how the job ended, unless it was cancelled
+    let fut = run(JobHandle(job.clone()));
+    let finished = job.clone();
+    let task = tokio::spawn(async move {
+      let res = fut.await;
+      let (outcome, error) = {
+        let mut report = finished.report.lock().unwrap();
+        match res {
+          Ok(()) => {
+            report.status = JobStatus::Completed;
+            (Outcome::Completed, None)
+          },
+          Err(e) => {
+            warn!(job = %finished.id, error = %e, "Background job failed");
+            report.status = JobStatus::Failed;
+            report.errors += 1;
+            report.last_error = Some(format!("{:#}", e));
+            (Outcome::Failed, report.last_error.clone())
+          }
+        }
+      };
+      finished.notify(outcome, error).await;
+    });
+
+    // The job can only be cancelled once it is listed, so the handle is always there by then
+    *job.abort.lock().unwrap() = Some(task.abort_handle());
+    self.jobs.lock().unwrap().push(job);
+    id
+  }
+
+  fn find(&self, id: &str) -> Option<Arc<Job>> {
+    self.jobs.lock().unwrap().iter().find(|job| job.id == id).cloned()
+  }
+
+  /// Reports the state of all the jobs, in the order they were started.
+  pub fn list(&self) -> Vec<JobReport> {
+    self.jobs.lock().unwrap().iter().map(|job| job.report()).collect()
+  }
+
+  /// Reports the state of the job with the given id.
+  pub fn get(&self, id: &str) -> Option<JobReport> {
+    self.find(id).map(|job| job.report())
+  }
+
+  /// Stops the job with the given id, if it is still running, and reports its state.
+  pub fn cancel(&self, id: &str) -> Option<JobReport> {
+    let job = self.find(id)?;
+    if let Some(abort) = job.abort.lock().unwrap().as_ref() {
+      abort.abort();
+    }
+    let mut report = job.report.lock().unwrap();
+    if report.status == JobStatus::Running {
+      report.status = JobStatus::Cancelled;
+    }
+    drop(report);
+    Some(job.report())
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::time::Duration;
+
+  #[tokio::test]
+  async fn test_jobs() {
+    let jobs = Jobs::default();
+
+    let failing = jobs.spawn("export", |job| async move {
+      job.progress(1, Some(4));
+      Err(anyhow::anyhow!("Translator unavailable"))
+    });
+    let running = jobs.spawn("prewarm", |job| async move {
+      job.progress(1, Some(4));
+      job.error(&"Cannot prewarm upstream connection");
+      tokio::time::sleep(Duration::from_secs(60)).await;
+      Ok(())
+    });
+    assert_eq!(running, "prewarm-2");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let report = jobs.get(&failing).unwrap();
+    assert_eq!(report.status, JobStatus::Failed);
+    assert_eq!(report.last_error.as_deref(), Some("Translator unavailable"));
+
+    let report = jobs.get(&running).unwrap();
+    assert_eq!((report.status, report.done, report.total, report.errors), (JobStatus::Running, 1, Some(4), 1));
+    assert!(report.eta_seconds.is_some());
+
+    assert_eq!(jobs.cancel(&running).unwrap().status, JobStatus::Cancelled);
+    assert_eq!(jobs.list().len(), 2);
+    assert!(jobs.get("refresh-3").is_none());
+  }
+
+  #[tokio::test]
+  async fn test_leader_without_election() {
+    let jobs = Jobs::default().with_leader(None);
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    jobs.spawn("daily", |job| async move {
+      let _ = sender.send(job.is_leader().await);
+      Ok(())
+    });
+
+    // Without election, every replica runs the singleton jobs
+    assert!(receiver.await.unwrap());
+  }
+
+}
@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::cache::redis::RedisCache;
+
+/// Elects a single replica among the ones sharing a Redis instance, holding a lease renewed at each round.
+///
+/// If the leader dies, its lease expires after `ttl` and another replica takes over at its next round.
+pub struct LeaderElection {
+  redis: RedisCache,
+  name: String,
+  ttl: Duration,
+  token: Mutex<Option<String>>
+}
+
+impl LeaderElection {
+
+  pub fn new(redis: RedisCache, name: &str, ttl: Duration) -> Self {
+    LeaderElection {
+      redis,
+      name: format!("leader:{}", name),
+      ttl,
+      token: Mutex::new(None)
+    }
+  }
+
+  /// Renews the lease if this replica is the leader, or tries to take it otherwise.
+  /// Returns whether this replica is the leader for the next `ttl`.
+  pub async fn acquire(&self) -> bool {
+    let mut token = self.token.lock().await;
+
+    // Renew the lease we already hold
+    if let Some(held) = token.as_deref() {
+      match self.redis.renew_lock(&self.name, held, self.ttl).await {
+        Ok(true) => return true,
+        Ok(false) => {
+          info!(election = %self.name, "Leadership lost");
+          *token = None;
+        },
+        Err(e) => {
+          // Let the lease expire rather than risk two leaders
+          warn!(election = %self.name, error = %e, "Cannot renew leadership");
+          *token = None;
+          return false;
+        }
+      }
+    }
+
+    match self.redis.try_lock(&self.name, self.ttl).await {
+      Ok(Some(acquired)) => {
+        info!(election = %self.name, "Leadership acquired");
+        *token = Some(acquired);
+        true
+      },
+      Ok(None) => false,
+      Err(e) => {
+        warn!(election = %self.name, error = %e, "Cannot acquire leadership");
+        false
+      }
+    }
+  }
+
+}
@@ -0,0 +1,37 @@
+//! Serves the descriptions of the Pokemon translated to Shakespearean English.
+//!
+//! The [`clients`](clients) of the PokeAPI and of the translators are also usable on their own,
+//! by the services which call the upstream APIs directly. The other public modules back the binary,
+//! and are not meant to be used outside of it.
+
+pub mod build_info;
+pub mod cache;
+pub mod cli;
+pub mod clients;
+pub mod config;
+pub mod export;
+pub mod health;
+pub mod jobs;
+pub mod leader;
+pub mod loadtest;
+pub mod logging;
+pub mod metrics;
+pub mod mock_upstreams;
+pub mod prewarm;
+pub mod pushgateway;
+pub mod routes;
+
+mod checkpoint;
+mod env_vars;
+mod log_sampling;
+mod pipeline;
+mod profanity;
+mod prose;
+mod request_stats;
+mod slo;
+mod summary;
+mod trace_context;
+mod webhook;
+
+pub use clients::{PokemonApi, PokemonClient, ShakespeareClient, Translator, Translators};
+pub use clients::openai::OpenAiClient;
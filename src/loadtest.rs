@@ -0,0 +1,122 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow};
+use rand::distributions::{Distribution, WeightedIndex};
+use reqwest::{Client, Url};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::cli::{LoadtestArgs, MAX_RPS};
+
+/// Names requested when no custom distribution is given, weighted by how popular they are.
+const DEFAULT_NAMES: &str = "pikachu:30,charizard:15,bulbasaur:10,squirtle:10,mewtwo:8,eevee:8,gengar:6,snorlax:5,ditto:4,magikarp:4";
+
+/// Outcome of a single request.
+struct Sample {
+  latency: Duration,
+  success: bool
+}
+
+/// Parses a weighted list of names, like `pikachu:10,ditto:1`. Names without a weight count as `1`.
+fn parse_names(s: &str) -> Result<(Vec<String>, WeightedIndex<u32>)> {
+  let mut names = Vec::new();
+  let mut weights = Vec::new();
+  for item in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+    let (name, weight) = match item.split_once(':') {
+      Some((name, weight)) => (name, weight.parse::<u32>().with_context(|| format!("Invalid weight for {}", name))?),
+      None => (item, 1)
+    };
+    names.push(name.to_string());
+    weights.push(weight);
+  }
+  let dist = WeightedIndex::new(&weights).map_err(|e| anyhow!("Invalid name distribution: {}", e))?;
+  Ok((names, dist))
+}
+
+/// Returns the value at the given percentile of a sorted list of latencies.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+  if sorted.is_empty() {
+    return Duration::default();
+  }
+  let idx = ((p / 100.0) * (sorted.len() - 1) as f64).round() as usize;
+  sorted[idx.min(sorted.len() - 1)]
+}
+
+/// Runs the load test described by the given arguments and prints a report to stdout.
+pub async fn run(args: LoadtestArgs) -> Result<()> {
+
+  let target = Url::parse(&args.target).context("Invalid target url")?;
+  let (names, dist) = parse_names(args.names.as_deref().unwrap_or(DEFAULT_NAMES))?;
+  if args.rps == 0 || args.rps > MAX_RPS {
+    return Err(anyhow!("--rps must be between 1 and {}", MAX_RPS));
+  }
+
+  info!(target = %target, rps = args.rps, duration = ?args.duration, "Starting load test");
+
+  let client = Client::new();
+  let samples = Arc::new(Mutex::new(Vec::new()));
+  let mut tasks = Vec::new();
+  let mut ticker = tokio::time::interval(Duration::from_secs(1) / args.rps);
+  let start = Instant::now();
+
+  // Fire requests at a steady rate, without waiting for the previous ones to complete
+  while start.elapsed() < args.duration {
+    ticker.tick().await;
+    let name = &names[dist.sample(&mut rand::thread_rng())];
+    let url = target.join(&format!("pokemon/{}", name))?;
+    let client = client.clone();
+    let samples = samples.clone();
+    tasks.push(tokio::spawn(async move {
+      let sent = Instant::now();
+      let success = match client.get(url).send().await {
+        Ok(res) => res.status().is_success(),
+        Err(_) => false
+      };
+      samples.lock().await.push(Sample { latency: sent.elapsed(), success });
+    }));
+  }
+  futures::future::join_all(tasks).await;
+  let elapsed = start.elapsed();
+
+  // Compute and print the report
+  let samples = samples.lock().await;
+  let mut latencies = samples.iter().map(|s| s.latency).collect::<Vec<_>>();
+  latencies.sort();
+  let failures = samples.iter().filter(|s| !s.success).count();
+
+  println!("Requests:    {}", samples.len());
+  println!("Failures:    {}", failures);
+  println!("Elapsed:     {:.2?}", elapsed);
+  println!("Actual rps:  {:.1}", samples.len() as f64 / elapsed.as_secs_f64());
+  for p in &[ 50.0, 90.0, 95.0, 99.0 ] {
+    println!("p{:<10} {:.2?}", p, percentile(&latencies, *p));
+  }
+  println!("max         {:.2?}", latencies.last().copied().unwrap_or_default());
+
+  Ok(())
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse_names() {
+    let (names, _) = parse_names("pikachu:10, ditto").unwrap();
+    assert_eq!(names, vec![ "pikachu", "ditto" ]);
+    assert!(parse_names(DEFAULT_NAMES).is_ok());
+    assert!(parse_names("pikachu:lots").is_err());
+    assert!(parse_names("").is_err());
+  }
+
+  #[test]
+  fn test_percentile() {
+    let latencies = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+    assert_eq!(percentile(&latencies, 50.0), Duration::from_millis(51));
+    assert_eq!(percentile(&latencies, 99.0), Duration::from_millis(99));
+    assert_eq!(percentile(&[], 99.0), Duration::default());
+  }
+
+}
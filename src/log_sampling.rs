@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+
+/// Maximum number of distinct error kinds tracked. Further kinds share a single bucket.
+const MAX_KINDS: usize = 256;
+
+/// How many occurrences of the same error kind get logged in each window.
+#[derive(Clone, Debug)]
+pub struct SamplingPolicy {
+  /// Number of occurrences always logged at the beginning of each window.
+  pub first: u64,
+  /// After the first ones, only one occurrence every `every` is logged. `0` suppresses all of them.
+  pub every: u64,
+  /// Length of the window after which the counters are reset.
+  pub window: Duration
+}
+
+impl Default for SamplingPolicy {
+  fn default() -> Self {
+    SamplingPolicy {
+      first: 10,
+      every: 100,
+      window: Duration::from_secs(60)
+    }
+  }
+}
+
+/// Outcome of [`LogSampler::sample`](LogSampler::sample).
+#[derive(Debug, PartialEq, Eq)]
+pub enum Decision {
+  /// The occurrence should be logged, reporting how many similar ones were suppressed since the last logged one.
+  Log { suppressed: u64 },
+  /// The occurrence should not be logged.
+  Suppress
+}
+
+struct KindState {
+  window_start: Instant,
+  seen: u64,
+  suppressed: u64
+}
+
+/// Bounds the volume of the logs produced by repeated errors of the same kind.
+pub struct LogSampler {
+  policy: SamplingPolicy,
+  kinds: Mutex<HashMap<String, KindState>>
+}
+
+impl LogSampler {
+
+  pub fn new(policy: SamplingPolicy) -> Self {
+    LogSampler {
+      policy,
+      kinds: Mutex::new(HashMap::new())
+    }
+  }
+
+  /// Returns the number of occurrences of each error kind seen in the current window, most frequent first.
+  pub fn recent(&self) -> Vec<(String, u64)> {
+    let kinds = self.kinds.lock().unwrap();
+    let mut recent = kinds.iter()
+      .filter(|(_, state)| state.window_start.elapsed() < self.policy.window)
+      .map(|(kind, state)| (kind.clone(), state.seen))
+      .collect::<Vec<_>>();
+    recent.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    recent
+  }
+
+  /// Records an occurrence of an error of the given kind and decides whether it should be logged.
+  pub fn sample(&self, kind: &str) -> Decision {
+
+    let mut kinds = self.kinds.lock().unwrap();
+    let kind = if kinds.len() >= MAX_KINDS && !kinds.contains_key(kind) { "other" } else { kind };
+    let state = kinds.entry(kind.to_string()).or_insert_with(|| KindState {
+      window_start: Instant::now(),
+      seen: 0,
+      suppressed: 0
+    });
+
+    // Start a new window, keeping the suppressed count so that it gets reported
+    if state.window_start.elapsed() >= self.policy.window {
+      state.window_start = Instant::now();
+      state.seen = 0;
+    }
+    state.seen += 1;
+
+    let after_first = state.seen.saturating_sub(self.policy.first);
+    if after_first == 0 || (self.policy.every > 0 && after_first.is_multiple_of(self.policy.every)) {
+      Decision::Log { suppressed: std::mem::take(&mut state.suppressed) }
+    } else {
+      state.suppressed += 1;
+      metrics::SUPPRESSED_ERROR_LOGS.inc();
+      Decision::Suppress
+    }
+
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn sampler(first: u64, every: u64, window: Duration) -> LogSampler {
+    LogSampler::new(SamplingPolicy { first, every, window })
+  }
+
+  #[test]
+  fn test_first_then_one_every() {
+    let sampler = sampler(2, 3, Duration::from_secs(60));
+    let decisions = (0..8).map(|_| sampler.sample("boom")).collect::<Vec<_>>();
+    assert_eq!(decisions, vec![
+      Decision::Log { suppressed: 0 },
+      Decision::Log { suppressed: 0 },
+      Decision::Suppress,
+      Decision::Suppress,
+      Decision::Log { suppressed: 2 },
+      Decision::Suppress,
+      Decision::Suppress,
+      Decision::Log { suppressed: 2 }
+    ]);
+
+    // Other kinds are sampled independently
+    assert_eq!(sampler.sample("other boom"), Decision::Log { suppressed: 0 });
+    assert_eq!(sampler.recent(), vec![("boom".to_string(), 8), ("other boom".to_string(), 1)]);
+  }
+
+  #[test]
+  fn test_window_reset_reports_suppressed() {
+    let sampler = sampler(1, 0, Duration::from_millis(20));
+    assert_eq!(sampler.sample("boom"), Decision::Log { suppressed: 0 });
+    assert_eq!(sampler.sample("boom"), Decision::Suppress);
+    assert_eq!(sampler.sample("boom"), Decision::Suppress);
+
+    std::thread::sleep(Duration::from_millis(30));
+    assert_eq!(sampler.sample("boom"), Decision::Log { suppressed: 2 });
+  }
+
+}
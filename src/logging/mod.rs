@@ -0,0 +1,115 @@
+mod syslog;
+
+use std::env;
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{fmt, EnvFilter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Parses how often the log file is rotated.
+fn parse_rotation(s: &str) -> Result<Rotation> {
+  match s {
+    "minutely" => Ok(Rotation::MINUTELY),
+    "hourly" => Ok(Rotation::HOURLY),
+    "daily" => Ok(Rotation::DAILY),
+    "never" => Ok(Rotation::NEVER),
+    other => Err(anyhow!("Unknown log rotation: {}", other))
+  }
+}
+
+/// Destinations of the logs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Output {
+  Stdout,
+  /// Rotated files, named after `LOG_FILE`.
+  File,
+  /// The systemd journal, keeping the fields of the events as journal fields.
+  Journald
+}
+
+impl std::str::FromStr for Output {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "stdout" => Ok(Output::Stdout),
+      "file" => Ok(Output::File),
+      "journald" => Ok(Output::Journald),
+      other => Err(anyhow!("Unknown log output: {}", other))
+    }
+  }
+}
+
+/// Installs the global tracing collector, writing to the output selected by `LOG_OUTPUT`,
+/// and also to the syslog endpoint at `SYSLOG_ADDRESS` if set.
+///
+/// The returned guard flushes the logs written to file when dropped, so it must be kept alive until the very end.
+pub fn init() -> Result<Option<WorkerGuard>> {
+
+  let filter = EnvFilter::new(env::var("RUST_LOG").unwrap_or_else(|_| "info".to_owned()));
+  let file = env::var("LOG_FILE").ok();
+  let output = match env::var("LOG_OUTPUT") {
+    Ok(output) => output.parse().context("Invalid LOG_OUTPUT")?,
+    Err(_) if file.is_some() => Output::File,
+    Err(_) => Output::Stdout
+  };
+  let syslog = match env::var("SYSLOG_ADDRESS") {
+    Ok(address) => {
+      let syslog = syslog::Syslog::connect(&address).context("Invalid SYSLOG_ADDRESS")?;
+      Some(fmt::layer().with_ansi(false).without_time().with_writer(syslog))
+    },
+    Err(_) => None
+  };
+  let registry = tracing_subscriber::registry().with(filter).with(syslog);
+
+  match output {
+    Output::Stdout => {
+      registry.with(fmt::layer()).init();
+      Ok(None)
+    },
+    Output::File => {
+
+      // The file name is used as a prefix, followed by the date of the rotation period
+      let file = file.ok_or_else(|| anyhow!("Missing LOG_FILE"))?;
+      let rotation = parse_rotation(&env::var("LOG_ROTATION").unwrap_or_else(|_| "daily".to_owned()))
+        .context("Invalid LOG_ROTATION")?;
+      let path = Path::new(&file);
+      let name = path.file_name().ok_or_else(|| anyhow!("Invalid LOG_FILE: {}", file))?;
+      let directory = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+      let (writer, guard) = tracing_appender::non_blocking(RollingFileAppender::new(rotation, directory, name));
+
+      registry.with(fmt::layer().with_ansi(false).with_writer(writer)).init();
+      Ok(Some(guard))
+
+    },
+    Output::Journald => {
+      let journald = tracing_journald::layer().context("Cannot connect to journald")?;
+      registry.with(journald).init();
+      Ok(None)
+    }
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse_rotation() {
+    assert_eq!(parse_rotation("hourly").unwrap(), Rotation::HOURLY);
+    assert_eq!(parse_rotation("never").unwrap(), Rotation::NEVER);
+    assert!(parse_rotation("weekly").is_err());
+  }
+
+  #[test]
+  fn test_parse_output() {
+    assert_eq!("journald".parse::<Output>().unwrap(), Output::Journald);
+    assert!("stderr".parse::<Output>().is_err());
+  }
+
+}
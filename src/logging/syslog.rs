@@ -0,0 +1,271 @@
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{SecondsFormat, Utc};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::metrics;
+
+/// Facility of the messages, `user-level messages`.
+const FACILITY: u8 = 1;
+
+/// Number of messages waiting to be sent over TCP, the later ones being dropped.
+const TCP_QUEUE_SIZE: usize = 1024;
+
+/// Longest wait to connect to the endpoint, or to send a message to it.
+const TCP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Waits before connecting again after a failure, doubled at each consecutive failure.
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Connection to the syslog endpoint.
+enum Transport {
+  Udp(UdpSocket),
+  /// Messages are queued for a dedicated thread, so that a slow endpoint never blocks the logging threads.
+  Tcp(SyncSender<Vec<u8>>),
+  #[cfg(unix)]
+  Unix(UnixDatagram)
+}
+
+impl Transport {
+
+  fn send(&self, message: &[u8]) -> io::Result<()> {
+    let res = match self {
+      Transport::Udp(socket) => socket.send(message).map(|_| ()),
+      Transport::Tcp(queue) => match queue.try_send(message.to_vec()) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(_)) => Err(io::Error::new(io::ErrorKind::WouldBlock, "Syslog queue full")),
+        Err(TrySendError::Disconnected(_)) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "Syslog thread stopped"))
+      },
+      #[cfg(unix)]
+      Transport::Unix(socket) => socket.send(message).map(|_| ())
+    };
+    if res.is_err() {
+      metrics::SYSLOG_DROPPED_MESSAGES.inc();
+    }
+    res
+  }
+
+}
+
+fn connect_tcp(address: &str) -> io::Result<TcpStream> {
+  let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No address resolved");
+  for addr in address.to_socket_addrs()? {
+    match TcpStream::connect_timeout(&addr, TCP_TIMEOUT) {
+      Ok(stream) => {
+        stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+        return Ok(stream);
+      },
+      Err(e) => last_error = e
+    }
+  }
+  Err(last_error)
+}
+
+/// Sends the queued messages over TCP, until the queue is dropped.
+///
+/// When the connection drops, the endpoint is connected again with an exponential backoff,
+/// the messages logged in the meantime being dropped.
+fn run_tcp(address: String, mut stream: Option<TcpStream>, queue: Receiver<Vec<u8>>) {
+  let mut backoff = MIN_RECONNECT_BACKOFF;
+  let mut next_attempt = Instant::now();
+  for message in queue {
+
+    if stream.is_none() && Instant::now() >= next_attempt {
+      match connect_tcp(&address) {
+        Ok(connected) => {
+          stream = Some(connected);
+          backoff = MIN_RECONNECT_BACKOFF;
+        },
+        Err(_) => {
+          next_attempt = Instant::now() + backoff;
+          backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+        }
+      }
+    }
+
+    // Octet counting framing, as per RFC 6587
+    let mut framed = format!("{} ", message.len()).into_bytes();
+    framed.extend_from_slice(&message);
+    let sent = match stream.as_mut() {
+      Some(connected) => connected.write_all(&framed).is_ok(),
+      None => false
+    };
+    if !sent {
+      stream = None;
+      metrics::SYSLOG_DROPPED_MESSAGES.inc();
+    }
+
+  }
+}
+
+/// Sends each event as an RFC 5424 message to a syslog endpoint.
+#[derive(Clone)]
+pub struct Syslog {
+  transport: Arc<Transport>,
+  hostname: String,
+  pid: u32
+}
+
+impl Syslog {
+
+  /// Connects to an endpoint like `udp://host:514`, `tcp://host:601` or `unix:///dev/log`.
+  pub fn connect(address: &str) -> Result<Self> {
+    let transport = if let Some(address) = address.strip_prefix("udp://") {
+      let socket = UdpSocket::bind("0.0.0.0:0")?;
+      socket.connect(address)?;
+      Transport::Udp(socket)
+    } else if let Some(address) = address.strip_prefix("tcp://") {
+      let stream = connect_tcp(address)?;
+      let (sender, receiver) = mpsc::sync_channel(TCP_QUEUE_SIZE);
+      let address = address.to_string();
+      std::thread::Builder::new()
+        .name("syslog".to_string())
+        .spawn(move || run_tcp(address, Some(stream), receiver))
+        .context("Cannot start the syslog thread")?;
+      Transport::Tcp(sender)
+    } else if let Some(path) = address.strip_prefix("unix://") {
+      #[cfg(unix)]
+      {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        // A full receive buffer drops the messages instead of blocking
+        socket.set_nonblocking(true)?;
+        Transport::Unix(socket)
+      }
+      #[cfg(not(unix))]
+      return Err(anyhow!("Unix sockets are not supported on this platform: {}", path));
+    } else {
+      return Err(anyhow!("Unknown syslog address: {}", address));
+    };
+
+    Ok(Syslog {
+      transport: Arc::new(transport),
+      hostname: std::env::var("HOSTNAME").unwrap_or_else(|_| "-".to_owned()),
+      pid: std::process::id()
+    })
+  }
+
+  /// Formats a message with the RFC 5424 header.
+  fn format(&self, level: Level, message: &str) -> String {
+    let severity = match level {
+      Level::ERROR => 3,
+      Level::WARN => 4,
+      Level::INFO => 6,
+      Level::DEBUG | Level::TRACE => 7
+    };
+    format!(
+      "<{}>1 {} {} {} {} - - {}",
+      FACILITY * 8 + severity,
+      Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+      self.hostname,
+      env!("CARGO_PKG_NAME"),
+      self.pid,
+      message.trim_end()
+    )
+  }
+
+}
+
+/// Collects a single formatted event, and sends it when dropped.
+pub struct SyslogWriter {
+  syslog: Syslog,
+  level: Level,
+  buffer: Vec<u8>
+}
+
+impl Write for SyslogWriter {
+
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.buffer.extend_from_slice(buf);
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+
+}
+
+impl Drop for SyslogWriter {
+  fn drop(&mut self) {
+    if self.buffer.is_empty() {
+      return;
+    }
+    let message = self.syslog.format(self.level, &String::from_utf8_lossy(&self.buffer));
+    // There is nowhere to report the failure to
+    let _ = self.syslog.transport.send(message.as_bytes());
+  }
+}
+
+impl MakeWriter for Syslog {
+  type Writer = SyslogWriter;
+
+  fn make_writer(&self) -> Self::Writer {
+    SyslogWriter { syslog: self.clone(), level: Level::INFO, buffer: Vec::new() }
+  }
+
+  fn make_writer_for(&self, meta: &Metadata<'_>) -> Self::Writer {
+    SyslogWriter { syslog: self.clone(), level: *meta.level(), buffer: Vec::new() }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_udp_messages() {
+
+    let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let syslog = Syslog::connect(&format!("udp://{}", receiver.local_addr().unwrap())).unwrap();
+
+    let mut writer = syslog.make_writer();
+    writer.level = Level::WARN;
+    writer.write_all(b"Cannot prewarm upstream connection\n").unwrap();
+    drop(writer);
+
+    let mut buffer = [0; 1024];
+    let len = receiver.recv(&mut buffer).unwrap();
+    let message = std::str::from_utf8(&buffer[..len]).unwrap();
+    assert!(message.starts_with("<12>1 "), "Unexpected message {}", message);
+    assert!(message.ends_with(&format!(" {} {} - - Cannot prewarm upstream connection", env!("CARGO_PKG_NAME"), std::process::id())));
+
+  }
+
+  #[test]
+  fn test_tcp_messages() {
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let syslog = Syslog::connect(&format!("tcp://{}", listener.local_addr().unwrap())).unwrap();
+    let (mut receiver, _) = listener.accept().unwrap();
+
+    let mut writer = syslog.make_writer();
+    writer.write_all(b"Server started\n").unwrap();
+    drop(writer);
+
+    // Messages are framed with their length
+    receiver.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buffer = [0; 1024];
+    let len = io::Read::read(&mut receiver, &mut buffer).unwrap();
+    let message = std::str::from_utf8(&buffer[..len]).unwrap();
+    let (length, message) = message.split_once(' ').unwrap();
+    assert_eq!(length.parse::<usize>().unwrap(), message.len());
+    assert!(message.starts_with("<14>1 ") && message.ends_with(" - - Server started"), "Unexpected message {}", message);
+
+  }
+
+  #[test]
+  fn test_invalid_address() {
+    assert!(Syslog::connect("syslog.example.com:514").is_err());
+  }
+
+}
@@ -1,62 +1,217 @@
-mod routes;
-mod clients;
-mod metrics;
+use std::sync::Arc;
 
-use std::env;
-
-use anyhow::Result;
+use anyhow::{Result, anyhow};
+use clap::Parser;
 use futures::stream::StreamExt;
 use signal_hook::consts::signal::*;
 use signal_hook_tokio::Signals;
-use tracing::{info, warn, error};
+use tracing::{info, error, warn};
 use warp::Filter;
 
-use crate::clients::{PokemonClient, ShakespeareClient};
+use truelayer_pokemon_challenge::{build_info, cli, export, loadtest, logging, metrics, mock_upstreams, prewarm, pushgateway, routes};
+use truelayer_pokemon_challenge::cache::Cache;
+use truelayer_pokemon_challenge::cache::redis::RedisCache;
+use truelayer_pokemon_challenge::cli::{Cli, Command};
+use truelayer_pokemon_challenge::clients::{PokemonClient, ShakespeareClient, Translator, Translators};
+#[cfg(feature = "chaos")]
+use truelayer_pokemon_challenge::clients::chaos::Chaos;
+use truelayer_pokemon_challenge::clients::dns::Resolver;
+use truelayer_pokemon_challenge::clients::openai::OpenAiClient;
+use truelayer_pokemon_challenge::config::{Config, TranslatorKind};
+use truelayer_pokemon_challenge::health::Draining;
+use truelayer_pokemon_challenge::jobs::{self, Jobs};
+use truelayer_pokemon_challenge::leader::LeaderElection;
+
+/// Builds the client of the given translation provider.
+fn build_translator(kind: TranslatorKind, config: &Config, resolver: Option<Arc<Resolver>>) -> Result<Arc<dyn Translator>> {
+  match kind {
+    TranslatorKind::Shakespeare => {
+      let url = config.shakespeare_url.as_deref().ok_or_else(|| anyhow!("Missing Shakespeare configuration"))?;
+      let mut builder = ShakespeareClient::builder()
+        .base_url(url)
+        .path(&config.shakespeare_path)
+        .auth(config.shakespeare_auth.clone())
+        .max_chunk_chars(config.shakespeare_max_chunk_chars)
+        .retries(config.upstream_retry)
+        .retry_budget(config.retry_budget_ratio)
+        .resolver(resolver)
+        .cassette(config.cassette.clone());
+      if let Some((calls, period, max_wait)) = config.shakespeare_budget {
+        builder = builder.budget(calls, period, max_wait);
+      }
+      if let Some((failures, cooldown)) = config.translator_breaker {
+        builder = builder.circuit_breaker(failures, cooldown);
+      }
+      #[cfg(feature = "chaos")]
+      let builder = builder.chaos(Chaos::from_env("SHAKESPEARE")?);
+      Ok(Arc::new(builder.build()?))
+    },
+    TranslatorKind::OpenAi => {
+      let openai = config.openai.as_ref().ok_or_else(|| anyhow!("Missing OpenAI configuration"))?;
+      let mut client = OpenAiClient::new(&openai.url)?
+        .with_api_key(openai.api_key.clone())
+        .with_model(&openai.model)
+        .with_retry(config.upstream_retry)
+        .with_retry_budget(config.retry_budget_ratio)
+        .with_resolver(resolver)?
+        .with_cassette(config.cassette.clone());
+      if let Some((failures, cooldown)) = config.translator_breaker {
+        client = client.with_circuit_breaker(failures, cooldown);
+      }
+      #[cfg(feature = "chaos")]
+      let client = client.with_chaos(Chaos::from_env("OPENAI")?);
+      Ok(Arc::new(client))
+    }
+  }
+}
+
+/// Builds the clients of all the configured translation providers, the one of `TRANSLATOR` being the primary.
+fn build_translators(config: &Config, resolver: Option<Arc<Resolver>>) -> Result<Translators> {
+  let mut translators = Translators::new(build_translator(config.translator, config, resolver.clone())?);
+  if config.shakespeare_url.is_some() {
+    translators = translators.with(build_translator(TranslatorKind::Shakespeare, config, resolver.clone())?);
+  }
+  if config.openai.is_some() {
+    translators = translators.with(build_translator(TranslatorKind::OpenAi, config, resolver)?);
+  }
+  Ok(translators)
+}
+
+/// Builds the clients of the upstreams, sharing the same resolver.
+fn build_clients(config: &Config) -> Result<(PokemonClient, Translators)> {
+
+  let resolver = Some(Arc::new(Resolver::new(&config.dns)?));
+  let mut pokemon_client = PokemonClient::builder()
+    .base_url(&config.pokemon_url)
+    .paths(&config.pokemon_species_path, &config.pokemon_pokemon_path)
+    .balancing(config.pokemon_balancing)
+    .retry_budget(config.retry_budget_ratio)
+    .retries(config.upstream_retry)
+    .resolver(resolver.clone())
+    .cassette(config.cassette.clone());
+  if let Some((failures, cooldown)) = config.pokemon_breaker {
+    pokemon_client = pokemon_client.circuit_breaker(failures, cooldown);
+  }
+
+  // Inject faults in the PokeAPI client, if requested and compiled in
+  #[cfg(feature = "chaos")]
+  let pokemon_client = pokemon_client.chaos(Chaos::from_env("POKEAPI")?);
+
+  let translators = build_translators(config, resolver)?;
+  Ok((pokemon_client.build()?, translators))
+
+}
 
 async fn run() -> Result<()> {
   
   // Register the termination signals handlers
-  let mut signals = Signals::new(&[
+  let mut signals = Signals::new([
     SIGTERM,
     SIGINT,
     SIGQUIT,
   ])?;
 
-  // Get the port to bind to from the env
-  let port = env::var("PORT")
-    .map_err(|_| ())
-    .and_then(|s| s.parse::<u16>().map_err(|_| ()))
-    .unwrap_or_else(|_| {
-      warn!("Invalid or missing PORT env value. Defauling to 8080.");
-      8080
-    });
+  // Read the configuration from the env
+  let config = Config::from_env()?;
 
-  // Extract all the required envs for the clients
-  let pokemon_url = env::var("POKEAPI_ENDPOINT")?;
-  let pokemon_cache_size = env::var("POKEAPI_CACHE_SIZE")?.parse::<usize>()?;
-  let shakespeare_url = env::var("SHAKESPEARE_TRANSLATOR_ENDPOINT")?;
+  metrics::configure_latency_buckets(config.latency_buckets.clone());
+  metrics::REQUEST_DURATION_SUMMARY.configure(&config.latency_summary_quantiles, config.latency_summary_window);
 
   // Build the clients
-  let pokemon_client = PokemonClient::new(&pokemon_url)?;
-  let shakespeare_client = ShakespeareClient::new(&shakespeare_url)?;
+  let (pokemon_client, translators) = build_clients(&config)?;
+
+  // With leader election, the singleton jobs only run on the replica holding the lease
+  let leader = if config.leader_election {
+    let redis = RedisCache::connect(config.redis_url.as_deref().unwrap_or_default()).await?;
+    Some(LeaderElection::new(redis, "jobs", jobs::LEADER_LEASE))
+  } else {
+    None
+  };
+  let jobs = Arc::new(Jobs::default().with_webhook(config.job_webhook.clone()).with_leader(leader));
+
+  // Keep warm the connections to the upstreams, if requested
+  if let Some(interval) = config.prewarm_interval {
+    let (pokemon_client, translator) = (pokemon_client.clone(), translators.primary().clone());
+    jobs.spawn("prewarm", move |job| async move {
+      prewarm::run(pokemon_client, translator, interval, job).await;
+      Ok(())
+    });
+  }
+
+  // Build the cache, connecting to the shared backend if needed
+  let cache = Cache::from_config(&config).await?;
+
+  // Check the entries persisted in the shared cache, without failing the startup if the check itself fails
+  if let Err(e) = cache.check_integrity().await {
+    warn!(error = %e, "Cannot check the integrity of the shared cache");
+  }
 
   // Build the application routes.
   // Also, enable tracing for all requests.
-  let r = routes::routes(pokemon_client, shakespeare_client, pokemon_cache_size)
+  let draining = Arc::new(Draining::default());
+  let r = routes::routes(&config, pokemon_client, translators, cache, draining.clone(), jobs)
+    .await
     .with(warp::trace::request());
 
   // Start the HTTP server and stop it when a termination signal is received
+  let shutdown_delay = config.shutdown_delay;
   let (bound_address, server_future) = warp::serve(r)
     .try_bind_with_graceful_shutdown(
-      ([ 0, 0, 0, 0 ], port),
+      ([ 0, 0, 0, 0 ], config.port),
       async move {
         signals.next().await;
         info!("Received termination signal. Begin graceful shutdown.");
+        draining.start();
+
+        // Keep accepting requests until the load balancers notice that the instance is not ready anymore
+        if !shutdown_delay.is_zero() {
+          info!(delay_seconds = shutdown_delay.as_secs(), "Waiting before closing the listener");
+          tokio::time::sleep(shutdown_delay).await;
+        }
       }
     )?;
-  info!("Server bound on {}", bound_address);
+  // A single event telling how the instance is configured, right from the start
+  info!(
+    address = %bound_address,
+    cache_backend = ?config.cache_backend,
+    git_sha = build_info::GIT_SHA,
+    features = ?build_info::features(),
+    config = ?config.redacted(),
+    "Server bound on {}", bound_address
+  );
   server_future.await;
 
+  // Short-lived instances may never be scraped, so leave a last snapshot of the metrics
+  metrics::log_summary();
+  if let Some(url) = &config.metrics_push_url {
+    if let Err(e) = pushgateway::push(url, config.metrics_push_instance.as_deref()).await {
+      warn!(error = %e, "Cannot push the metrics to the Pushgateway");
+    }
+  }
+
+  Ok(())
+
+}
+
+/// Exports the translated description of all the species, going through the cache like the server.
+async fn export_pokedex(args: cli::ExportPokedexArgs) -> Result<()> {
+
+  let config = Config::from_env()?;
+  let (pokemon_client, translators) = build_clients(&config)?;
+  let cache = Arc::new(Cache::from_config(&config).await?);
+  export::run(args, routes::State::from_config(&config, pokemon_client, translators, cache), config.job_webhook.as_ref()).await
+
+}
+
+/// Reads and validates the configuration, building the clients without contacting them, and prints it.
+fn check_config() -> Result<()> {
+
+  let config = Config::from_env()?;
+  let resolver = Some(Arc::new(Resolver::new(&config.dns)?));
+  PokemonClient::builder().base_url(&config.pokemon_url).build()?;
+  build_translators(&config, resolver)?;
+
+  println!("{:#?}", config.redacted());
   Ok(())
 
 }
@@ -64,13 +219,28 @@ async fn run() -> Result<()> {
 #[tokio::main]
 async fn main() {
 
+  // Parse the command line before anything else, so that `--help` works without a configuration
+  let cli = Cli::parse();
+
   // Configure tracing collector as soon as possible
-  tracing_subscriber::fmt()
-    .with_env_filter(std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_owned()))
-    .init();
+  let log_guard = match logging::init() {
+    Ok(guard) => guard,
+    Err(e) => {
+      eprintln!("Cannot configure the logs: {:#}", e);
+      std::process::exit(1);
+    }
+  };
 
-  // Delegate to the `run` function
-  let exit_code = match run().await {
+  // Delegate to the function implementing the requested command
+  let res = match cli.command.unwrap_or(Command::Serve) {
+    Command::Serve => run().await,
+    Command::Loadtest(args) => loadtest::run(args).await,
+    Command::MockUpstreams(args) => mock_upstreams::run(args).await,
+    Command::CheckConfig => check_config(),
+    Command::ExportOpenApi(args) => routes::openapi::export(args),
+    Command::ExportPokedex(args) => export_pokedex(args).await
+  };
+  let exit_code = match res {
     Err(e) => {
       error!(error = %e, "Fatal error");
       1
@@ -80,6 +250,9 @@ async fn main() {
       0
     }
   };
+
+  // Exiting skips the destructors, so flush the logs first
+  drop(log_guard);
   std::process::exit(exit_code);
 
 }
\ No newline at end of file
@@ -1,7 +1,16 @@
+use std::collections::HashSet;
+use std::sync::{Mutex, RwLock};
+
 use lazy_static::lazy_static;
-use prometheus::{IntCounter, register_int_counter};
+use tracing::info;
+use prometheus::{GaugeVec, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, register_gauge_vec, register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge, register_int_gauge_vec};
+
+use crate::summary::LatencySummary;
 
 lazy_static! {
+
+  /// Bucket boundaries of the latency histograms, in seconds.
+  static ref LATENCY_BUCKETS: RwLock<Vec<f64>> = RwLock::new(prometheus::DEFAULT_BUCKETS.to_vec());
   
   pub static ref POKEAPI_REQUESTS: IntCounter =
     register_int_counter!("pokechallenge_pokeapi_requests", "Requests to the PokeAPI service").unwrap();
@@ -9,7 +18,315 @@ lazy_static! {
   pub static ref SHAKESPEARE_REQUESTS: IntCounter =
     register_int_counter!("pokechallenge_shakespeare_requests", "Requests to the Shakespeare Translator service").unwrap();
 
+  pub static ref OPENAI_REQUESTS: IntCounter =
+    register_int_counter!("pokechallenge_openai_requests", "Requests to the OpenAI compatible translation service").unwrap();
+
   pub static ref CACHE_HITS: IntCounter =
     register_int_counter!("pokechallenge_cache_hits", "Number of cache hits").unwrap();
 
-}
\ No newline at end of file
+  pub static ref DESCRIPTION_CACHE_HITS: IntCounter =
+    register_int_counter!("pokechallenge_description_cache_hits", "Number of cache hits for untranslated descriptions").unwrap();
+
+  pub static ref STATS_CACHE_HITS: IntCounter =
+    register_int_counter!("pokechallenge_stats_cache_hits", "Number of cache hits for the base stats of the Pokemon").unwrap();
+
+  pub static ref SPECIES_CACHE_HITS: IntCounter =
+    register_int_counter!("pokechallenge_species_cache_hits", "Number of cache hits for the species of the forms and varieties").unwrap();
+
+  pub static ref PROFILE_CACHE_HITS: IntCounter =
+    register_int_counter!("pokechallenge_profile_cache_hits", "Number of cache hits for the habitats and types of the Pokemon").unwrap();
+
+  pub static ref TEXT_CACHE_HITS: IntCounter =
+    register_int_counter!("pokechallenge_text_cache_hits", "Number of cache hits for the texts translated by POST /translate").unwrap();
+
+  pub static ref CACHE_DISCARDED_ENTRIES: IntCounterVec =
+    register_int_counter_vec!("pokechallenge_cache_discarded_entries_total", "Number of entries read from a shared cache backend discarded because they could not be trusted", &["reason"]).unwrap();
+
+  pub static ref CACHE_QUARANTINED_ENTRIES: IntCounterVec =
+    register_int_counter_vec!("pokechallenge_cache_quarantined_entries_total", "Number of entries of a shared cache backend moved to the quarantine by the integrity check at startup", &["keyspace"]).unwrap();
+
+  pub static ref CACHE_EVICTIONS: IntCounterVec =
+    register_int_counter_vec!("pokechallenge_cache_evictions_total", "Number of entries evicted from the in-memory cache to make room for new ones", &["keyspace"]).unwrap();
+
+  pub static ref CACHE_EXPIRED: IntCounterVec =
+    register_int_counter_vec!("pokechallenge_cache_expired_total", "Number of entries discarded from the in-memory cache because their TTL expired", &["keyspace"]).unwrap();
+
+  pub static ref SUPPRESSED_ERROR_LOGS: IntCounter =
+    register_int_counter!("pokechallenge_suppressed_error_logs", "Number of error logs suppressed by the log sampling").unwrap();
+
+  pub static ref SLOW_REQUESTS: IntCounter =
+    register_int_counter!("pokechallenge_slow_requests", "Number of requests slower than SLOW_REQUEST_MS").unwrap();
+
+  pub static ref REJECTED_REQUESTS: IntCounterVec =
+    register_int_counter_vec!("pokechallenge_rejected_requests_total", "Number of abusive requests rejected before reaching the upstreams", &["reason"]).unwrap();
+
+  pub static ref PROFANITY_MASKED_TERMS: IntCounter =
+    register_int_counter!("pokechallenge_profanity_masked_terms", "Number of terms masked by the profanity filter").unwrap();
+
+  pub static ref TRANSLATOR_BUDGET_REMAINING: IntGauge =
+    register_int_gauge!("pokechallenge_translator_budget_remaining", "Estimated number of calls which can still be made to the Shakespeare Translator").unwrap();
+
+  pub static ref TRANSLATOR_BUDGET_WAITING: IntGaugeVec =
+    register_int_gauge_vec!("pokechallenge_translator_budget_waiting", "Number of translations waiting for the Shakespeare Translator budget", &["priority"]).unwrap();
+
+  pub static ref UPSTREAM_MIRROR_RESPONSES: IntCounterVec =
+    register_int_counter_vec!("pokechallenge_upstream_mirror_responses_total", "Number of responses served by each mirror of the PokeAPI", &["mirror"]).unwrap();
+
+  pub static ref UPSTREAM_MIRROR_HEALTHY: IntGaugeVec =
+    register_int_gauge_vec!("pokechallenge_upstream_mirror_healthy", "Whether each mirror of the PokeAPI answered the last request successfully", &["mirror"]).unwrap();
+
+  pub static ref UPSTREAM_RETRIES: IntCounterVec =
+    register_int_counter_vec!("pokechallenge_upstream_retries_total", "Number of upstream calls retried after a failure", &["upstream"]).unwrap();
+
+  pub static ref RETRY_BUDGET_EXHAUSTED: IntCounterVec =
+    register_int_counter_vec!("pokechallenge_retry_budget_exhausted_total", "Number of upstream calls not retried because the retry budget was exhausted", &["upstream"]).unwrap();
+
+  pub static ref SLO_EVENTS: IntCounterVec =
+    register_int_counter_vec!("pokechallenge_slo_events_total", "Number of API requests meeting (good) or missing (bad) the service level objective", &["outcome"]).unwrap();
+
+  pub static ref SLO_WINDOW_EVENTS: IntGaugeVec =
+    register_int_gauge_vec!("pokechallenge_slo_window_events", "Number of good and bad API requests in the last window", &["window", "outcome"]).unwrap();
+
+  pub static ref SLO_BURN_RATE: GaugeVec =
+    register_gauge_vec!("pokechallenge_slo_burn_rate", "Rate at which the error budget is spent in the last window, 1 meaning exactly the rate allowed by the objective", &["window"]).unwrap();
+
+  pub static ref REQUEST_DURATION: HistogramVec =
+    register_histogram_vec!("pokechallenge_request_duration_seconds", "End-to-end latency of the API requests", &["route"], latency_buckets()).unwrap();
+
+  pub static ref REQUEST_DURATION_SUMMARY: LatencySummary = {
+    let summary = LatencySummary::new("pokechallenge_request_duration_summary_seconds", "End-to-end latency of the API requests, over a sliding window");
+    prometheus::register(Box::new(summary.clone())).unwrap();
+    summary
+  };
+
+  pub static ref SYSLOG_DROPPED_MESSAGES: IntCounter =
+    register_int_counter!("pokechallenge_syslog_dropped_messages_total", "Number of log messages which could not be sent to the syslog endpoint").unwrap();
+
+  pub static ref DEPENDENCY_UNREACHABLE: IntCounter =
+    register_int_counter!("pokechallenge_dependency_unreachable_total", "Number of requests failed because an upstream API could not be reached").unwrap();
+
+  pub static ref UPSTREAM_PREWARM_DURATION: HistogramVec =
+    register_histogram_vec!("pokechallenge_upstream_prewarm_seconds", "Duration of the requests keeping warm the connections to the upstreams", &["upstream"], latency_buckets()).unwrap();
+
+  pub static ref JOBS_LEADER: IntGauge =
+    register_int_gauge!("pokechallenge_jobs_leader", "Whether this replica is the one running the singleton background jobs").unwrap();
+
+  pub static ref DNS_RESOLUTION_DURATION: Histogram =
+    register_histogram!("pokechallenge_dns_resolution_seconds", "Duration of the DNS resolutions of the upstream hosts", latency_buckets()).unwrap();
+
+  pub static ref DEGRADED_RESPONSES: IntCounterVec =
+    register_int_counter_vec!("pokechallenge_degraded_responses_total", "Number of best-effort responses served when the translation was not available", &["reason"]).unwrap();
+
+  pub static ref CACHE_MEMORY_BYTES: IntGaugeVec =
+    register_int_gauge_vec!("pokechallenge_cache_memory_bytes", "Approximate memory taken by the entries of the in-memory cache", &["keyspace"]).unwrap();
+
+  pub static ref CACHE_OPERATION_DURATION: HistogramVec =
+    register_histogram_vec!(
+      "pokechallenge_cache_operation_seconds",
+      "Duration of the reads and writes of each cache backend",
+      &["backend", "operation"],
+      // From 100µs, as the in-memory tier is much faster than any upstream
+      prometheus::exponential_buckets(0.0001, 4.0, 8).unwrap()
+    ).unwrap();
+
+  pub static ref REQUEST_QUEUE_DEPTH: IntGauge =
+    register_int_gauge!("pokechallenge_request_queue_depth", "Number of requests waiting for a slot under the concurrency limit").unwrap();
+
+  pub static ref REQUEST_QUEUE_WAIT: Histogram =
+    register_histogram!("pokechallenge_request_queue_wait_seconds", "Time the requests over the concurrency limit waited for a slot", latency_buckets()).unwrap();
+
+  pub static ref SHED_REQUESTS: IntCounterVec =
+    register_int_counter_vec!("pokechallenge_shed_requests_total", "Number of requests rejected because the instance was overloaded", &["reason"]).unwrap();
+
+  pub static ref IDEMPOTENT_REPLAYS: IntCounter =
+    register_int_counter!("pokechallenge_idempotent_replays_total", "Number of retried submissions answered with the stored response of their idempotency key").unwrap();
+
+  pub static ref IDEMPOTENCY_EVICTIONS: IntCounter =
+    register_int_counter!("pokechallenge_idempotency_evictions_total", "Number of stored responses evicted before their expiry to make room for new idempotency keys").unwrap();
+
+  pub static ref CIRCUIT_BREAKER_TRANSITIONS: IntCounterVec =
+    register_int_counter_vec!("pokechallenge_circuit_breaker_transitions_total", "Number of state changes of the circuit breakers of the upstreams", &["breaker", "state"]).unwrap();
+
+  pub static ref CIRCUIT_BREAKER_STATE: IntGaugeVec =
+    register_int_gauge_vec!("pokechallenge_circuit_breaker_state", "State of the circuit breakers of the upstreams: 0 closed, 1 half-open, 2 open", &["breaker"]).unwrap();
+
+  pub static ref UNKNOWN_PATH_HITS: IntCounterVec =
+    register_int_counter_vec!("pokechallenge_unknown_path_hits_total", "Number of requests to paths not served by any route, by first segment", &["prefix"]).unwrap();
+
+  /// Urls of the mirrors used as labels.
+  pub static ref MIRROR_LABELS: LabelGuard = LabelGuard::new(MAX_MIRROR_LABELS);
+
+  /// Prefixes of the unknown paths used as labels.
+  pub static ref UNKNOWN_PATH_LABELS: LabelGuard = LabelGuard::new(MAX_UNKNOWN_PATH_LABELS);
+
+}
+
+/// Logs the main counters, to keep a trace of the activity of the instance when it terminates.
+pub fn log_summary() {
+  info!(
+    pokeapi_requests = POKEAPI_REQUESTS.get(),
+    shakespeare_requests = SHAKESPEARE_REQUESTS.get(),
+    openai_requests = OPENAI_REQUESTS.get(),
+    cache_hits = CACHE_HITS.get(),
+    slow_requests = SLOW_REQUESTS.get(),
+    dependency_unreachable = DEPENDENCY_UNREACHABLE.get(),
+    "Final metrics"
+  );
+}
+
+/// Sets the bucket boundaries of all the latency histograms.
+/// Must be called before the first use of the metrics, as the histograms cannot change their buckets once created.
+pub fn configure_latency_buckets(buckets: Vec<f64>) {
+  *LATENCY_BUCKETS.write().unwrap() = buckets;
+}
+
+fn latency_buckets() -> Vec<f64> {
+  LATENCY_BUCKETS.read().unwrap().clone()
+}
+
+/// Parses a comma separated list of bucket boundaries, in seconds.
+pub fn parse_buckets(s: &str) -> anyhow::Result<Vec<f64>> {
+  let buckets = s.split(',')
+    .map(str::trim)
+    .filter(|b| !b.is_empty())
+    .map(|b| b.parse::<f64>().map_err(anyhow::Error::from))
+    .collect::<anyhow::Result<Vec<_>>>()?;
+  if buckets.is_empty() {
+    return Err(anyhow::anyhow!("At least one bucket is required"));
+  }
+  if buckets.iter().any(|b| !b.is_finite() || *b <= 0.0) || buckets.windows(2).any(|w| w[0] >= w[1]) {
+    return Err(anyhow::anyhow!("Buckets must be positive and strictly increasing"));
+  }
+  Ok(buckets)
+}
+
+/// Label value replacing the unknown ones, so that the labeled metrics cannot explode the cardinality of Prometheus.
+pub const OTHER: &str = "other";
+
+/// Upstreams allowed in the `upstream` labels.
+pub const UPSTREAMS: &[&str] = &["pokeapi", "shakespeare", "openai"];
+
+/// Cache backends allowed in the `backend` labels.
+pub const CACHE_BACKENDS: &[&str] = &["memory", "redis", "memcached"];
+
+/// Maximum number of distinct mirrors in the labels.
+const MAX_MIRROR_LABELS: usize = 16;
+
+/// Maximum number of distinct prefixes of the unknown paths in the labels.
+const MAX_UNKNOWN_PATH_LABELS: usize = 32;
+
+/// Returns the value if it is one of the allowed ones, `other` otherwise.
+pub fn bounded<'a>(value: &'a str, allowed: &[&str]) -> &'a str {
+  if allowed.contains(&value) { value } else { OTHER }
+}
+
+/// Returns the label of an upstream, `other` if it is unknown.
+pub fn upstream_label(upstream: &str) -> &str {
+  bounded(upstream, UPSTREAMS)
+}
+
+/// Records the duration of an operation of a cache backend, started at `started`.
+pub fn observe_cache_operation(backend: &str, operation: &'static str, started: std::time::Instant) {
+  CACHE_OPERATION_DURATION
+    .with_label_values(&[bounded(backend, CACHE_BACKENDS), operation])
+    .observe(started.elapsed().as_secs_f64());
+}
+
+/// Returns the template of the route serving a path, to be used as a label instead of the raw path.
+pub fn route_label(path: &str) -> &'static str {
+  let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+  match segments.as_slice() {
+    [""] => "/",
+    ["health"] => "/health",
+    ["health", "ready"] => "/health/ready",
+    ["metrics"] => "/metrics",
+    ["status"] => "/status",
+    ["openapi.json"] => "/openapi.json",
+    ["schemas", _] => "/schemas/{name}",
+    ["admin", "pokedex"] => "/admin/pokedex",
+    ["admin", "jobs"] => "/admin/jobs",
+    ["admin", "jobs", _] => "/admin/jobs/{id}",
+    ["admin", "cache", "rehydrate"] => "/admin/cache/rehydrate",
+    ["admin", "degraded-mode"] => "/admin/degraded-mode",
+    ["pokemon", "compare"] => "/pokemon/compare",
+    ["pokemon", "batch"] => "/pokemon/batch",
+    ["pokemon", "daily"] => "/pokemon/daily",
+    ["pokemon", _] => "/pokemon/{name}",
+    ["translate"] => "/translate",
+    _ => OTHER
+  }
+}
+
+/// Bounds the cardinality of a label with values not known in advance,
+/// keeping the first `max` distinct values and mapping the later ones to `other`.
+pub struct LabelGuard {
+  max: usize,
+  seen: Mutex<HashSet<String>>
+}
+
+impl LabelGuard {
+
+  pub fn new(max: usize) -> Self {
+    LabelGuard { max, seen: Mutex::new(HashSet::new()) }
+  }
+
+  /// Returns the label for the given value.
+  pub fn label(&self, value: &str) -> String {
+    let mut seen = self.seen.lock().unwrap();
+    if seen.contains(value) {
+      return value.to_string();
+    }
+    if seen.len() < self.max {
+      seen.insert(value.to_string());
+      return value.to_string();
+    }
+    OTHER.to_string()
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_bounded_labels() {
+    assert_eq!(upstream_label("pokeapi"), "pokeapi");
+    assert_eq!(upstream_label("mocked"), OTHER);
+
+    assert_eq!(route_label("/pokemon/pikachu"), "/pokemon/{name}");
+    assert_eq!(route_label("/pokemon/compare"), "/pokemon/compare");
+    assert_eq!(route_label("/pokemon/batch"), "/pokemon/batch");
+    assert_eq!(route_label("/translate"), "/translate");
+    assert_eq!(route_label("/pokemon/pikachu/../../etc"), OTHER);
+  }
+
+  #[test]
+  fn test_admin_route_labels() {
+    assert_eq!(route_label("/admin/pokedex"), "/admin/pokedex");
+    assert_eq!(route_label("/admin/jobs"), "/admin/jobs");
+    assert_eq!(route_label("/admin/jobs/2f1c"), "/admin/jobs/{id}");
+    assert_eq!(route_label("/admin/cache/rehydrate"), "/admin/cache/rehydrate");
+    assert_eq!(route_label("/admin/degraded-mode"), "/admin/degraded-mode");
+    assert_eq!(route_label("/admin/jobs/2f1c/logs"), OTHER);
+  }
+
+  #[test]
+  fn test_parse_buckets() {
+    assert_eq!(parse_buckets("0.05, 0.1,0.5,5").unwrap(), vec![0.05, 0.1, 0.5, 5.0]);
+    assert!(parse_buckets("").is_err());
+    assert!(parse_buckets("0.5,0.1").is_err());
+    assert!(parse_buckets("0,1").is_err());
+    assert!(parse_buckets("fast").is_err());
+  }
+
+  #[test]
+  fn test_label_guard() {
+    let guard = LabelGuard::new(2);
+    assert_eq!(guard.label("a"), "a");
+    assert_eq!(guard.label("b"), "b");
+    assert_eq!(guard.label("c"), OTHER);
+    assert_eq!(guard.label("a"), "a");
+  }
+
+}
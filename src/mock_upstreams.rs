@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde_json::json;
+use tracing::info;
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+
+use crate::cli::MockUpstreamsArgs;
+
+/// Canned Pokemon descriptions, formatted like the PokeAPI does (with line breaks and form feeds).
+const SPECIES: &[(&str, &str)] = &[
+  ("bulbasaur", "A strange seed was\nplanted on its\nback at birth.\x0cThe plant sprouts\nand grows with\nthis POKéMON."),
+  ("charmander", "Obviously prefers\nhot places. When\nit rains, steam\x0cis said to spout\nfrom the tip of\nits tail."),
+  ("squirtle", "After birth, its\nback swells and\nhardens into a\x0cshell. Powerfully\nsprays foam from\nits mouth."),
+  ("pikachu", "When several of\nthese POKéMON\ngather, their\x0celectricity could\nbuild and cause\nlightning storms."),
+  ("ditto", "Capable of copying\nan enemy's genetic\ncode to instantly\x0ctransform itself\ninto a duplicate\nof the enemy."),
+  ("mewtwo", "It was created by\na scientist after\nyears of horrific\x0cgene splicing and\nDNA engineering\nexperiments.")
+];
+
+/// Simple word substitutions to make the mocked translations look vaguely Shakespearean.
+const SUBSTITUTIONS: &[(&str, &str)] = &[
+  ("you", "thee"), ("your", "thy"), ("are", "art"), ("is", "is't"), ("has", "hath"),
+  ("does", "doth"), ("its", "its'"), ("it", "'t"), ("when", "at which hour"), ("could", "couldst")
+];
+
+/// Fixed window rate limiter emulating the public FunTranslations quota.
+struct RateLimit {
+  limit: u32,
+  window: Duration,
+  state: Mutex<(Instant, u32)>
+}
+
+impl RateLimit {
+
+  /// Counts a new request, returning the number of remaining requests or `None` if the limit is exceeded.
+  fn acquire(&self) -> Option<u32> {
+    let mut state = self.state.lock().unwrap();
+    if state.0.elapsed() >= self.window {
+      *state = (Instant::now(), 0);
+    }
+    if state.1 >= self.limit {
+      return None;
+    }
+    state.1 += 1;
+    Some(self.limit - state.1)
+  }
+
+}
+
+fn translate(text: &str) -> String {
+  text.split(' ')
+    .map(|word| {
+      let lower = word.to_lowercase();
+      SUBSTITUTIONS.iter()
+        .find(|(from, _)| *from == lower)
+        .map(|(_, to)| to.to_string())
+        .unwrap_or_else(|| word.to_string())
+    })
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+/// Returns made up, but stable, base stats for the canned Pokemon.
+fn handle_pokemon(name: String) -> impl Reply {
+  if !SPECIES.iter().any(|(n, _)| *n == name.to_lowercase()) {
+    return warp::reply::with_status(warp::reply::json(&json!("Not Found")), StatusCode::NOT_FOUND);
+  }
+  let seed = name.bytes().fold(0u32, |acc, b| acc.wrapping_mul(31).wrapping_add(b as u32));
+  let stats = ["hp", "attack", "defense", "special-attack", "special-defense", "speed"].iter()
+    .enumerate()
+    .map(|(i, stat)| json!({ "base_stat": 20 + (seed >> (i * 4)) % 100, "stat": { "name": stat } }))
+    .collect::<Vec<_>>();
+  warp::reply::with_status(warp::reply::json(&json!({ "name": name, "stats": stats })), StatusCode::OK)
+}
+
+/// Query parameters of the species listing.
+#[derive(serde::Deserialize)]
+struct ListQuery {
+  #[serde(default)]
+  offset: usize,
+  limit: Option<usize>
+}
+
+fn handle_species_list(query: ListQuery) -> impl Reply {
+  let results = SPECIES.iter()
+    .skip(query.offset)
+    .take(query.limit.unwrap_or(20))
+    .map(|(name, _)| json!({ "name": name }))
+    .collect::<Vec<_>>();
+  warp::reply::json(&json!({ "count": SPECIES.len(), "results": results }))
+}
+
+fn handle_species(name: String) -> impl Reply {
+  match SPECIES.iter().find(|(n, _)| *n == name.to_lowercase()) {
+    Some((_, description)) => warp::reply::with_status(
+      warp::reply::json(&json!({
+        "name": name,
+        "flavor_text_entries": [
+          { "flavor_text": "Descrizione in italiano.", "language": { "name": "it" } },
+          { "flavor_text": description, "language": { "name": "en" } }
+        ]
+      })),
+      StatusCode::OK
+    ),
+    None => warp::reply::with_status(warp::reply::json(&json!("Not Found")), StatusCode::NOT_FOUND)
+  }
+}
+
+fn handle_translate(form: HashMap<String, String>, rate_limit: Arc<RateLimit>) -> impl Reply {
+  let limit = rate_limit.limit.to_string();
+  let (body, status, remaining) = match rate_limit.acquire() {
+    Some(remaining) => {
+      let text = form.get("text").cloned().unwrap_or_default();
+      let body = json!({
+        "success": { "total": 1 },
+        "contents": { "translated": translate(&text), "text": text, "translation": "shakespeare" }
+      });
+      (body, StatusCode::OK, remaining)
+    },
+    None => {
+      let body = json!({
+        "error": {
+          "code": 429,
+          "message": format!("Too Many Requests: Rate limit of {} requests per {:?} exceeded.", rate_limit.limit, rate_limit.window)
+        }
+      });
+      (body, StatusCode::TOO_MANY_REQUESTS, 0)
+    }
+  };
+
+  let reply = warp::reply::with_status(warp::reply::json(&body), status);
+  let reply = warp::reply::with_header(reply, "X-RateLimit-Limit", limit);
+  warp::reply::with_header(reply, "X-RateLimit-Remaining", remaining.to_string())
+}
+
+/// Builds the routes emulating the PokeAPI and the Shakespeare Translator.
+fn routes(args: &MockUpstreamsArgs) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+
+  let rate_limit = Arc::new(RateLimit {
+    limit: args.translator_limit,
+    window: args.translator_window,
+    state: Mutex::new((Instant::now(), 0))
+  });
+
+  // GET /pokemon-species?offset={number}&limit={number}
+  let species_list = warp::get()
+    .and(warp::path!("pokemon-species"))
+    .and(warp::query::<ListQuery>())
+    .map(handle_species_list);
+
+  // GET /pokemon-species/{name}
+  let species = warp::get()
+    .and(warp::path!("pokemon-species" / String))
+    .map(handle_species);
+
+  // GET /pokemon/{name}
+  let pokemon = warp::get()
+    .and(warp::path!("pokemon" / String))
+    .map(handle_pokemon);
+
+  // POST /translate/shakespeare.json
+  let translate = warp::post()
+    .and(warp::path!("translate" / "shakespeare.json"))
+    .and(warp::body::form())
+    .and(warp::any().map(move || rate_limit.clone()))
+    .map(handle_translate);
+
+  species_list.or(species).or(pokemon).or(translate)
+
+}
+
+/// Runs the mock upstreams server until a termination signal is received.
+pub async fn run(args: MockUpstreamsArgs) -> Result<()> {
+
+  let (bound_address, server_future) = warp::serve(routes(&args).with(warp::trace::request()))
+    .try_bind_with_graceful_shutdown(([ 127, 0, 0, 1 ], args.port), async {
+      let _ = tokio::signal::ctrl_c().await;
+    })?;
+
+  info!("Mock upstreams bound on {}", bound_address);
+  info!("Point both POKEAPI_ENDPOINT and SHAKESPEARE_TRANSLATOR_ENDPOINT to http://{}/", bound_address);
+  server_future.await;
+
+  Ok(())
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn args() -> MockUpstreamsArgs {
+    MockUpstreamsArgs {
+      port: 0,
+      translator_limit: 1,
+      translator_window: Duration::from_secs(3600)
+    }
+  }
+
+  #[tokio::test]
+  async fn test_species() {
+    let routes = routes(&args());
+
+    let res = warp::test::request().path("/pokemon-species/Pikachu").reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(String::from_utf8_lossy(res.body()).contains("lightning storms"));
+
+    let res = warp::test::request().path("/pokemon-species/missingno").reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let res = warp::test::request().path("/pokemon-species/?limit=1&offset=3").reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let page: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(page["count"], SPECIES.len());
+    assert_eq!(page["results"][0]["name"], "pikachu");
+  }
+
+  #[tokio::test]
+  async fn test_translator_rate_limit() {
+    let routes = routes(&args());
+    let request = || warp::test::request()
+      .method("POST")
+      .path("/translate/shakespeare.json")
+      .header("content-type", "application/x-www-form-urlencoded")
+      .body("text=When+you+are+here");
+
+    let res = request().reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(String::from_utf8_lossy(res.body()).contains("at which hour thee art here"));
+
+    let res = request().reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.headers()["X-RateLimit-Remaining"], "0");
+  }
+
+}
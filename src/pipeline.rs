@@ -0,0 +1,159 @@
+use std::str::FromStr;
+
+use anyhow::{Result, anyhow};
+
+/// A single transformation step of a [`TextPipeline`](crate::pipeline::TextPipeline).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+  /// Removes control characters (like the form feeds the PokeAPI loves to put in flavor texts).
+  StripControl,
+  /// Collapses runs of whitespace into a single space and trims the text.
+  CollapseWhitespace,
+  /// Replaces straight quotes with typographic ones.
+  SmartQuotes,
+  /// Truncates the text to at most the given number of characters.
+  MaxLength(usize)
+}
+
+impl FromStr for Stage {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s.trim() {
+      "strip_control" => Ok(Stage::StripControl),
+      "collapse_whitespace" => Ok(Stage::CollapseWhitespace),
+      "smart_quotes" => Ok(Stage::SmartQuotes),
+      other => Err(anyhow!("Unknown text pipeline stage: {}", other))
+    }
+  }
+}
+
+impl Stage {
+
+  fn apply(&self, text: &str) -> String {
+    match self {
+      Stage::StripControl => text.chars()
+        .map(|c| if c.is_control() && c != '\n' && c != '\t' { ' ' } else { c })
+        .collect(),
+      Stage::CollapseWhitespace => text.split_whitespace().collect::<Vec<_>>().join(" "),
+      Stage::SmartQuotes => smart_quotes(text),
+      Stage::MaxLength(max) => truncate(text, *max)
+    }
+  }
+
+}
+
+/// A sequence of [`Stage`](crate::pipeline::Stage)s applied to the descriptions
+/// before they are sent to the translator and after the translation comes back.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextPipeline {
+  stages: Vec<Stage>
+}
+
+impl Default for TextPipeline {
+  fn default() -> Self {
+    TextPipeline::new(vec![ Stage::StripControl, Stage::CollapseWhitespace ])
+  }
+}
+
+impl TextPipeline {
+
+  /// Creates a new pipeline running the given stages in order.
+  pub fn new(stages: Vec<Stage>) -> Self {
+    TextPipeline { stages }
+  }
+
+  /// Parses a pipeline from a comma separated list of stage names, like `strip_control,collapse_whitespace`.
+  /// If `max_length` is given, a final [`Stage::MaxLength`](crate::pipeline::Stage::MaxLength) is appended.
+  pub fn parse(stages: &str, max_length: Option<usize>) -> Result<Self> {
+    let mut stages = stages.split(',')
+      .filter(|s| !s.trim().is_empty())
+      .map(Stage::from_str)
+      .collect::<Result<Vec<_>>>()?;
+    if let Some(max) = max_length {
+      stages.push(Stage::MaxLength(max));
+    }
+    Ok(TextPipeline::new(stages))
+  }
+
+  /// Runs the given text through all the stages of the pipeline.
+  pub fn apply(&self, text: &str) -> String {
+    self.stages.iter().fold(text.to_string(), |text, stage| stage.apply(&text))
+  }
+
+}
+
+fn smart_quotes(text: &str) -> String {
+  let mut res = String::with_capacity(text.len());
+  let mut prev: Option<char> = None;
+  for c in text.chars() {
+    let opening = prev.is_none_or(|p| p.is_whitespace() || "([{".contains(p));
+    res.push(match (c, opening) {
+      ('"', true) => '“',
+      ('"', false) => '”',
+      ('\'', true) => '‘',
+      ('\'', false) => '’',
+      (c, _) => c
+    });
+    prev = Some(c);
+  }
+  res
+}
+
+/// Truncates the text to at most `max` characters, cutting after the last complete sentence if there is one.
+pub fn truncate_sentences(text: &str, max: usize) -> String {
+  let truncated = truncate(text, max);
+  if truncated.len() == text.len() {
+    return truncated;
+  }
+  match truncated.rfind(|c| ".!?".contains(c)) {
+    Some(idx) => truncated[..=idx].to_string(),
+    None => truncated.trim_end().to_string()
+  }
+}
+
+fn truncate(text: &str, max: usize) -> String {
+  match text.char_indices().nth(max) {
+    Some((idx, _)) => text[..idx].to_string(),
+    None => text.to_string()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_default_pipeline() {
+    let pipeline = TextPipeline::default();
+    assert_eq!(pipeline.apply("When several of\nthese POKéMON\x0cgather,  their\nelectricity"), "When several of these POKéMON gather, their electricity");
+  }
+
+  #[test]
+  fn test_smart_quotes() {
+    let pipeline = TextPipeline::new(vec![ Stage::SmartQuotes ]);
+    assert_eq!(pipeline.apply("It's called \"Pikachu\""), "It’s called “Pikachu”");
+  }
+
+  #[test]
+  fn test_max_length() {
+    let pipeline = TextPipeline::parse("", Some(5)).unwrap();
+    assert_eq!(pipeline.apply("Pokémon"), "Pokém");
+    assert_eq!(pipeline.apply("Poké"), "Poké");
+  }
+
+  #[test]
+  fn test_truncate_sentences() {
+    assert_eq!(truncate_sentences("It keeps its tail raised. It is wary.", 30), "It keeps its tail raised.");
+    assert_eq!(truncate_sentences("It keeps its tail raised.", 30), "It keeps its tail raised.");
+    assert_eq!(truncate_sentences("It keeps its tail raised", 9), "It keeps");
+  }
+
+  #[test]
+  fn test_parse() {
+    let pipeline = TextPipeline::parse("strip_control, smart_quotes", Some(10)).unwrap();
+    assert_eq!(pipeline.stages, vec![ Stage::StripControl, Stage::SmartQuotes, Stage::MaxLength(10) ]);
+    assert!(TextPipeline::parse("uppercase", None).is_err());
+  }
+
+}
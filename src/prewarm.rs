@@ -0,0 +1,90 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tracing::{debug, warn};
+
+use crate::clients::{PokemonClient, Translator};
+use crate::jobs::JobHandle;
+use crate::metrics;
+
+/// Pings an upstream to open or refresh a pooled connection, recording how long it took.
+async fn warm(upstream: &'static str, ping: impl std::future::Future<Output = Result<()>>, job: &JobHandle) {
+  let started = Instant::now();
+  match ping.await {
+    Ok(()) => {
+      let elapsed = started.elapsed();
+      debug!(upstream, elapsed_ms = elapsed.as_millis() as u64, "Upstream connection warmed");
+      metrics::UPSTREAM_PREWARM_DURATION.with_label_values(&[metrics::upstream_label(upstream)]).observe(elapsed.as_secs_f64());
+    },
+    Err(e) => {
+      warn!(upstream, error = %e, "Cannot prewarm upstream connection");
+      job.error(&format!("{}: {}", upstream, e));
+    }
+  }
+}
+
+/// Keeps warm the connections to the upstreams, so that the requests do not pay the DNS, TCP and TLS handshakes.
+///
+/// The upstreams are pinged right away, and then every `interval`, which should be shorter than the
+/// idle timeout of the connection pool for the connections to survive quiet periods.
+/// Every replica pings the PokeAPI, as each one has its own connections to keep warm,
+/// while with leader election only the leader pings the translator, not to spend its quota from every replica.
+pub async fn run(pokemon_client: PokemonClient, translator: Arc<dyn Translator>, interval: Duration, job: JobHandle) {
+  let mut ticker = tokio::time::interval(interval);
+  let mut rounds = 0;
+  loop {
+    ticker.tick().await;
+    let warm_translator = async {
+      if job.is_leader().await {
+        warm(translator.name(), translator.ping(), &job).await;
+      }
+    };
+    futures::join!(
+      warm("pokeapi", pokemon_client.ping(), &job),
+      warm_translator
+    );
+    rounds += 1;
+    job.progress(rounds, None);
+
+    // Only the first round is worth a notification: the connections are warm from then on
+    if rounds == 1 {
+      job.round_finished(None).await;
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use httpmock::{MockServer, Method};
+
+  use crate::clients::ShakespeareClient;
+  use crate::jobs::Jobs;
+
+  #[tokio::test]
+  async fn test_prewarm_pings_upstreams() {
+
+    let server = MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.method(Method::GET);
+      then.status(200);
+    }).await;
+    let pokemon_client = PokemonClient::builder().base_url(&server.base_url()).build().unwrap();
+    let translator: Arc<dyn Translator> = Arc::new(ShakespeareClient::builder().base_url(&server.base_url()).build().unwrap());
+
+    let jobs = Jobs::default();
+    let id = jobs.spawn("prewarm", move |job| async move {
+      run(pokemon_client, translator, Duration::from_millis(20), job).await;
+      Ok(())
+    });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(jobs.cancel(&id).unwrap().done >= 2);
+
+    // Both upstreams are pinged right away, and then again
+    assert!(mock.hits_async().await >= 4);
+    assert!(metrics::UPSTREAM_PREWARM_DURATION.with_label_values(&["pokeapi"]).get_sample_count() >= 2);
+
+  }
+
+}
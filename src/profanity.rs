@@ -0,0 +1,82 @@
+use std::fs;
+
+use anyhow::{Context, Result, anyhow};
+use regex::{Regex, Captures};
+
+use crate::metrics;
+
+/// Word list used when no custom one is provided.
+const DEFAULT_WORDS: &str = include_str!("profanity_words.txt");
+
+/// Masks a list of terms in the translated descriptions, replacing every character with a `*`.
+#[derive(Clone, Debug)]
+pub struct ProfanityFilter {
+  regex: Regex
+}
+
+impl ProfanityFilter {
+
+  /// Creates a new filter masking the terms in the default word list.
+  pub fn with_default_words() -> Result<Self> {
+    ProfanityFilter::from_word_list(DEFAULT_WORDS)
+  }
+
+  /// Creates a new filter loading the word list from the given file.
+  pub fn from_file(path: &str) -> Result<Self> {
+    let words = fs::read_to_string(path)
+      .with_context(|| format!("Cannot read profanity word list {}", path))?;
+    ProfanityFilter::from_word_list(&words)
+  }
+
+  /// Creates a new filter from a word list with one term per line.
+  /// Empty lines and lines starting with `#` are ignored.
+  pub fn from_word_list(words: &str) -> Result<Self> {
+    let words = words.lines()
+      .map(str::trim)
+      .filter(|line| !line.is_empty() && !line.starts_with('#'))
+      .map(regex::escape)
+      .collect::<Vec<_>>();
+    if words.is_empty() {
+      return Err(anyhow!("The profanity word list is empty"));
+    }
+
+    Ok(ProfanityFilter {
+      regex: Regex::new(&format!(r"(?i)\b({})\b", words.join("|")))?
+    })
+  }
+
+  /// Masks all the listed terms in the given text.
+  pub fn mask(&self, text: &str) -> String {
+    let mut masked = 0;
+    let res = self.regex.replace_all(text, |caps: &Captures| {
+      masked += 1;
+      "*".repeat(caps[0].chars().count())
+    });
+    metrics::PROFANITY_MASKED_TERMS.inc_by(masked);
+    res.into_owned()
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_mask() {
+    let filter = ProfanityFilter::from_word_list("# comment\nknave\n\nvillain\n").unwrap();
+    assert_eq!(filter.mask("Thou KNAVE, thou villainous villain!"), "Thou *****, thou villainous *******!");
+  }
+
+  #[test]
+  fn test_default_words() {
+    let filter = ProfanityFilter::with_default_words().unwrap();
+    assert_eq!(filter.mask("Damn this whoreson"), "**** this ********");
+  }
+
+  #[test]
+  fn test_empty_word_list() {
+    assert!(ProfanityFilter::from_word_list("# nothing here\n").is_err());
+  }
+
+}
@@ -0,0 +1,103 @@
+use std::fs;
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::clients::pokemon::PokemonProfile;
+
+/// Templates used when no custom ones are provided.
+const DEFAULT_TEMPLATES: &str = include_str!("prose_templates.txt");
+
+/// Stands for the habitat of the species which have none.
+const UNKNOWN_HABITAT: &str = "wilds unknown";
+
+/// Frames the translated descriptions in a sentence mentioning the habitat and the types of the Pokemon.
+#[derive(Clone, Debug)]
+pub struct ProseTemplates {
+  templates: Vec<String>
+}
+
+impl ProseTemplates {
+
+  /// Creates the built-in templates.
+  pub fn with_default_templates() -> Result<Self> {
+    ProseTemplates::from_template_list(DEFAULT_TEMPLATES)
+  }
+
+  /// Loads the templates from the given file.
+  pub fn from_file(path: &str) -> Result<Self> {
+    let templates = fs::read_to_string(path)
+      .with_context(|| format!("Cannot read prose templates {}", path))?;
+    ProseTemplates::from_template_list(&templates)
+  }
+
+  /// Parses a list with one template per line, each containing the `{description}` placeholder.
+  /// Empty lines and lines starting with `#` are ignored.
+  pub fn from_template_list(templates: &str) -> Result<Self> {
+    let templates = templates.lines()
+      .map(str::trim)
+      .filter(|line| !line.is_empty() && !line.starts_with('#'))
+      .map(str::to_string)
+      .collect::<Vec<_>>();
+    if templates.is_empty() {
+      return Err(anyhow!("The prose template list is empty"));
+    }
+    if let Some(template) = templates.iter().find(|template| !template.contains("{description}")) {
+      return Err(anyhow!("Prose template without a {{description}} placeholder: {}", template));
+    }
+    Ok(ProseTemplates { templates })
+  }
+
+  /// Frames the description of a Pokemon.
+  /// The template is picked by the name, so that the same Pokemon always gets the same framing.
+  pub fn render(&self, name: &str, profile: &PokemonProfile, description: &str) -> String {
+    let index = name.bytes().map(usize::from).sum::<usize>() % self.templates.len();
+    let habitat = profile.habitat.as_deref().map(humanize).unwrap_or_else(|| UNKNOWN_HABITAT.to_string());
+    let types = match profile.types.split_last() {
+      Some((last, [])) => humanize(last),
+      Some((last, rest)) => format!("{} and {}", rest.iter().map(|t| humanize(t)).collect::<Vec<_>>().join(", "), humanize(last)),
+      None => "mystery".to_string()
+    };
+    self.templates[index]
+      .replace("{name}", &capitalize(&humanize(name)))
+      .replace("{habitat}", &habitat)
+      .replace("{types}", &types)
+      .replace("{description}", description)
+  }
+
+}
+
+/// Turns an identifier of the PokeAPI into words, like `rough terrain` for `rough-terrain`.
+fn humanize(id: &str) -> String {
+  id.replace('-', " ")
+}
+
+fn capitalize(s: &str) -> String {
+  let mut chars = s.chars();
+  match chars.next() {
+    Some(first) => first.to_uppercase().chain(chars).collect(),
+    None => String::new()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_render() {
+    let templates = ProseTemplates::from_template_list("# Comment\n\nIn the {habitat} lives {name}, of {types}. {description}\n").unwrap();
+    let profile = PokemonProfile { habitat: Some("rough-terrain".to_string()), genus: None, types: vec!["grass".to_string(), "poison".to_string()] };
+    assert_eq!(templates.render("mr-mime", &profile, "Verily."), "In the rough terrain lives Mr mime, of grass and poison. Verily.");
+
+    let profile = PokemonProfile { habitat: None, genus: None, types: vec!["electric".to_string()] };
+    assert_eq!(templates.render("pikachu", &profile, "Verily."), "In the wilds unknown lives Pikachu, of electric. Verily.");
+  }
+
+  #[test]
+  fn test_invalid_templates() {
+    assert!(ProseTemplates::from_template_list("# Only comments\n").is_err());
+    assert!(ProseTemplates::from_template_list("Hark, {name}!").is_err());
+    assert!(ProseTemplates::with_default_templates().is_ok());
+  }
+
+}
@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use prometheus::{Encoder, TextEncoder};
+use reqwest::Url;
+use tracing::info;
+
+/// Name of the job the metrics are pushed under.
+const JOB: &str = "pokechallenge";
+
+/// Maximum time allowed to the push, so that it cannot hold the shutdown for long.
+const PUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Pushes a snapshot of all the metrics to a Prometheus Pushgateway, replacing the previous one of the same instance.
+pub async fn push(base_url: &Url, instance: Option<&str>) -> Result<()> {
+
+  let mut buffer = Vec::new();
+  TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+
+  // The grouping key of the metrics goes in the path
+  let mut path = format!("metrics/job/{}", JOB);
+  if let Some(instance) = instance {
+    path = format!("{}/instance/{}", path, percent_encoding::utf8_percent_encode(instance, percent_encoding::NON_ALPHANUMERIC));
+  }
+  let url = base_url.join(&path).context("Invalid Pushgateway URL")?;
+
+  let res = reqwest::Client::new()
+    .put(url)
+    .header("content-type", TextEncoder::new().format_type())
+    .timeout(PUSH_TIMEOUT)
+    .body(buffer)
+    .send()
+    .await?;
+  if !res.status().is_success() {
+    return Err(anyhow!("HTTP error: {}", res.status()));
+  }
+
+  info!("Metrics pushed to the Pushgateway");
+  Ok(())
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use httpmock::{MockServer, Method};
+
+  use crate::clients::http::parse_base_url;
+  use crate::metrics;
+
+  #[tokio::test]
+  async fn test_push() {
+
+    metrics::POKEAPI_REQUESTS.inc();
+    let server = MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.method(Method::PUT)
+        .path("/metrics/job/pokechallenge/instance/pod%2D1")
+        .body_contains("pokechallenge_pokeapi_requests");
+      then.status(200);
+    }).await;
+
+    push(&parse_base_url(&server.base_url()).unwrap(), Some("pod-1")).await.unwrap();
+    mock.assert_async().await;
+
+  }
+
+}
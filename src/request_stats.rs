@@ -0,0 +1,98 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+tokio::task_local! {
+  static CURRENT: Arc<Mutex<RequestStats>>;
+}
+
+/// Facts collected while handling a request, reported on its span.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RequestStats {
+  /// Name of the requested Pokemon.
+  pub pokemon: Option<String>,
+  /// Whether the first cache lookup of the request was a hit.
+  pub cache_hit: Option<bool>,
+  /// Translator which produced the returned description.
+  pub translator: Option<String>,
+  /// Number of requests sent to the upstream APIs.
+  pub upstream_requests: u32,
+  /// Time spent looking up the cache.
+  pub cache_time: Duration,
+  /// Time spent waiting for each upstream API.
+  pub upstream_time: BTreeMap<&'static str, Duration>
+}
+
+impl RequestStats {
+
+  /// Formats the time spent in the cache and in each upstream, like `cache=1ms pokeapi=120ms`.
+  pub fn breakdown(&self) -> String {
+    std::iter::once(("cache", self.cache_time))
+      .chain(self.upstream_time.iter().map(|(upstream, time)| (*upstream, *time)))
+      .map(|(name, time)| format!("{}={}ms", name, time.as_millis()))
+      .collect::<Vec<_>>()
+      .join(" ")
+  }
+
+}
+
+/// Updates the stats of the request being handled, if any.
+pub fn record(f: impl FnOnce(&mut RequestStats)) {
+  let _ = CURRENT.try_with(|stats| f(&mut stats.lock().unwrap()));
+}
+
+/// Runs a future collecting the stats recorded while it runs.
+/// Nested collections share the stats of the outer one.
+pub async fn collect<F: Future>(f: F) -> (F::Output, RequestStats) {
+  let (stats, output) = match CURRENT.try_with(|stats| stats.clone()) {
+    Ok(stats) => (stats, f.await),
+    Err(_) => {
+      let stats = Arc::new(Mutex::new(RequestStats::default()));
+      (stats.clone(), CURRENT.scope(stats, f).await)
+    }
+  };
+  let stats = stats.lock().unwrap().clone();
+  (output, stats)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_collect() {
+
+    // Recording outside of a request is a no-op
+    record(|stats| stats.upstream_requests += 1);
+
+    let (output, stats) = collect(async {
+      record(|stats| stats.upstream_requests += 1);
+      record(|stats| stats.upstream_requests += 1);
+      record(|stats| { stats.cache_hit.get_or_insert(false); });
+      record(|stats| { stats.cache_hit.get_or_insert(true); });
+      record(|stats| *stats.upstream_time.entry("pokeapi").or_default() += Duration::from_millis(20));
+      record(|stats| *stats.upstream_time.entry("pokeapi").or_default() += Duration::from_millis(22));
+      42
+    }).await;
+
+    assert_eq!(output, 42);
+    assert_eq!(stats, RequestStats {
+      pokemon: None,
+      cache_hit: Some(false),
+      translator: None,
+      upstream_requests: 2,
+      cache_time: Duration::ZERO,
+      upstream_time: vec![("pokeapi", Duration::from_millis(42))].into_iter().collect()
+    });
+    assert_eq!(stats.breakdown(), "cache=0ms pokeapi=42ms");
+
+    // The stats recorded by a nested collection reach the outer one
+    let (_, stats) = collect(async {
+      collect(async { record(|stats| stats.upstream_requests += 1) }).await
+    }).await;
+    assert_eq!(stats.upstream_requests, 1);
+
+  }
+
+}
@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use warp::http::header::CONTENT_TYPE;
+use warp::hyper::Body;
+use warp::{Rejection, Reply};
+
+use crate::cache::Keyspace;
+use crate::jobs::Jobs;
+use crate::routes::State;
+use crate::metrics;
+use crate::routes::errors::{BadRequest, CustomRejection, Unauthorized};
+use crate::routes::translate::find_key;
+
+/// Number of entries of each keyspace copied by `POST /admin/cache/rehydrate`, unless asked otherwise.
+const DEFAULT_REHYDRATE_LIMIT: usize = 100;
+
+/// API keys accepted by the admin routes, separate from the ones of the `POST /translate` consumers.
+pub struct AdminAuth {
+  keys: Vec<(String, ())>
+}
+
+impl AdminAuth {
+
+  /// Accepts the given keys.
+  pub fn new(keys: &[String]) -> Self {
+    AdminAuth {
+      keys: keys.iter().map(|key| (key.clone(), ())).collect()
+    }
+  }
+
+  /// Checks the `Authorization: Bearer <key>` header.
+  pub fn authorize(&self, authorization: Option<&str>) -> std::result::Result<(), Rejection> {
+    authorization
+      .and_then(|header| header.strip_prefix("Bearer "))
+      .and_then(|key| find_key(&self.keys, key.trim()))
+      .ok_or_else(|| {
+        metrics::REJECTED_REQUESTS.with_label_values(&["unauthorized"]).inc();
+        warp::reject::custom(Unauthorized)
+      })
+      .map(|_| ())
+  }
+
+}
+
+/// A line of the `GET /admin/pokedex` dump.
+#[derive(Serialize)]
+struct PokedexLine {
+  name: String,
+  description: String,
+  translator: Option<String>,
+  /// Age of the cached translation, in seconds.
+  age: u64
+}
+
+/// Handler for the `GET /admin/pokedex` route.
+/// Streams all the translations held in the local cache as JSON lines, from the most recently used.
+pub async fn handle_pokedex(state: State) -> std::result::Result<impl Reply, Rejection> {
+
+  let lines = state.cache.local_entries(Keyspace::Translations)
+    .into_iter()
+    .filter_map(|(key, entry)| {
+      // The translations of the secondary translators are keyed by the name and the translator, like `pikachu@openai`
+      let line = PokedexLine {
+        name: key.split_once('@').map(|(name, _)| name.to_string()).unwrap_or(key),
+        age: entry.age().as_secs(),
+        description: entry.text,
+        translator: entry.translator
+      };
+      serde_json::to_string(&line).ok().map(|line| Ok::<_, Infallible>(line + "\n"))
+    });
+
+  let body = Body::wrap_stream(futures::stream::iter(lines));
+  Ok(warp::reply::with_header(warp::reply::Response::new(body), CONTENT_TYPE, "application/x-ndjson"))
+
+}
+
+/// Handler for the `GET /admin/jobs/{id}` route.
+pub async fn handle_job(id: String, jobs: Arc<Jobs>) -> std::result::Result<impl Reply, Rejection> {
+  let report = jobs.get(&id).ok_or_else(warp::reject::not_found)?;
+  Ok(warp::reply::json(&report))
+}
+
+/// Handler for the `DELETE /admin/jobs/{id}` route.
+/// Cancelling a job which already ended is a no-op, and returns its final state.
+pub async fn handle_cancel_job(id: String, jobs: Arc<Jobs>) -> std::result::Result<impl Reply, Rejection> {
+  let report = jobs.cancel(&id).ok_or_else(warp::reject::not_found)?;
+  info!(job = %id, status = ?report.status, "Job cancelled");
+  Ok(warp::reply::json(&report))
+}
+
+/// Body of the `PUT /admin/degraded-mode` route.
+#[derive(Deserialize, Serialize)]
+pub struct DegradedModeRequest {
+  enabled: bool
+}
+
+/// Handler for the `PUT /admin/degraded-mode` route.
+pub async fn handle_degraded_mode(request: DegradedModeRequest, state: State) -> std::result::Result<impl Reply, Rejection> {
+  state.degraded_mode.set(request.enabled, &state.cache).await
+    .map_err(|e| CustomRejection::new(e))?;
+  info!(enabled = request.enabled, "Degraded mode switched");
+  Ok(warp::reply::json(&request))
+}
+
+/// Query parameters accepted by the `POST /admin/cache/rehydrate` route.
+#[derive(Deserialize)]
+pub struct RehydrateQuery {
+  limit: Option<usize>
+}
+
+#[derive(Serialize)]
+struct RehydrateResponse {
+  /// Number of entries copied for each keyspace.
+  rehydrated: BTreeMap<&'static str, usize>
+}
+
+/// Handler for the `POST /admin/cache/rehydrate` route.
+/// Copies the most recent entries of the shared cache to the local one.
+pub async fn handle_rehydrate(query: RehydrateQuery, state: State) -> std::result::Result<impl Reply, Rejection> {
+
+  if state.cache.shared().is_none() {
+    return Err(warp::reject::custom(BadRequest("No shared cache configured")));
+  }
+  let copied = state.cache.rehydrate(query.limit.unwrap_or(DEFAULT_REHYDRATE_LIMIT)).await
+    .map_err(|e| CustomRejection::new(e))?;
+
+  Ok(warp::reply::json(&RehydrateResponse {
+    rehydrated: copied.into_iter().map(|(keyspace, count)| (keyspace.name(), count)).collect()
+  }))
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::sync::Arc;
+  use std::time::Duration;
+  use async_trait::async_trait;
+  use crate::cache::{Cache, CacheBackend, CacheEntry, Discarded};
+  use crate::cache::memory::MemoryCache;
+  use crate::clients::{PokemonClient, ShakespeareClient, Translators};
+  use crate::pipeline::TextPipeline;
+  use crate::prose::ProseTemplates;
+
+  fn build_state(cache: Cache) -> State {
+    State {
+      pokemon_client: PokemonClient::builder().base_url("http://localhost/").build().unwrap(),
+      translators: Translators::new(Arc::new(ShakespeareClient::builder().base_url("http://localhost/").build().unwrap())),
+      cache: Arc::new(cache),
+      text_pipeline: TextPipeline::default(),
+      profanity_filter: None,
+      prose_templates: ProseTemplates::with_default_templates().unwrap(),
+      untranslated_fallback: false,
+      translate_genus: false,
+      degraded_mode: Default::default()
+    }
+  }
+
+  /// Shared backend holding a couple of translations.
+  struct FakeShared;
+
+  #[async_trait]
+  impl CacheBackend for FakeShared {
+    fn name(&self) -> &'static str {
+      "fake"
+    }
+    async fn get(&self, _keyspace: Keyspace, _key: &str) -> anyhow::Result<Option<CacheEntry>> {
+      Ok(None)
+    }
+    async fn put(&self, _keyspace: Keyspace, _key: &str, _entry: &CacheEntry, _ttl: Option<Duration>) -> anyhow::Result<()> {
+      Ok(())
+    }
+    async fn ping(&self) -> anyhow::Result<()> {
+      Ok(())
+    }
+    async fn recent(&self, keyspace: Keyspace, limit: usize) -> anyhow::Result<(Vec<(String, CacheEntry)>, Discarded)> {
+      let entries = match keyspace {
+        Keyspace::Translations => vec![
+          ("pikachu".to_string(), CacheEntry::translated("shakespeare", "en", "Translated".to_string())),
+          ("ditto".to_string(), CacheEntry::translated("shakespeare", "en", "Translated".to_string()))
+        ],
+        _ => Vec::new()
+      };
+      Ok((entries.into_iter().take(limit).collect(), Discarded::default()))
+    }
+  }
+
+  #[tokio::test]
+  async fn test_pokedex_dump() {
+
+    let state = build_state(Cache::new(MemoryCache::new(2, 2)));
+    state.cache.put(Keyspace::Translations, "pikachu".to_string(), CacheEntry::translated("shakespeare", "en", "Translated".to_string())).await;
+
+    let res = handle_pokedex(state).await.unwrap().into_response();
+    assert_eq!(res.headers()[CONTENT_TYPE], "application/x-ndjson");
+    let body = warp::hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let line: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(line["name"], "pikachu");
+    assert_eq!(line["description"], "Translated");
+
+  }
+
+  #[tokio::test]
+  async fn test_pokedex_requires_credentials() {
+
+    let state = build_state(Cache::new(MemoryCache::new(2, 2)));
+    let auth = Arc::new(AdminAuth::new(&["secret".to_string()]));
+    let route = crate::routes::pokedex_route(state.clone(), Some(auth));
+    let err = warp::test::request().path("/admin/pokedex").filter(&route).await.err().unwrap();
+    assert!(err.find::<Unauthorized>().is_some());
+    let res = warp::test::request().path("/admin/pokedex").header("authorization", "Bearer secret").reply(&route).await;
+    assert_eq!(res.status(), 200);
+
+    // Without any admin key, the route does not exist
+    let route = crate::routes::pokedex_route(state, None);
+    assert!(warp::test::request().path("/admin/pokedex").filter(&route).await.err().unwrap().is_not_found());
+
+  }
+
+  #[tokio::test]
+  async fn test_rehydrate() {
+
+    let state = build_state(Cache::new(MemoryCache::new(2, 2)).with_shared(Arc::new(FakeShared)));
+    let res = handle_rehydrate(RehydrateQuery { limit: Some(1) }, state.clone()).await.unwrap().into_response();
+    let body: serde_json::Value = serde_json::from_slice(&warp::hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["rehydrated"]["translations"], 1);
+    assert_eq!(state.cache.local_entries(Keyspace::Translations)[0].0, "pikachu");
+
+    // Nothing to rehydrate from without a shared cache
+    let state = build_state(Cache::new(MemoryCache::new(2, 2)));
+    assert!(handle_rehydrate(RehydrateQuery { limit: None }, state).await.is_err());
+
+  }
+
+}
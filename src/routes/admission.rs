@@ -0,0 +1,178 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use warp::{Filter, Rejection};
+
+use crate::metrics;
+use crate::routes::errors::Overloaded;
+
+/// Limits of the requests served at the same time.
+#[derive(Clone, Copy, Debug)]
+pub struct AdmissionLimits {
+  /// Maximum number of requests served at the same time.
+  pub max_concurrent: usize,
+  /// Maximum number of requests waiting for one of the others to complete.
+  pub queue_size: usize,
+  /// Maximum time a request waits in the queue before being rejected.
+  pub max_wait: Duration
+}
+
+/// A request waiting in the queue, which leaves it when dropped, even if the request is cancelled while waiting.
+struct Queued<'a> {
+  queued: &'a AtomicUsize,
+  started: Instant
+}
+
+impl<'a> Queued<'a> {
+
+  /// Enters the queue, unless it is full.
+  fn enter(queued: &'a AtomicUsize, queue_size: usize) -> Option<Self> {
+    if queued.fetch_add(1, Ordering::SeqCst) >= queue_size {
+      queued.fetch_sub(1, Ordering::SeqCst);
+      return None;
+    }
+    metrics::REQUEST_QUEUE_DEPTH.inc();
+    Some(Queued { queued, started: Instant::now() })
+  }
+
+}
+
+impl Drop for Queued<'_> {
+  fn drop(&mut self) {
+    self.queued.fetch_sub(1, Ordering::SeqCst);
+    metrics::REQUEST_QUEUE_DEPTH.dec();
+    metrics::REQUEST_QUEUE_WAIT.observe(self.started.elapsed().as_secs_f64());
+  }
+}
+
+/// Admits the requests up to the concurrency limit, queueing the ones over it for a short while
+/// so that short bursts are smoothed out instead of rejected.
+pub struct Admission {
+  limits: AdmissionLimits,
+  permits: Arc<Semaphore>,
+  queued: AtomicUsize
+}
+
+impl Admission {
+
+  pub fn new(limits: AdmissionLimits) -> Self {
+    Admission {
+      limits,
+      permits: Arc::new(Semaphore::new(limits.max_concurrent)),
+      queued: AtomicUsize::new(0)
+    }
+  }
+
+  /// Waits for a slot to serve a request, which is released when the returned permit is dropped.
+  /// Fails when the queue is full, or when no slot frees up in time.
+  pub async fn admit(&self) -> Result<OwnedSemaphorePermit, Overloaded> {
+
+    // Most of the time there is no need to wait
+    if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+      return Ok(permit);
+    }
+
+    let queued = match Queued::enter(&self.queued, self.limits.queue_size) {
+      Some(queued) => queued,
+      None => {
+        metrics::SHED_REQUESTS.with_label_values(&["queue_full"]).inc();
+        return Err(Overloaded);
+      }
+    };
+    let res = tokio::time::timeout(self.limits.max_wait, self.permits.clone().acquire_owned()).await;
+    drop(queued);
+
+    match res {
+      Ok(Ok(permit)) => Ok(permit),
+      // The semaphore is never closed, so the only failure is the timeout
+      _ => {
+        metrics::SHED_REQUESTS.with_label_values(&["timeout"]).inc();
+        Err(Overloaded)
+      }
+    }
+
+  }
+
+}
+
+/// Filter holding a slot for the rest of the request, if the concurrency is limited.
+pub fn filter(admission: Option<Arc<Admission>>) -> impl Filter<Extract = (Option<OwnedSemaphorePermit>,), Error = Rejection> + Clone {
+  warp::any().and_then(move || {
+    let admission = admission.clone();
+    async move {
+      match admission {
+        Some(admission) => admission.admit().await.map(Some).map_err(warp::reject::custom),
+        None => Ok(None)
+      }
+    }
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_bounded_queue() {
+
+    let admission = Arc::new(Admission::new(AdmissionLimits {
+      max_concurrent: 1,
+      queue_size: 1,
+      max_wait: Duration::from_millis(500)
+    }));
+    let first = admission.admit().await.unwrap();
+
+    // The second request waits for the first one, while the third does not fit in the queue
+    let queued = tokio::spawn({
+      let admission = admission.clone();
+      async move { admission.admit().await.is_ok() }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(admission.admit().await.is_err());
+
+    drop(first);
+    assert!(queued.await.unwrap());
+
+  }
+
+  #[tokio::test]
+  async fn test_max_wait() {
+
+    let admission = Admission::new(AdmissionLimits {
+      max_concurrent: 1,
+      queue_size: 8,
+      max_wait: Duration::from_millis(20)
+    });
+    let _first = admission.admit().await.unwrap();
+    assert!(admission.admit().await.is_err());
+
+    // The request which timed out left the queue
+    assert_eq!(admission.queued.load(Ordering::SeqCst), 0);
+
+  }
+
+  #[tokio::test]
+  async fn test_cancelled_while_queued() {
+
+    let admission = Admission::new(AdmissionLimits {
+      max_concurrent: 1,
+      queue_size: 1,
+      max_wait: Duration::from_secs(10)
+    });
+    let first = admission.admit().await.unwrap();
+
+    // A client giving up while queued leaves room for the next one
+    assert!(tokio::time::timeout(Duration::from_millis(20), admission.admit()).await.is_err());
+    assert_eq!(admission.queued.load(Ordering::SeqCst), 0);
+    let release = async move {
+      tokio::time::sleep(Duration::from_millis(20)).await;
+      drop(first);
+    };
+    let (next, ()) = futures::join!(admission.admit(), release);
+    assert!(next.is_ok());
+
+  }
+
+}
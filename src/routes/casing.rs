@@ -0,0 +1,123 @@
+use std::convert::Infallible;
+
+use anyhow::anyhow;
+use serde_json::Value;
+use warp::path::FullPath;
+use warp::reply::Response;
+use warp::Reply;
+
+use crate::routes::json;
+
+/// Casing of the field names of the JSON responses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Casing {
+  /// Field names as they are defined, like `original_description`.
+  #[default]
+  Snake,
+  /// Field names like `originalDescription`.
+  Camel
+}
+
+impl std::str::FromStr for Casing {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> anyhow::Result<Self> {
+    match s {
+      "snake" | "snake_case" => Ok(Casing::Snake),
+      "camel" | "camelCase" => Ok(Casing::Camel),
+      other => Err(anyhow!("Unknown field casing: {}", other))
+    }
+  }
+}
+
+fn camel_case(name: &str) -> String {
+  let mut result = String::with_capacity(name.len());
+  let mut upper = false;
+  for c in name.chars() {
+    if c == '_' && !result.is_empty() {
+      upper = true;
+    } else if upper {
+      result.extend(c.to_uppercase());
+      upper = false;
+    } else {
+      result.push(c);
+    }
+  }
+  result
+}
+
+/// Fields holding maps keyed by data, like the names of the stats, whose keys are not field names.
+const DATA_MAPS: &[&str] = &["stats", "rehydrated"];
+
+/// Paths serving documents whose keys are not fields of the API, like the schemas.
+fn is_excluded(path: &str) -> bool {
+  path == "/openapi.json" || path.starts_with("/schemas/")
+}
+
+/// Renames all the fields of the objects in the value, leaving the keys of the data maps untouched.
+fn rename_fields(value: Value, rename: &impl Fn(&str) -> String) -> Value {
+  match value {
+    Value::Object(fields) => Value::Object(
+      fields.into_iter()
+        .map(|(name, value)| {
+          let value = if DATA_MAPS.contains(&name.as_str()) { value } else { rename_fields(value, rename) };
+          (rename(&name), value)
+        })
+        .collect()
+    ),
+    Value::Array(items) => Value::Array(items.into_iter().map(|item| rename_fields(item, rename)).collect()),
+    other => other
+  }
+}
+
+/// Renames the fields of the JSON body of a reply to the given casing.
+/// Replies which are not JSON, the OpenAPI document and the schemas are returned untouched.
+pub async fn apply(casing: Casing, path: FullPath, reply: impl Reply) -> Result<Response, Infallible> {
+  let res = reply.into_response();
+  match casing {
+    Casing::Camel if json::is_json(&res) && !is_excluded(path.as_str()) => Ok(json::map_body(res, |_, body| rename_fields(body, &camel_case)).await),
+    _ => Ok(res)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+  use warp::Filter;
+
+  #[test]
+  fn test_camel_case() {
+    assert_eq!(camel_case("original_description"), "originalDescription");
+    assert_eq!(camel_case("name"), "name");
+    assert_eq!(camel_case("_private"), "_private");
+    assert_eq!(camel_case("special-attack"), "special-attack");
+  }
+
+  async fn apply_to(path: &'static str, body: Value) -> Value {
+    let route = warp::path::full().and_then(move |path| apply(Casing::Camel, path, warp::reply::json(&body)));
+    let res = warp::test::request().path(path).reply(&route).await;
+    serde_json::from_slice(res.body()).unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_apply() {
+    let body = apply_to("/pokemon/mewtwo", json!({ "original_description": "Hi", "pokemons": [{ "latency_ms": 1 }] })).await;
+    assert_eq!(body, json!({ "originalDescription": "Hi", "pokemons": [{ "latencyMs": 1 }] }));
+  }
+
+  #[tokio::test]
+  async fn test_data_maps_and_documents() {
+
+    // The keys of the data maps are not renamed
+    let body = apply_to("/compare", json!({ "pokemons": [{ "stats": { "special_attack": 1 } }] })).await;
+    assert_eq!(body, json!({ "pokemons": [{ "stats": { "special_attack": 1 } }] }));
+
+    // Neither are the fields of the documents describing the API
+    let schema = json!({ "properties": { "original_description": { "type": "string" } } });
+    assert_eq!(apply_to("/schemas/pokemon.json", schema.clone()).await, schema);
+    assert_eq!(apply_to("/openapi.json", schema.clone()).await, schema);
+
+  }
+
+}
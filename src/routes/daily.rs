@@ -0,0 +1,212 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+use warp::Rejection;
+
+use crate::cache::SharedError;
+use crate::clients::budget;
+use crate::jobs::JobHandle;
+use crate::request_stats;
+use crate::routes::State;
+use crate::routes::errors::CustomRejection;
+use crate::routes::pokemons::get_translation;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Serialize, JsonSchema)]
+pub struct DailyPokemonResponse {
+  /// UTC date, formatted as `YYYY-MM-DD`.
+  date: String,
+  name: String,
+  description: String
+}
+
+/// Keeps the Pokemon of the day, selected deterministically from the current UTC date,
+/// so that all the replicas agree on it without coordination.
+#[derive(Default)]
+pub struct DailyPokemon {
+  /// The current pick, together with the day (since the Unix epoch) it was made for.
+  current: Mutex<Option<(u64, DailyPokemonResponse)>>
+}
+
+impl DailyPokemon {
+
+  pub fn new() -> Self {
+    DailyPokemon { current: Mutex::new(None) }
+  }
+
+  /// Returns the Pokemon of the current UTC day, selecting and translating it if needed.
+  pub async fn get(&self, state: &State) -> std::result::Result<DailyPokemonResponse, SharedError> {
+    self.get_for(today(), state).await
+  }
+
+  pub async fn get_for(&self, day: u64, state: &State) -> std::result::Result<DailyPokemonResponse, SharedError> {
+
+    // Hold the lock during the selection, so that concurrent requests do not select it twice
+    let mut current = self.current.lock().await;
+    if let Some((current_day, pokemon)) = &*current {
+      if *current_day == day {
+        return Ok(pokemon.clone());
+      }
+    }
+
+    // Pick a species by hashing the date over the number of species
+    let date = format_date(day);
+    let (count, _) = state.pokemon_client.get_species_at(0).await?;
+    if count == 0 {
+      return Err(Arc::new(anyhow!("The Pokemon API does not list any species")));
+    }
+    let name = state.pokemon_client.get_species_at(fnv1a(&date) % count).await?.1
+      .ok_or_else(|| anyhow!("Cannot find the Pokemon of the day"))?;
+
+    // The translation goes through the cache like any other
+    let translated = get_translation(&name, &**state.translators.primary(), state).await?
+      .ok_or_else(|| anyhow!("Cannot find the description of the Pokemon of the day"))?;
+
+    let pokemon = DailyPokemonResponse { date, name, description: translated.text };
+    *current = Some((day, pokemon.clone()));
+    Ok(pokemon)
+
+  }
+
+  /// Selects the Pokemon of the day right after every UTC midnight, so that the first requests of the day are fast.
+  /// As a singleton job, it only runs on the leader: the other replicas find its translation in the shared cache.
+  pub async fn pregenerate(self: Arc<Self>, state: State, job: JobHandle) {
+    let mut days = 0;
+    loop {
+      let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+      tokio::time::sleep(Duration::from_secs(SECONDS_PER_DAY - now % SECONDS_PER_DAY)).await;
+      days += 1;
+      job.progress(days, None);
+      if !job.is_leader().await {
+        continue;
+      }
+      match budget::background(self.get(&state)).await {
+        Ok(pokemon) => {
+          info!(pokemon = %pokemon.name, "Selected the Pokemon of the day");
+          job.round_finished(None).await;
+        },
+        Err(e) => {
+          warn!(error = %e, "Cannot pre-generate the Pokemon of the day");
+          job.error(&e);
+          job.round_finished(Some(e.to_string())).await;
+        }
+      }
+    }
+  }
+
+}
+
+/// Handler for the `GET /pokemon/daily` route.
+pub async fn handle_daily(daily: Arc<DailyPokemon>, state: State) -> std::result::Result<DailyPokemonResponse, Rejection> {
+  let pokemon = daily.get(&state).await.map_err(|e| CustomRejection::shared(e))?;
+  request_stats::record(|stats| stats.pokemon = Some(pokemon.name.clone()));
+  Ok(pokemon)
+}
+
+/// Returns the current UTC day, as the number of days since the Unix epoch.
+fn today() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / SECONDS_PER_DAY
+}
+
+/// Formats a number of days since the Unix epoch as a `YYYY-MM-DD` date.
+fn format_date(day: u64) -> String {
+
+  // Days to civil date conversion, working in 400 years eras starting on March 1st
+  let z = day as i64 + 719_468;
+  let era = z.div_euclid(146_097);
+  let doe = z.rem_euclid(146_097);
+  let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let d = doy - (153 * mp + 2) / 5 + 1;
+  let m = if mp < 10 { mp + 3 } else { mp - 9 };
+  let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+
+  format!("{:04}-{:02}-{:02}", y, m, d)
+
+}
+
+/// 64 bit FNV-1a hash, stable across builds and platforms unlike the std hasher.
+fn fnv1a(s: &str) -> u64 {
+  s.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, b| (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::cache::Cache;
+  use crate::cache::memory::MemoryCache;
+  use crate::clients::{PokemonClient, ShakespeareClient, Translators};
+  use crate::pipeline::TextPipeline;
+  use crate::prose::ProseTemplates;
+  use httpmock::{MockServer, Method};
+  use regex::Regex;
+  use serde_json::json;
+
+  #[test]
+  fn test_format_date() {
+    assert_eq!(format_date(0), "1970-01-01");
+    assert_eq!(format_date(11_016), "2000-02-29");
+    assert_eq!(format_date(20_740), "2026-10-14");
+  }
+
+  #[tokio::test]
+  async fn test_daily_pokemon() {
+
+    let server = MockServer::start_async().await;
+    let list_mock = server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon-species/");
+      then.status(200)
+        .json_body(json!({ "count": 1, "results": [{ "name": "ditto" }] }));
+    }).await;
+    let species_mock = server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path_matches(Regex::new("^/pokemon-species/ditto$").unwrap());
+      then.status(200)
+        .json_body(json!({ "flavor_text_entries": [{ "flavor_text": "Copies.", "language": { "name": "en" } }] }));
+    }).await;
+    let translate_mock = server.mock_async(|when, then| {
+      when.method(Method::POST)
+        .path("/translate/shakespeare.json");
+      then.status(200)
+        .json_body(json!({ "contents": { "translated": "Copies, forsooth.", "text": "Copies." } }));
+    }).await;
+    let state = State {
+      pokemon_client: PokemonClient::builder().base_url(&server.base_url()).build().unwrap(),
+      translators: Translators::new(Arc::new(ShakespeareClient::builder().base_url(&server.base_url()).build().unwrap())),
+      cache: Arc::new(Cache::new(MemoryCache::new(2, 2))),
+      text_pipeline: TextPipeline::default(),
+      profanity_filter: None,
+      prose_templates: ProseTemplates::with_default_templates().unwrap(),
+      untranslated_fallback: false,
+      translate_genus: false,
+      degraded_mode: Default::default()
+    };
+
+    let daily = DailyPokemon::new();
+    let pokemon = daily.get_for(20_740, &state).await.unwrap();
+    assert_eq!(pokemon.date, "2026-10-14");
+    assert_eq!(pokemon.name, "ditto");
+    assert_eq!(pokemon.description, "Copies, forsooth.");
+    list_mock.assert_hits(2);
+
+    // The pick is kept for the whole day
+    daily.get_for(20_740, &state).await.unwrap();
+    list_mock.assert_hits(2);
+
+    // The next day a new pick is made, reusing the cached translations
+    assert_eq!(daily.get_for(20_741, &state).await.unwrap().date, "2026-10-15");
+    list_mock.assert_hits(4);
+    species_mock.assert_hits(1);
+    translate_mock.assert_hits(1);
+
+  }
+
+}
@@ -0,0 +1,88 @@
+use std::convert::Infallible;
+
+use serde_json::json;
+use warp::http::header::VARY;
+use warp::http::HeaderValue;
+use warp::reply::Response;
+use warp::Reply;
+
+use crate::routes::json;
+
+/// Header the clients can use to ask for, or opt out of, the envelope.
+pub const ENVELOPE_HEADER: &str = "x-envelope";
+
+/// Returns whether the response should be enveloped, given the configured default and the header of the request.
+pub fn enabled(default: bool, header: Option<String>) -> bool {
+  match header.as_deref().map(str::trim) {
+    Some("true") | Some("1") => true,
+    Some("false") | Some("0") => false,
+    _ => default
+  }
+}
+
+/// Wraps the JSON body of a reply in a `{ "data": ..., "error": ..., "meta": { "status": ... } }` envelope,
+/// the original body going in `data` for successful responses, and in `error` for the others.
+/// Replies which are not JSON are returned untouched.
+pub async fn wrap(enabled: bool, reply: impl Reply) -> Result<Response, Infallible> {
+
+  let mut res = reply.into_response();
+  if !json::is_json(&res) {
+    return Ok(res);
+  }
+
+  // The same url can be served with and without the envelope
+  res.headers_mut().append(VARY, HeaderValue::from_static(ENVELOPE_HEADER));
+  if !enabled {
+    return Ok(res);
+  }
+
+  Ok(json::map_body(res, |status, payload| {
+    let meta = json!({ "status": status.as_u16() });
+    if status.is_success() {
+      json!({ "data": payload, "error": null, "meta": meta })
+    } else {
+      json!({ "data": null, "error": payload, "meta": meta })
+    }
+  }).await)
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::Value;
+  use warp::http::StatusCode;
+
+  async fn body(res: Response) -> Value {
+    serde_json::from_slice(&warp::hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap()
+  }
+
+  #[test]
+  fn test_enabled() {
+    assert!(enabled(true, None));
+    assert!(!enabled(true, Some("false".to_string())));
+    assert!(enabled(false, Some("1".to_string())));
+    assert!(!enabled(false, Some("maybe".to_string())));
+  }
+
+  #[tokio::test]
+  async fn test_wrap() {
+
+    let res = wrap(true, warp::reply::json(&json!({ "name": "pikachu" }))).await.unwrap();
+    assert_eq!(res.headers()[VARY], ENVELOPE_HEADER);
+    assert_eq!(body(res).await, json!({ "data": { "name": "pikachu" }, "error": null, "meta": { "status": 200 } }));
+
+    let error = warp::reply::with_status(warp::reply::json(&json!({ "message": "Not Found" })), StatusCode::NOT_FOUND);
+    let res = wrap(true, error).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert_eq!(body(res).await, json!({ "data": null, "error": { "message": "Not Found" }, "meta": { "status": 404 } }));
+
+    // Disabled envelopes and other content types are left alone
+    let res = wrap(false, warp::reply::json(&json!({ "name": "pikachu" }))).await.unwrap();
+    assert_eq!(body(res).await, json!({ "name": "pikachu" }));
+    let res = wrap(true, warp::reply::html("<p>Hi</p>")).await.unwrap();
+    assert!(res.headers().get(VARY).is_none());
+
+  }
+
+}
@@ -1,52 +1,485 @@
 use std::convert::Infallible;
+use std::panic::Location;
+use std::sync::Arc;
 
-use serde_json::json;
+use schemars::JsonSchema;
+use serde::Serialize;
 use tracing::error;
+use warp::http::header::CONTENT_TYPE;
 use warp::{http::StatusCode, Rejection, Reply};
 
+use crate::cache::singleflight::TooManyWaiters;
+use crate::clients::breaker::BreakerOpen;
+use crate::clients::http::is_connection_error;
+use crate::health::DegradedModeEnabled;
+use crate::log_sampling::{Decision, LogSampler};
+use crate::metrics;
+use crate::routes::json::PROBLEM_JSON;
+
 /// Wrapper for an [`anyhow::Error`](anyhow::Error) to make it play nice with warp's rejections.
+/// The location where the error has been turned into a rejection is kept to fingerprint it.
 #[derive(Debug)]
-pub struct CustomRejection(anyhow::Error);
+pub struct CustomRejection(Arc<anyhow::Error>, &'static Location<'static>);
 impl warp::reject::Reject for CustomRejection {}
 
 impl CustomRejection {
+  #[track_caller]
   pub fn new(inner: anyhow::Error) -> Self {
-    CustomRejection(inner)
+    CustomRejection(Arc::new(inner), Location::caller())
+  }
+
+  /// Wraps an error shared among multiple requests.
+  #[track_caller]
+  pub fn shared(inner: Arc<anyhow::Error>) -> Self {
+    CustomRejection(inner, Location::caller())
+  }
+}
+
+/// Rejection for names which do not match any Pokemon, answered with a `404 Not Found`
+/// told apart from the one of the unknown routes.
+#[derive(Debug)]
+pub struct PokemonNotFound {
+  pub name: String,
+  /// Known names close to the requested one.
+  pub suggestions: Vec<String>
+}
+impl warp::reject::Reject for PokemonNotFound {}
+
+/// Rejection for the paths not served by any route, answered with a `404 Not Found` echoing the path.
+#[derive(Debug)]
+pub struct UnknownRoute(pub String);
+impl warp::reject::Reject for UnknownRoute {}
+
+/// Where the routes of the API are described.
+pub const OPENAPI_PATH: &str = "/openapi.json";
+
+/// Maximum length of the paths echoed in the responses.
+const MAX_ECHOED_PATH: usize = 128;
+
+/// Rejection for requests which are malformed, answered with a `400 Bad Request` and the given message.
+#[derive(Debug)]
+pub struct BadRequest(pub &'static str);
+impl warp::reject::Reject for BadRequest {}
+
+/// Rejection for Pokemon names longer than `MAX_NAME_LENGTH`, answered with a `400 Bad Request`.
+/// It is counted by the rejection handler, as many routes may reject the same request.
+#[derive(Debug)]
+pub struct NameTooLong;
+impl warp::reject::Reject for NameTooLong {}
+
+/// Rejection for requests without valid credentials, answered with a `401 Unauthorized`.
+#[derive(Debug)]
+pub struct Unauthorized;
+impl warp::reject::Reject for Unauthorized {}
+
+/// Rejection for clients which exceeded their quota, answered with a `429 Too Many Requests`.
+#[derive(Debug)]
+pub struct TooManyRequests;
+impl warp::reject::Reject for TooManyRequests {}
+
+/// Rejection for texts longer than the translator accepts, answered with a `422 Unprocessable Entity`.
+#[derive(Debug)]
+pub struct TextTooLong;
+impl warp::reject::Reject for TextTooLong {}
+
+/// Rejection for requests which could not get a slot under the concurrency limit in time,
+/// answered with a `503 Service Unavailable`.
+#[derive(Debug)]
+pub struct Overloaded;
+impl warp::reject::Reject for Overloaded {}
+
+/// Rejection for requests not handled within the timeout of their route, answered with a `504 Gateway Timeout`.
+#[derive(Debug)]
+pub struct TimedOut;
+impl warp::reject::Reject for TimedOut {}
+
+/// Rejection for idempotency keys reused for a different request, answered with a `422 Unprocessable Entity`.
+#[derive(Debug)]
+pub struct IdempotencyConflict;
+impl warp::reject::Reject for IdempotencyConflict {}
+
+/// Body of the error responses.
+#[derive(Serialize, JsonSchema)]
+pub struct ErrorBody {
+  message: &'static str,
+  /// Machine readable kind of the problem, only for the errors which need to be told apart.
+  #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+  problem: Option<&'static str>,
+  /// Fingerprint of the server errors, matching the `error_id` of their logs.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error_id: Option<String>,
+  /// Name of the Pokemon which was not found.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  name: Option<String>,
+  /// Known names close to the one of the Pokemon which was not found.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  suggestions: Option<Vec<String>>,
+  /// Path which is not served by any route.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  path: Option<String>,
+  /// Path of the OpenAPI document listing the routes, for the unknown paths.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  spec: Option<&'static str>
+}
+
+/// Path safe to be echoed: without control characters, and truncated.
+fn sanitize_path(path: &str) -> String {
+  path.chars().filter(|c| !c.is_control()).take(MAX_ECHOED_PATH).collect()
+}
+
+/// Prefix of the unknown paths counted by the metrics: their first segment, if it looks like one of ours.
+fn path_prefix(path: &str) -> String {
+  let segment = path.trim_start_matches('/').split('/').next().unwrap_or_default();
+  let valid = segment.len() <= 32 && segment.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+  match segment {
+    "" => "/".to_string(),
+    segment if valid => format!("/{}", segment.to_ascii_lowercase()),
+    _ => metrics::OTHER.to_string()
+  }
+}
+
+/// Name of the type of a cause of an error, for the types known to fail in distinct ways.
+fn cause_type(cause: &(dyn std::error::Error + 'static)) -> Option<&'static str> {
+  if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+    Some(if e.is_timeout() { "reqwest::timeout" } else if e.is_connect() { "reqwest::connect" } else if e.is_decode() { "reqwest::decode" } else { "reqwest" })
+  } else if cause.is::<serde_json::Error>() {
+    Some("serde_json")
+  } else if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+    Some(match e.kind() {
+      std::io::ErrorKind::TimedOut => "io::timed_out",
+      std::io::ErrorKind::ConnectionRefused => "io::connection_refused",
+      _ => "io"
+    })
+  } else {
+    None
+  }
+}
+
+/// Names of the known types of the causes of an error, outermost first.
+fn cause_types(e: &anyhow::Error) -> String {
+  e.chain().filter_map(cause_type).collect::<Vec<_>>().join(",")
+}
+
+/// Short fingerprint of an error, stable across restarts and replicas running the same version:
+/// the hash of its kind, of the location where it was rejected and of the known types of its causes.
+/// The message is left out, so that all the occurrences of the same failure share it, whatever their details.
+fn fingerprint(kind: &str, location: &Location<'_>, types: &str) -> String {
+  let location = format!("{}:{}", location.file(), location.line());
+
+  // FNV-1a, as the hashers of the standard library are not guaranteed to be stable
+  let mut hash: u32 = 0x811c9dc5;
+  for byte in kind.bytes().chain([0]).chain(location.bytes()).chain([0]).chain(types.bytes()) {
+    hash ^= byte as u32;
+    hash = hash.wrapping_mul(0x01000193);
+  }
+  format!("{:08x}", hash)
+}
+
+/// Logs an unhandled error, unless too many errors with the same id have been logged recently.
+fn log_sampled(sampler: &LogSampler, kind: &str, error_id: &str, details: &dyn std::fmt::Debug) {
+  if let Decision::Log { suppressed } = sampler.sample(error_id) {
+    if suppressed > 0 {
+      error!(error = %kind, error_id, suppressed, "Unhandled error: {:?} ({} similar errors suppressed)", details, suppressed);
+    } else {
+      error!(error = %kind, error_id, "Unhandled error: {:?}", details);
+    }
+  }
+}
+
+/// Fingerprints a server error which is not a bug, like an overload or an open circuit breaker, and logs it sampled,
+/// so that it gets an `error_id` like the unexpected ones.
+fn server_error_id(kind: &str, err: &Rejection, sampler: &LogSampler) -> String {
+  match err.find::<CustomRejection>() {
+    Some(CustomRejection(e, location)) => {
+      let id = fingerprint(kind, location, &cause_types(e));
+      log_sampled(sampler, &e.to_string(), &id, e);
+      id
+    },
+    None => {
+      let id = fingerprint(kind, Location::caller(), "");
+      log_sampled(sampler, kind, &id, err);
+      id
+    }
   }
 }
 
 /// Warp rejection handler.
 /// This function is invoked when an error occurs during the processing of a request,
 /// and builds a consistent error response.
-pub async fn handle_rejection(err: Rejection) -> std::result::Result<impl Reply, Infallible> {
+pub async fn handle_rejection(err: Rejection, sampler: Arc<LogSampler>) -> std::result::Result<impl Reply, Infallible> {
   let code;
   let message;
+  let mut problem = None;
+  let mut error_id = None;
+  let mut not_found = None;
+  let mut unknown_path = None;
 
-  if err.is_not_found() {
+  if let Some(PokemonNotFound { name, suggestions }) = err.find::<PokemonNotFound>() {
+    code = StatusCode::NOT_FOUND;
+    message = "Pokemon Not Found";
+    problem = Some("pokemon_not_found");
+    not_found = Some((name.clone(), suggestions.clone()));
+  } else if err.is_not_found() {
     code = StatusCode::NOT_FOUND;
     message = "Not Found";
+  } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+    code = StatusCode::PAYLOAD_TOO_LARGE;
+    message = "Payload Too Large";
   } else if err.find::<warp::filters::body::BodyDeserializeError>().is_some() {
     code = StatusCode::BAD_REQUEST;
     message = "Invalid Body";
-  } else if let Some(CustomRejection(e)) = err.find::<CustomRejection>() {
-    error!(error = %e, "Unhandled error: {:?}", e);
+  } else if err.find::<NameTooLong>().is_some() {
+    metrics::REJECTED_REQUESTS.with_label_values(&["name_too_long"]).inc();
+    code = StatusCode::BAD_REQUEST;
+    message = "Pokemon name too long";
+  } else if let Some(BadRequest(reason)) = err.find::<BadRequest>() {
+    code = StatusCode::BAD_REQUEST;
+    message = reason;
+  } else if err.find::<Unauthorized>().is_some() {
+    code = StatusCode::UNAUTHORIZED;
+    message = "Unauthorized";
+  } else if err.find::<TooManyRequests>().is_some() {
+    code = StatusCode::TOO_MANY_REQUESTS;
+    message = "Too Many Requests";
+  } else if err.find::<TextTooLong>().is_some() {
+    code = StatusCode::UNPROCESSABLE_ENTITY;
+    message = "Text Too Long";
+    problem = Some("text_too_long");
+  } else if err.find::<IdempotencyConflict>().is_some() {
+    code = StatusCode::UNPROCESSABLE_ENTITY;
+    message = "Idempotency Key Reused";
+    problem = Some("idempotency_key_reused");
+  } else if err.find::<Overloaded>().is_some() || err.find::<CustomRejection>().is_some_and(|CustomRejection(e, _)| e.is::<TooManyWaiters>()) {
+    code = StatusCode::SERVICE_UNAVAILABLE;
+    message = "Service Unavailable";
+    problem = Some("overloaded");
+    error_id = Some(server_error_id("overloaded", &err, &sampler));
+  } else if err.find::<TimedOut>().is_some() {
+    code = StatusCode::GATEWAY_TIMEOUT;
+    message = "Gateway Timeout";
+    problem = Some("timeout");
+    error_id = Some(server_error_id("timeout", &err, &sampler));
+  } else if err.find::<CustomRejection>().is_some_and(|CustomRejection(e, _)| e.is::<DegradedModeEnabled>()) {
+    code = StatusCode::SERVICE_UNAVAILABLE;
+    message = "Service Unavailable";
+    problem = Some("degraded_mode");
+    error_id = Some(server_error_id("degraded_mode", &err, &sampler));
+  } else if err.find::<CustomRejection>().is_some_and(|CustomRejection(e, _)| e.is::<BreakerOpen>()) {
+    code = StatusCode::SERVICE_UNAVAILABLE;
+    message = "Service Unavailable";
+    problem = Some("circuit_open");
+    error_id = Some(server_error_id("circuit_open", &err, &sampler));
+  } else if let Some(CustomRejection(e, location)) = err.find::<CustomRejection>().filter(|CustomRejection(e, _)| is_connection_error(e)) {
+    let id = fingerprint("dependency_unreachable", location, &cause_types(e));
+    log_sampled(&sampler, &e.to_string(), &id, e);
+    error_id = Some(id);
+    metrics::DEPENDENCY_UNREACHABLE.inc();
+    code = StatusCode::SERVICE_UNAVAILABLE;
+    message = "Service Unavailable";
+    problem = Some("dependency_unreachable");
+  } else if let Some(CustomRejection(e, location)) = err.find::<CustomRejection>() {
+    let id = fingerprint("internal", location, &cause_types(e));
+    log_sampled(&sampler, &e.to_string(), &id, e);
+    error_id = Some(id);
     code = StatusCode::INTERNAL_SERVER_ERROR;
     message = "Internal Server Error";
   } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
     code = StatusCode::METHOD_NOT_ALLOWED;
     message = "Method Not Allowed";
+  } else if err.find::<warp::reject::InvalidQuery>().is_some() {
+    // Checked before the unknown routes, as the catch-all also rejects the known routes with an invalid query
+    code = StatusCode::BAD_REQUEST;
+    message = "Invalid Query String";
+  } else if let Some(UnknownRoute(path)) = err.find::<UnknownRoute>() {
+    let label = metrics::UNKNOWN_PATH_LABELS.label(&path_prefix(path));
+    metrics::UNKNOWN_PATH_HITS.with_label_values(&[&label]).inc();
+    code = StatusCode::NOT_FOUND;
+    message = "Not Found";
+    unknown_path = Some(sanitize_path(path));
   } else {
-    error!(error = ?err, "Unhandled error: {:?}", err);
+    let id = fingerprint("rejection", Location::caller(), "");
+    log_sampled(&sampler, "rejection", &id, &err);
+    error_id = Some(id);
     code = StatusCode::INTERNAL_SERVER_ERROR;
     message = "Internal Server Error";
   }
 
+  let (name, suggestions) = not_found.unzip();
   Ok(
-    warp::reply::with_status(
-      warp::reply::json(&json!({
-        "message": message
-      })),
-      code
+    warp::reply::with_header(
+      warp::reply::with_status(
+        warp::reply::json(&ErrorBody {
+          message,
+          problem,
+          error_id,
+          name,
+          suggestions,
+          spec: unknown_path.as_ref().map(|_| OPENAPI_PATH),
+          path: unknown_path
+        }),
+        code
+      ),
+      CONTENT_TYPE,
+      PROBLEM_JSON
     )
   )
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::log_sampling::SamplingPolicy;
+
+  #[tokio::test]
+  async fn test_unreachable_dependency() {
+
+    let sampler = Arc::new(LogSampler::new(SamplingPolicy::default()));
+    let e = reqwest::get("http://127.0.0.1:1/").await.unwrap_err();
+    let rejection = warp::reject::custom(CustomRejection::new(anyhow::Error::new(e).context("Cannot send request")));
+    let res = handle_rejection(rejection, sampler.clone()).await.unwrap().into_response();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = warp::hyper::body::to_bytes(res.into_body()).await.unwrap();
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["type"], "dependency_unreachable");
+
+    // Other errors are still internal errors
+    let rejection = warp::reject::custom(CustomRejection::new(anyhow::anyhow!("HTTP error: 500")));
+    let res = handle_rejection(rejection, sampler).await.unwrap().into_response();
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+  }
+
+  #[tokio::test]
+  async fn test_name_too_long() {
+
+    // Rejected by both the route and the fallback, but counted once
+    let sampler = Arc::new(LogSampler::new(SamplingPolicy::default()));
+    let before = metrics::REJECTED_REQUESTS.with_label_values(&["name_too_long"]).get();
+    let res = handle_rejection(warp::reject::custom(NameTooLong), sampler).await.unwrap().into_response();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(metrics::REJECTED_REQUESTS.with_label_values(&["name_too_long"]).get(), before + 1);
+
+  }
+
+  #[tokio::test]
+  async fn test_pokemon_not_found() {
+
+    let sampler = Arc::new(LogSampler::new(SamplingPolicy::default()));
+    let rejection = warp::reject::custom(PokemonNotFound { name: "pikachuu".to_string(), suggestions: vec!["pikachu".to_string()] });
+    let res = handle_rejection(rejection, sampler.clone()).await.unwrap().into_response();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert_eq!(res.headers()[CONTENT_TYPE], PROBLEM_JSON);
+    let body: serde_json::Value = serde_json::from_slice(&warp::hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["type"], "pokemon_not_found");
+    assert_eq!(body["name"], "pikachuu");
+    assert_eq!(body["suggestions"], serde_json::json!(["pikachu"]));
+
+    // Unknown routes keep the generic body, still as problem details
+    let res = handle_rejection(warp::reject::not_found(), sampler).await.unwrap().into_response();
+    assert_eq!(res.headers()[CONTENT_TYPE], PROBLEM_JSON);
+    let body: serde_json::Value = serde_json::from_slice(&warp::hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body, serde_json::json!({ "message": "Not Found" }));
+
+  }
+
+  #[tokio::test]
+  async fn test_unknown_route() {
+
+    let sampler = Arc::new(LogSampler::new(SamplingPolicy::default()));
+    let before = metrics::UNKNOWN_PATH_HITS.with_label_values(&["/pokemons"]).get();
+    let rejection = warp::reject::custom(UnknownRoute("/pokemons/pikachu".to_string()));
+    let res = handle_rejection(rejection, sampler).await.unwrap().into_response();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body: serde_json::Value = serde_json::from_slice(&warp::hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["path"], "/pokemons/pikachu");
+    assert_eq!(body["spec"], OPENAPI_PATH);
+    assert_eq!(metrics::UNKNOWN_PATH_HITS.with_label_values(&["/pokemons"]).get(), before + 1);
+
+  }
+
+  #[tokio::test]
+  async fn test_invalid_query_on_known_route() {
+
+    #[derive(serde::Deserialize)]
+    struct Query {
+      #[allow(dead_code)]
+      expand: bool
+    }
+
+    use warp::Filter;
+
+    // The invalid query of a known route is not reported as an unknown route by the catch-all
+    let sampler = Arc::new(LogSampler::new(SamplingPolicy::default()));
+    let catch_all = warp::path::full()
+      .and_then(|path: warp::path::FullPath| async move {
+        Err::<String, Rejection>(warp::reject::custom(UnknownRoute(path.as_str().to_string())))
+      });
+    let routes = warp::path!("pokemon" / String)
+      .and(warp::query::<Query>())
+      .map(|name, _| name)
+      .or(catch_all).unify()
+      .recover(move |err| handle_rejection(err, sampler.clone()));
+    let res = warp::test::request().path("/pokemon/pikachu?expand=maybe").reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(body["message"], "Invalid Query String");
+
+  }
+
+  #[test]
+  fn test_path_prefix() {
+    assert_eq!(path_prefix("/"), "/");
+    assert_eq!(path_prefix("/Pokemons/pikachu"), "/pokemons");
+    assert_eq!(path_prefix("/%3Cscript%3E"), metrics::OTHER);
+    assert_eq!(sanitize_path("/a\u{7}b"), "/ab");
+    assert_eq!(sanitize_path(&"a".repeat(200)).len(), MAX_ECHOED_PATH);
+  }
+
+  #[tokio::test]
+  async fn test_error_id() {
+
+    // The same failure gets the same id, whatever its message
+    let reject = |message: &str| CustomRejection::new(anyhow::anyhow!("{}", message.to_string()));
+    let ids = ["HTTP error: 500", "HTTP error: 502"].iter()
+      .map(|message| {
+        let CustomRejection(e, location) = reject(message);
+        fingerprint("internal", location, &cause_types(&e))
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(ids[0], ids[1]);
+
+    // Unlike the failures rejected somewhere else, of another kind or caused by other types
+    let CustomRejection(e, location) = CustomRejection::new(anyhow::anyhow!("HTTP error: 500"));
+    assert_ne!(fingerprint("internal", location, &cause_types(&e)), ids[0]);
+    assert_ne!(fingerprint("internal", location, "serde_json"), fingerprint("internal", location, ""));
+    assert_ne!(fingerprint("dependency_unreachable", location, ""), fingerprint("internal", location, ""));
+    let json = serde_json::from_str::<u32>("{").unwrap_err();
+    assert_eq!(cause_types(&anyhow::Error::new(json).context("Invalid JSON body")), "serde_json");
+
+    let sampler = Arc::new(LogSampler::new(SamplingPolicy::default()));
+    let rejection = CustomRejection::new(anyhow::anyhow!("HTTP error: 500"));
+    let id = fingerprint("internal", rejection.1, "");
+    let res = handle_rejection(warp::reject::custom(rejection), sampler.clone()).await.unwrap().into_response();
+    let body: serde_json::Value = serde_json::from_slice(&warp::hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["error_id"], id);
+    assert_eq!(sampler.recent(), vec![(id, 1)]);
+
+    // As do the expected server errors
+    let res = handle_rejection(warp::reject::custom(TimedOut), sampler.clone()).await.unwrap().into_response();
+    assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+    let body: serde_json::Value = serde_json::from_slice(&warp::hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["type"], "timeout");
+    assert!(body["error_id"].is_string());
+    let rejection = warp::reject::custom(CustomRejection::new(anyhow::Error::new(BreakerOpen)));
+    let res = handle_rejection(rejection, sampler.clone()).await.unwrap().into_response();
+    let body: serde_json::Value = serde_json::from_slice(&warp::hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["type"], "circuit_open");
+    assert!(body["error_id"].is_string());
+
+    // Client errors have no id
+    let res = handle_rejection(warp::reject::not_found(), sampler).await.unwrap().into_response();
+    let body: serde_json::Value = serde_json::from_slice(&warp::hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+    assert!(body.get("error_id").is_none());
+
+  }
+
+}
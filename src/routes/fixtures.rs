@@ -0,0 +1,125 @@
+//! Contract tests of the public payloads.
+//!
+//! Every endpoint is exercised against mocked upstreams, and its canonicalized response compared with the fixture
+//! recorded under `fixtures/v{VERSION}/`. Consumers can use the same fixtures for their own contract tests.
+//! Run the tests with `UPDATE_FIXTURES=1` to record them again, after an intended change of the payloads.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use httpmock::{MockServer, Method};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{json, Value};
+use warp::Reply;
+
+use crate::cache::Cache;
+use crate::cache::memory::MemoryCache;
+use crate::clients::{PokemonClient, ShakespeareClient, Translators};
+use crate::log_sampling::{LogSampler, SamplingPolicy};
+use crate::pipeline::TextPipeline;
+use crate::prose::ProseTemplates;
+use crate::routes::{daily, errors, pokemons, translate, State};
+
+/// Version of the public payloads. Bump it, and record the new fixtures, on breaking changes.
+const VERSION: u32 = 1;
+
+/// Canonical form of a payload: sorted keys and pretty printed, with a trailing newline.
+/// Objects are backed by sorted maps, so the keys come out sorted.
+fn canonicalize(value: &Value) -> String {
+  format!("{}\n", serde_json::to_string_pretty(value).unwrap())
+}
+
+/// Compares a payload with its fixture, or records it if `UPDATE_FIXTURES` is set.
+fn assert_fixture(name: &str, payload: &impl Serialize) {
+  let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "fixtures", &format!("v{}", VERSION), &format!("{}.json", name)].iter().collect();
+  let actual = canonicalize(&serde_json::to_value(payload).unwrap());
+
+  if std::env::var("UPDATE_FIXTURES").is_ok() {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, actual).unwrap();
+    return;
+  }
+
+  let expected = std::fs::read_to_string(&path).unwrap_or_else(|_| panic!("Missing fixture {}", path.display()));
+  assert_eq!(actual, expected, "Payload of {} changed: if intended, bump the version or run with UPDATE_FIXTURES=1", name);
+}
+
+async fn mock_upstreams(server: &MockServer) {
+  server.mock_async(|when, then| {
+    when.method(Method::GET)
+      .path("/pokemon-species/");
+    then.status(200)
+      .json_body(json!({ "count": 1, "results": [{ "name": "pikachu" }] }));
+  }).await;
+  server.mock_async(|when, then| {
+    when.method(Method::GET)
+      .path_matches(Regex::new("^/pokemon-species/.+").unwrap());
+    then.status(200)
+      .json_body(json!({
+        "flavor_text_entries": [{ "flavor_text": "It keeps its tail raised.", "language": { "name": "en" } }]
+      }));
+  }).await;
+  server.mock_async(|when, then| {
+    when.method(Method::GET)
+      .path_matches(Regex::new("^/pokemon/.+").unwrap());
+    then.status(200)
+      .json_body(json!({
+        "stats": [
+          { "base_stat": 35, "stat": { "name": "hp" } },
+          { "base_stat": 55, "stat": { "name": "attack" } }
+        ]
+      }));
+  }).await;
+  server.mock_async(|when, then| {
+    when.method(Method::POST)
+      .path("/translate/shakespeare.json");
+    then.status(200)
+      .json_body(json!({ "contents": { "translated": "'t keepeth its tail did raise.", "text": "It keeps its tail raised." } }));
+  }).await;
+}
+
+fn build_state(server: &MockServer) -> State {
+  State {
+    pokemon_client: PokemonClient::builder().base_url(&server.base_url()).build().unwrap(),
+    translators: Translators::new(Arc::new(ShakespeareClient::builder().base_url(&server.base_url()).build().unwrap())),
+    cache: Arc::new(Cache::new(MemoryCache::new(8, 8))),
+    text_pipeline: TextPipeline::default(),
+    profanity_filter: None,
+    prose_templates: ProseTemplates::with_default_templates().unwrap(),
+    untranslated_fallback: false,
+    translate_genus: false,
+    degraded_mode: Default::default()
+  }
+}
+
+#[tokio::test]
+async fn test_payloads_match_fixtures() {
+
+  let server = MockServer::start_async().await;
+  mock_upstreams(&server).await;
+  let state = build_state(&server);
+
+  let query = serde_json::from_value(json!({ "include_original": true })).unwrap();
+  let pokemon = pokemons::handle_get_pokemon("pikachu".to_string(), query, None, state.clone()).await.unwrap();
+  assert_fixture("pokemon", &pokemon);
+
+  let query = serde_json::from_value(json!({ "names": "pikachu,raichu" })).unwrap();
+  let compare = pokemons::handle_compare(query, 32, state.clone()).await.unwrap();
+  assert_fixture("compare", &compare);
+
+  // 2021-06-01
+  let daily = daily::DailyPokemon::new().get_for(18779, &state).await.unwrap();
+  assert_fixture("daily", &daily);
+
+  let auth = Arc::new(translate::TranslateAuth::new(&["secret".to_string()], 10));
+  let request = serde_json::from_value(json!({ "text": "It keeps its tail raised." })).unwrap();
+  let translated = translate::handle_translate(auth, None, Some("Bearer secret".to_string()), None, request, state.clone()).await.unwrap();
+  assert_fixture("translate", &translated);
+
+  let sampler = Arc::new(LogSampler::new(SamplingPolicy::default()));
+  let res = errors::handle_rejection(warp::reject::not_found(), sampler).await.unwrap().into_response();
+  let error: Value = serde_json::from_slice(&warp::hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+  assert_fixture("error", &error);
+
+}
@@ -0,0 +1,112 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+use crate::build_info;
+use crate::health::{DegradedMode, DependencyStatus, Draining, HealthChecker};
+
+#[derive(Serialize)]
+struct LivenessResponse {
+  status: &'static str,
+  version: &'static str,
+  git_sha: &'static str,
+  build_date: &'static str,
+  features: Vec<&'static str>,
+  /// Whether the untranslated descriptions are served, because forced by the operators or because of the breaker.
+  degraded_mode: bool,
+  /// Whether the circuit breaker of the translator is open, enabling the degraded mode on its own.
+  translator_breaker_open: bool
+}
+
+#[derive(Serialize)]
+struct ReadinessResponse {
+  status: &'static str,
+  /// Age of the reported status, in seconds.
+  age: u64,
+  dependencies: Vec<DependencyStatus>
+}
+
+/// Handler for the `GET /health` route, reporting what is running.
+pub fn handle_live(degraded_mode: &DegradedMode) -> impl Reply {
+  warp::reply::json(&LivenessResponse {
+    status: "up",
+    version: build_info::VERSION,
+    git_sha: build_info::GIT_SHA,
+    build_date: build_info::BUILD_DATE,
+    features: build_info::features(),
+    degraded_mode: degraded_mode.is_enabled(),
+    translator_breaker_open: degraded_mode.is_breaker_open()
+  })
+}
+
+/// Handler for the `GET /health/ready` route.
+pub async fn handle_ready(checker: Arc<HealthChecker>, draining: Arc<Draining>) -> std::result::Result<impl Reply, Rejection> {
+
+  // No need to check the dependencies when shutting down
+  if draining.is_draining() {
+    return Ok(warp::reply::with_status(
+      warp::reply::json(&ReadinessResponse { status: "draining", age: 0, dependencies: Vec::new() }),
+      StatusCode::SERVICE_UNAVAILABLE
+    ));
+  }
+
+  let report = checker.report().await;
+  let (status, code) = if report.is_up() {
+    ("up", StatusCode::OK)
+  } else {
+    ("down", StatusCode::SERVICE_UNAVAILABLE)
+  };
+
+  Ok(warp::reply::with_status(
+    warp::reply::json(&ReadinessResponse {
+      status,
+      age: report.checked_at.elapsed().as_secs(),
+      dependencies: report.dependencies
+    }),
+    code
+  ))
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::time::Duration;
+
+  use crate::cache::Cache;
+  use crate::cache::memory::MemoryCache;
+  use crate::clients::{PokemonClient, ShakespeareClient};
+
+  #[tokio::test]
+  async fn test_live_reports_build() {
+    let res = handle_live(&DegradedMode::default()).into_response();
+    let body: serde_json::Value = serde_json::from_slice(&warp::hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    assert!(body["git_sha"].is_string());
+    assert_eq!(body["degraded_mode"], false);
+    assert_eq!(body["translator_breaker_open"], false);
+  }
+
+  #[tokio::test]
+  async fn test_not_ready_while_draining() {
+
+    // The dependencies are never contacted
+    let checker = Arc::new(HealthChecker::new(
+      PokemonClient::builder().base_url("http://localhost:1").build().unwrap(),
+      Arc::new(ShakespeareClient::builder().base_url("http://localhost:1").build().unwrap()),
+      Arc::new(Cache::new(MemoryCache::new(1, 1))),
+      Duration::from_secs(30)
+    ));
+    let draining = Arc::new(Draining::default());
+    draining.start();
+
+    let res = handle_ready(checker, draining).await.unwrap().into_response();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body: serde_json::Value = serde_json::from_slice(&warp::hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["status"], "draining");
+
+  }
+
+}
@@ -0,0 +1,283 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+use warp::Rejection;
+
+use crate::metrics;
+use crate::routes::errors::{BadRequest, IdempotencyConflict};
+
+/// Header the clients use to make their submissions idempotent.
+pub const IDEMPOTENCY_HEADER: &str = "idempotency-key";
+
+/// Maximum length of the idempotency keys.
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Maximum number of stored responses, to bound the memory taken by the keys of a misbehaving client.
+const MAX_ENTRIES: usize = 10_000;
+
+struct Stored<T> {
+  stored_at: Instant,
+  /// Hash of the request, to tell a retry from a different request reusing the same key.
+  fingerprint: u64,
+  response: T
+}
+
+enum Entry<T> {
+  /// The request is running: the retries wait for it, and are told when it ends by the closing of the channel.
+  InFlight { fingerprint: u64, done: watch::Receiver<()> },
+  Stored(Stored<T>)
+}
+
+/// What to do with a request carrying an idempotency key.
+enum Begin<'a, T> {
+  Replay(T),
+  Wait(watch::Receiver<()>),
+  Run(Reservation<'a, T>),
+  /// The store is full of requests in flight, so this one runs without being stored.
+  Untracked
+}
+
+/// Responses of the idempotent submissions, kept for a while so that the retries of the clients
+/// get the same response without running the request again.
+pub struct IdempotencyStore<T> {
+  ttl: Duration,
+  entries: Mutex<HashMap<String, Entry<T>>>
+}
+
+impl<T: Clone> IdempotencyStore<T> {
+
+  pub fn new(ttl: Duration) -> Self {
+    IdempotencyStore {
+      ttl,
+      entries: Mutex::new(HashMap::new())
+    }
+  }
+
+  /// Looks up the key, reserving it if the request has to run.
+  /// Fails if the key was used for a different request.
+  fn begin(&self, key: &str, fingerprint: u64) -> Result<Begin<'_, T>, IdempotencyConflict> {
+    let mut entries = self.entries.lock().unwrap();
+    if let Some(entry) = entries.get(key).filter(|entry| self.is_live(entry)) {
+      return match entry {
+        Entry::InFlight { fingerprint: running, .. } | Entry::Stored(Stored { fingerprint: running, .. }) if *running != fingerprint => Err(IdempotencyConflict),
+        Entry::InFlight { done, .. } => Ok(Begin::Wait(done.clone())),
+        Entry::Stored(stored) => Ok(Begin::Replay(stored.response.clone()))
+      };
+    }
+
+    if !entries.contains_key(key) && entries.len() >= MAX_ENTRIES && !self.make_room(&mut entries) {
+      return Ok(Begin::Untracked);
+    }
+    let (sender, done) = watch::channel(());
+    entries.insert(key.to_string(), Entry::InFlight { fingerprint, done });
+    Ok(Begin::Run(Reservation { store: self, key: key.to_string(), fingerprint, sender: Some(sender) }))
+  }
+
+  fn is_live(&self, entry: &Entry<T>) -> bool {
+    match entry {
+      Entry::InFlight { .. } => true,
+      Entry::Stored(stored) => stored.stored_at.elapsed() < self.ttl
+    }
+  }
+
+  /// Drops the expired responses, or else the oldest one, returning whether there is room for another entry.
+  fn make_room(&self, entries: &mut HashMap<String, Entry<T>>) -> bool {
+    entries.retain(|_, entry| self.is_live(entry));
+    if entries.len() < MAX_ENTRIES {
+      return true;
+    }
+    let oldest = entries.iter()
+      .filter_map(|(key, entry)| match entry {
+        Entry::Stored(stored) => Some((key, stored.stored_at)),
+        Entry::InFlight { .. } => None
+      })
+      .min_by_key(|(_, stored_at)| *stored_at)
+      .map(|(key, _)| key.clone());
+    match oldest {
+      Some(oldest) => {
+        entries.remove(&oldest);
+        metrics::IDEMPOTENCY_EVICTIONS.inc();
+        true
+      },
+      None => false
+    }
+  }
+
+}
+
+/// A key reserved by the request running for it, released when dropped unless the response has been stored,
+/// so that a failed or cancelled request can be retried.
+struct Reservation<'a, T> {
+  store: &'a IdempotencyStore<T>,
+  key: String,
+  fingerprint: u64,
+  sender: Option<watch::Sender<()>>
+}
+
+impl<T> Reservation<'_, T> {
+
+  fn complete(mut self, response: T) {
+    let mut entries = self.store.entries.lock().unwrap();
+    entries.insert(self.key.clone(), Entry::Stored(Stored { stored_at: Instant::now(), fingerprint: self.fingerprint, response }));
+    // Wakes up the retries waiting for the response
+    self.sender = None;
+  }
+
+}
+
+impl<T> Drop for Reservation<'_, T> {
+  fn drop(&mut self) {
+    if self.sender.is_some() {
+      let mut entries = self.store.entries.lock().unwrap();
+      if matches!(entries.get(&self.key), Some(Entry::InFlight { .. })) {
+        entries.remove(&self.key);
+      }
+    }
+  }
+}
+
+/// Runs the request, unless a successful response has already been stored for the same idempotency key.
+/// Requests without a key are always run, and failed requests are not stored, so that they can be retried.
+/// A retry arriving while the request is still running waits for its response.
+pub async fn run<R, T, F, Fut>(store: &IdempotencyStore<T>, key: Option<String>, request: R, f: F) -> Result<T, Rejection>
+  where R: Hash,
+        T: Clone,
+        F: FnOnce(R) -> Fut,
+        Fut: Future<Output = Result<T, Rejection>>
+{
+
+  let key = match key {
+    Some(key) => key,
+    None => return f(request).await
+  };
+  if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+    return Err(warp::reject::custom(BadRequest("Invalid idempotency key")));
+  }
+
+  let mut hasher = DefaultHasher::new();
+  request.hash(&mut hasher);
+  let fingerprint = hasher.finish();
+  let reservation = loop {
+    match store.begin(&key, fingerprint).map_err(warp::reject::custom)? {
+      Begin::Replay(response) => {
+        metrics::IDEMPOTENT_REPLAYS.inc();
+        return Ok(response);
+      },
+      // Whichever way the running request ends, look again
+      Begin::Wait(mut done) => {
+        let _ = done.changed().await;
+      },
+      Begin::Run(reservation) => break Some(reservation),
+      Begin::Untracked => break None
+    }
+  };
+
+  let response = f(request).await?;
+  if let Some(reservation) = reservation {
+    reservation.complete(response.clone());
+  }
+  Ok(response)
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  #[tokio::test]
+  async fn test_replayed_response() {
+
+    let store = IdempotencyStore::new(Duration::from_secs(60));
+    let runs = AtomicUsize::new(0);
+    let submit = |key: Option<&str>, request: &'static str| run(&store, key.map(str::to_string), request, |request| {
+      let run = runs.fetch_add(1, Ordering::SeqCst);
+      async move { Ok(format!("{} #{}", request, run)) }
+    });
+
+    // The retry gets the stored response
+    assert_eq!(submit(Some("a"), "pikachu").await.unwrap(), "pikachu #0");
+    assert_eq!(submit(Some("a"), "pikachu").await.unwrap(), "pikachu #0");
+
+    // Requests without a key, or with a different one, run again
+    assert_eq!(submit(None, "pikachu").await.unwrap(), "pikachu #1");
+    assert_eq!(submit(Some("b"), "pikachu").await.unwrap(), "pikachu #2");
+
+    // A different request cannot reuse the key
+    let err = submit(Some("a"), "raichu").await.unwrap_err();
+    assert!(err.find::<IdempotencyConflict>().is_some());
+
+  }
+
+  #[tokio::test]
+  async fn test_expired_response() {
+
+    let store = IdempotencyStore::new(Duration::from_millis(10));
+    run(&store, Some("a".to_string()), 1, |_| async { Ok(1) }).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(run(&store, Some("a".to_string()), 1, |_| async { Ok(2) }).await.unwrap(), 2);
+
+  }
+
+  #[tokio::test]
+  async fn test_concurrent_retries() {
+
+    let store = IdempotencyStore::new(Duration::from_secs(60));
+    let runs = AtomicUsize::new(0);
+    let submit = || run(&store, Some("a".to_string()), "pikachu", |request| {
+      let run = runs.fetch_add(1, Ordering::SeqCst);
+      async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        Ok(format!("{} #{}", request, run))
+      }
+    });
+
+    // The retry sent while the first submission runs waits for its response
+    let (first, retry) = futures::join!(submit(), submit());
+    assert_eq!(first.unwrap(), "pikachu #0");
+    assert_eq!(retry.unwrap(), "pikachu #0");
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+  }
+
+  #[tokio::test]
+  async fn test_failed_and_cancelled_runs() {
+
+    let store = IdempotencyStore::<u32>::new(Duration::from_secs(60));
+
+    // A failed submission releases its key
+    let failed = run(&store, Some("a".to_string()), 1, |_| async { Err(warp::reject::not_found()) }).await;
+    assert!(failed.is_err());
+    assert_eq!(run(&store, Some("a".to_string()), 1, |_| async { Ok(2) }).await.unwrap(), 2);
+
+    // And so does a cancelled one
+    let slow = run(&store, Some("b".to_string()), 1, |_| async {
+      tokio::time::sleep(Duration::from_secs(10)).await;
+      Ok(3)
+    });
+    assert!(tokio::time::timeout(Duration::from_millis(20), slow).await.is_err());
+    assert_eq!(run(&store, Some("b".to_string()), 1, |_| async { Ok(4) }).await.unwrap(), 4);
+
+  }
+
+  #[tokio::test]
+  async fn test_full_store() {
+
+    let store = IdempotencyStore::new(Duration::from_secs(60));
+    for i in 0..MAX_ENTRIES {
+      run(&store, Some(format!("key-{}", i)), i, |i| async move { Ok(i) }).await.unwrap();
+    }
+
+    // One of the oldest responses makes room for the new key
+    assert_eq!(run(&store, Some("new".to_string()), 0, |_| async { Ok(1) }).await.unwrap(), 1);
+    assert_eq!(run(&store, Some("new".to_string()), 0, |_| async { Ok(2) }).await.unwrap(), 1);
+    assert_eq!(store.entries.lock().unwrap().len(), MAX_ENTRIES);
+
+  }
+
+}
@@ -0,0 +1,36 @@
+use serde_json::Value;
+use warp::http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use warp::http::StatusCode;
+use warp::hyper::Body;
+use warp::reply::Response;
+
+/// Content type of the problem details, a flavor of JSON.
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Returns whether the body of the response is JSON.
+pub fn is_json(res: &Response) -> bool {
+  res.headers()
+    .get(CONTENT_TYPE)
+    .and_then(|value| value.to_str().ok())
+    .is_some_and(|value| value.starts_with("application/json") || value.starts_with(PROBLEM_JSON))
+}
+
+/// Buffers the JSON body of a response and replaces it with the result of `f`.
+/// Bodies which cannot be parsed, like the empty ones of the `HEAD` requests, are left untouched.
+pub async fn map_body<F>(res: Response, f: F) -> Response
+  where F: FnOnce(StatusCode, Value) -> Value
+{
+  let (mut parts, body) = res.into_parts();
+  let bytes = match warp::hyper::body::to_bytes(body).await {
+    Ok(bytes) => bytes,
+    Err(_) => return Response::from_parts(parts, Body::empty())
+  };
+  let payload: Value = match serde_json::from_slice(&bytes) {
+    Ok(payload) => payload,
+    Err(_) => return Response::from_parts(parts, Body::from(bytes))
+  };
+
+  let payload = f(parts.status, payload);
+  parts.headers.remove(CONTENT_LENGTH);
+  Response::from_parts(parts, Body::from(payload.to_string()))
+}
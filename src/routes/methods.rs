@@ -0,0 +1,75 @@
+use serde_json::json;
+use warp::http::{Method, StatusCode};
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+/// Methods accepted by the read-only routes of this application.
+pub const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// Matches both `GET` and `HEAD` requests.
+/// The body of the responses to `HEAD` requests is dropped by the server, leaving only the headers.
+pub fn get_or_head() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+  warp::get().or(warp::head()).unify()
+}
+
+/// Answers the requests to a known path which have not been accepted by its route:
+/// `OPTIONS` gets a `204 No Content` and any other method a `405 Method Not Allowed`,
+/// both listing the `allowed` methods in the `Allow` header.
+///
+/// The allowed methods other than `OPTIONS` are rejected, so that the rejection of the actual route is reported instead.
+pub fn fallback(allowed: &'static str) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+  warp::method()
+    .and_then(move |method: Method| async move {
+      if method != Method::OPTIONS && allowed.split(", ").any(|allowed| allowed == method.as_str()) {
+        return Err(warp::reject::not_found());
+      }
+
+      let reply = if method == Method::OPTIONS {
+        StatusCode::NO_CONTENT.into_response()
+      } else {
+        warp::reply::with_status(
+          warp::reply::json(&json!({
+            "message": "Method Not Allowed"
+          })),
+          StatusCode::METHOD_NOT_ALLOWED
+        ).into_response()
+      };
+      Ok(warp::reply::with_header(reply, "Allow", allowed).into_response())
+    })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_fallback() {
+    let filter = fallback(ALLOWED_METHODS);
+
+    let res = warp::test::request().method("OPTIONS").reply(&filter).await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(res.headers()["Allow"], ALLOWED_METHODS);
+
+    let res = warp::test::request().method("DELETE").reply(&filter).await;
+    assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(res.headers()["Allow"], ALLOWED_METHODS);
+
+    assert!(warp::test::request().method("GET").filter(&filter).await.is_err());
+    assert!(warp::test::request().method("HEAD").filter(&filter).await.is_err());
+
+    let filter = fallback("POST, OPTIONS");
+    let res = warp::test::request().method("GET").reply(&filter).await;
+    assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(res.headers()["Allow"], "POST, OPTIONS");
+    assert!(warp::test::request().method("POST").filter(&filter).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_get_or_head() {
+    let filter = get_or_head();
+    assert!(warp::test::request().method("GET").filter(&filter).await.is_ok());
+    assert!(warp::test::request().method("HEAD").filter(&filter).await.is_ok());
+    assert!(warp::test::request().method("POST").filter(&filter).await.is_err());
+  }
+
+}
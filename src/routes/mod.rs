@@ -1,35 +1,123 @@
+pub mod admin;
+pub mod admission;
+pub mod casing;
+pub mod daily;
+pub mod envelope;
 pub mod errors;
+#[cfg(test)]
+mod fixtures;
+pub mod health;
+pub mod idempotency;
+pub mod json;
+pub mod methods;
+pub mod openapi;
 pub mod pokemons;
+pub mod schemas;
+pub mod server_timing;
+pub mod status;
+pub mod timeout;
+pub mod translate;
 
 use std::convert::Infallible;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::time::Instant;
 
-use lru::LruCache;
 use prometheus::{Encoder, TextEncoder};
 use serde::Serialize;
-use tracing::error;
-use warp::{http::StatusCode, Filter, Reply, Rejection};
+use tracing::{error, warn};
+use warp::filters::BoxedFilter;
+use warp::{Filter, Reply, Rejection};
 
-use crate::clients::{PokemonClient, ShakespeareClient};
-use crate::routes::errors::CustomRejection;
+use crate::cache::Cache;
+use crate::clients::{PokemonClient, Translators};
+use crate::config::Config;
+use crate::health::{DegradedMode, Draining, HealthChecker};
+use crate::jobs::Jobs;
+use crate::log_sampling::LogSampler;
+use crate::metrics;
+use crate::pipeline::TextPipeline;
+use crate::profanity::ProfanityFilter;
+use crate::prose::ProseTemplates;
+use crate::routes::errors::{CustomRejection, UnknownRoute};
+use crate::routes::server_timing::Timed;
+use crate::slo::Slo;
+use crate::trace_context;
+
+/// Maximum size of the request bodies.
+const MAX_BODY_BYTES: u64 = 16 * 1024;
+
+/// Demo page, embedded in the binary.
+const INDEX_HTML: &str = include_str!("index.html");
 
 /// Shared state for all the requests.
 #[derive(Clone)]
 pub struct State {
   pub pokemon_client: PokemonClient,
-  pub shakespeare_client: ShakespeareClient,
-  pub cache: Arc<Mutex<LruCache<String, String>>>
+  /// All the configured translators, the requests selecting one by its name.
+  pub translators: Translators,
+  pub cache: Arc<Cache>,
+  pub text_pipeline: TextPipeline,
+  pub profanity_filter: Option<ProfanityFilter>,
+  pub prose_templates: ProseTemplates,
+  /// Whether to serve the untranslated descriptions when the translation fails.
+  pub untranslated_fallback: bool,
+  /// Whether to translate the genus of the species.
+  pub translate_genus: bool,
+  /// Whether the untranslated descriptions are served instead of contacting the translator.
+  pub degraded_mode: Arc<DegradedMode>
+}
+
+impl State {
+
+  /// Builds the state described by the given configuration.
+  pub fn from_config(config: &Config, pokemon_client: PokemonClient, translators: Translators, cache: Arc<Cache>) -> Self {
+    let degraded_mode = DegradedMode::default().with_breaker(translators.primary().circuit_breaker());
+    State {
+      pokemon_client,
+      translators,
+      cache,
+      text_pipeline: config.text_pipeline.clone(),
+      profanity_filter: config.profanity_filter.clone(),
+      prose_templates: config.prose_templates.clone(),
+      untranslated_fallback: config.untranslated_fallback,
+      translate_genus: config.translate_genus,
+      degraded_mode: Arc::new(degraded_mode)
+    }
+  }
+
 }
 
 fn with_state(state: State) -> impl Filter<Extract = (State,), Error = Infallible> + Clone {
   warp::any().map(move || state.clone())
 }
 
-async fn json_or_fail<T: Serialize>(obj: T) -> std::result::Result<impl Reply, Rejection> {
-  Ok(warp::reply::json(&obj))
+/// Restricts a route to the operators with one of the admin API keys, disabling it if none is configured.
+fn with_admin_auth(auth: Option<Arc<admin::AdminAuth>>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+  warp::header::optional::<String>("authorization")
+    .and_then(move |authorization: Option<String>| {
+      let auth = auth.clone();
+      async move {
+        auth.ok_or_else(warp::reject::not_found)?.authorize(authorization.as_deref())
+      }
+    })
+    .untuple_one()
+}
+
+/// Builds the `GET /admin/pokedex` route, only for the operators as it dumps the whole cache.
+fn pokedex_route(state: State, auth: Option<Arc<admin::AdminAuth>>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+  warp::path!("admin" / "pokedex")
+    .and(methods::get_or_head())
+    .and(with_admin_auth(auth))
+    .and(with_state(state))
+    .and_then(admin::handle_pokedex)
+}
+
+async fn json_or_fail<T: Serialize>(obj: Timed<T>) -> std::result::Result<impl Reply, Rejection> {
+  Ok(obj.map(|obj| warp::reply::json(&obj)))
 }
 
-async fn handle_metrics() -> std::result::Result<impl Reply, Rejection> {
+async fn handle_metrics(slo: Arc<Slo>) -> std::result::Result<impl Reply, Rejection> {
+  slo.refresh_metrics();
   let mut buffer = Vec::new();
   let metric_families = prometheus::gather();
 
@@ -42,33 +130,321 @@ async fn handle_metrics() -> std::result::Result<impl Reply, Rejection> {
 }
 
 /// Builds a [`warp::Filter`](warp::Filter) matching all the routes of this application.
-pub fn routes(pokemon_client: PokemonClient, shakespeare_client: ShakespeareClient, pokemon_cache_size: usize) -> impl Filter<Extract = impl Reply> + Clone {
+pub async fn routes(config: &Config, pokemon_client: PokemonClient, translators: Translators, cache: Cache, draining: Arc<Draining>, jobs: Arc<Jobs>) -> BoxedFilter<(Box<dyn Reply>,)> {
   
-  let state = State {
-    pokemon_client,
-    shakespeare_client,
-    cache: Arc::new(Mutex::new(LruCache::new(pokemon_cache_size)))
-  };
+  let cache = Arc::new(cache);
+  let checker = Arc::new(HealthChecker::new(
+    pokemon_client.clone(),
+    translators.primary().clone(),
+    cache.clone(),
+    config.health_check_interval
+  ));
+  let state = State::from_config(config, pokemon_client, translators, cache);
+
+  // The degraded mode set before the last restart still holds, before the first request is served
+  if let Err(e) = state.degraded_mode.restore(&state.cache).await {
+    warn!(error = %e, "Cannot restore the degraded mode");
+  }
+  let trace_config = config.trace.clone();
+  let sampler = Arc::new(LogSampler::new(config.error_log_sampling.clone()));
+  let slo = Arc::new(Slo::new(config.slo.clone()));
+  let timeouts = config.route_timeouts;
+  let admin_auth = config.admin_api_keys.as_ref().map(|keys| Arc::new(admin::AdminAuth::new(keys)));
+
+  // GET /
+  // Interactive demo page.
+  let index = warp::path::end()
+    .and(methods::get_or_head())
+    .map(|| warp::reply::html(INDEX_HTML));
+
+  // GET /health/ready
+  // Readiness endpoint, reporting the status of the dependencies.
+  let health_ready = warp::path!("health" / "ready")
+    .and(methods::get_or_head())
+    .map(move || (checker.clone(), draining.clone()))
+    .untuple_one()
+    .and_then(health::handle_ready);
 
   // GET /health
   // Healthcheck endpoint.
-  let health = warp::path("health")
-    .map(|| StatusCode::OK);
+  let live_degraded_mode = state.degraded_mode.clone();
+  let health = warp::path!("health")
+    .and(methods::get_or_head())
+    .map(move || health::handle_live(&live_degraded_mode));
 
   // GET /metrics
   // Prometheus metrics.
-  let metrics = warp::path("metrics")
+  let metrics_slo = slo.clone();
+  let metrics = warp::path!("metrics")
+    .and(methods::get_or_head())
+    .map(move || metrics_slo.clone())
     .and_then(handle_metrics);
 
-  // GET /pokemon/{string}
+  // GET /status
+  // Human readable status page.
+  let status_info = status::StatusInfo { started_at: Instant::now(), sampler: sampler.clone() };
+  let status = warp::path!("status")
+    .and(methods::get_or_head())
+    .map(move || status_info.clone())
+    .and(with_state(state.clone()))
+    .and_then(status::handle_status);
+
+  // GET /openapi.json
+  // OpenAPI document of the public API.
+  let openapi_document = Arc::new(openapi::document());
+  let openapi = warp::path!("openapi.json")
+    .and(methods::get_or_head())
+    .map(move || warp::reply::json(&*openapi_document));
+
+  // GET /schemas/{name}.json
+  // JSON Schemas of the response bodies.
+  let schemas = warp::path!("schemas" / String)
+    .and(methods::get_or_head())
+    .and_then(schemas::handle_schema);
+
+  // GET /admin/pokedex
+  // Dump of all the cached translations, as JSON lines.
+  let pokedex = pokedex_route(state.clone(), admin_auth.clone());
+
+  // GET /admin/jobs
+  // GET /admin/jobs/{id}
+  // DELETE /admin/jobs/{id}
+  // Progress of the background jobs, which can be cancelled.
+  let list_jobs_registry = jobs.clone();
+  let list_jobs = warp::path!("admin" / "jobs")
+    .and(methods::get_or_head())
+    .and(with_admin_auth(admin_auth.clone()))
+    .map(move || warp::reply::json(&list_jobs_registry.list()));
+  let get_job_registry = jobs.clone();
+  let get_job = warp::path!("admin" / "jobs" / String)
+    .and(methods::get_or_head())
+    .and(with_admin_auth(admin_auth.clone()))
+    .map(move |id| (id, get_job_registry.clone()))
+    .untuple_one()
+    .and_then(admin::handle_job);
+  let cancel_job_registry = jobs.clone();
+  let cancel_job = warp::path!("admin" / "jobs" / String)
+    .and(warp::delete())
+    .and(with_admin_auth(admin_auth.clone()))
+    .map(move |id| (id, cancel_job_registry.clone()))
+    .untuple_one()
+    .and_then(admin::handle_cancel_job);
+
+  // PUT /admin/degraded-mode
+  // Forces the untranslated descriptions on or off, for example during a planned outage of the translator.
+  let degraded_mode = warp::path!("admin" / "degraded-mode")
+    .and(warp::put())
+    .and(with_admin_auth(admin_auth.clone()))
+    .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+    .and(warp::body::json())
+    .and(with_state(state.clone()))
+    .and_then(admin::handle_degraded_mode);
+
+  // POST /admin/cache/rehydrate
+  // Warms up the local cache with the most recent entries of the shared one.
+  let rehydrate = warp::path!("admin" / "cache" / "rehydrate")
+    .and(warp::post())
+    .and(with_admin_auth(admin_auth.clone()))
+    .and(warp::query::<admin::RehydrateQuery>())
+    .and(with_state(state.clone()))
+    .and_then(admin::handle_rehydrate);
+
+  // The time spent in each phase of the API requests, reported to the browsers if enabled
+  let server_timing = config.server_timing;
+
+  // GET /pokemon/daily
+  // Returns the Pokemon of the day, the same for all the replicas.
+  let daily_pokemon = Arc::new(daily::DailyPokemon::new());
+  let (pregenerated, pregenerate_state) = (daily_pokemon.clone(), state.clone());
+  jobs.spawn("daily", move |job| async move {
+    pregenerated.pregenerate(pregenerate_state, job).await;
+    Ok(())
+  });
+  let daily_trace_config = config.trace.clone();
+  let daily = warp::path!("pokemon" / "daily")
+    .and(methods::get_or_head())
+    .map(move || daily_pokemon.clone())
+    .and(with_state(state.clone()))
+    .and(trace_context::filter(config.trace.clone()))
+    .and_then(move |daily_pokemon, state, context| {
+      let trace_config = daily_trace_config.clone();
+      async move {
+        server_timing::collect(server_timing, trace_context::scope(context, &trace_config, timeout::limit(timeouts.pokemon, daily::handle_daily(daily_pokemon, state)))).await
+      }
+    })
+    .and_then(json_or_fail);
+
+  // GET /pokemon/compare?names={string},{string}
+  // Returns the translated descriptions and the stats of multiple Pokemon, side by side.
+  let max_name_length = config.max_name_length;
+  let compare_trace_config = config.trace.clone();
+  let compare = warp::path!("pokemon" / "compare")
+    .and(methods::get_or_head())
+    .and(warp::query::<pokemons::CompareQuery>())
+    .and(with_state(state.clone()))
+    .and(trace_context::filter(config.trace.clone()))
+    .and_then(move |query, state, context| {
+      let trace_config = compare_trace_config.clone();
+      async move {
+        server_timing::collect(server_timing, trace_context::scope(context, &trace_config, timeout::limit(timeouts.compare, pokemons::handle_compare(query, max_name_length, state)))).await
+      }
+    })
+    .and_then(json_or_fail);
+
+  // POST /pokemon/batch
+  // Returns the translated descriptions of many Pokemon, retries with the same idempotency key getting the same response.
+  let batch_store = Arc::new(idempotency::IdempotencyStore::new(config.idempotency_ttl));
+  let batch_trace_config = config.trace.clone();
+  let batch = warp::path!("pokemon" / "batch")
+    .and(warp::post())
+    .and(warp::header::optional::<String>(idempotency::IDEMPOTENCY_HEADER))
+    .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+    .and(warp::body::json())
+    .and(with_state(state.clone()))
+    .and(trace_context::filter(config.trace.clone()))
+    .and_then(move |key, request, state, context| {
+      let (store, trace_config) = (batch_store.clone(), batch_trace_config.clone());
+      async move {
+        let handler = idempotency::run(&store, key, request, |request| pokemons::handle_batch(request, max_name_length, state));
+        server_timing::collect(server_timing, trace_context::scope(context, &trace_config, timeout::limit(timeouts.batch, handler))).await
+      }
+    })
+    .and_then(json_or_fail);
+
+  // GET /pokemon/{string}?include_original={bool}&translator={string}
   // Returns the Shakespearean translation of the description of a Pokemon.
-  let get_pokemon = warp::path!("pokemon" / String)
+  let get_pokemon = pokemons::path(config.max_name_length)
+    .and(methods::get_or_head())
+    .and(warp::query::<pokemons::GetPokemonQuery>())
+    .and(warp::header::optional::<String>("x-translator"))
+    .and(with_state(state.clone()))
+    .and(trace_context::filter(config.trace.clone()))
+    .and_then(move |name, query, translator, state, context| {
+      let trace_config = trace_config.clone();
+      async move {
+        server_timing::collect(server_timing, trace_context::scope(context, &trace_config, timeout::limit(timeouts.pokemon, pokemons::handle_get_pokemon(name, query, translator, state)))).await
+      }
+    });
+
+  // POST /translate
+  // Translates arbitrary texts, for the clients with an API key.
+  let translate_auth = config.translate_api_keys.as_ref()
+    .map(|keys| Arc::new(translate::TranslateAuth::new(keys, config.translate_rate_limit)));
+  let translate_cap = config.translation_cap;
+  let translate_trace_config = config.trace.clone();
+  let translate = warp::path!("translate")
+    .and(warp::post())
+    .and_then(move || {
+      let auth = translate_auth.clone();
+      async move { auth.ok_or_else(warp::reject::not_found) }
+    })
+    .and(warp::header::optional::<String>("authorization"))
+    .and(warp::header::optional::<String>("x-translator"))
+    .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+    .and(warp::body::json())
     .and(with_state(state))
-    .and_then(pokemons::handle_get_pokemon)
+    .and(trace_context::filter(config.trace.clone()))
+    .and_then(move |auth, authorization, translator, request, state, context| {
+      let trace_config = translate_trace_config.clone();
+      async move {
+        let handler = translate::handle_translate(auth, translate_cap, authorization, translator, request, state);
+        server_timing::collect(server_timing, trace_context::scope(context, &trace_config, timeout::limit(timeouts.translate, handler))).await
+      }
+    })
     .and_then(json_or_fail);
 
-  health.or(metrics).or(get_pokemon)
-    .recover(errors::handle_rejection)
-    .boxed()
+  // OPTIONS and unsupported methods on all the known paths
+  let fallback = warp::path!("pokemon" / "batch").and(methods::fallback("POST, OPTIONS"))
+    .or(warp::path::end()
+    .or(warp::path!("health" / "ready")).unify()
+    .or(warp::path!("health")).unify()
+    .or(warp::path!("metrics")).unify()
+    .or(warp::path!("status")).unify()
+    .or(warp::path!("openapi.json")).unify()
+    .or(warp::path!("schemas" / String).map(|_| ()).untuple_one()).unify()
+    .or(warp::path!("admin" / "pokedex")).unify()
+    .or(warp::path!("admin" / "jobs")).unify()
+    .or(pokemons::path(config.max_name_length).map(|_| ()).untuple_one()).unify()
+    .and(methods::fallback(methods::ALLOWED_METHODS))).unify()
+    .or(warp::path!("translate").and(methods::fallback("POST, OPTIONS"))).unify()
+    .or(warp::path!("admin" / "cache" / "rehydrate").and(methods::fallback("POST, OPTIONS"))).unify()
+    .or(warp::path!("admin" / "degraded-mode").and(methods::fallback("PUT, OPTIONS"))).unify()
+    .or(warp::path!("admin" / "jobs" / String).map(|_| ()).untuple_one().and(methods::fallback("GET, HEAD, DELETE, OPTIONS"))).unify();
+
+  // Anything else is an unknown path, reported with the path itself to help the misconfigured clients
+  let catch_all = warp::path::full()
+    .and_then(|path: warp::path::FullPath| async move {
+      Err::<warp::reply::Response, Rejection>(warp::reject::custom(UnknownRoute(path.as_str().to_string())))
+    });
+
+  // Requests over the concurrency limit wait for a slot, which is held until the reply is built.
+  // The probes and the scrapes bypass the limit, so that they keep succeeding when the API is saturated.
+  let admission = config.admission.map(|limits| Arc::new(admission::Admission::new(limits)));
+  let admitted = admission::filter(admission)
+    .and(index.or(status).or(openapi).or(schemas).or(pokedex).or(list_jobs).or(get_job).or(cancel_job).or(degraded_mode).or(rehydrate).or(daily).or(compare).or(batch).or(get_pokemon).or(translate).or(fallback).or(catch_all))
+    .map(|permit, reply| {
+      drop(permit);
+      reply
+    });
+  let routes = health_ready.or(health).or(metrics).or(admitted)
+    .recover(move |err| errors::handle_rejection(err, sampler.clone()))
+    .with(warp::log::custom(move |info| {
+      // Only the API requests count against the objective
+      if info.path().starts_with("/pokemon/") || info.path() == "/translate" {
+        slo.record(info.status(), info.elapsed());
+        metrics::REQUEST_DURATION.with_label_values(&[metrics::route_label(info.path())]).observe(info.elapsed().as_secs_f64());
+        metrics::REQUEST_DURATION_SUMMARY.observe(info.elapsed().as_secs_f64());
+      }
+    }));
 
-}
\ No newline at end of file
+  // Rename the fields of the JSON responses, and wrap them in an envelope if requested
+  let casing = config.response_casing;
+  let routes = warp::path::full()
+    .and(routes)
+    .and_then(move |path, reply| casing::apply(casing, path, reply));
+  let envelope_default = config.response_envelope;
+  let routes = warp::header::optional::<String>(envelope::ENVELOPE_HEADER)
+    .and(routes)
+    .and_then(move |header, reply| envelope::wrap(envelope::enabled(envelope_default, header), reply));
+
+  // CORS preflight requests are answered before reaching the routes
+  match &config.cors_allowed_origins {
+    Some(origins) => {
+      let cors = warp::cors()
+        .allow_methods(vec!["GET", "HEAD", "POST"])
+        .allow_headers(vec!["traceparent", "tracestate", "b3", "x-b3-traceid", "x-b3-spanid", "x-b3-parentspanid", "x-b3-sampled", "x-b3-flags", "authorization", "x-translator", "content-type", idempotency::IDEMPOTENCY_HEADER, envelope::ENVELOPE_HEADER]);
+      let cors = if origins.iter().any(|origin| origin == "*") {
+        cors.allow_any_origin()
+      } else {
+        cors.allow_origins(origins.iter().map(String::as_str))
+      };
+      routes.with(cors).map(|reply| Box::new(reply) as Box<dyn Reply>).boxed()
+    },
+    None => routes.map(|reply| Box::new(reply) as Box<dyn Reply>).boxed()
+  }
+
+}
+#[cfg(test)]
+mod test {
+  use super::*;
+  use warp::http::StatusCode;
+  use crate::routes::errors::Unauthorized;
+
+  #[tokio::test]
+  async fn test_with_admin_auth() {
+
+    let auth = Arc::new(admin::AdminAuth::new(&["secret".to_string()]));
+    let route = warp::path!("admin").and(with_admin_auth(Some(auth))).map(warp::reply);
+    let res = warp::test::request().path("/admin").header("authorization", "Bearer secret").reply(&route).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let err = warp::test::request().path("/admin").header("authorization", "Bearer wrong").filter(&route).await.err().unwrap();
+    assert!(err.find::<Unauthorized>().is_some());
+
+    // Without any key configured, the route does not exist
+    let route = warp::path!("admin").and(with_admin_auth(None)).map(warp::reply);
+    let err = warp::test::request().path("/admin").filter(&route).await.err().unwrap();
+    assert!(err.is_not_found());
+
+  }
+
+}
@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::JsonSchema;
+use serde_json::{json, Value};
+
+use crate::cli::{ExportOpenApiArgs, OpenApiFormat};
+use crate::routes::daily::DailyPokemonResponse;
+use crate::routes::errors::ErrorBody;
+use crate::routes::json::PROBLEM_JSON;
+use crate::routes::pokemons::{BatchRequest, BatchResponse, CompareResponse, GetPokemonReponse};
+use crate::routes::translate::{TranslateRequest, TranslateResponse};
+
+/// Describes a JSON response, referencing the schema of its body.
+fn json_response<T: JsonSchema>(generator: &mut SchemaGenerator, description: &str) -> Value {
+  response::<T>(generator, "application/json", description)
+}
+
+/// Describes a response of the given content type, referencing the schema of its body.
+fn response<T: JsonSchema>(generator: &mut SchemaGenerator, content_type: &str, description: &str) -> Value {
+  json!({
+    "description": description,
+    "content": { content_type: { "schema": generator.subschema_for::<T>() } }
+  })
+}
+
+/// Builds the OpenAPI 3 document of the public API, the schemas of the bodies being generated from the Rust types.
+pub fn document() -> Value {
+
+  let mut generator = SchemaSettings::openapi3().into_generator();
+  let error = response::<ErrorBody>(&mut generator, PROBLEM_JSON, "Error");
+  let responses = |body: Value| {
+    json!({ "200": body, "4XX": error.clone(), "5XX": error.clone() })
+  };
+  let name = json!({ "name": "name", "in": "path", "required": true, "schema": { "type": "string" } });
+  let translator_header = json!({ "name": "X-Translator", "in": "header", "schema": { "type": "string" } });
+
+  let mut paths = BTreeMap::new();
+  paths.insert("/pokemon/{name}", json!({
+    "get": {
+      "summary": "Translated description of a Pokemon",
+      "parameters": [
+        name,
+        { "name": "include_original", "in": "query", "schema": { "type": "boolean", "default": false } },
+        { "name": "flavor", "in": "query", "schema": { "type": "string", "enum": ["plain", "prose"], "default": "plain" } },
+        { "name": "expand", "in": "query", "schema": { "type": "boolean", "default": false } },
+        { "name": "translator", "in": "query", "schema": { "type": "string" } },
+        translator_header.clone()
+      ],
+      "responses": responses(json_response::<GetPokemonReponse>(&mut generator, "Translated description"))
+    }
+  }));
+  paths.insert("/pokemon/compare", json!({
+    "get": {
+      "summary": "Translated descriptions and base stats of multiple Pokemon",
+      "parameters": [
+        { "name": "names", "in": "query", "required": true, "description": "Comma separated list of 2 to 6 names", "schema": { "type": "string" } }
+      ],
+      "responses": responses(json_response::<CompareResponse>(&mut generator, "Compared Pokemon"))
+    }
+  }));
+  let batch_request = generator.subschema_for::<BatchRequest>();
+  paths.insert("/pokemon/batch", json!({
+    "post": {
+      "summary": "Translated descriptions of up to 20 Pokemon",
+      "parameters": [
+        { "name": "Idempotency-Key", "in": "header", "description": "Retries with the same key get the same response", "schema": { "type": "string" } }
+      ],
+      "requestBody": { "required": true, "content": { "application/json": { "schema": batch_request } } },
+      "responses": responses(json_response::<BatchResponse>(&mut generator, "Translated Pokemon"))
+    }
+  }));
+  paths.insert("/pokemon/daily", json!({
+    "get": {
+      "summary": "Pokemon of the day",
+      "responses": responses(json_response::<DailyPokemonResponse>(&mut generator, "Pokemon of the day"))
+    }
+  }));
+  let translate_request = generator.subschema_for::<TranslateRequest>();
+  paths.insert("/translate", json!({
+    "post": {
+      "summary": "Translation of an arbitrary text",
+      "security": [{ "apiKey": [] }],
+      "parameters": [translator_header],
+      "requestBody": { "required": true, "content": { "application/json": { "schema": translate_request } } },
+      "responses": responses(json_response::<TranslateResponse>(&mut generator, "Translated text"))
+    }
+  }));
+  paths.insert("/health", json!({
+    "get": { "summary": "Liveness probe", "responses": { "200": { "description": "Alive" } } }
+  }));
+  paths.insert("/health/ready", json!({
+    "get": {
+      "summary": "Readiness probe, checking the dependencies",
+      "responses": { "200": { "description": "Ready" }, "503": { "description": "Not ready" } }
+    }
+  }));
+  paths.insert("/metrics", json!({
+    "get": {
+      "summary": "Prometheus metrics",
+      "responses": { "200": { "description": "Metrics", "content": { "text/plain": { "schema": { "type": "string" } } } } }
+    }
+  }));
+
+  json!({
+    "openapi": "3.0.3",
+    "info": {
+      "title": "Shakespearean Pokemon API",
+      "version": env!("CARGO_PKG_VERSION")
+    },
+    "paths": paths,
+    "components": {
+      "schemas": generator.take_definitions(),
+      "securitySchemes": { "apiKey": { "type": "http", "scheme": "bearer" } }
+    }
+  })
+
+}
+
+/// Writes the OpenAPI document to the requested file, or to the standard output.
+pub fn export(args: ExportOpenApiArgs) -> Result<()> {
+
+  let document = document();
+  let serialized = match args.format {
+    OpenApiFormat::Json => format!("{}\n", serde_json::to_string_pretty(&document)?),
+    OpenApiFormat::Yaml => serde_yaml::to_string(&document)?
+  };
+
+  match args.output {
+    Some(path) => std::fs::write(path, serialized)?,
+    None => print!("{}", serialized)
+  }
+  Ok(())
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_document() {
+    let document = document();
+    assert!(document["paths"]["/pokemon/{name}"]["get"].is_object());
+
+    // All the references point to the components
+    let reference = document["paths"]["/translate"]["post"]["responses"]["200"]["content"]["application/json"]["schema"]["$ref"].as_str().unwrap();
+    assert_eq!(reference, "#/components/schemas/TranslateResponse");
+    for name in ["GetPokemonReponse", "CompareResponse", "ComparedPokemon", "BatchRequest", "BatchResponse", "DailyPokemonResponse", "TranslateRequest", "TranslateResponse", "ErrorBody"] {
+      assert!(document["components"]["schemas"][name].is_object(), "Missing schema {}", name);
+    }
+  }
+
+}
@@ -1,70 +1,580 @@
-use serde::Serialize;
-use tracing::debug;
-use warp::Rejection;
+use std::sync::Arc;
 
+use futures::future::try_join_all;
+use lazy_static::lazy_static;
+use schemars::JsonSchema;
+use serde::{Serialize, Deserialize};
+use tracing::warn;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::cache::{CacheEntry, Keyspace, PopulateResult, SharedError};
+use crate::clients::Translator;
+use crate::clients::pokemon::{PokemonProfile, PokemonStats};
+use crate::health::DegradedModeEnabled;
 use crate::metrics;
+use crate::request_stats;
 use crate::routes::State;
-use crate::routes::errors::CustomRejection;
+use crate::routes::errors::{BadRequest, CustomRejection, NameTooLong, PokemonNotFound};
+use crate::routes::translate::select_translator;
+
+/// Reasons why a description is not of full quality.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Degradation {
+  /// The translation expired, and could not be refreshed.
+  Stale,
+  /// The description could not be translated, and is returned as is.
+  Untranslated
+}
+
+impl Degradation {
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Degradation::Stale => "stale",
+      Degradation::Untranslated => "untranslated"
+    }
+  }
+
+  /// Value of the `Warning` header describing the degradation.
+  fn warning(&self) -> &'static str {
+    match self {
+      Degradation::Stale => "110 - \"Response is Stale\"",
+      Degradation::Untranslated => "199 - \"Description not translated\""
+    }
+  }
 
-#[derive(Serialize)]
+}
+
+#[derive(Serialize, JsonSchema)]
 pub struct GetPokemonReponse {
+  name: String,
+  description: String,
+  /// Name of the translator of the description, missing if it is not translated.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  translator: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  original_description: Option<String>,
+  /// Genus of the species, like `Seed Pokémon`, only with `?expand=true`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  genus: Option<String>,
+  /// Types of the Pokemon, primary first, only with `?expand=true`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  types: Option<Vec<String>>,
+  /// Base stats of the Pokemon, only with `?expand=true`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  stats: Option<PokemonStats>,
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  degraded: bool,
+  #[serde(skip)]
+  degradation: Option<Degradation>
+}
+
+impl Reply for GetPokemonReponse {
+  fn into_response(self) -> Response {
+    match self.degradation {
+      Some(degradation) => warp::reply::with_header(warp::reply::json(&self), "Warning", degradation.warning()).into_response(),
+      None => warp::reply::json(&self).into_response()
+    }
+  }
+}
+
+/// How the description is presented.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Flavor {
+  /// Just the translated description.
+  #[default]
+  Plain,
+  /// The description framed by a sentence mentioning the habitat and the types of the Pokemon.
+  Prose
+}
+
+/// Query parameters accepted by the `GET /pokemon/{name}` route.
+#[derive(Deserialize, Default)]
+pub struct GetPokemonQuery {
+  #[serde(default)]
+  include_original: bool,
+  #[serde(default)]
+  flavor: Flavor,
+  /// Whether to include the types and the base stats.
+  #[serde(default)]
+  expand: bool,
+  /// Name of the translator, taking precedence over the `X-Translator` header.
+  translator: Option<String>
+}
+
+/// Minimum and maximum number of Pokemon accepted by the `GET /pokemon/compare` route.
+const MIN_COMPARED: usize = 2;
+const MAX_COMPARED: usize = 6;
+
+lazy_static! {
+  static ref COMPARED_NAMES_MESSAGE: String = format!("Between {} and {} names must be given", MIN_COMPARED, MAX_COMPARED);
+  static ref BATCHED_NAMES_MESSAGE: String = format!("Between 1 and {} names must be given", MAX_BATCHED);
+}
+
+/// Query parameters accepted by the `GET /pokemon/compare` route.
+#[derive(Deserialize)]
+pub struct CompareQuery {
+  /// Comma separated list of names.
+  #[serde(default)]
+  names: String
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ComparedPokemon {
+  name: String,
+  description: String,
+  stats: PokemonStats
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct CompareResponse {
+  pokemon: Vec<ComparedPokemon>
+}
+
+/// Maximum number of names suggested when a Pokemon is not found.
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Maximum number of Pokemon accepted by the `POST /pokemon/batch` route.
+const MAX_BATCHED: usize = 20;
+
+/// Body of the `POST /pokemon/batch` route.
+#[derive(Hash, Deserialize, JsonSchema)]
+pub struct BatchRequest {
+  names: Vec<String>
+}
+
+#[derive(Clone, Serialize, JsonSchema)]
+pub struct BatchedPokemon {
   name: String,
   description: String
 }
 
+#[derive(Clone, Serialize, JsonSchema)]
+pub struct BatchResponse {
+  pokemon: Vec<BatchedPokemon>,
+  /// Names which do not match any Pokemon.
+  not_found: Vec<String>
+}
+
+/// Normalizes a Pokemon name to the form used by the PokeAPI, like `mr-mime` for `Mr. Mime`.
+fn normalize_name(name: &str) -> String {
+  let mut normalized = String::with_capacity(name.len());
+  for c in name.trim().to_lowercase().chars() {
+    match c {
+      ' ' | '_' => normalized.push('-'),
+      '.' | '\'' | '’' | ':' => {},
+      '♀' => normalized.push_str("-f"),
+      '♂' => normalized.push_str("-m"),
+      'é' | 'è' | 'ê' => normalized.push('e'),
+      c => normalized.push(c)
+    }
+  }
+
+  // Collapse the dashes introduced by the replacements, like in `mr. mime`
+  normalized.split('-').filter(|part| !part.is_empty()).collect::<Vec<_>>().join("-")
+}
+
+/// Matches the `/pokemon/{name}` path, with or without a trailing slash.
+/// Names are percent-decoded and normalized before reaching the handlers, as they are case insensitive.
+/// Names longer than `max_length` (before decoding) are rejected without being forwarded upstream.
+pub fn path(max_length: usize) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+  warp::path!("pokemon" / String)
+    .and_then(move |name: String| async move {
+      if name.len() > max_length {
+        return Err(warp::reject::custom(NameTooLong));
+      }
+      percent_encoding::percent_decode_str(&name)
+        .decode_utf8()
+        .map(|name| normalize_name(&name))
+        .map_err(|_| warp::reject::custom(BadRequest("Invalid Pokemon name")))
+    })
+}
+
 /// Handler for the `GET /pokemon/{name}` route.
-pub async fn handle_get_pokemon(pokemon_name: String, state: State) -> std::result::Result<GetPokemonReponse, Rejection> {
+pub async fn handle_get_pokemon(pokemon_name: String, query: GetPokemonQuery, translator_header: Option<String>, state: State) -> std::result::Result<GetPokemonReponse, Rejection> {
+
+  request_stats::record(|stats| stats.pokemon = Some(pokemon_name.clone()));
+  let translator = select_translator(&state.translators, query.translator.as_deref(), translator_header.as_deref())?;
+
+  // Look for a cached translation, or compute it.
+  // Return a 404 if no pokemon has been found, and a best-effort description if the translation failed.
+  let (description, translator, degradation) = match get_translation(&pokemon_name, &**translator, &state).await {
+    Ok(Some(translated)) => (translated.text, translated.translator, None),
+    Ok(None) => return Err(not_found(pokemon_name, &state)),
+    Err(e) => {
+      let (description, translator, degradation) = degraded_description(&pokemon_name, &**translator, &state).await
+        .ok_or_else(|| CustomRejection::shared(e.clone()))?;
+      warn!(error = %e, degradation = degradation.name(), "Serving a degraded description");
+      metrics::DEGRADED_RESPONSES.with_label_values(&[degradation.name()]).inc();
+      (description, translator, Some(degradation))
+    }
+  };
+
+  // Frame the description for the demo page, keeping it plain if the habitat and the types cannot be fetched
+  let description = match query.flavor {
+    Flavor::Plain => description,
+    Flavor::Prose => match get_profile(&pokemon_name, &state).await {
+      Ok(Some(profile)) => state.prose_templates.render(&pokemon_name, &profile, &description),
+      Ok(None) => description,
+      Err(e) => {
+        warn!(error = %e, "Cannot frame the description");
+        description
+      }
+    }
+  };
+
+  // The original description comes from its own cache, so it's usually free
+  let original_description = if query.include_original {
+    get_original_description(&pokemon_name, &state).await
+      .map_err(|e| CustomRejection::shared(e))?
+  } else {
+    None
+  };
+
+  // The profile brings the stats into the cache, so they are fetched right after it
+  let (genus, types, stats) = if query.expand {
+    let profile = get_profile(&pokemon_name, &state).await
+      .map_err(|e| CustomRejection::shared(e))?;
+    let stats = get_stats(&pokemon_name, &state).await
+      .map_err(|e| CustomRejection::shared(e))?;
+    match profile {
+      Some(profile) => (profile.genus, Some(profile.types), stats),
+      None => (None, None, stats)
+    }
+  } else {
+    (None, None, None)
+  };
+
+  Ok(GetPokemonReponse {
+    name: pokemon_name,
+    description,
+    translator,
+    original_description,
+    genus,
+    types,
+    stats,
+    degraded: degradation.is_some(),
+    degradation
+  })
+
+}
+
+/// Rejects a name which does not match any Pokemon, suggesting the closest names already looked up,
+/// as they are most likely what was meant.
+fn not_found(name: String, state: &State) -> Rejection {
+  let max_distance = (name.chars().count() / 3).clamp(1, 3);
+  let mut suggestions = state.cache.local_keys(Keyspace::Descriptions).into_iter()
+    .filter(|candidate| *candidate != name)
+    .map(|candidate| (edit_distance(&name, &candidate), candidate))
+    .filter(|(distance, _)| *distance <= max_distance)
+    .collect::<Vec<_>>();
+  suggestions.sort();
+  warp::reject::custom(PokemonNotFound {
+    name,
+    suggestions: suggestions.into_iter().take(MAX_SUGGESTIONS).map(|(_, candidate)| candidate).collect()
+  })
+}
 
-  // Before sending the request, check if we have a cached description
-  if let Some(cached) = state.cache.lock().unwrap().get(&pokemon_name) {
-    debug!("Cache hit");
-    metrics::CACHE_HITS.inc();
-    return Ok(GetPokemonReponse {
-      name: pokemon_name,
-      description: cached.clone()
-    });
+/// Levenshtein distance between two names.
+fn edit_distance(a: &str, b: &str) -> usize {
+  let b = b.chars().collect::<Vec<_>>();
+  let mut previous = (0..=b.len()).collect::<Vec<_>>();
+  for (i, ca) in a.chars().enumerate() {
+    let mut current = vec![i + 1; b.len() + 1];
+    for (j, cb) in b.iter().enumerate() {
+      let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+      current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+    }
+    previous = current;
   }
+  previous[b.len()]
+}
 
-  // First step: get the description of the pokemon
-  let description = state.pokemon_client.get_pokemon_description(&pokemon_name).await
-    .map_err(CustomRejection::new)?;
+/// Looks for a best-effort description when the translation fails:
+/// an expired translation if the cache still has it, or the untranslated description if the fallback is enabled.
+/// The name of the translator is returned too, if the description is translated.
+async fn degraded_description(pokemon_name: &str, translator: &dyn Translator, state: &State) -> Option<(String, Option<String>, Degradation)> {
+
+  if let Some(stale) = state.cache.get_stale(Keyspace::Translations, &translation_key(pokemon_name, translator, state)) {
+    return Some((stale.text, stale.translator, Degradation::Stale));
+  }
 
-  match description {
-    None => {
+  if state.untranslated_fallback || state.degraded_mode.is_enabled() {
+    if let Ok(Some(description)) = get_original_description(pokemon_name, state).await {
+      return Some((description, None, Degradation::Untranslated));
+    }
+  }
 
-      // Return a 404 if no pokemon has been found
-      Err(warp::reject::not_found())
+  None
 
-    },
-    Some(description) => {
+}
+
+/// Handler for the `GET /pokemon/compare?names={name},{name}` route.
+/// All the Pokemon are fetched concurrently, and the request fails if any of them cannot be found.
+pub async fn handle_compare(query: CompareQuery, max_name_length: usize, state: State) -> std::result::Result<CompareResponse, Rejection> {
+
+  let names = query.names.split(',')
+    .map(str::trim)
+    .filter(|name| !name.is_empty())
+    .collect::<Vec<_>>();
+  if names.len() < MIN_COMPARED || names.len() > MAX_COMPARED {
+    return Err(warp::reject::custom(BadRequest(&COMPARED_NAMES_MESSAGE)));
+  }
+  if names.iter().any(|name| name.len() > max_name_length) {
+    return Err(warp::reject::custom(NameTooLong));
+  }
+
+  let pokemon = try_join_all(names.into_iter().map(|name| {
+    let state = &state;
+    async move {
+      let name = normalize_name(name);
+      let (translated, stats) = futures::try_join!(get_translation(&name, &**state.translators.primary(), state), get_stats(&name, state))
+        .map_err(|e| CustomRejection::shared(e))?;
+      match (translated, stats) {
+        (Some(translated), Some(stats)) => Ok(ComparedPokemon { name, description: translated.text, stats }),
+        _ => Err(not_found(name, state))
+      }
+    }
+  })).await?;
+
+  Ok(CompareResponse { pokemon })
+
+}
 
-      // Translate the description and compose the final reply
-      let translated = state.shakespeare_client.translate(&description).await
-        .map_err(CustomRejection::new)?;
+/// Handler for the `POST /pokemon/batch` route.
+/// All the Pokemon are translated concurrently, and the ones which cannot be found are listed apart.
+pub async fn handle_batch(request: BatchRequest, max_name_length: usize, state: State) -> std::result::Result<BatchResponse, Rejection> {
 
-      // Cache the computed result
-      state.cache.lock().unwrap().put(pokemon_name.clone(), translated.as_str().to_string());
+  if request.names.is_empty() || request.names.len() > MAX_BATCHED {
+    return Err(warp::reject::custom(BadRequest(&BATCHED_NAMES_MESSAGE)));
+  }
+  if request.names.iter().any(|name| name.len() > max_name_length) {
+    return Err(warp::reject::custom(NameTooLong));
+  }
 
-      Ok(GetPokemonReponse {
-        name: pokemon_name,
-        description: translated.into_str()
-      })
-      
+  let translated = try_join_all(request.names.iter().map(|name| {
+    let state = &state;
+    async move {
+      let name = normalize_name(name);
+      let translated = get_translation(&name, &**state.translators.primary(), state).await.map_err(|e| CustomRejection::shared(e))?;
+      Ok::<_, Rejection>((name, translated))
     }
+  })).await?;
+
+  let mut response = BatchResponse { pokemon: Vec::new(), not_found: Vec::new() };
+  for (name, translated) in translated {
+    match translated {
+      Some(translated) => response.pokemon.push(BatchedPokemon { name, description: translated.text }),
+      None => response.not_found.push(name)
+    }
+  }
+  Ok(response)
+
+}
+
+/// Key of the cached translation of a Pokemon.
+/// The translations of the primary translator are keyed by the name alone, and the ones of the others by the name
+/// followed by the one of their translator, like `pikachu@openai`.
+fn translation_key(pokemon_name: &str, translator: &dyn Translator, state: &State) -> String {
+  if translator.name() == state.translators.primary().name() {
+    pokemon_name.to_string()
+  } else {
+    format!("{}@{}", pokemon_name, translator.name())
+  }
+}
+
+/// Returns the description of a Pokemon translated by the given translator, looking at the cache before translating it.
+pub async fn get_translation(pokemon_name: &str, translator: &dyn Translator, state: &State) -> std::result::Result<Option<CacheEntry>, SharedError> {
+
+  let translated = state.cache
+    .get_or_populate(Keyspace::Translations, &translation_key(pokemon_name, translator, state), || translate_description(pokemon_name, translator, state))
+    .await?;
+  if let Some(translated) = &translated {
+    request_stats::record(|stats| stats.translator = translated.translator.clone());
   }
+  Ok(translated)
+
+}
+
+/// Returns the base stats of a Pokemon, looking at the cache before contacting the PokeAPI.
+async fn get_stats(pokemon_name: &str, state: &State) -> std::result::Result<Option<PokemonStats>, SharedError> {
+
+  let stats = state.cache
+    .get_or_populate(Keyspace::Stats, pokemon_name, || async {
+      match state.pokemon_client.get_pokemon_stats(pokemon_name).await? {
+        Some(stats) => Ok(Some(CacheEntry::data(&stats)?)),
+        None => Ok(None)
+      }
+    })
+    .await?;
+
+  Ok(stats.map(|entry| entry.parse_data()).transpose()?)
+
+}
+
+/// Returns the habitat and the types of a Pokemon, looking at the cache before contacting the PokeAPI.
+/// The base stats come with them, and are cached as well.
+async fn get_profile(pokemon_name: &str, state: &State) -> std::result::Result<Option<PokemonProfile>, SharedError> {
+
+  let profile = state.cache
+    .get_or_populate(Keyspace::Profiles, pokemon_name, || async {
+      match state.pokemon_client.get_pokemon_profile(pokemon_name).await? {
+        Some((mut profile, stats)) => {
+          state.cache.put(Keyspace::Stats, pokemon_name.to_string(), CacheEntry::data(&stats)?).await;
+
+          // A missing translation is not worth failing the whole profile
+          if let (true, Some(genus)) = (state.translate_genus, &profile.genus) {
+            match state.degraded_mode.translate(&**state.translators.primary(), genus).await {
+              Ok(translated) => profile.genus = Some(state.text_pipeline.apply(&translated)),
+              Err(e) => warn!(error = %e, "Cannot translate the genus")
+            }
+          }
+
+          Ok(Some(CacheEntry::data(&profile)?))
+        },
+        None => Ok(None)
+      }
+    })
+    .await?;
+
+  Ok(profile.map(|entry| entry.parse_data()).transpose()?)
+
+}
+
+/// Fetches the description of a Pokemon and translates it.
+async fn translate_description(pokemon_name: &str, translator: &dyn Translator, state: &State) -> PopulateResult {
+
+  if state.degraded_mode.is_forced() {
+    return Err(Arc::new(DegradedModeEnabled.into()));
+  }
+
+  // First step: get the description of the pokemon
+  let description = match get_original_description(pokemon_name, state).await? {
+    Some(description) => description,
+    None => return Ok(None)
+  };
+
+  // Translate the description and post-process it
+  let translated = state.degraded_mode.translate(translator, &description).await?;
+  let mut translated = state.text_pipeline.apply(&translated);
+  if let Some(filter) = &state.profanity_filter {
+    translated = filter.mask(&translated);
+  }
+
+  Ok(Some(CacheEntry::translated(translator.name(), "en", translated)))
+
+}
+
+/// Fetches the description of a Pokemon from the PokeAPI.
+/// Names of forms and varieties, like `giratina-origin`, are not species: the description of their species is returned instead.
+/// Their species is cached, so that they are resolved only once.
+async fn fetch_description(pokemon_name: &str, state: &State) -> anyhow::Result<Option<String>> {
+
+  if let Some(species) = state.cache.get(Keyspace::Species, pokemon_name).await {
+    return state.pokemon_client.get_pokemon_description(&species.parse_data::<String>()?).await;
+  }
+
+  if let Some(description) = state.pokemon_client.get_pokemon_description(pokemon_name).await? {
+    return Ok(Some(description));
+  }
+
+  match state.pokemon_client.get_species_name(pokemon_name).await? {
+    Some(species) if species != pokemon_name => {
+      state.cache.put(Keyspace::Species, pokemon_name.to_string(), CacheEntry::data(&species)?).await;
+      state.pokemon_client.get_pokemon_description(&species).await
+    },
+    _ => Ok(None)
+  }
+
+}
+
+/// Returns the untranslated description of a Pokemon, looking at the cache before contacting the PokeAPI.
+async fn get_original_description(pokemon_name: &str, state: &State) -> std::result::Result<Option<String>, SharedError> {
+
+  let description = state.cache
+    .get_or_populate(Keyspace::Descriptions, pokemon_name, || async {
+      let description = fetch_description(pokemon_name, state).await?;
+      Ok(description.map(|d| CacheEntry::original("en", d)))
+    })
+    .await?;
+
+  // Clean up the description before handing it out
+  Ok(description.map(|d| state.text_pipeline.apply(&d.text)))
 
 }
 
 #[cfg(test)]
 mod test {
   use super::*;
-  use crate::clients::{PokemonClient, ShakespeareClient};
-  use std::sync::{Arc, Mutex};
-  use httpmock::{MockServer, Method};
-  use lru::LruCache;
+  use crate::cache::{Cache, Ttl};
+  use crate::cache::memory::MemoryCache;
+  use crate::clients::{PokemonClient, ShakespeareClient, Translators};
+  use crate::pipeline::TextPipeline;
+  use crate::prose::ProseTemplates;
+  use std::sync::Arc;
+  use std::time::Duration;
+  use httpmock::{MockServer, MockRef, Method};
   use regex::Regex;
   use serde_json::json;
 
+  async fn mock_pokemon_api(server: &MockServer) -> MockRef<'_> {
+    server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path_matches(Regex::new("^/pokemon-species/").unwrap());
+      then.status(200)
+        .json_body(json!({
+          "flavor_text_entries": [
+            {
+              "flavor_text": "This one!",
+              "language": {
+                "name": "en"
+              }
+            }
+          ]
+        }));
+    }).await
+  }
+
+  async fn mock_shakespeare_api(server: &MockServer, status: u16) -> MockRef<'_> {
+    server.mock_async(|when, then| {
+      when.method(Method::POST)
+        .path("/translate/shakespeare.json")
+        .body(
+          form_urlencoded::Serializer::new(String::new())
+            .append_pair("text", "This one!")
+            .finish()
+        );
+      if status == 200 {
+        then.status(200)
+          .json_body(json!({
+            "contents": {
+              "translated": "Mocked translation",
+              "text": "This one!"
+            }
+          }));
+      } else {
+        then.status(status)
+          .body("Internal server error");
+      }
+    }).await
+  }
+
+  fn build_state(server: &MockServer) -> State {
+    State {
+      pokemon_client: PokemonClient::builder().base_url(&server.base_url()).build().unwrap(),
+      translators: Translators::new(Arc::new(ShakespeareClient::builder().base_url(&server.base_url()).build().unwrap())),
+      cache: Arc::new(Cache::new(MemoryCache::new(1, 1))),
+      text_pipeline: TextPipeline::default(),
+      profanity_filter: None,
+      prose_templates: ProseTemplates::with_default_templates().unwrap(),
+      untranslated_fallback: false,
+      translate_genus: false,
+      degraded_mode: Default::default()
+    }
+  }
+
   #[tokio::test]
   async fn test_caching_behaviour() {
 
@@ -106,36 +616,403 @@ mod test {
 
     // Build the app state
     let state = State {
-      pokemon_client: PokemonClient::new(&server.base_url()).unwrap(),
-      shakespeare_client: ShakespeareClient::new(&server.base_url()).unwrap(),
-      cache: Arc::new(Mutex::new(LruCache::new(1)))
+      pokemon_client: PokemonClient::builder().base_url(&server.base_url()).build().unwrap(),
+      translators: Translators::new(Arc::new(ShakespeareClient::builder().base_url(&server.base_url()).build().unwrap())),
+      cache: Arc::new(Cache::new(MemoryCache::new(1, 1))),
+      text_pipeline: TextPipeline::default(),
+      profanity_filter: None,
+      prose_templates: ProseTemplates::with_default_templates().unwrap(),
+      untranslated_fallback: false,
+      translate_genus: false,
+      degraded_mode: Default::default()
     };
 
     // Perform the first request.
     // The first request will go through, since its the first one.
-    assert_eq!(handle_get_pokemon("pikachu".to_string(), state.clone()).await.unwrap().description, "Mocked translation");
+    assert_eq!(handle_get_pokemon("pikachu".to_string(), GetPokemonQuery::default(), None, state.clone()).await.unwrap().description, "Mocked translation");
     pokemon_mock.assert_hits(1);
     shakespeare_mock.assert_hits(1);
 
     // Now perform the same request and assert that the backend APIs have not been contacted a second time
-    assert_eq!(handle_get_pokemon("pikachu".to_string(), state.clone()).await.unwrap().description, "Mocked translation");
+    assert_eq!(handle_get_pokemon("pikachu".to_string(), GetPokemonQuery::default(), None, state.clone()).await.unwrap().description, "Mocked translation");
     pokemon_mock.assert_hits(1);
     shakespeare_mock.assert_hits(1);
 
     // Ask for the description of another pokemon
-    assert_eq!(handle_get_pokemon("bulbasaur".to_string(), state.clone()).await.unwrap().description, "Mocked translation");
+    assert_eq!(handle_get_pokemon("bulbasaur".to_string(), GetPokemonQuery::default(), None, state.clone()).await.unwrap().description, "Mocked translation");
     pokemon_mock.assert_hits(2);
     shakespeare_mock.assert_hits(2);
 
     // Now the second pokemon is cached
-    assert_eq!(handle_get_pokemon("bulbasaur".to_string(), state.clone()).await.unwrap().description, "Mocked translation");
+    assert_eq!(handle_get_pokemon("bulbasaur".to_string(), GetPokemonQuery::default(), None, state.clone()).await.unwrap().description, "Mocked translation");
     pokemon_mock.assert_hits(2);
     shakespeare_mock.assert_hits(2);
 
     // And if we ask for the first one, another request is fired bacause the cache is for only one item
-    assert_eq!(handle_get_pokemon("pikachu".to_string(), state.clone()).await.unwrap().description, "Mocked translation");
+    assert_eq!(handle_get_pokemon("pikachu".to_string(), GetPokemonQuery::default(), None, state.clone()).await.unwrap().description, "Mocked translation");
+    pokemon_mock.assert_hits(3);
+    shakespeare_mock.assert_hits(3);
+
+    // The original description is served from the cache when requested
+    let res = handle_get_pokemon("pikachu".to_string(), GetPokemonQuery { include_original: true, ..GetPokemonQuery::default() }, None, state.clone()).await.unwrap();
+    assert_eq!(res.description, "Mocked translation");
+    assert_eq!(res.original_description.as_deref(), Some("This one!"));
     pokemon_mock.assert_hits(3);
     shakespeare_mock.assert_hits(3);
 
   }
-}
\ No newline at end of file
+
+  #[tokio::test]
+  async fn test_translation_retry_reuses_description() {
+
+    // Translator is down at first
+    let server = MockServer::start_async().await;
+    let pokemon_mock = mock_pokemon_api(&server).await;
+    let failing_mock = mock_shakespeare_api(&server, 500).await;
+    let state = build_state(&server);
+
+    assert!(handle_get_pokemon("pikachu".to_string(), GetPokemonQuery::default(), None, state.clone()).await.is_err());
+    pokemon_mock.assert_hits(1);
+    failing_mock.assert_hits(1);
+
+    // When the translator comes back, the description is not fetched again
+    failing_mock.delete_async().await;
+    let shakespeare_mock = mock_shakespeare_api(&server, 200).await;
+    assert_eq!(handle_get_pokemon("pikachu".to_string(), GetPokemonQuery::default(), None, state.clone()).await.unwrap().description, "Mocked translation");
+    pokemon_mock.assert_hits(1);
+    shakespeare_mock.assert_hits(1);
+
+  }
+
+  #[tokio::test]
+  async fn test_translator_selection() {
+
+    let server = MockServer::start_async().await;
+    mock_pokemon_api(&server).await;
+    let shakespeare = mock_shakespeare_api(&server, 200).await;
+    let openai = server.mock_async(|when, then| {
+      when.method(Method::POST).path("/chat/completions");
+      then.status(200).json_body(json!({
+        "choices": [{ "index": 0, "message": { "role": "assistant", "content": "Thou art this one" } }]
+      }));
+    }).await;
+    let state = build_state(&server);
+    let state = State {
+      translators: state.translators.clone().with(Arc::new(crate::clients::openai::OpenAiClient::new(&server.base_url()).unwrap())),
+      cache: Arc::new(Cache::new(MemoryCache::new(2, 2))),
+      ..state
+    };
+    let query = |translator: Option<&str>| GetPokemonQuery { translator: translator.map(str::to_string), ..GetPokemonQuery::default() };
+
+    // The header selects the translator, and the query parameter takes precedence over it
+    let res = handle_get_pokemon("pikachu".to_string(), query(None), Some("openai".to_string()), state.clone()).await.unwrap();
+    assert_eq!((res.description.as_str(), res.translator.as_deref()), ("Thou art this one", Some("openai")));
+    let res = handle_get_pokemon("pikachu".to_string(), query(Some("shakespeare")), Some("openai".to_string()), state.clone()).await.unwrap();
+    assert_eq!((res.description.as_str(), res.translator.as_deref()), ("Mocked translation", Some("shakespeare")));
+
+    // Each translation is cached on its own
+    handle_get_pokemon("pikachu".to_string(), query(Some("openai")), None, state.clone()).await.unwrap();
+    handle_get_pokemon("pikachu".to_string(), query(None), None, state.clone()).await.unwrap();
+    openai.assert_hits(1);
+    shakespeare.assert_hits(1);
+
+    let err = handle_get_pokemon("pikachu".to_string(), query(Some("yoda")), None, state).await.err().unwrap();
+    assert!(err.find::<BadRequest>().is_some());
+
+  }
+
+  #[tokio::test]
+  async fn test_prose_flavor() {
+
+    let server = MockServer::start_async().await;
+    let profile_mock = server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon/pikachu");
+      then.status(200)
+        .json_body(json!({
+          "stats": [{ "base_stat": 35, "stat": { "name": "hp" } }],
+          "types": [{ "slot": 1, "type": { "name": "electric" } }],
+          "species": { "name": "pikachu" }
+        }));
+    }).await;
+    mock_pokemon_api(&server).await;
+    mock_shakespeare_api(&server, 200).await;
+    let state = State {
+      prose_templates: ProseTemplates::from_template_list("Of {types} is {name}, in the {habitat}. {description}").unwrap(),
+      ..build_state(&server)
+    };
+
+    let query = GetPokemonQuery { flavor: Flavor::Prose, ..GetPokemonQuery::default() };
+    let res = handle_get_pokemon("pikachu".to_string(), query, None, state.clone()).await.unwrap();
+    assert_eq!(res.description, "Of electric is Pikachu, in the wilds unknown. Mocked translation");
+
+    // The profile is cached, and plain descriptions do not need it
+    let query = GetPokemonQuery { flavor: Flavor::Prose, ..GetPokemonQuery::default() };
+    handle_get_pokemon("pikachu".to_string(), query, None, state.clone()).await.unwrap();
+    let res = handle_get_pokemon("pikachu".to_string(), GetPokemonQuery::default(), None, state.clone()).await.unwrap();
+    assert_eq!(res.description, "Mocked translation");
+    assert!(res.types.is_none() && res.stats.is_none());
+    profile_mock.assert_hits(1);
+
+    // The expanded payload finds both the types and the stats in the cache
+    let query = GetPokemonQuery { expand: true, ..GetPokemonQuery::default() };
+    let res = handle_get_pokemon("pikachu".to_string(), query, None, state).await.unwrap();
+    assert_eq!(res.types, Some(vec!["electric".to_string()]));
+    assert_eq!(res.stats.unwrap()["hp"], 35);
+    profile_mock.assert_hits(1);
+
+  }
+
+  #[tokio::test]
+  async fn test_translated_genus() {
+
+    let server = MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon/pikachu");
+      then.status(200)
+        .json_body(json!({ "types": [], "species": { "name": "pikachu" } }));
+    }).await;
+    server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon-species/pikachu");
+      then.status(200)
+        .json_body(json!({ "genera": [{ "genus": "Mouse Pokémon", "language": { "name": "en" } }] }));
+    }).await;
+    server.mock_async(|when, then| {
+      when.method(Method::POST)
+        .path("/translate/shakespeare.json");
+      then.status(200)
+        .json_body(json!({ "contents": { "translated": "Mouse Pokémon, forsooth", "text": "Mouse Pokémon" } }));
+    }).await;
+    let state = State {
+      translate_genus: true,
+      ..build_state(&server)
+    };
+
+    let profile = get_profile("pikachu", &state).await.unwrap().unwrap();
+    assert_eq!(profile.genus.as_deref(), Some("Mouse Pokémon, forsooth"));
+
+  }
+
+  #[tokio::test]
+  async fn test_degraded_responses() {
+
+    // Translator is down, and the fallback is enabled
+    let server = MockServer::start_async().await;
+    mock_pokemon_api(&server).await;
+    mock_shakespeare_api(&server, 500).await;
+    let state = State {
+      untranslated_fallback: true,
+      ..build_state(&server)
+    };
+
+    let res = handle_get_pokemon("pikachu".to_string(), GetPokemonQuery::default(), None, state.clone()).await.unwrap();
+    assert_eq!(res.description, "This one!");
+    assert_eq!(res.degradation, Some(Degradation::Untranslated));
+    let res = res.into_response();
+    assert_eq!(res.headers()["Warning"], "199 - \"Description not translated\"");
+    let body = warp::hyper::body::to_bytes(res.into_body()).await.unwrap();
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["degraded"], true);
+
+    // Expired translations win over the untranslated description
+    let cache = Cache::new(MemoryCache::new(1, 1).with_stale_grace(Duration::from_secs(60)))
+      .with_ttl(Ttl { base: Duration::ZERO, jitter: 0.0 });
+    let state = State {
+      cache: Arc::new(cache),
+      ..state
+    };
+    state.cache.put(Keyspace::Translations, "pikachu".to_string(), CacheEntry::translated("shakespeare", "en", "Old translation".to_string())).await;
+    let res = handle_get_pokemon("pikachu".to_string(), GetPokemonQuery::default(), None, state.clone()).await.unwrap();
+    assert_eq!(res.description, "Old translation");
+    assert_eq!(res.degradation, Some(Degradation::Stale));
+
+  }
+
+  #[tokio::test]
+  async fn test_degraded_mode() {
+
+    let server = MockServer::start_async().await;
+    mock_pokemon_api(&server).await;
+    let shakespeare_mock = mock_shakespeare_api(&server, 200).await;
+    let state = build_state(&server);
+    state.degraded_mode.set(true, &state.cache).await.unwrap();
+
+    // The translator is not contacted, even without the fallback configured
+    let res = handle_get_pokemon("pikachu".to_string(), GetPokemonQuery::default(), None, state.clone()).await.unwrap();
+    assert_eq!(res.description, "This one!");
+    assert_eq!(res.degradation, Some(Degradation::Untranslated));
+    shakespeare_mock.assert_hits(0);
+
+    state.degraded_mode.set(false, &state.cache).await.unwrap();
+    let res = handle_get_pokemon("pikachu".to_string(), GetPokemonQuery::default(), None, state).await.unwrap();
+    assert_eq!(res.description, "Mocked translation");
+
+  }
+
+  #[tokio::test]
+  async fn test_not_found_suggestions() {
+
+    let server = MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.method(Method::GET).path_matches(Regex::new("^/pokemon(-species)?/pikachuu$").unwrap());
+      then.status(404);
+    }).await;
+    let state = State {
+      cache: Arc::new(Cache::new(MemoryCache::new(4, 4))),
+      ..build_state(&server)
+    };
+    for name in ["pikachu", "raichu", "bulbasaur"] {
+      state.cache.put(Keyspace::Descriptions, name.to_string(), CacheEntry::original("en", "This one!".to_string())).await;
+    }
+
+    let err = handle_get_pokemon("pikachuu".to_string(), GetPokemonQuery::default(), None, state).await.err().unwrap();
+    let not_found = err.find::<PokemonNotFound>().unwrap();
+    assert_eq!(not_found.name, "pikachuu");
+    assert_eq!(not_found.suggestions, vec!["pikachu"]);
+
+  }
+
+  #[test]
+  fn test_edit_distance() {
+    assert_eq!(edit_distance("pikachu", "pikachu"), 0);
+    assert_eq!(edit_distance("pikachuu", "pikachu"), 1);
+    assert_eq!(edit_distance("pikahcu", "pikachu"), 2);
+    assert_eq!(edit_distance("", "mew"), 3);
+  }
+
+  #[tokio::test]
+  async fn test_forms_resolve_to_species() {
+
+    let server = MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon-species/deoxys-normal");
+      then.status(404);
+    }).await;
+    let form = server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon/deoxys-normal");
+      then.status(200)
+        .json_body(json!({ "stats": [], "species": { "name": "deoxys" } }));
+    }).await;
+    server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path("/pokemon-species/deoxys");
+      then.status(200)
+        .json_body(json!({ "flavor_text_entries": [{ "flavor_text": "This one!", "language": { "name": "en" } }] }));
+    }).await;
+    mock_shakespeare_api(&server, 200).await;
+
+    let state = build_state(&server);
+    let res = handle_get_pokemon("deoxys-normal".to_string(), GetPokemonQuery::default(), None, state.clone()).await.unwrap();
+    assert_eq!(res.name, "deoxys-normal");
+    assert_eq!(res.description, "Mocked translation");
+    form.assert();
+
+    // Once the descriptions are gone, the species is still known
+    let species = state.cache.get(Keyspace::Species, "deoxys-normal").await.unwrap();
+    assert_eq!(species.parse_data::<String>().unwrap(), "deoxys");
+    let state = State {
+      cache: Arc::new(Cache::new(MemoryCache::new(1, 1))),
+      ..state
+    };
+    state.cache.put(Keyspace::Species, "deoxys-normal".to_string(), species).await;
+    handle_get_pokemon("deoxys-normal".to_string(), GetPokemonQuery::default(), None, state).await.unwrap();
+    form.assert_hits(1);
+
+  }
+
+  #[tokio::test]
+  async fn test_path_normalization() {
+    let filter = path(32);
+    for path in &["/pokemon/pikachu", "/pokemon/pikachu/", "/pokemon/PikaChu"] {
+      assert_eq!(warp::test::request().path(path).filter(&filter).await.unwrap(), "pikachu");
+    }
+    assert!(warp::test::request().path("/pokemon/pikachu/extra").filter(&filter).await.is_err());
+
+    // Percent-encoded names are decoded and normalized
+    let cases = [
+      ("/pokemon/farfetch%27d", "farfetchd"),
+      ("/pokemon/nidoran%E2%99%80", "nidoran-f"),
+      ("/pokemon/Mr.%20Mime", "mr-mime"),
+      ("/pokemon/flab%C3%A9b%C3%A9", "flabebe"),
+      ("/pokemon/type%3A%20null", "type-null"),
+      ("/pokemon/a%2Fb%3Fc", "a/b?c")
+    ];
+    for (path, name) in &cases {
+      assert_eq!(&warp::test::request().path(path).filter(&filter).await.unwrap(), name);
+    }
+
+    // Invalid UTF-8 sequences and long names are rejected
+    assert!(warp::test::request().path("/pokemon/%FF%FE").filter(&filter).await.is_err());
+    let err = warp::test::request().path(&format!("/pokemon/{}", "a".repeat(33))).filter(&filter).await.unwrap_err();
+    assert!(err.find::<NameTooLong>().is_some());
+  }
+
+  #[tokio::test]
+  async fn test_compare() {
+
+    let server = MockServer::start_async().await;
+    let pokemon_mock = mock_pokemon_api(&server).await;
+    let shakespeare_mock = mock_shakespeare_api(&server, 200).await;
+    let stats_mock = server.mock_async(|when, then| {
+      when.method(Method::GET)
+        .path_matches(Regex::new("^/pokemon/").unwrap());
+      then.status(200)
+        .json_body(json!({
+          "stats": [{ "base_stat": 35, "stat": { "name": "hp" } }]
+        }));
+    }).await;
+    let state = State {
+      cache: Arc::new(Cache::new(MemoryCache::new(2, 2))),
+      ..build_state(&server)
+    };
+
+    let query = || CompareQuery { names: "pikachu, Raichu".to_string() };
+    let res = handle_compare(query(), 64, state.clone()).await.unwrap();
+    assert_eq!(res.pokemon.len(), 2);
+    assert_eq!(res.pokemon[1].name, "raichu");
+    assert_eq!(res.pokemon[1].description, "Mocked translation");
+    assert_eq!(res.pokemon[1].stats["hp"], 35);
+
+    // The second comparison is served from the caches
+    handle_compare(query(), 64, state.clone()).await.unwrap();
+    pokemon_mock.assert_hits(2);
+    shakespeare_mock.assert_hits(2);
+    stats_mock.assert_hits(2);
+
+    // A single name is not enough
+    assert!(handle_compare(CompareQuery { names: "pikachu".to_string() }, 64, state).await.is_err());
+
+  }
+
+  #[tokio::test]
+  async fn test_batch() {
+
+    let server = MockServer::start_async().await;
+    let pokemon_mock = server.mock_async(|when, then| {
+      when.method(Method::GET).path("/pokemon-species/pikachu");
+      then.status(200)
+        .json_body(json!({ "flavor_text_entries": [{ "flavor_text": "This one!", "language": { "name": "en" } }] }));
+    }).await;
+    let missing_mock = server.mock_async(|when, then| {
+      when.method(Method::GET).path("/pokemon-species/missingno");
+      then.status(404);
+    }).await;
+    mock_shakespeare_api(&server, 200).await;
+    let state = build_state(&server);
+
+    let request = BatchRequest { names: vec!["Pikachu".to_string(), "missingno".to_string()] };
+    let res = handle_batch(request, 64, state.clone()).await.unwrap();
+    assert_eq!(res.pokemon.len(), 1);
+    assert_eq!(res.pokemon[0].name, "pikachu");
+    assert_eq!(res.not_found, vec!["missingno"]);
+    pokemon_mock.assert_hits(1);
+    missing_mock.assert_hits(1);
+
+    assert!(handle_batch(BatchRequest { names: vec![] }, 64, state).await.is_err());
+
+  }
+
+}
@@ -0,0 +1,55 @@
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+use warp::{Rejection, Reply};
+
+use crate::routes::daily::DailyPokemonResponse;
+use crate::routes::errors::ErrorBody;
+use crate::routes::pokemons::{BatchResponse, CompareResponse, GetPokemonReponse};
+use crate::routes::translate::TranslateResponse;
+
+/// Returns the JSON Schema of the response type with the given name.
+pub fn schema(name: &str) -> Option<RootSchema> {
+  match name {
+    "pokemon" => Some(schema_for!(GetPokemonReponse)),
+    "compare" => Some(schema_for!(CompareResponse)),
+    "batch" => Some(schema_for!(BatchResponse)),
+    "daily" => Some(schema_for!(DailyPokemonResponse)),
+    "translate" => Some(schema_for!(TranslateResponse)),
+    "error" => Some(schema_for!(ErrorBody)),
+    _ => None
+  }
+}
+
+/// Serves the schema named by a `{name}.json` path segment.
+pub async fn handle_schema(file: String) -> std::result::Result<impl Reply, Rejection> {
+  file.strip_suffix(".json")
+    .and_then(schema)
+    .map(|schema| warp::reply::json(&schema))
+    .ok_or_else(warp::reject::not_found)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_all_schemas_exist() {
+    for name in ["pokemon", "compare", "batch", "daily", "translate", "error"] {
+      assert!(schema(name).is_some(), "Missing schema {}", name);
+    }
+    assert!(schema("unknown").is_none());
+
+    // Fields skipped by serde are not part of the schema
+    let pokemon = serde_json::to_value(schema("pokemon").unwrap()).unwrap();
+    assert!(pokemon["properties"]["description"].is_object());
+    assert!(pokemon["properties"]["degradation"].is_null());
+  }
+
+  #[tokio::test]
+  async fn test_handle_schema() {
+    let res = handle_schema("error.json".to_string()).await.unwrap().into_response();
+    assert_eq!(res.status(), 200);
+    assert!(handle_schema("error".to_string()).await.is_err());
+  }
+
+}
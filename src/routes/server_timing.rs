@@ -0,0 +1,94 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use warp::http::HeaderValue;
+use warp::reply::Response;
+use warp::Reply;
+
+use crate::request_stats::{self, RequestStats};
+
+/// Reply of a request, with the time spent in each phase reported in the `Server-Timing` header if enabled,
+/// so that it shows up in the network tab of the browsers.
+pub struct Timed<T> {
+  reply: T,
+  stats: Option<RequestStats>,
+  serialize_time: Duration
+}
+
+impl<T> Timed<T> {
+
+  /// Converts the reply, like serializing it to JSON, counting the time it takes as serialization.
+  pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Timed<U> {
+    let started = Instant::now();
+    let reply = f(self.reply);
+    Timed {
+      reply,
+      stats: self.stats,
+      serialize_time: self.serialize_time + started.elapsed()
+    }
+  }
+
+}
+
+impl<T: Reply> Reply for Timed<T> {
+  fn into_response(self) -> Response {
+    let started = Instant::now();
+    let mut res = self.reply.into_response();
+    if let Some(stats) = self.stats {
+      if let Ok(value) = HeaderValue::from_str(&header_value(&stats, self.serialize_time + started.elapsed())) {
+        res.headers_mut().insert("server-timing", value);
+      }
+    }
+    res
+  }
+}
+
+/// Runs the handler of a request, collecting the time spent in each phase if enabled.
+pub async fn collect<T, E>(enabled: bool, f: impl Future<Output = Result<T, E>>) -> Result<Timed<T>, E> {
+  let (res, stats) = if enabled {
+    let (res, stats) = request_stats::collect(f).await;
+    (res, Some(stats))
+  } else {
+    (f.await, None)
+  };
+  res.map(|reply| Timed { reply, stats, serialize_time: Duration::ZERO })
+}
+
+/// Formats the phases like `cache;dur=1.2, pokeapi;dur=120.0, translate;dur=310.5, serialize;dur=0.1`.
+/// The calls to the translator are reported as `translate`, whatever the provider.
+fn header_value(stats: &RequestStats, serialize_time: Duration) -> String {
+  let (pokeapi, translate) = stats.upstream_time.iter()
+    .fold((Duration::ZERO, Duration::ZERO), |(pokeapi, translate), (upstream, time)| match *upstream {
+      "pokeapi" => (pokeapi + *time, translate),
+      _ => (pokeapi, translate + *time)
+    });
+  [("cache", stats.cache_time), ("pokeapi", pokeapi), ("translate", translate), ("serialize", serialize_time)].iter()
+    .map(|(phase, time)| format!("{};dur={:.1}", phase, time.as_secs_f64() * 1000.0))
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_header() {
+
+    let timed = collect(true, async {
+      request_stats::record(|stats| stats.cache_time += Duration::from_micros(1200));
+      request_stats::record(|stats| *stats.upstream_time.entry("pokeapi").or_default() += Duration::from_millis(120));
+      request_stats::record(|stats| *stats.upstream_time.entry("shakespeare").or_default() += Duration::from_millis(310));
+      Ok::<_, ()>("Hello")
+    }).await.unwrap();
+    let res = timed.into_response();
+    let value = res.headers()["server-timing"].to_str().unwrap();
+    assert!(value.starts_with("cache;dur=1.2, pokeapi;dur=120.0, translate;dur=310.0, serialize;dur="), "{}", value);
+
+    // Nothing is reported when disabled
+    let res = collect(false, async { Ok::<_, ()>("Hello") }).await.unwrap().into_response();
+    assert!(res.headers().get("server-timing").is_none());
+
+  }
+
+}
@@ -0,0 +1,143 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use warp::{Rejection, Reply};
+
+use crate::build_info;
+use crate::cache::Keyspace;
+use crate::log_sampling::LogSampler;
+use crate::metrics;
+use crate::routes::State;
+
+/// Data needed to render the status page, besides the shared state.
+#[derive(Clone)]
+pub struct StatusInfo {
+  pub started_at: Instant,
+  pub sampler: Arc<LogSampler>
+}
+
+fn escape(s: &str) -> String {
+  s.replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+fn format_uptime(secs: u64) -> String {
+  format!("{}d {}h {}m {}s", secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60)
+}
+
+fn render(info: &StatusInfo, state: &State) -> String {
+
+  // Cache stats, one row per keyspace
+  let cache_rows = Keyspace::ALL.iter()
+    .map(|keyspace| {
+      let (len, cap) = state.cache.local_usage(*keyspace);
+      let hits = match keyspace {
+        Keyspace::Descriptions => metrics::DESCRIPTION_CACHE_HITS.get(),
+        Keyspace::Translations => metrics::CACHE_HITS.get(),
+        Keyspace::Stats => metrics::STATS_CACHE_HITS.get(),
+        Keyspace::Species => metrics::SPECIES_CACHE_HITS.get(),
+        Keyspace::Profiles => metrics::PROFILE_CACHE_HITS.get(),
+        Keyspace::Texts => metrics::TEXT_CACHE_HITS.get(),
+        Keyspace::Checkpoints => 0
+      };
+      format!(
+        "<tr><td>{}</td><td>{} / {}</td><td>{} KiB</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+        keyspace.name(), len, cap, state.cache.local_memory_usage(*keyspace) / 1024, hits,
+        metrics::CACHE_EVICTIONS.with_label_values(&[keyspace.name()]).get(),
+        metrics::CACHE_EXPIRED.with_label_values(&[keyspace.name()]).get()
+      )
+    })
+    .collect::<String>();
+  let shared = state.cache.shared().map(|shared| shared.name()).unwrap_or("none");
+
+  // Circuit breakers of the upstreams
+  let breaker_rows = std::iter::once(("pokeapi", state.pokemon_client.circuit_breaker()))
+    .chain(state.translators.iter().map(|translator| (translator.name(), translator.circuit_breaker())))
+    .map(|(upstream, breaker)| format!(
+      "<tr><td>{}</td><td>{}</td></tr>",
+      upstream, breaker.as_ref().map(|breaker| breaker.state()).unwrap_or("disabled")
+    ))
+    .collect::<String>();
+
+  // Errors seen in the current sampling window
+  let recent = info.sampler.recent();
+  let error_rows = if recent.is_empty() {
+    "<tr><td colspan=\"2\">No recent errors</td></tr>".to_string()
+  } else {
+    recent.iter()
+      .map(|(kind, count)| format!("<tr><td>{}</td><td>{}</td></tr>", escape(kind), count))
+      .collect()
+  };
+
+  format!(r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Pokemon Challenge status</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; margin-bottom: 2em; }}
+th, td {{ border: 1px solid #ccc; padding: 0.3em 0.8em; text-align: left; }}
+</style>
+</head>
+<body>
+<h1>Pokemon Challenge status</h1>
+<table>
+<tr><th>Version</th><td>{version}</td></tr>
+<tr><th>Uptime</th><td>{uptime}</td></tr>
+<tr><th>PokeAPI requests</th><td>{pokeapi}</td></tr>
+<tr><th>Shakespeare Translator requests</th><td>{shakespeare}</td></tr>
+</table>
+<h2>Cache</h2>
+<p>Shared backend: {shared}</p>
+<table>
+<tr><th>Keyspace</th><th>Entries</th><th>Memory</th><th>Hits</th><th>Evictions</th><th>Expired</th></tr>
+{cache_rows}
+</table>
+<h2>Circuit breakers</h2>
+<table>
+<tr><th>Upstream</th><th>State</th></tr>
+{breaker_rows}
+</table>
+<h2>Recent errors</h2>
+<table>
+<tr><th>Error</th><th>Count</th></tr>
+{error_rows}
+</table>
+</body>
+</html>
+"#,
+    version = build_info::LONG_VERSION,
+    uptime = format_uptime(info.started_at.elapsed().as_secs()),
+    pokeapi = metrics::POKEAPI_REQUESTS.get(),
+    shakespeare = metrics::SHAKESPEARE_REQUESTS.get(),
+    shared = shared,
+    cache_rows = cache_rows,
+    breaker_rows = breaker_rows,
+    error_rows = error_rows
+  )
+
+}
+
+/// Handler for the `GET /status` route.
+pub async fn handle_status(info: StatusInfo, state: State) -> std::result::Result<impl Reply, Rejection> {
+  Ok(warp::reply::html(render(&info, &state)))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_escape() {
+    assert_eq!(escape("<b>\"a\" & b</b>"), "&lt;b&gt;&quot;a&quot; &amp; b&lt;/b&gt;");
+  }
+
+  #[test]
+  fn test_format_uptime() {
+    assert_eq!(format_uptime(90061), "1d 1h 1m 1s");
+  }
+
+}
@@ -0,0 +1,86 @@
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use warp::Rejection;
+
+use crate::routes::errors::TimedOut;
+
+/// Maximum time given to the requests of each group of routes, `None` meaning no limit.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RouteTimeouts {
+  /// `GET /pokemon/{name}` and `GET /pokemon/daily`.
+  pub pokemon: Option<Duration>,
+  /// `GET /pokemon/compare`, which fetches a few Pokemon at once.
+  pub compare: Option<Duration>,
+  /// `POST /pokemon/batch`, which fetches up to the whole batch at once.
+  pub batch: Option<Duration>,
+  /// `POST /translate`.
+  pub translate: Option<Duration>
+}
+
+/// Parses a comma separated list of `group=milliseconds` pairs, like `pokemon=2000,compare=30000`.
+/// `none` disables the timeout of a group, as do the groups not listed.
+impl FromStr for RouteTimeouts {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut timeouts = RouteTimeouts::default();
+    for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+      let (group, value) = pair.split_once('=').ok_or_else(|| anyhow!("Expected group=milliseconds, got {}", pair))?;
+      let timeout = match value.trim() {
+        "none" => None,
+        ms => Some(Duration::from_millis(ms.parse().map_err(|_| anyhow!("Invalid timeout of {}: {}", group, ms))?))
+      };
+      match group.trim() {
+        "pokemon" => timeouts.pokemon = timeout,
+        "compare" => timeouts.compare = timeout,
+        "batch" => timeouts.batch = timeout,
+        "translate" => timeouts.translate = timeout,
+        other => return Err(anyhow!("Unknown route group: {}", other))
+      }
+    }
+    Ok(timeouts)
+  }
+}
+
+/// Fails the request with a `504 Gateway Timeout` if it is not handled within the given time, if any.
+pub async fn limit<T>(timeout: Option<Duration>, fut: impl Future<Output = Result<T, Rejection>>) -> Result<T, Rejection> {
+  match timeout {
+    Some(timeout) => tokio::time::timeout(timeout, fut).await
+      .unwrap_or_else(|_| Err(warp::reject::custom(TimedOut))),
+    None => fut.await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    let timeouts: RouteTimeouts = "pokemon=2000, compare=30000,batch=60000,translate=none".parse().unwrap();
+    assert_eq!(timeouts.pokemon, Some(Duration::from_secs(2)));
+    assert_eq!(timeouts.compare, Some(Duration::from_secs(30)));
+    assert_eq!(timeouts.batch, Some(Duration::from_secs(60)));
+    assert_eq!(timeouts.translate, None);
+
+    assert!("pokemon".parse::<RouteTimeouts>().is_err());
+    assert!("pokemon=fast".parse::<RouteTimeouts>().is_err());
+    assert!("events=1000".parse::<RouteTimeouts>().is_err());
+  }
+
+  #[tokio::test]
+  async fn test_limit() {
+    let slow = async {
+      tokio::time::sleep(Duration::from_millis(200)).await;
+      Ok::<_, Rejection>(())
+    };
+    let err = limit(Some(Duration::from_millis(10)), slow).await.unwrap_err();
+    assert!(err.find::<TimedOut>().is_some());
+
+    assert!(limit(None, async { Ok::<_, Rejection>(()) }).await.is_ok());
+  }
+
+}
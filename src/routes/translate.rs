@@ -0,0 +1,262 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use schemars::JsonSchema;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use warp::Rejection;
+
+use crate::cache::{CacheEntry, Keyspace};
+use crate::clients::{Translator, Translators};
+use crate::metrics;
+use crate::pipeline;
+use crate::request_stats;
+use crate::routes::State;
+use crate::routes::errors::{BadRequest, CustomRejection, TextTooLong, TooManyRequests, Unauthorized};
+
+/// Length of the window over which the translations of each API key are counted.
+const QUOTA_WINDOW: Duration = Duration::from_secs(60);
+
+/// Body accepted by the `POST /translate` route.
+#[derive(Deserialize, JsonSchema)]
+pub struct TranslateRequest {
+  text: String,
+  /// Defaults to the `X-Translator` header, and then to the primary translator.
+  translator: Option<String>
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct TranslateResponse {
+  translator: String,
+  text: String,
+  translated: String,
+  /// Whether the text was truncated to fit the length cap before being translated.
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  truncated: bool
+}
+
+/// What to do with the texts longer than the cap.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+  /// The request is answered with a `422 Unprocessable Entity`.
+  #[default]
+  Reject,
+  /// The text is truncated at the last sentence which fits.
+  Truncate
+}
+
+impl std::str::FromStr for Overflow {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> anyhow::Result<Self> {
+    match s {
+      "reject" => Ok(Overflow::Reject),
+      "truncate" => Ok(Overflow::Truncate),
+      other => Err(anyhow::anyhow!("Unknown translation overflow: {}", other))
+    }
+  }
+}
+
+/// Maximum length of the texts sent to the translator, to protect its quota from pathological inputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LengthCap {
+  pub max_chars: usize,
+  pub overflow: Overflow
+}
+
+impl LengthCap {
+
+  /// Returns the text to translate and whether it was truncated, or rejects it.
+  fn enforce(&self, text: String) -> std::result::Result<(String, bool), Rejection> {
+    if text.chars().count() <= self.max_chars {
+      return Ok((text, false));
+    }
+    match self.overflow {
+      Overflow::Reject => {
+        metrics::REJECTED_REQUESTS.with_label_values(&["text_too_long"]).inc();
+        Err(warp::reject::custom(TextTooLong))
+      },
+      Overflow::Truncate => Ok((pipeline::truncate_sentences(&text, self.max_chars), true))
+    }
+  }
+
+}
+
+/// Looks for the given API key, comparing it in constant time with all the known ones,
+/// so that the response time does not tell how close a guess is.
+pub fn find_key<'a, T>(keys: &'a [(String, T)], key: &str) -> Option<&'a T> {
+  keys.iter().fold(None, |found, (candidate, value)| {
+    let matches: bool = candidate.as_bytes().ct_eq(key.as_bytes()).into();
+    if matches { Some(value) } else { found }
+  })
+}
+
+/// API keys allowed to use the `POST /translate` route, each with its own fixed window quota.
+pub struct TranslateAuth {
+  limit: u32,
+  quotas: Vec<(String, Mutex<(Instant, u32)>)>
+}
+
+impl TranslateAuth {
+
+  /// Accepts the given keys, allowing `limit` translations per minute to each of them.
+  pub fn new(keys: &[String], limit: u32) -> Self {
+    TranslateAuth {
+      limit,
+      quotas: keys.iter().map(|key| (key.clone(), Mutex::new((Instant::now(), 0)))).collect()
+    }
+  }
+
+  /// Returns the quota of the key in the `Authorization: Bearer <key>` header, or rejects the request.
+  fn quota(&self, authorization: Option<&str>) -> std::result::Result<&Mutex<(Instant, u32)>, Rejection> {
+    authorization
+      .and_then(|header| header.strip_prefix("Bearer "))
+      .and_then(|key| find_key(&self.quotas, key.trim()))
+      .ok_or_else(|| {
+        metrics::REJECTED_REQUESTS.with_label_values(&["unauthorized"]).inc();
+        warp::reject::custom(Unauthorized)
+      })
+  }
+
+  /// Checks the `Authorization: Bearer <key>` header, and counts the request against the quota of the key.
+  fn authorize(&self, authorization: Option<&str>) -> std::result::Result<(), Rejection> {
+
+    let mut quota = self.quota(authorization)?.lock().unwrap();
+    if quota.0.elapsed() >= QUOTA_WINDOW {
+      *quota = (Instant::now(), 0);
+    }
+    if quota.1 >= self.limit {
+      metrics::REJECTED_REQUESTS.with_label_values(&["rate_limited"]).inc();
+      return Err(warp::reject::custom(TooManyRequests));
+    }
+    quota.1 += 1;
+    Ok(())
+
+  }
+
+}
+
+/// Picks the translator requested by the request itself, in its query string or its body, or failing that
+/// in the `X-Translator` header, and the primary one otherwise. Only the configured translators can be selected.
+pub fn select_translator<'a>(translators: &'a Translators, requested: Option<&str>, header: Option<&str>) -> std::result::Result<&'a Arc<dyn Translator>, Rejection> {
+  match requested.or(header) {
+    Some(name) => translators.get(name).ok_or_else(|| warp::reject::custom(BadRequest("Unknown translator"))),
+    None => Ok(translators.primary())
+  }
+}
+
+/// Key of the cached translation of a text, not to store arbitrary texts in the keys of the shared backends.
+fn text_key(translator: &str, text: &str) -> String {
+  let digest = Sha256::new()
+    .chain_update(translator)
+    .chain_update([0])
+    .chain_update(text)
+    .finalize();
+  format!("{:x}", digest)
+}
+
+/// Handler for the `POST /translate` route.
+pub async fn handle_translate(auth: Arc<TranslateAuth>, cap: Option<LengthCap>, authorization: Option<String>, translator_header: Option<String>, request: TranslateRequest, state: State) -> std::result::Result<TranslateResponse, Rejection> {
+
+  auth.authorize(authorization.as_deref())?;
+  if request.text.trim().is_empty() {
+    return Err(warp::reject::custom(BadRequest("Missing text")));
+  }
+  let translator = select_translator(&state.translators, request.translator.as_deref(), translator_header.as_deref())?.clone();
+  let (text, truncated) = match cap {
+    Some(cap) => cap.enforce(request.text)?,
+    None => (request.text, false)
+  };
+
+  // Translate the text, masking the profanities like for the descriptions.
+  // The same texts are often sent again, so the translations are cached.
+  request_stats::record(|stats| stats.translator = Some(translator.name().to_string()));
+  let translated = state.cache
+    .get_or_populate(Keyspace::Texts, &text_key(translator.name(), &text), || async {
+      let translated = state.degraded_mode.translate(&*translator, &text).await?;
+      let translated = match &state.profanity_filter {
+        Some(filter) => filter.mask(&translated),
+        None => translated
+      };
+      Ok(Some(CacheEntry::translated(translator.name(), "en", translated)))
+    })
+    .await
+    .map_err(|e| CustomRejection::shared(e))?
+    .map(|entry| entry.text)
+    .unwrap_or_default();
+
+  Ok(TranslateResponse {
+    translator: translator.name().to_string(),
+    text,
+    translated,
+    truncated
+  })
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::clients::ShakespeareClient;
+  use crate::clients::openai::OpenAiClient;
+
+  #[test]
+  fn test_authorize() {
+    let auth = TranslateAuth::new(&["secret".to_string()], 2);
+
+    assert!(auth.authorize(None).is_err());
+    assert!(auth.authorize(Some("Bearer wrong")).is_err());
+    assert!(auth.authorize(Some("secret")).is_err());
+
+    // The quota is exhausted after two requests
+    assert!(auth.authorize(Some("Bearer secret")).is_ok());
+    assert!(auth.authorize(Some("Bearer secret")).is_ok());
+    let err = auth.authorize(Some("Bearer secret")).unwrap_err();
+    assert!(err.find::<TooManyRequests>().is_some());
+  }
+
+  #[test]
+  fn test_find_key() {
+    let keys = vec![("first".to_string(), 1), ("second".to_string(), 2)];
+    assert_eq!(find_key(&keys, "second"), Some(&2));
+    assert_eq!(find_key(&keys, "secon"), None);
+    assert_eq!(find_key(&keys, "seconds"), None);
+  }
+
+  #[test]
+  fn test_select_translator() {
+    let translators = Translators::new(Arc::new(ShakespeareClient::builder().base_url("http://localhost/").build().unwrap()))
+      .with(Arc::new(OpenAiClient::new("http://localhost/").unwrap()));
+    let select = |requested, header| select_translator(&translators, requested, header).map(|translator| translator.name());
+    assert_eq!(select(None, None).unwrap(), "shakespeare");
+    assert_eq!(select(None, Some("openai")).unwrap(), "openai");
+    assert!(select(None, Some("yoda")).is_err());
+
+    // The request takes precedence over the header
+    assert_eq!(select(Some("shakespeare"), Some("openai")).unwrap(), "shakespeare");
+    assert!(select(Some("yoda"), Some("shakespeare")).is_err());
+  }
+
+  #[test]
+  fn test_text_key() {
+    assert_eq!(text_key("shakespeare", "Hello"), text_key("shakespeare", "Hello"));
+    assert_ne!(text_key("shakespeare", "Hello"), text_key("openai", "Hello"));
+    assert_ne!(text_key("shakespeare", "Hello"), text_key("shakespeareH", "ello"));
+  }
+
+  #[test]
+  fn test_length_cap() {
+    let text = "It keeps its tail raised. It is wary.".to_string();
+
+    let cap = LengthCap { max_chars: 40, overflow: Overflow::Reject };
+    assert_eq!(cap.enforce(text.clone()).unwrap(), (text.clone(), false));
+
+    let cap = LengthCap { max_chars: 30, overflow: Overflow::Reject };
+    assert!(cap.enforce(text.clone()).unwrap_err().find::<TextTooLong>().is_some());
+
+    let cap = LengthCap { max_chars: 30, overflow: Overflow::Truncate };
+    assert_eq!(cap.enforce(text).unwrap(), ("It keeps its tail raised.".to_string(), true));
+  }
+
+}
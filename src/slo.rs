@@ -0,0 +1,150 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use warp::http::StatusCode;
+
+use crate::metrics;
+
+/// Windows over which the good and bad events are counted, as used by multi-window burn-rate alerts.
+const WINDOWS: [(&str, u64); 4] = [("5m", 5), ("30m", 30), ("1h", 60), ("6h", 360)];
+
+/// Number of one minute buckets kept, enough for the longest window.
+const BUCKETS: usize = 360;
+
+/// Service level objective of the API requests.
+#[derive(Clone, Debug)]
+pub struct SloConfig {
+  /// Requests slower than this are bad events.
+  pub latency: Duration,
+  /// Fraction of good events to be met, like `0.99`.
+  pub target: f64
+}
+
+impl Default for SloConfig {
+  fn default() -> Self {
+    SloConfig {
+      latency: Duration::from_millis(500),
+      target: 0.99
+    }
+  }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Bucket {
+  minute: u64,
+  good: u64,
+  bad: u64
+}
+
+/// Tracks the requests against the objective, counting the good and bad events of the last minutes.
+pub struct Slo {
+  config: SloConfig,
+  started_at: Instant,
+  buckets: Mutex<Vec<Bucket>>
+}
+
+impl Slo {
+
+  pub fn new(config: SloConfig) -> Self {
+    Slo {
+      config,
+      started_at: Instant::now(),
+      buckets: Mutex::new(vec![Bucket::default(); BUCKETS])
+    }
+  }
+
+  fn minute(&self) -> u64 {
+    self.started_at.elapsed().as_secs() / 60
+  }
+
+  /// Records a request completed with the given status after `elapsed`.
+  /// Server errors and requests slower than the objective are bad events.
+  pub fn record(&self, status: StatusCode, elapsed: Duration) {
+    let good = !status.is_server_error() && elapsed <= self.config.latency;
+    self.record_at(self.minute(), good);
+  }
+
+  fn record_at(&self, minute: u64, good: bool) {
+    metrics::SLO_EVENTS.with_label_values(&[if good { "good" } else { "bad" }]).inc();
+    let mut buckets = self.buckets.lock().unwrap();
+    let bucket = &mut buckets[minute as usize % BUCKETS];
+    if bucket.minute != minute {
+      *bucket = Bucket { minute, ..Bucket::default() };
+    }
+    if good {
+      bucket.good += 1;
+    } else {
+      bucket.bad += 1;
+    }
+  }
+
+  /// Returns the good and bad events of the last `minutes`, including the current one.
+  fn window_at(&self, minute: u64, minutes: u64) -> (u64, u64) {
+    let buckets = self.buckets.lock().unwrap();
+    buckets.iter()
+      .filter(|bucket| bucket.minute <= minute && bucket.minute + minutes > minute && bucket.good + bucket.bad > 0)
+      .fold((0, 0), |(good, bad), bucket| (good + bucket.good, bad + bucket.bad))
+  }
+
+  /// Updates the gauges of the events and of the burn rate of each window, to be called before exporting the metrics.
+  pub fn refresh_metrics(&self) {
+    let minute = self.minute();
+    for (name, minutes) in WINDOWS {
+      let (good, bad) = self.window_at(minute, minutes);
+      metrics::SLO_WINDOW_EVENTS.with_label_values(&[name, "good"]).set(good as i64);
+      metrics::SLO_WINDOW_EVENTS.with_label_values(&[name, "bad"]).set(bad as i64);
+      metrics::SLO_BURN_RATE.with_label_values(&[name]).set(self.burn_rate(good, bad));
+    }
+  }
+
+  /// How fast the error budget is being spent: `1` means exactly at the rate allowed by the objective.
+  fn burn_rate(&self, good: u64, bad: u64) -> f64 {
+    if good + bad == 0 {
+      return 0.0;
+    }
+    (bad as f64 / (good + bad) as f64) / (1.0 - self.config.target)
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_windows() {
+    let slo = Slo::new(SloConfig::default());
+    slo.record_at(0, false);
+    slo.record_at(10, true);
+    slo.record_at(58, true);
+    slo.record_at(60, true);
+    slo.record_at(60, false);
+
+    assert_eq!(slo.window_at(60, 5), (2, 1));
+    assert_eq!(slo.window_at(60, 60), (3, 1));
+    assert_eq!(slo.window_at(60, 360), (3, 2));
+
+    // Buckets are reused once their minute is out of all the windows
+    slo.record_at(360, true);
+    assert_eq!(slo.window_at(360, 360), (4, 1));
+  }
+
+  #[test]
+  fn test_burn_rate() {
+    let slo = Slo::new(SloConfig::default());
+    assert_eq!(slo.burn_rate(0, 0), 0.0);
+    assert!((slo.burn_rate(99, 1) - 1.0).abs() < 1e-9);
+    assert!((slo.burn_rate(98, 2) - 2.0).abs() < 1e-9);
+  }
+
+  #[test]
+  fn test_record() {
+    let slo = Slo::new(SloConfig { latency: Duration::from_millis(100), target: 0.9 });
+    slo.record(StatusCode::OK, Duration::from_millis(50));
+    slo.record(StatusCode::NOT_FOUND, Duration::from_millis(50));
+    slo.record(StatusCode::OK, Duration::from_millis(150));
+    slo.record(StatusCode::SERVICE_UNAVAILABLE, Duration::from_millis(50));
+    assert_eq!(slo.window_at(slo.minute(), 5), (2, 2));
+  }
+
+}
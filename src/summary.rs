@@ -0,0 +1,189 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{Metric, MetricFamily, MetricType, Quantile, Summary};
+
+/// Default quantiles exported by the summaries.
+pub const DEFAULT_QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// Default time window the quantiles are computed over.
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(600);
+
+/// Maximum number of observations kept to compute the quantiles, the oldest being dropped first.
+const MAX_SAMPLES: usize = 10_000;
+
+struct Samples {
+  quantiles: Vec<f64>,
+  window: Duration,
+  observations: VecDeque<(Instant, f64)>,
+  count: u64,
+  sum: f64
+}
+
+impl Samples {
+
+  fn prune(&mut self, now: Instant) {
+    while self.observations.front().is_some_and(|(t, _)| now.duration_since(*t) > self.window) {
+      self.observations.pop_front();
+    }
+  }
+
+}
+
+/// A Prometheus summary computing its quantiles over the observations of a sliding time window.
+///
+/// The Prometheus client only provides histograms, whose quantiles depend on the buckets.
+/// At most the last 10000 observations are kept, so under heavy traffic the quantiles
+/// cover a shorter time than the window.
+#[derive(Clone)]
+pub struct LatencySummary {
+  desc: Desc,
+  samples: Arc<Mutex<Samples>>
+}
+
+impl LatencySummary {
+
+  pub fn new(name: &str, help: &str) -> Self {
+    LatencySummary {
+      desc: Desc::new(name.to_string(), help.to_string(), vec![], Default::default()).expect("Invalid summary descriptor"),
+      samples: Arc::new(Mutex::new(Samples {
+        quantiles: DEFAULT_QUANTILES.to_vec(),
+        window: DEFAULT_WINDOW,
+        observations: VecDeque::new(),
+        count: 0,
+        sum: 0.0
+      }))
+    }
+  }
+
+  /// Sets the exported quantiles, and the time window they are computed over.
+  pub fn configure(&self, quantiles: &[f64], window: Duration) {
+    let mut samples = self.samples.lock().unwrap();
+    samples.quantiles = quantiles.to_vec();
+    samples.window = window;
+  }
+
+  pub fn observe(&self, value: f64) {
+    let now = Instant::now();
+    let mut samples = self.samples.lock().unwrap();
+    samples.count += 1;
+    samples.sum += value;
+    samples.prune(now);
+    if samples.observations.len() >= MAX_SAMPLES {
+      samples.observations.pop_front();
+    }
+    samples.observations.push_back((now, value));
+  }
+
+  /// Returns the configured quantiles of the observations in the window, by the nearest rank method.
+  fn quantiles(&self) -> Vec<(f64, f64)> {
+    let mut samples = self.samples.lock().unwrap();
+    samples.prune(Instant::now());
+    let mut values: Vec<f64> = samples.observations.iter().map(|(_, value)| *value).collect();
+    values.sort_by(|a, b| a.total_cmp(b));
+    samples.quantiles.iter()
+      .map(|q| {
+        let value = if values.is_empty() {
+          f64::NAN
+        } else {
+          let rank = (q * values.len() as f64).ceil() as usize;
+          values[rank.clamp(1, values.len()) - 1]
+        };
+        (*q, value)
+      })
+      .collect()
+  }
+
+}
+
+impl Collector for LatencySummary {
+
+  fn desc(&self) -> Vec<&Desc> {
+    vec![&self.desc]
+  }
+
+  fn collect(&self) -> Vec<MetricFamily> {
+    let quantiles = self.quantiles()
+      .into_iter()
+      .map(|(q, value)| {
+        let mut quantile = Quantile::default();
+        quantile.set_quantile(q);
+        quantile.set_value(value);
+        quantile
+      })
+      .collect::<Vec<_>>();
+
+    let mut summary = Summary::default();
+    {
+      let samples = self.samples.lock().unwrap();
+      summary.set_sample_count(samples.count);
+      summary.set_sample_sum(samples.sum);
+    }
+    summary.set_quantile(quantiles.into());
+
+    let mut metric = Metric::default();
+    metric.set_summary(summary);
+    let mut family = MetricFamily::default();
+    family.set_name(self.desc.fq_name.clone());
+    family.set_help(self.desc.help.clone());
+    family.set_field_type(MetricType::SUMMARY);
+    family.mut_metric().push(metric);
+    vec![family]
+  }
+
+}
+
+/// Parses a comma separated list of quantiles, like `0.5,0.9,0.99`.
+pub fn parse_quantiles(s: &str) -> anyhow::Result<Vec<f64>> {
+  s.split(',')
+    .map(str::trim)
+    .filter(|q| !q.is_empty())
+    .map(|q| {
+      let q: f64 = q.parse()?;
+      if !(0.0..=1.0).contains(&q) {
+        return Err(anyhow::anyhow!("Quantiles must be between 0 and 1"));
+      }
+      Ok(q)
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_quantiles() {
+    let summary = LatencySummary::new("test_summary", "Test summary");
+    assert!(summary.quantiles()[0].1.is_nan());
+
+    for value in 1..=100 {
+      summary.observe(value as f64);
+    }
+    assert_eq!(summary.quantiles(), vec![(0.5, 50.0), (0.9, 90.0), (0.99, 99.0)]);
+
+    let family = &summary.collect()[0];
+    assert_eq!(family.get_field_type(), MetricType::SUMMARY);
+    assert_eq!(family.get_metric()[0].get_summary().get_sample_count(), 100);
+    assert_eq!(family.get_metric()[0].get_summary().get_sample_sum(), 5050.0);
+  }
+
+  #[test]
+  fn test_window() {
+    let summary = LatencySummary::new("test_summary", "Test summary");
+    summary.configure(&[1.0], Duration::ZERO);
+    summary.observe(1.0);
+    std::thread::sleep(Duration::from_millis(5));
+    assert!(summary.quantiles()[0].1.is_nan());
+  }
+
+  #[test]
+  fn test_parse_quantiles() {
+    assert_eq!(parse_quantiles("0.5, 0.99").unwrap(), vec![0.5, 0.99]);
+    assert!(parse_quantiles("1.5").is_err());
+    assert!(parse_quantiles("median").is_err());
+  }
+
+}
@@ -0,0 +1,414 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use rand::Rng;
+use tracing::field::Empty;
+use tracing::{Instrument, Span, debug_span, error_span, info_span, warn};
+use warp::http::HeaderMap;
+use warp::Filter;
+
+use crate::metrics;
+use crate::request_stats::{self, RequestStats};
+
+tokio::task_local! {
+  static CURRENT: (TraceContext, Vec<Propagator>);
+}
+
+/// Sampled flag of the W3C trace context.
+const FLAG_SAMPLED: u8 = 0x01;
+
+/// A trace context, propagated from the incoming requests to the requests made to the upstream APIs.
+/// It is modelled after the [W3C trace context](https://www.w3.org/TR/trace-context/).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+  pub trace_id: u128,
+  /// Id of the span which made the request: the caller for incoming requests, this service for outgoing ones.
+  pub parent_id: u64,
+  pub flags: u8,
+  /// Vendor specific data, forwarded untouched.
+  pub tracestate: Option<String>
+}
+
+/// Header formats used to read and write the trace context.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Propagator {
+  /// W3C `traceparent` and `tracestate` headers.
+  W3c,
+  /// Zipkin single `b3` header.
+  B3,
+  /// Zipkin `X-B3-*` headers.
+  B3Multi
+}
+
+impl std::str::FromStr for Propagator {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "w3c" | "tracecontext" => Ok(Propagator::W3c),
+      "b3" => Ok(Propagator::B3),
+      "b3multi" => Ok(Propagator::B3Multi),
+      other => Err(anyhow!("Unknown trace propagator: {}", other))
+    }
+  }
+}
+
+/// How the trace context is propagated and sampled.
+#[derive(Clone, Debug)]
+pub struct TraceConfig {
+  pub propagators: Vec<Propagator>,
+  /// Probability, between `0` and `1`, for a new trace to be sampled.
+  /// Traces started by the callers keep their own sampling decision.
+  pub sample_ratio: f64,
+  /// Record the failed requests even if their trace is not sampled.
+  pub sample_errors: bool,
+  /// Requests slower than this get logged with the breakdown of their timings.
+  pub slow_request_threshold: Option<Duration>
+}
+
+impl Default for TraceConfig {
+  fn default() -> Self {
+    TraceConfig {
+      propagators: vec![Propagator::W3c],
+      sample_ratio: 1.0,
+      sample_errors: true,
+      slow_request_threshold: None
+    }
+  }
+}
+
+/// Parses a comma separated list of propagators.
+pub fn parse_propagators(s: &str) -> Result<Vec<Propagator>> {
+  s.split(',')
+    .map(str::trim)
+    .filter(|s| !s.is_empty())
+    .map(str::parse)
+    .collect()
+}
+
+fn parse_hex_id(s: &str, lens: &[usize]) -> Option<u128> {
+  if !lens.contains(&s.len()) || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+    return None;
+  }
+  u128::from_str_radix(s, 16).ok().filter(|id| *id != 0)
+}
+
+impl TraceContext {
+
+  /// Starts a new trace.
+  pub fn new_root(sampled: bool) -> Self {
+    let mut rng = rand::thread_rng();
+    TraceContext {
+      trace_id: rng.gen_range(1..=u128::MAX),
+      parent_id: rng.gen_range(1..=u64::MAX),
+      flags: if sampled { FLAG_SAMPLED } else { 0 },
+      tracestate: None
+    }
+  }
+
+  /// Parses the value of a `traceparent` header, returning `None` if it is invalid.
+  pub fn parse(traceparent: &str, tracestate: Option<String>) -> Option<Self> {
+
+    let parts = traceparent.trim().split('-').collect::<Vec<_>>();
+    if parts.len() < 4 {
+      return None;
+    }
+
+    // Version 00 has exactly four fields, future versions might append more
+    let version = u8::from_str_radix(parts[0], 16).ok().filter(|_| parts[0].len() == 2)?;
+    if version == 0xff || (version == 0 && parts.len() != 4) {
+      return None;
+    }
+
+    let trace_id = parse_hex_id(parts[1], &[32])?;
+    let parent_id = parse_hex_id(parts[2], &[16])? as u64;
+    let flags = u8::from_str_radix(parts[3], 16).ok().filter(|_| parts[3].len() == 2)?;
+
+    Some(TraceContext { trace_id, parent_id, flags, tracestate })
+
+  }
+
+  /// Parses the value of a single `b3` header, returning `None` if it is invalid.
+  pub fn parse_b3(b3: &str) -> Option<Self> {
+
+    let parts = b3.trim().split('-').collect::<Vec<_>>();
+
+    // A lone sampling decision means that the caller does not want the request to be traced
+    if parts.len() == 1 && parts[0] == "0" {
+      return Some(Self::new_root(false));
+    }
+    if parts.len() < 2 || parts.len() > 4 {
+      return None;
+    }
+
+    let trace_id = parse_hex_id(parts[0], &[16, 32])?;
+    let parent_id = parse_hex_id(parts[1], &[16])? as u64;
+    let flags = match parts.get(2) {
+      Some(&"0") => 0,
+      Some(&"1") | Some(&"d") | None => FLAG_SAMPLED,
+      Some(_) => return None
+    };
+
+    Some(TraceContext { trace_id, parent_id, flags, tracestate: None })
+
+  }
+
+  /// Builds the context from the `X-B3-*` headers, returning `None` if they are missing or invalid.
+  pub fn parse_b3_multi(trace_id: &str, span_id: &str, sampled: Option<&str>, debug: Option<&str>) -> Option<Self> {
+    let flags = match (sampled, debug) {
+      (_, Some("1")) => FLAG_SAMPLED,
+      (Some("0"), _) | (Some("false"), _) => 0,
+      _ => FLAG_SAMPLED
+    };
+    Some(TraceContext {
+      trace_id: parse_hex_id(trace_id.trim(), &[16, 32])?,
+      parent_id: parse_hex_id(span_id.trim(), &[16])? as u64,
+      flags,
+      tracestate: None
+    })
+  }
+
+  /// Reads the context from the headers of an incoming request, trying the propagators in order.
+  pub fn extract(headers: &HeaderMap, propagators: &[Propagator]) -> Option<Self> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    propagators.iter()
+      .find_map(|propagator| match propagator {
+        Propagator::W3c => Self::parse(header("traceparent")?, header("tracestate").map(str::to_string)),
+        Propagator::B3 => Self::parse_b3(header("b3")?),
+        Propagator::B3Multi => Self::parse_b3_multi(
+          header("x-b3-traceid")?,
+          header("x-b3-spanid")?,
+          header("x-b3-sampled"),
+          header("x-b3-flags")
+        )
+      })
+  }
+
+  /// Returns the context to propagate to the upstreams, with a new span id for this service.
+  pub fn child(&self) -> Self {
+    TraceContext {
+      parent_id: rand::thread_rng().gen_range(1..=u64::MAX),
+      ..self.clone()
+    }
+  }
+
+  pub fn is_sampled(&self) -> bool {
+    self.flags & FLAG_SAMPLED != 0
+  }
+
+  /// Formats the context as a `traceparent` header value.
+  pub fn traceparent(&self) -> String {
+    format!("00-{:032x}-{:016x}-{:02x}", self.trace_id, self.parent_id, self.flags)
+  }
+
+  /// Returns the headers carrying this context in the formats of the given propagators.
+  pub fn headers(&self, propagators: &[Propagator]) -> Vec<(&'static str, String)> {
+    let sampled = if self.is_sampled() { "1" } else { "0" };
+    let mut headers = Vec::new();
+    for propagator in propagators {
+      match propagator {
+        Propagator::W3c => {
+          headers.push(("traceparent", self.traceparent()));
+          if let Some(tracestate) = &self.tracestate {
+            headers.push(("tracestate", tracestate.clone()));
+          }
+        },
+        Propagator::B3 => {
+          headers.push(("b3", format!("{:032x}-{:016x}-{}", self.trace_id, self.parent_id, sampled)));
+        },
+        Propagator::B3Multi => {
+          headers.push(("x-b3-traceid", format!("{:032x}", self.trace_id)));
+          headers.push(("x-b3-spanid", format!("{:016x}", self.parent_id)));
+          headers.push(("x-b3-sampled", sampled.to_string()));
+        }
+      }
+    }
+    headers
+  }
+
+}
+
+/// Warp filter extracting the trace context from the headers of the request.
+/// A new trace is started, and sampled according to the configured ratio, if the headers do not carry a valid context.
+pub fn filter(config: TraceConfig) -> impl Filter<Extract = (TraceContext,), Error = Infallible> + Clone {
+  warp::header::headers_cloned()
+    .map(move |headers: HeaderMap| {
+      TraceContext::extract(&headers, &config.propagators)
+        .unwrap_or_else(|| TraceContext::new_root(rand::thread_rng().gen::<f64>() < config.sample_ratio))
+        .child()
+    })
+}
+
+/// Reports the stats collected while handling a request on its span.
+fn record_stats(span: &Span, stats: &RequestStats) {
+  if let Some(pokemon) = &stats.pokemon {
+    span.record("pokemon.name", pokemon.as_str());
+  }
+  if let Some(cache_hit) = stats.cache_hit {
+    span.record("cache.hit", cache_hit);
+  }
+  if let Some(translator) = &stats.translator {
+    span.record("translator.used", translator.as_str());
+  }
+  span.record("upstream.requests", stats.upstream_requests);
+}
+
+/// Runs the handler of a request with the given trace context, so that the upstream clients can propagate it.
+///
+/// Sampled traces get an `info` level span, the others a `debug` level one.
+/// If the handler fails and errors must always be sampled, an `error` level span is recorded anyway.
+/// The stats collected while handling the request are reported as fields of the span,
+/// and logged if the request is slower than the configured threshold.
+pub async fn scope<T, E, F>(context: TraceContext, config: &TraceConfig, f: F) -> Result<T, E>
+  where F: Future<Output = Result<T, E>>
+{
+  let trace_id = format!("{:032x}", context.trace_id);
+  let sampled = context.is_sampled();
+  let span = if sampled {
+    info_span!("trace", %trace_id, pokemon.name = Empty, cache.hit = Empty, translator.used = Empty, upstream.requests = Empty)
+  } else {
+    debug_span!("trace", %trace_id, pokemon.name = Empty, cache.hit = Empty, translator.used = Empty, upstream.requests = Empty)
+  };
+
+  let started = Instant::now();
+  let (res, stats) = request_stats::collect(CURRENT.scope((context, config.propagators.clone()), f))
+    .instrument(span.clone())
+    .await;
+  let elapsed = started.elapsed();
+  record_stats(&span, &stats);
+
+  if config.slow_request_threshold.is_some_and(|threshold| elapsed > threshold) {
+    metrics::SLOW_REQUESTS.inc();
+    span.in_scope(|| {
+      warn!(elapsed_ms = elapsed.as_millis() as u64, breakdown = %stats.breakdown(), "Slow request");
+    });
+  }
+
+  if res.is_err() && !sampled && config.sample_errors {
+    let span = error_span!("trace", %trace_id, sampled = false, pokemon.name = Empty, cache.hit = Empty, translator.used = Empty, upstream.requests = Empty);
+    record_stats(&span, &stats);
+    span.in_scope(|| {
+      warn!("Request failed in a trace which was not sampled");
+    });
+  }
+
+  res
+}
+
+/// Returns the headers to add to the upstream requests to propagate the current trace context.
+pub fn outgoing_headers() -> Vec<(&'static str, String)> {
+  CURRENT.try_with(|(context, propagators)| context.headers(propagators)).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    let context = TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01", Some("congo=t61rcWkgMzE".to_string())).unwrap();
+    assert_eq!(context.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+    assert_eq!(context.parent_id, 0x00f067aa0ba902b7);
+    assert_eq!(context.flags, 0x01);
+    assert_eq!(context.traceparent(), "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+
+    // Future versions can have more fields
+    assert!(TraceContext::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra", None).is_some());
+
+    // Invalid values
+    assert!(TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra", None).is_none());
+    assert!(TraceContext::parse("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01", None).is_none());
+    assert!(TraceContext::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01", None).is_none());
+    assert!(TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01", None).is_none());
+    assert!(TraceContext::parse("00-4bf92f3577b34da6-00f067aa0ba902b7-01", None).is_none());
+    assert!(TraceContext::parse("garbage", None).is_none());
+  }
+
+  #[test]
+  fn test_parse_b3() {
+    let context = TraceContext::parse_b3("80f198ee56343ba864fe8b2a57d3eff7-e457b5a2e4d86bd1-1-05e3ac9a4f6e3b90").unwrap();
+    assert_eq!(context.trace_id, 0x80f198ee56343ba864fe8b2a57d3eff7);
+    assert_eq!(context.parent_id, 0xe457b5a2e4d86bd1);
+    assert!(context.is_sampled());
+
+    // 64 bit trace ids are accepted, and the sampling state is optional
+    let context = TraceContext::parse_b3("64fe8b2a57d3eff7-e457b5a2e4d86bd1").unwrap();
+    assert_eq!(context.trace_id, 0x64fe8b2a57d3eff7);
+
+    assert!(!TraceContext::parse_b3("64fe8b2a57d3eff7-e457b5a2e4d86bd1-0").unwrap().is_sampled());
+    assert!(!TraceContext::parse_b3("0").unwrap().is_sampled());
+    assert!(TraceContext::parse_b3("64fe8b2a57d3eff7-e457b5a2e4d86bd1-x").is_none());
+    assert!(TraceContext::parse_b3("64fe8b2a57d3eff7").is_none());
+  }
+
+  #[test]
+  fn test_extract_and_inject() {
+    let mut headers = HeaderMap::new();
+    headers.insert("x-b3-traceid", "80f198ee56343ba864fe8b2a57d3eff7".parse().unwrap());
+    headers.insert("x-b3-spanid", "e457b5a2e4d86bd1".parse().unwrap());
+    headers.insert("x-b3-sampled", "0".parse().unwrap());
+
+    // Propagators are tried in order, skipping the ones without headers
+    let context = TraceContext::extract(&headers, &[Propagator::W3c, Propagator::B3Multi]).unwrap();
+    assert_eq!(context.trace_id, 0x80f198ee56343ba864fe8b2a57d3eff7);
+    assert!(!context.is_sampled());
+
+    // Without a matching propagator a new trace has to be started
+    assert!(TraceContext::extract(&headers, &[Propagator::W3c]).is_none());
+
+    assert_eq!(context.headers(&[Propagator::W3c, Propagator::B3]), vec![
+      ("traceparent", "00-80f198ee56343ba864fe8b2a57d3eff7-e457b5a2e4d86bd1-00".to_string()),
+      ("b3", "80f198ee56343ba864fe8b2a57d3eff7-e457b5a2e4d86bd1-0".to_string())
+    ]);
+  }
+
+  #[test]
+  fn test_child_keeps_trace() {
+    let parent = TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01", Some("a=b".to_string())).unwrap();
+    let child = parent.child();
+    assert_eq!(child.trace_id, parent.trace_id);
+    assert_eq!(child.tracestate.as_deref(), Some("a=b"));
+    assert_ne!(child.parent_id, parent.parent_id);
+  }
+
+  #[tokio::test]
+  async fn test_scope() {
+    assert!(outgoing_headers().is_empty());
+    let context = TraceContext::new_root(true);
+    let headers = scope(context.clone(), &TraceConfig::default(), async { Ok::<_, ()>(outgoing_headers()) }).await;
+    assert_eq!(headers, Ok(vec![("traceparent", context.traceparent())]));
+  }
+
+  #[tokio::test]
+  async fn test_sampling_ratio() {
+    let sampled = |sample_ratio| {
+      let filter = filter(TraceConfig { sample_ratio, ..TraceConfig::default() });
+      async move { warp::test::request().filter(&filter).await.unwrap().is_sampled() }
+    };
+    assert!(sampled(1.0).await);
+    assert!(!sampled(0.0).await);
+
+    // The decision of the caller always wins
+    let filter = filter(TraceConfig { sample_ratio: 0.0, ..TraceConfig::default() });
+    let context = warp::test::request()
+      .header("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+      .filter(&filter)
+      .await
+      .unwrap();
+    assert!(context.is_sampled());
+  }
+
+  #[tokio::test]
+  async fn test_slow_requests_are_counted() {
+    let config = TraceConfig { slow_request_threshold: Some(Duration::from_millis(5)), ..TraceConfig::default() };
+    let before = metrics::SLOW_REQUESTS.get();
+    let _ = scope(TraceContext::new_root(true), &config, async {
+      tokio::time::sleep(Duration::from_millis(10)).await;
+      Ok::<_, ()>(())
+    }).await;
+    assert!(metrics::SLOW_REQUESTS.get() > before);
+  }
+
+}
@@ -0,0 +1,132 @@
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, anyhow};
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use serde::Serialize;
+use sha2::Sha256;
+use tracing::{debug, warn};
+
+/// Header carrying the signature of the payload.
+pub const SIGNATURE_HEADER: &str = "x-signature-256";
+
+/// Maximum time allowed to the receiver, so that a slow one cannot hold the jobs for long.
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How a job, or a round of a recurring job, ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+  Completed,
+  Failed
+}
+
+/// Payload sent to the webhook.
+#[derive(Serialize)]
+pub struct JobEvent<'a> {
+  pub job: &'a str,
+  pub kind: &'a str,
+  pub outcome: Outcome,
+  /// Number of items or rounds processed.
+  pub done: u64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+  /// Time of the event, in seconds since the Unix epoch, so that receivers can reject replayed payloads.
+  pub timestamp: u64
+}
+
+/// Endpoint notified when the background jobs end, so that external orchestration can chain on them.
+#[derive(Clone)]
+pub struct Webhook {
+  url: Url,
+  secret: Option<String>
+}
+
+impl fmt::Debug for Webhook {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Webhook")
+      .field("url", &self.url.as_str())
+      .field("secret", &self.secret.as_ref().map(|_| "***"))
+      .finish()
+  }
+}
+
+/// Signs a payload with HMAC-SHA256, formatted as `sha256=<hex digest>`.
+fn sign(secret: &str, payload: &[u8]) -> String {
+  let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+  mac.update(payload);
+  let digest = mac.finalize().into_bytes();
+  format!("sha256={}", digest.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+}
+
+pub fn now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+impl Webhook {
+
+  /// Notifies the given url, signing the payloads with `secret` if given.
+  pub fn new(url: Url, secret: Option<String>) -> Self {
+    Webhook { url, secret }
+  }
+
+  /// Sends an event, in the `X-Signature-256` header if a secret is configured.
+  pub async fn send(&self, event: &JobEvent<'_>) -> Result<()> {
+    let payload = serde_json::to_vec(event)?;
+    let mut req = reqwest::Client::new()
+      .post(self.url.clone())
+      .header("content-type", "application/json")
+      .timeout(SEND_TIMEOUT);
+    if let Some(secret) = &self.secret {
+      req = req.header(SIGNATURE_HEADER, sign(secret, &payload));
+    }
+    let res = req.body(payload).send().await?;
+    if !res.status().is_success() {
+      return Err(anyhow!("HTTP error: {}", res.status()));
+    }
+    debug!(job = event.job, outcome = ?event.outcome, "Webhook notified");
+    Ok(())
+  }
+
+  /// Sends an event, logging the failures: the jobs do not depend on the receiver.
+  pub async fn notify(&self, event: &JobEvent<'_>) {
+    if let Err(e) = self.send(event).await {
+      warn!(job = event.job, error = %e, "Cannot notify the job webhook");
+    }
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use httpmock::{MockServer, Method};
+
+  #[tokio::test]
+  async fn test_signed_payload() {
+
+    let event = JobEvent { job: "daily-1", kind: "daily", outcome: Outcome::Failed, done: 3, error: Some("HTTP error: 500".to_string()), timestamp: 1_600_000_000 };
+    let payload = serde_json::to_vec(&event).unwrap();
+    let server = MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.method(Method::POST)
+        .path("/hooks/jobs")
+        .header(SIGNATURE_HEADER, &sign("secret", &payload))
+        .json_body(serde_json::json!({ "job": "daily-1", "kind": "daily", "outcome": "failed", "done": 3, "error": "HTTP error: 500", "timestamp": 1_600_000_000 }));
+      then.status(204);
+    }).await;
+
+    let webhook = Webhook::new(Url::parse(&server.url("/hooks/jobs")).unwrap(), Some("secret".to_string()));
+    webhook.send(&event).await.unwrap();
+    mock.assert();
+
+  }
+
+  #[test]
+  fn test_sign() {
+    // Reference value from `echo -n '{}' | openssl dgst -sha256 -hmac secret`
+    assert_eq!(sign("secret", b"{}"), "sha256=77325902caca812dc259733aacd046b73817372c777b8d95b402647474516e13");
+  }
+
+}